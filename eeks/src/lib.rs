@@ -109,6 +109,12 @@ impl<'a> ExtensionStorageLoader<'a> {
 		self.world.spawn()
 	}
 
+	/// Handles global commands starting with this keyword, which are given the rest of the command. 
+	pub fn command(&mut self, keyword: impl AsRef<str>, function: ExtensionCommand) -> &mut Self {
+		self.storages.commands.push((keyword.as_ref().to_string(), function));
+		self
+	}
+
 	// Should have functions to access world
 	// Some resources might need info from other resources 
 	// But that's outside of our current scope 
@@ -206,10 +212,16 @@ pub enum DirtyLevel {
 }
 
 
+/// A global command provided by an extension. 
+pub type ExtensionCommand = fn(&World, &[&str]) -> anyhow::Result<String>;
+
+
 #[derive(Debug, Default)]
 pub struct ExtensionStorages {
 	pub components: Vec<String>,
 	pub resources: Vec<String>,
+	// Removed with the extension so that nothing points into its library
+	pub commands: Vec<(String, ExtensionCommand)>,
 }
 
 
@@ -1199,6 +1211,13 @@ impl ExtensionRegistry {
 			_ => {
 				info!("Global command '{}'", keyword);
 				// I've decided that running commands doesn't need to be optimized 
+				let native = self.extensions.iter()
+					.filter_map(|e| e.library.as_ref()?.storages.as_ref().map(|s| (e, s)))
+					.find_map(|(e, s)| s.commands.iter().find(|(c, _)| c == keyword).map(|(_, f)| (e, *f)));
+				if let Some((e, f)) = native {
+					trace!("Command '{}' from '{}'", keyword, e.name);
+					return f(world, &command[1..]);
+				}
 				for e in self.lua_extensions.iter() {
 					if let Some(l) = e.library.as_ref() {
 						for command in l.commands.iter() {
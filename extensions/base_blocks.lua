@@ -9,7 +9,17 @@ local extension = {}
 
 function extension.systems()
 	add_system(new_system("client_init", "load_base_blocks"))
-	add_system(new_system("on_placed_grass", "print_grass_placement"))
+	-- Block event handlers run between dispatch and resolution
+	-- Lua can't register handlers with BlockEventResource, it looks for its events here instead
+	local grass = new_system("client_tick", "print_grass_placement")
+	grass:run_after("block_event_dispatch")
+	grass:run_before("block_event_resolution")
+	add_system(grass)
+	-- Block tick handlers run between collection and application
+	local ticks = new_system("client_tick", "base_block_ticks")
+	ticks:run_after("block_tick_collection")
+	ticks:run_before("block_tick_application")
+	add_system(ticks)
 end
 
 -- function extension.load(world)
@@ -92,18 +102,227 @@ function extension.load_base_blocks(world)
 		on_interact: false,
 		on_break: false,
 	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "water",
+		render_type: Colour,
+		floats: {
+			"colour": [0.120, 0.250, 0.700, 0.6],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		liquid: Some((
+			spread: 2,
+		)),
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "dirt_pillar",
+		render_type: Cube(
+			xp: Path("/materials/dirt.ron"),
+			xn: Path("/materials/dirt.ron"),
+			yp: Path("/materials/grass_top.ron"),
+			yn: Path("/materials/grass_top.ron"),
+			zp: Path("/materials/dirt.ron"),
+			zn: Path("/materials/dirt.ron"),
+		),
+		floats: {
+			"colour": [0.200, 0.154, 0.108, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		properties: {
+			"axis": Axis,
+		},
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "stone_slab",
+		render_type: Model(
+			mesh: Path("/meshes/slab.obj"),
+			material: Path("/materials/stone.ron"),
+			covers: (yn: true),
+		),
+		floats: {
+			"colour": [0.230, 0.230, 0.230, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		properties: {
+			"facing": Facing,
+		},
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "glass",
+		render_type: Cube(
+			xp: Path("/materials/glass.ron"),
+			xn: Path("/materials/glass.ron"),
+			yp: Path("/materials/glass.ron"),
+			yn: Path("/materials/glass.ron"),
+			zp: Path("/materials/glass.ron"),
+			zn: Path("/materials/glass.ron"),
+		),
+		floats: {
+			"colour": [0.700, 0.850, 0.900, 0.3],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		opacity: Translucent,
+		light_filter: Some((0.8, 0.9, 1.0)),
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "leaves",
+		render_type: Cube(
+			xp: Path("/materials/leaves.ron"),
+			xn: Path("/materials/leaves.ron"),
+			yp: Path("/materials/leaves.ron"),
+			yn: Path("/materials/leaves.ron"),
+			zp: Path("/materials/leaves.ron"),
+			zn: Path("/materials/leaves.ron"),
+		),
+		floats: {
+			"colour": [0.150, 0.400, 0.120, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		opacity: Cutout,
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "log",
+		render_type: Colour,
+		floats: {
+			"colour": [0.400, 0.280, 0.160, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		properties: {
+			"axis": Axis,
+		},
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "wheat",
+		render_type: Colour,
+		floats: {
+			"colour": [0.800, 0.700, 0.250, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		opacity: Cutout,
+		properties: {
+			"age": Values(["0", "1", "2", "3"]),
+		},
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "glowstone",
+		render_type: Colour,
+		floats: {
+			"colour": [0.950, 0.800, 0.450, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		light_emission: Some((15, 12, 6)),
+	)]], materials))
+	assert(br:register_block_from_string([[(
+		name: "sign",
+		render_type: Colour,
+		floats: {
+			"colour": [0.550, 0.400, 0.220, 1.0],
+		},
+		sounds: {},
+		on_place: false,
+		on_interact: false,
+		on_break: false,
+		instance_data: Some({
+			"text": Text(""),
+		}),
+	)]], materials))
 end
 
 function extension.print_grass_placement(world)
-	print("A grass voxel was placed")
-	-- get block resource
-	-- Iterate over all placed grasses
-	-- block place resource will be cleared automatically
+	local events = get_resource("BlockEventResource")
+	for _, i in ipairs(events:events("place", "grass")) do
+		local x, y, z = events:position(i)
+		print("A grass voxel was placed at " .. x .. " " .. y .. " " .. z)
+	end
+end
+
+function extension.base_block_ticks(world)
+	local ticks = get_resource("BlockTickResource")
+	local terrain = get_resource("TerrainResource")
+	local chunks = get_resource("ChunksResource")
+	local blocks = get_resource("BlockResource")
+
+	-- Grass spreads to nearby dirt that is not covered
+	for _, i in ipairs(ticks:ticks("random", "grass")) do
+		local x, y, z = ticks:position(i)
+		local nx = x + ticks:random(-1, 1)
+		local ny = y + ticks:random(-1, 1)
+		local nz = z + ticks:random(-1, 1)
+		if terrain:block(chunks, blocks, nx, ny, nz) == "dirt" and terrain:block(chunks, blocks, nx, ny + 1, nz) == nil then
+			ticks:set_block(nx, ny, nz, "grass", blocks)
+		end
+	end
+
+	-- Wheat grows on dirt or grass
+	for _, i in ipairs(ticks:ticks("random", "wheat")) do
+		local x, y, z = ticks:position(i)
+		local age = tonumber(string.match(terrain:block(chunks, blocks, x, y, z), "age=(%d)"))
+		local below = terrain:block(chunks, blocks, x, y - 1, z)
+		if age < 3 and (below == "dirt" or below == "grass") and ticks:random(1, 3) == 1 then
+			ticks:set_block(x, y, z, "wheat[age=" .. (age + 1) .. "]", blocks)
+		end
+	end
+
+	-- Leaves decay when there is no log nearby
+	for _, i in ipairs(ticks:ticks("random", "leaves")) do
+		local x, y, z = ticks:position(i)
+		local supported = false
+		for dx = -3, 3 do
+			for dy = -3, 3 do
+				for dz = -3, 3 do
+					local b = terrain:block(chunks, blocks, x + dx, y + dy, z + dz)
+					if b ~= nil and string.match(b, "^log") then
+						supported = true
+						break
+					end
+				end
+				if supported then break end
+			end
+			if supported then break end
+		end
+		if not supported then
+			ticks:set_block(x, y, z, "air", blocks)
+		end
+	end
 
-	-- block system borrows entire world
-	-- runs on placed workload for each entry in placed list
-	-- clears placed list
-	-- map.place(thing, blockmanager)
+	-- Sand falls until it lands on something
+	local function fall(i)
+		local x, y, z = ticks:position(i)
+		if terrain:block(chunks, blocks, x, y - 1, z) == nil then
+			ticks:set_block(x, y, z, "air", blocks)
+			ticks:set_block(x, y - 1, z, "sand", blocks)
+			ticks:schedule(x, y - 1, z, 2)
+		end
+	end
+	for _, i in ipairs(ticks:ticks("random", "sand")) do
+		fall(i)
+	end
+	for _, i in ipairs(ticks:ticks("scheduled", "sand")) do
+		fall(i)
+	end
 end
 
 return extension
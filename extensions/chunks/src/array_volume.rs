@@ -1,7 +1,20 @@
 use glam::UVec3;
+use crate::cube_iterator_xyz_uvec;
 
 
 
+/// Storage for a cuboid of voxels, each of which might be empty. 
+pub trait VoxelVolume<V> {
+	/// Size in voxels. 
+	fn extent(&self) -> UVec3;
+	fn get(&self, position: UVec3) -> Option<&V>;
+	fn insert(&mut self, position: UVec3, data: V);
+	fn remove(&mut self, position: UVec3);
+	/// Positions and contents of the voxels that are not empty. 
+	fn iter<'a>(&'a self) -> impl Iterator<Item = (UVec3, &'a V)> + 'a where V: 'a;
+}
+
+
 #[derive(Clone, Debug)]
 pub struct ArrayVolume<V: std::fmt::Debug> {
 	pub size: UVec3,
@@ -126,3 +139,26 @@ impl<V: Clone + std::fmt::Debug + PartialEq + Eq> ArrayVolume<V> {
 		s
 	}
 }
+impl<V: Clone + std::fmt::Debug> VoxelVolume<V> for ArrayVolume<V> {
+	fn extent(&self) -> UVec3 {
+		self.size
+	}
+
+	fn get(&self, position: UVec3) -> Option<&V> {
+		ArrayVolume::get(self, position)
+	}
+
+	fn insert(&mut self, position: UVec3, data: V) {
+		ArrayVolume::insert(self, position, data)
+	}
+
+	fn remove(&mut self, position: UVec3) {
+		ArrayVolume::remove(self, position)
+	}
+
+	fn iter<'a>(&'a self) -> impl Iterator<Item = (UVec3, &'a V)> + 'a where V: 'a {
+		cube_iterator_xyz_uvec(self.size)
+			.filter(|_| !self.is_empty())
+			.filter_map(|p| ArrayVolume::get(self, p).map(|v| (p, v)))
+	}
+}
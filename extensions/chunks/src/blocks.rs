@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::ffi::OsStr;
@@ -14,6 +15,9 @@ use serde::{Serialize, Deserialize};
 use slotmap::SlotMap;
 use slotmap::new_key_type;
 use eeks::prelude::*;
+use crate::instance::BlockInstanceData;
+use crate::states::{BlockPropertySpecification, BlockState, BlockStates};
+use crate::textures::BlockTextures;
 
 
 
@@ -43,7 +47,7 @@ impl mlua::UserData for BlockResource {
 					specification.canonicalize("./").unwrap();
 					let e = BlockEntry::from_specification(specification, &mut materials).unwrap();
 					trace!("Lua registers block '{}'", e.specification.name);
-					this.blocks.write().insert(e);
+					this.blocks.write().insert_textured(e, &materials);
 
 					Ok(true)
 				},
@@ -80,6 +84,30 @@ pub struct BlockSpecification {
 	pub on_place: bool,
 	pub on_interact: bool,
 	pub on_break: bool,
+
+	// If some, placing this block creates a source of this liquid
+	#[serde(default)]
+	pub liquid: Option<LiquidSpecification>,
+
+	// If some, voxels of this block have instance data with these fields
+	// The values given here are the defaults
+	#[serde(default)]
+	pub instance_data: Option<BlockInstanceData>,
+
+	// Each combination of property values is a state of this block
+	#[serde(default)]
+	pub properties: BTreeMap<String, BlockPropertySpecification>,
+
+	#[serde(default)]
+	pub opacity: BlockOpacity,
+	// Light passing through this block is multiplied by this colour
+	// Only matters for blocks that let light through
+	#[serde(default)]
+	pub light_filter: Option<[f32; 3]>,
+	// If some, this block gives off torchlight of this colour
+	// Channels go from 0 to 15
+	#[serde(default)]
+	pub light_emission: Option<[u16; 3]>,
 }
 impl BlockSpecification {
 	pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
@@ -116,13 +144,38 @@ impl BlockSpecification {
 				yn.canonicalize(context)?;
 				zp.canonicalize(context)?;
 				zn.canonicalize(context)?;
-			}
+			},
+			BlockSpecificationRenderType::Model { mesh, material, .. } => {
+				mesh.canonicalize(context)?;
+				material.canonicalize(context)?;
+			},
 		}
 		Ok(())
 	}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How much a block hides what is behind it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockOpacity {
+	#[default]
+	Opaque,
+	// Fully see-through in places, like leaves
+	// Drawn with everything else, its material should discard the see-through parts
+	Cutout,
+	// Partially see-through, like glass or water
+	// Drawn after everything else, back to front
+	Translucent,
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct LiquidSpecification {
+	// How much level is lost for each voxel of sideways flow
+	pub spread: u8,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PathOrLabel {
 	Path(PathBuf),
 	Label(String),
@@ -158,11 +211,36 @@ pub enum BlockSpecificationRenderType {
 		zn: PathOrLabel,
 	}, // path or name
 	// ScriptedCube,
-	// Model,
+	// An obj mesh drawn once for every voxel of this block
+	Model {
+		mesh: PathOrLabel,
+		material: PathOrLabel,
+		// Faces that completely hide the neighbouring voxel's face
+		// A slab covers only yn, a plant covers nothing
+		#[serde(default)]
+		covers: BlockFaces,
+	},
 	// ScriptedModel,
 }
 
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BlockFaces {
+	pub xp: bool,
+	pub xn: bool,
+	pub yp: bool,
+	pub yn: bool,
+	pub zp: bool,
+	pub zn: bool,
+}
+impl BlockFaces {
+	pub fn to_array(self) -> [bool; 6] {
+		[self.xp, self.xn, self.yp, self.yn, self.zp, self.zn]
+	}
+}
+
+
 #[derive(Debug)]
 pub enum BlockRenderType {
 	// make sure floats hs colour value? Or else hash the name? 
@@ -172,7 +250,12 @@ pub enum BlockRenderType {
 	// Maybe it should be Option<[MaterialKey; 6]> and then we call a thing to pull unpulled keys
 	Cube([MaterialKey; 6]), 
 	// ScriptedCube,
-	// Model,
+	// Meshes are loaded by whatever draws them
+	Model {
+		mesh: PathOrLabel,
+		material: MaterialKey,
+		covers: [bool; 6],
+	},
 	// ScriptedModel,
 }
 
@@ -185,7 +268,8 @@ pub struct BlockEntry {
 	// This could have been Option<BlockRenderType> for more parallelization
 	// I very much doubt, however, that it's worth the effort
 	pub render_type: BlockRenderType, 
-	pub covering: bool, // transparent or is model
+	pub covering: bool, // false if not opaque or is model
+	pub states: BlockStates,
 }
 impl BlockEntry {
 	pub fn read(path: impl AsRef<Path>, materials: &mut MaterialManager) -> anyhow::Result<Self> {
@@ -219,16 +303,61 @@ impl BlockEntry {
 				get_thing(zp, materials),
 				get_thing(zn, materials),
 			]),
+			BlockSpecificationRenderType::Model { mesh, material, covers } => BlockRenderType::Model {
+				mesh: mesh.clone(),
+				material: get_thing(material, materials),
+				covers: covers.to_array(),
+			},
 		};
+		// Models don't fill their voxel
+		let covering = specification.opacity == BlockOpacity::Opaque
+			&& !matches!(render_type, BlockRenderType::Model { .. });
+
+		let states = BlockStates::new(&specification.properties);
+		if states.count() > u16::MAX as usize {
+			return Err(anyhow::anyhow!("Block '{}' has {} states, which is too many", specification.name, states.count()));
+		}
 
 		Ok(Self {
 			specification,
 			path: None,
 			render_type,
-			covering: true,
+			covering,
+			states,
 		})
 	}
 
+	/// Face materials indexed by world direction. 
+	pub fn face_materials(&self, state: BlockState) -> Option<[MaterialKey; 6]> {
+		match self.render_type {
+			BlockRenderType::Cube(faces) => Some(self.states.orientation(state).rotate(faces)),
+			_ => None,
+		}
+	}
+
+	/// Does light pass through this block?
+	pub fn transmits_light(&self) -> bool {
+		!self.covering
+	}
+
+	/// Should this be drawn in the translucent pass?
+	pub fn is_translucent(&self) -> bool {
+		self.specification.opacity == BlockOpacity::Translucent
+	}
+
+	/// Does this block fill its voxel? Models and liquids don't.
+	pub fn is_solid(&self) -> bool {
+		!matches!(self.render_type, BlockRenderType::Model { .. }) && self.specification.liquid.is_none()
+	}
+
+	/// Does this block completely hide the face of whatever is next to it in this (world) direction?
+	pub fn covers(&self, state: BlockState, direction: usize) -> bool {
+		match self.render_type {
+			BlockRenderType::Model { covers, .. } => self.states.orientation(state).rotate(covers)[direction],
+			_ => self.covering,
+		}
+	}
+
 	// Reads colour or hashes name
 	pub fn colour(&self) -> [f32; 4] {
 		self.specification.floats.get("colour")
@@ -255,15 +384,28 @@ pub struct BlockManager {
 	// Might want a rwlock so we can put this in an Arc and give to meshing threads
 	pub blocks: SlotMap<BlockKey, BlockEntry>,
 	key_by_name: HashMap<String, BlockKey>,
+	// Layers of the block texture array
+	pub textures: BlockTextures,
 }
 impl BlockManager {
 	pub fn new() -> Self {
 		Self {
 			blocks: SlotMap::with_key(),
 			key_by_name: HashMap::new(),
+			textures: BlockTextures::default(),
 		}
 	}
 
+	/// Inserts a block and gives the textures of its faces layers in the block texture array. 
+	pub fn insert_textured(&mut self, block: BlockEntry, materials: &MaterialManager) -> BlockKey {
+		if let BlockRenderType::Cube(faces) = &block.render_type {
+			for &material in faces {
+				self.textures.insert(material, materials);
+			}
+		}
+		self.insert(block)
+	}
+
 	pub fn insert(&mut self, block: BlockEntry) -> BlockKey {
 		if let Some(key) = self.key_by_name.get(&block.specification.name) {
 			warn!("Overwriting block '{}'", block.specification.name);
@@ -282,6 +424,27 @@ impl BlockManager {
 		self.blocks.get(key)
 	}
 
+	/// Parses a block with an optional state, such as "log" or "log[axis=x]". 
+	pub fn parse_block_state(&self, s: &str) -> Option<(BlockKey, BlockState)> {
+		let (name, values) = match s.split_once('[') {
+			Some((name, rest)) => (name, rest.strip_suffix(']')?),
+			None => (s, ""),
+		};
+		let key = self.key_by_name(&name.to_string())?;
+		let values = values.split(',')
+			.filter(|v| !v.is_empty())
+			.map(|v| v.split_once('='))
+			.collect::<Option<Vec<_>>>()?;
+		let state = self.get(key)?.states.state(values)?;
+		Some((key, state))
+	}
+
+	/// The inverse of [Self::parse_block_state].
+	pub fn block_state_string(&self, key: BlockKey, state: BlockState) -> Option<String> {
+		let e = self.get(key)?;
+		Some(format!("{}{}", e.specification.name, e.states.describe(state)))
+	}
+
 	pub fn key_by_name(&self, name: &String) -> Option<BlockKey> {
 		self.key_by_name.get(name).copied()
 	}
@@ -336,7 +499,7 @@ pub fn load_all_blocks_in_directory(
 		.filter(|p| p.extension() == Some(OsStr::new("ron")));
 
 	for file in files {
-		blocks.insert(BlockEntry::read(file, materials)?);
+		blocks.insert_textured(BlockEntry::read(file, materials)?, materials);
 	}
 
 	Ok(())
@@ -354,7 +517,7 @@ pub fn load_all_blocks_in_file(
 	for specification in specifications {
 		let mut e = BlockEntry::from_specification(specification, materials)?;
 		e.path = Some(path.to_path_buf());
-		blocks.insert(e);
+		blocks.insert_textured(e, materials);
 	}
 
 	Ok(())
@@ -1,4 +1,4 @@
-use std::{sync::Arc, collections::HashMap};
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}, sync::Arc, time::Instant};
 use eeks::prelude::*;
 use glam::{IVec3, UVec3, Vec3};
 use parking_lot::RwLock;
@@ -34,12 +34,26 @@ new_key_type! {
 /// The meshing system holds a copy of this resource for the duration of its meshing. 
 /// Be aware of this. 
 #[derive(Debug, Resource, Clone)]
+#[sda(lua = true)]
 pub struct ChunksResource(Arc<RwLock<Chunks>>);
 impl ChunksResource {
 	pub fn new() -> Self {
 		Self(Arc::new(RwLock::new(Chunks::new())))
 	}
 }
+// Lua can pass this to other resources' methods
+impl mlua::UserData for ChunksResource {
+	fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(_fields: &mut F) {}
+	fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+		// Keeps the chunk at this chunk position loaded until unpinned
+		methods.add_method("pin", |_lua, this, (x, y, z, holder): (i32, i32, i32, String)| {
+			Ok(this.write().pin(IVec3::new(x, y, z), PinHolder::System(holder), None).0)
+		});
+		methods.add_method("unpin", |_lua, this, ticket: u64| {
+			Ok(this.write().unpin(PinTicket(ticket)))
+		});
+	}
+}
 impl std::ops::Deref for ChunksResource {
 	type Target = Arc<RwLock<Chunks>>;
 	fn deref(&self) -> &Self::Target {
@@ -48,16 +62,62 @@ impl std::ops::Deref for ChunksResource {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkEvent {
+	Loaded(ChunkKey, IVec3),
+	Unloaded(ChunkKey, IVec3),
+}
+
+
+/// Whatever is keeping a chunk pinned. 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinHolder {
+	System(String),
+	Entity(Entity),
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PinTicket(pub u64);
+
+
+#[derive(Debug, Clone)]
+pub struct ChunkPin {
+	pub position: IVec3,
+	pub holder: PinHolder,
+	pub expires: Option<Instant>,
+}
+
+
 /// The chunks resouce describes the chunks of the world that are loaded. 
 /// Other resources, such as for terrain and lighting, are based on this. 
+/// 
+/// Instead of comparing their own storage to this, they should look at [Chunks::events]. 
+/// It holds the loads and unloads since the start of this tick's [chunk_loading_system], 
+/// so systems that use it should run after that. 
+/// Events that happen outside of that system are kept for an extra tick so that everything sees them, 
+/// meaning that they might be seen twice. 
 #[derive(Debug, Clone)]
 pub struct Chunks {
 	// We could reduce memory usage by not storing positions here but it is 
 	// useful to iterate over these
 	pub chunks: SlotMap<ChunkKey, IVec3>,
 	/// All chunk keys, but sorted by distance to a loader. 
-	/// Be aware that distance is not linear distance. 
+	/// Be aware that distance is not linear distance, and is weighted by view direction. 
 	pub chunks_by_distance: Vec<(ChunkKey, i32)>,
+	/// The most chunks that the loading system will create in one tick. 
+	pub max_loads_per_tick: usize,
+	// Positions waiting to be loaded, lowest priority value first
+	load_queue: BinaryHeap<Reverse<(i32, [i32; 3])>>,
+	// Loader chunks and coarse view directions from when the queue was last built
+	// None if it must be rebuilt
+	loader_state: Option<Vec<(IVec3, IVec3)>>,
+	pub events: Vec<ChunkEvent>,
+	// Events from before this are removed when the loading system next runs
+	events_seen: usize,
+	// Pinned chunks stay loaded even if no loader wants them
+	pins: HashMap<PinTicket, ChunkPin>,
+	next_pin: u64,
 	// pub min: IVec3,
 	// pub max: IVec3,
 
@@ -79,6 +139,13 @@ impl Chunks {
 		Self {
 			chunks: SlotMap::with_key(),
 			chunks_by_distance: Vec::new(),
+			max_loads_per_tick: 64,
+			load_queue: BinaryHeap::new(),
+			loader_state: None,
+			events: Vec::new(),
+			events_seen: 0,
+			pins: HashMap::new(),
+			next_pin: 0,
 			hm: HashMap::new(),
 		}
 	}
@@ -91,13 +158,21 @@ impl Chunks {
 		self.hm.get(&pos).copied()
 	}
 
-	/// Create a key for this position and 
+	/// Create a key for this position if it does not have one. 
+	/// It is given the lowest priority until the loading system next sorts the chunks. 
 	pub fn load(&mut self, pos: IVec3) -> ChunkKey {
+		self.load_with_priority(pos, i32::MAX)
+	}
+
+	fn load_with_priority(&mut self, pos: IVec3, priority: i32) -> ChunkKey {
 		match self.get_position(pos) {
 			Some(k) => k,
 			None => {
 				let k = self.chunks.insert(pos);
 				self.hm.insert(pos, k);
+				let i = self.chunks_by_distance.partition_point(|&(_, d)| d <= priority);
+				self.chunks_by_distance.insert(i, (k, priority));
+				self.events.push(ChunkEvent::Loaded(k, pos));
 				k
 			},
 		}
@@ -110,146 +185,313 @@ impl Chunks {
 	pub fn unload(&mut self, key: ChunkKey) {
 		if let Some(p) = self.chunks.remove(key) {
 			self.hm.remove(&p);
+			self.chunks_by_distance.retain(|&(k, _)| k != key);
+			self.events.push(ChunkEvent::Unloaded(key, p));
 		}
 	}
 
 	pub fn post_unload(&mut self) {
 
 	}
+
+	/// Loads a chunk and keeps it loaded until the ticket is released or expires. 
+	pub fn pin(&mut self, position: IVec3, holder: PinHolder, expires: Option<Instant>) -> PinTicket {
+		let ticket = PinTicket(self.next_pin);
+		self.next_pin += 1;
+		self.load(position);
+		self.pins.insert(ticket, ChunkPin { position, holder, expires, });
+		ticket
+	}
+
+	/// Releases a pin, returning false if it did not exist. 
+	/// The chunk might be unloaded during the next loading tick. 
+	pub fn unpin(&mut self, ticket: PinTicket) -> bool {
+		let removed = self.pins.remove(&ticket).is_some();
+		if removed {
+			// Make the loading system look for chunks to prune
+			self.loader_state = None;
+		}
+		removed
+	}
+
+	/// Releases every pin held by this holder. 
+	pub fn unpin_holder(&mut self, holder: &PinHolder) {
+		let n = self.pins.len();
+		self.pins.retain(|_, p| &p.holder != holder);
+		if self.pins.len() != n {
+			self.loader_state = None;
+		}
+	}
+
+	pub fn is_pinned(&self, position: IVec3) -> bool {
+		self.pins.values().any(|p| p.position == position)
+	}
+
+	pub fn pins(&self) -> impl Iterator<Item = (PinTicket, &ChunkPin)> {
+		self.pins.iter().map(|(&t, p)| (t, p))
+	}
+
+	fn expire_pins(&mut self, now: Instant) {
+		let n = self.pins.len();
+		self.pins.retain(|_, p| p.expires.map_or(true, |e| e > now));
+		if self.pins.len() != n {
+			self.loader_state = None;
+		}
+	}
 }
 
 
 
+/// The shape of the volume that a loader keeps loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadingShape {
+	#[default]
+	Cube,
+	// An ellipsoid with the vertical radius as its y radius
+	Sphere,
+	// Round in the xz plane, extending the vertical radius up and down
+	Cylinder,
+}
+
+
 #[derive(Debug, Component)]
 pub struct ChunkLoadingComponent {
 	pub radius: i32,
+	pub vertical_radius: i32,
 	pub tolerence: i32,
+	pub shape: LoadingShape,
+	// How much more distant chunks behind the loader seem than those in front of it
+	// Zero disables view weighting
+	pub view_weight: f32,
 }
 impl ChunkLoadingComponent {
-	pub fn new(radius: i32) -> Self { 
+	pub fn new(radius: i32) -> Self {
 		assert!(radius > 0);
-		Self { radius, tolerence: 2, }
+		Self { radius, vertical_radius: radius, tolerence: 2, shape: LoadingShape::Cube, view_weight: 0.0, }
 	}
 
+	/// The cube containing every chunk that should be loaded.
 	pub fn loading_volume(&self, pos: Vec3) -> VoxelCube {
-		VoxelCube::new(chunk_of_point(pos), UVec3::splat(self.radius as u32))
+		self.bounds(chunk_of_point(pos))
+	}
+
+	fn bounds(&self, loader_chunk: IVec3) -> VoxelCube {
+		VoxelCube::new(loader_chunk, UVec3::new(self.radius as u32, self.vertical_radius as u32, self.radius as u32))
 	}
 
 	// Volume but expanded by tolerence
 	pub fn un_loading_volume(&self, pos: Vec3) -> VoxelCube {
-		VoxelCube::new(chunk_of_point(pos), UVec3::splat((self.radius + self.tolerence) as u32))
+		let r = (self.radius + self.tolerence) as u32;
+		let v = (self.vertical_radius + self.tolerence) as u32;
+		VoxelCube::new(chunk_of_point(pos), UVec3::new(r, v, r))
+	}
+
+	/// Is a chunk at this offset from the loader's chunk within its shape when grown by `extra`?
+	fn within(&self, offset: IVec3, extra: i32) -> bool {
+		let r = (self.radius + extra) as i64;
+		let v = (self.vertical_radius + extra) as i64;
+		let [x, y, z] = offset.to_array().map(|c| c as i64);
+		match self.shape {
+			LoadingShape::Cube => x.abs() <= r && y.abs() <= v && z.abs() <= r,
+			LoadingShape::Sphere => (x.pow(2) + z.pow(2)) * v.pow(2) + y.pow(2) * r.pow(2) <= r.pow(2) * v.pow(2),
+			LoadingShape::Cylinder => x.pow(2) + z.pow(2) <= r.pow(2) && y.abs() <= v,
+		}
+	}
+
+	pub fn should_load(&self, loader_chunk: IVec3, chunk: IVec3) -> bool {
+		self.within(chunk - loader_chunk, 0)
+	}
+
+	pub fn should_keep(&self, loader_chunk: IVec3, chunk: IVec3) -> bool {
+		self.within(chunk - loader_chunk, self.tolerence)
+	}
+
+	/// Squared distance to a chunk, scaled up for chunks away from the view direction.
+	/// Lower values should be loaded first.
+	pub fn priority(&self, loader_chunk: IVec3, forward: Vec3, chunk: IVec3) -> i32 {
+		let offset = chunk - loader_chunk;
+		let d = offset.length_squared();
+		if self.view_weight == 0.0 || d == 0 {
+			return d;
+		}
+		// 0 in front, 1 behind
+		let away = (1.0 - offset.as_vec3().normalize().dot(forward.normalize_or_zero())) / 2.0;
+		(d as f32 * (1.0 + self.view_weight * away)).round() as i32
 	}
 }
 
 
+/// Loads chunks within the volumes of [ChunkLoadingComponent]s and unloads those outside of them.
+///
+/// Chunks to load are kept in a priority queue that is only rebuilt when a loader
+/// moves to another chunk or turns. At most `max_loads_per_tick` chunks are loaded each tick.
 pub fn chunk_loading_system(
 	chunks: ResMut<ChunksResource>,
 	map_loaders: Comp<ChunkLoadingComponent>,
 	transforms: Comp<TransformComponent>,
-) { 
-	// loading_volumes.iter()
-	// 	.flat_map(|(loader_chunk, volume)| 
-	// 		volume.iter()
-	// 		.filter(|&p| chunks_read.get_position(p).is_none())
-	// 		.map(|p| (p, loader_chunk.distance_squared(p)))) 
-	// 	.collect::<Vec<_>>()
-	// let loading_volumes = (&map_loaders, &transforms).iter()
-	// 	.map(|(loader, transform)| (chunk_of_point(transform.translation), loader.loading_volume(transform.translation)))
-	// 	.collect::<Vec<_>>();
-
-	let loading_volumes = (&map_loaders, &transforms).iter()
-		.map(|(loader, transform)| (chunk_of_point(transform.translation), loader.loading_volume(transform.translation)))
+) {
+	let loaders = (&map_loaders, &transforms).iter()
+		.map(|(loader, transform)| (loader, chunk_of_point(transform.translation), transform.rotation * Vec3::Z))
 		.collect::<Vec<_>>();
+	// Nearest loader
+	let priority = |p: IVec3| loaders.iter()
+		.map(|(l, c, f)| l.priority(*c, *f, p))
+		.min();
 
-	let un_loading_volumes = (&map_loaders, &transforms).iter()
-		.map(|(loader, transform)| loader.un_loading_volume(transform.translation))
-		.collect::<Vec<_>>();
-
-	let chunks_read = chunks.read();
-
-	// Note: This is not inexpensive!
-	// In a debug build, hashmap lookups take ~278ns, 19^3 lookups in ~1.6ms
-	// In a release build, hashmap lookups take ~19ns, 19^3 lookups in ~0.1ms
-	// FxHash is much faster
-	// In a debug build, FxHashMap lookups take ~147ns, 19^3 lookups in ~1.0ms
-	// In a release build, hashmap lookups take ~4ns, 19^3 lookups in ~0.03ms
-	// This data was collected using benchmarks and is reflected by profiling
-	let chunks_to_load = {
-		// profiling::scope!("Collect chunks to load");
-		loading_volumes.iter()
-			.flat_map(|(_, lv)| lv.iter())
-			.filter(|&pos| chunks_read.get_position(pos).is_none()).collect::<Vec<_>>()
-	};
-
-	let chunks_to_prune = {
-		// profiling::scope!("Collect chunks to prune");
-		chunks_read.chunks.iter()
-			.filter(|(_, &p)| !un_loading_volumes.iter()
-				.any(|lv| lv.contains(p)))
-			.map(|(k, &p)| (k, p))
-			.collect::<Vec<_>>()
-	};
-
-	drop(chunks_read);
 	let mut chunks_write = chunks.write();
+	let chunks = &mut *chunks_write;
+	let seen = chunks.events_seen;
+	chunks.events.drain(..seen);
+	chunks.expire_pins(Instant::now());
+
+	// Turning only matters in large enough steps
+	let loader_state = loaders.iter()
+		.map(|(_, c, f)| (*c, (*f * 4.0).round().as_ivec3()))
+		.collect::<Vec<_>>();
+	if chunks.loader_state.as_ref() != Some(&loader_state) {
+		chunks.loader_state = Some(loader_state);
+
+		{ // Prune chunks that should not be loaded
+			// profiling::scope!("Prune chunks");
+			let pinned = chunks.pins.values().map(|p| p.position).collect::<HashSet<_>>();
+			let chunks_to_prune = chunks.chunks.iter()
+				.filter(|(_, p)| !pinned.contains(p))
+				.filter(|(_, &p)| !loaders.iter().any(|(l, c, _)| l.should_keep(*c, p)))
+				.map(|(k, &p)| (k, p))
+				.collect::<Vec<_>>();
+			if chunks_to_prune.len() != 0 {
+				debug!("Prune {} chunks", chunks_to_prune.len());
+			}
+			for &(key, position) in chunks_to_prune.iter() {
+				chunks.chunks.remove(key);
+				chunks.hm.remove(&position);
+				chunks.events.push(ChunkEvent::Unloaded(key, position));
+			}
+			chunks.chunks_by_distance.retain(|&(key, _)| chunks.chunks.contains_key(key));
+		}
 
-	{ // Prune chunks that should not be loaded
-		// profiling::scope!("Prune chunks");
-		if chunks_to_prune.len() != 0 {
-			debug!("Prune {} chunks", chunks_to_prune.len());
+		{ // Find chunks that should be loaded but are not
+			// Note: This is not inexpensive!
+			// In a debug build, hashmap lookups take ~278ns, 19^3 lookups in ~1.6ms
+			// In a release build, hashmap lookups take ~19ns, 19^3 lookups in ~0.1ms
+			// This is why it is not done every tick
+			// profiling::scope!("Collect chunks to load");
+			let to_load = loaders.iter()
+				.flat_map(|(l, c, _)| l.bounds(*c).iter()
+					.filter(|&p| l.should_load(*c, p))
+					.collect::<Vec<_>>())
+				.filter(|&p| chunks.get_position(p).is_none())
+				.collect::<HashSet<_>>();
+			chunks.load_queue = to_load.into_iter()
+				.map(|p| Reverse((priority(p).unwrap(), p.to_array())))
+				.collect();
 		}
-		for (key, _) in chunks_to_prune {
-			if let Some(p) = chunks_write.chunks.remove(key) {
-				chunks_write.hm.remove(&p);
+
+		{ // Sort chunks by priority
+			// profiling::scope!("Sort chunks by distance");
+			for (k, d) in chunks.chunks_by_distance.iter_mut() {
+				*d = priority(chunks.chunks[*k]).unwrap_or(i32::MAX);
 			}
+			chunks.chunks_by_distance.sort_unstable_by_key(|&(_, d)| d);
 		}
-		let chunks = &mut *chunks_write;
-		chunks.chunks_by_distance.retain(|&(key, _)| chunks.chunks.contains_key(key));
 	}
 
-	{ // Insert entries for chunks that should be in the HM but are not
+	{ // Load the highest priority chunks
 		// profiling::scope!("Insert chunk entries");
-		if chunks_to_load.len() != 0 {			
-			debug!("Load {} chunks", chunks_to_load.len());
+		let mut n = 0;
+		while n < chunks.max_loads_per_tick {
+			let Some(Reverse((d, p))) = chunks.load_queue.pop() else { break };
+			let position = IVec3::from_array(p);
+			if chunks.get_position(position).is_some() {
+				continue
+			}
+			chunks.load_with_priority(position, d);
+			n += 1;
 		}
-		for position in chunks_to_load {
-			let key = match chunks_write.get_position(position) {
-				Some(k) => k,
-				None => {
-					let k = chunks_write.chunks.insert(position);
-					chunks_write.hm.insert(position, k);
-					k
-				},
-			};
-			chunks_write.chunks_by_distance.push((key, 0));
+		if n != 0 {
+			debug!("Load {} chunks ({} queued)", n, chunks.load_queue.len());
 		}
+		assert_eq!(chunks.chunks.len(), chunks.chunks_by_distance.len());
 	}
 
-	{ // Sort chunks by distance to a loader 
-		// profiling::scope!("Sort chunks by distance");
-		let chunks = &mut *chunks_write;
-		for (k, d) in chunks.chunks_by_distance.iter_mut() {
-			let p = *chunks.chunks.get(*k).unwrap();
-			*d = loading_volumes.iter()
-				.map(|(c, _)| c.distance_squared(p))
-				.min().unwrap();
-		}
+	chunks.events_seen = chunks.events.len();
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-		insertion_sort_by_key(&mut chunks_write.chunks_by_distance, |&(_, p)| p);
-		assert_eq!(chunks_write.chunks.len(), chunks_write.chunks_by_distance.len());
+	fn loader(shape: LoadingShape) -> ChunkLoadingComponent {
+		ChunkLoadingComponent { shape, vertical_radius: 2, ..ChunkLoadingComponent::new(4) }
 	}
-}
 
+	#[test]
+	fn test_loading_shapes() {
+		let cube = loader(LoadingShape::Cube);
+		let sphere = loader(LoadingShape::Sphere);
+		let cylinder = loader(LoadingShape::Cylinder);
+		let corner = IVec3::new(4, 2, 4);
+		assert!(cube.should_load(IVec3::ZERO, corner));
+		assert!(!sphere.should_load(IVec3::ZERO, corner));
+		assert!(!cylinder.should_load(IVec3::ZERO, corner));
+
+		// The vertical radius is shorter
+		assert!(cylinder.should_load(IVec3::ZERO, IVec3::new(4, 2, 0)));
+		assert!(!cylinder.should_load(IVec3::ZERO, IVec3::new(0, 3, 0)));
+		assert!(sphere.should_load(IVec3::ZERO, IVec3::new(0, 2, 0)));
+		assert!(sphere.should_load(IVec3::ZERO, IVec3::new(4, 0, 0)));
+		assert!(!sphere.should_load(IVec3::ZERO, IVec3::new(3, 2, 0)));
+
+		// Tolerence keeps chunks just outside
+		assert!(sphere.should_keep(IVec3::ZERO, IVec3::new(5, 0, 0)));
+		assert!(sphere.should_load(IVec3::ONE, IVec3::new(5, 1, 1)));
+	}
 
-// https://medium.com/@spyr1014/sorting-in-rust-selection-insertion-and-counting-sort-2c4d3575e364
-// It's so short! So elegant! 
-fn insertion_sort_by_key<T, K: Ord + Copy, F: FnMut(&T) -> K>(data: &mut [T], mut f: F) {
-	for i in 1..data.len() {
-		for j in (1..i+1).rev() {
-			if f(&data[j-1]) <= f(&data[j]) { 
-				break 
-			}
-			data.swap(j-1, j);
-		}
+	#[test]
+	fn test_chunk_events() {
+		let mut chunks = Chunks::new();
+		let a = chunks.load(IVec3::ZERO);
+		assert_eq!(a, chunks.load(IVec3::ZERO));
+		chunks.unload(a);
+		chunks.unload(a);
+		assert_eq!(vec![
+			ChunkEvent::Loaded(a, IVec3::ZERO),
+			ChunkEvent::Unloaded(a, IVec3::ZERO),
+		], chunks.events);
+		assert!(chunks.chunks_by_distance.is_empty());
+	}
+
+	#[test]
+	fn test_pins() {
+		let mut chunks = Chunks::new();
+		let now = Instant::now();
+		let spawn = chunks.pin(IVec3::ZERO, PinHolder::System("spawn".into()), None);
+		let _ = chunks.pin(IVec3::X, PinHolder::System("job".into()), Some(now));
+		assert_eq!(2, chunks.chunks.len());
+		assert!(chunks.is_pinned(IVec3::X));
+
+		chunks.expire_pins(now);
+		assert!(!chunks.is_pinned(IVec3::X));
+		assert!(chunks.is_pinned(IVec3::ZERO));
+
+		assert!(chunks.unpin(spawn));
+		assert!(!chunks.unpin(spawn));
+		assert!(!chunks.is_pinned(IVec3::ZERO));
+		// Unpinning doesn't unload, that is left to the loading system
+		assert_eq!(2, chunks.chunks.len());
+	}
+
+	#[test]
+	fn test_view_priority() {
+		let mut l = loader(LoadingShape::Sphere);
+		let front = IVec3::new(0, 0, 3);
+		let back = IVec3::new(0, 0, -3);
+		assert_eq!(l.priority(IVec3::ZERO, Vec3::Z, front), l.priority(IVec3::ZERO, Vec3::Z, back));
+		l.view_weight = 1.0;
+		assert_eq!(9, l.priority(IVec3::ZERO, Vec3::Z, front));
+		assert_eq!(18, l.priority(IVec3::ZERO, Vec3::Z, back));
+		// The loader's own chunk always comes first
+		assert_eq!(0, l.priority(IVec3::ZERO, Vec3::Z, IVec3::ZERO));
 	}
 }
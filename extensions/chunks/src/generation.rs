@@ -1,4 +1,6 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::VecDeque};
+use glam::UVec3;
+use crate::CHUNK_SIZE;
 
 
 
@@ -31,3 +33,53 @@ impl PartialOrd for KGeneration {
 		Some(self.cmp(other))
 	}
 }
+
+
+
+/// The generation before each recent change to a chunk and the bounds of the voxels it changed, oldest first. 
+/// Lets readers of the chunk find what changed since the generation they last saw. 
+#[derive(Debug, Default, Clone)]
+pub struct ChangeHistory(VecDeque<(KGeneration, UVec3, UVec3)>);
+impl ChangeHistory {
+	// Older changes are forgotten
+	pub const LENGTH: usize = 16;
+
+	pub fn new() -> Self {
+		Self(VecDeque::new())
+	}
+
+	/// Increments the generation and remembers which voxels were changed. 
+	pub fn record(&mut self, generation: &mut KGeneration, min: UVec3, max: UVec3) {
+		if self.0.len() == Self::LENGTH {
+			self.0.pop_front();
+		}
+		self.0.push_back((*generation, min, max));
+		generation.increment();
+	}
+
+	/// The bounds of the voxels changed since a generation, or None if it is the current one. 
+	/// If the changes aren't all known then this is the whole chunk. 
+	pub fn changed_since(&self, current: KGeneration, generation: KGeneration) -> Option<(UVec3, UVec3)> {
+		if generation == current {
+			return None;
+		}
+		let whole = Some((UVec3::ZERO, UVec3::splat(CHUNK_SIZE - 1)));
+		let Some(i) = self.0.iter().position(|&(g, _, _)| g == generation) else {
+			return whole;
+		};
+		// Each change must follow the last, or else something was not recorded
+		let mut expected = generation;
+		let mut bounds = (UVec3::MAX, UVec3::ZERO);
+		for &(g, min, max) in self.0.range(i..) {
+			if g != expected {
+				return whole;
+			}
+			expected.increment();
+			bounds = (bounds.0.min(min), bounds.1.max(max));
+		}
+		if expected != current {
+			return whole;
+		}
+		Some(bounds)
+	}
+}
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use eeks::prelude::*;
+use serde::{Serialize, Deserialize};
+
+
+
+/// A value of a block instance data field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InstanceValue {
+	Bool(bool),
+	Integer(i64),
+	Float(f64),
+	Text(String),
+	List(Vec<InstanceValue>),
+}
+impl<'lua> mlua::IntoLua<'lua> for InstanceValue {
+	fn into_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+		match self {
+			Self::Bool(v) => v.into_lua(lua),
+			Self::Integer(v) => v.into_lua(lua),
+			Self::Float(v) => v.into_lua(lua),
+			Self::Text(v) => v.into_lua(lua),
+			Self::List(v) => v.into_lua(lua),
+		}
+	}
+}
+impl<'lua> mlua::FromLua<'lua> for InstanceValue {
+	fn from_lua(value: mlua::Value<'lua>, lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+		match value {
+			mlua::Value::Boolean(v) => Ok(Self::Bool(v)),
+			mlua::Value::Integer(v) => Ok(Self::Integer(v as i64)),
+			mlua::Value::Number(v) => Ok(Self::Float(v)),
+			mlua::Value::String(_) => Ok(Self::Text(String::from_lua(value, lua)?)),
+			mlua::Value::Table(_) => Ok(Self::List(Vec::<InstanceValue>::from_lua(value, lua)?)),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "InstanceValue",
+				message: None,
+			}),
+		}
+	}
+}
+
+
+/// Per-voxel data for blocks that need it, such as sign text or a container's inventory.
+///
+/// A block specification declares its fields and their default values.
+/// Instances are created with those defaults when the block is placed.
+pub type BlockInstanceData = BTreeMap<String, InstanceValue>;
@@ -4,12 +4,19 @@ pub mod chunk;
 pub mod chunks;
 pub mod fvt;
 pub mod generation;
+pub mod instance;
+pub mod octree;
+pub mod states;
+#[cfg(any(test, feature = "test_util"))]
+pub mod test_util;
+pub mod textures;
 
 use blocks::BlockResource;
-use chunks::{chunk_loading_system, ChunkLoadingComponent, ChunksResource};
+use chunks::{chunk_loading_system, ChunkLoadingComponent, ChunksResource, LoadingShape};
 use eeks::prelude::*;
 use glam::{Vec3, IVec3, UVec3};
 use pinecore::player::PlayerSpawnResource;
+use textures::block_texture_array_system;
 
 #[macro_use]
 extern crate log;
@@ -133,7 +140,12 @@ fn player_chunk_loader(
 ) {
 	for entity in psr.entities.iter().copied() {
 		debug!("Insert chunk loding component for player");
-		loaders.insert(entity, ChunkLoadingComponent::new(5));
+		loaders.insert(entity, ChunkLoadingComponent {
+			vertical_radius: 3,
+			shape: LoadingShape::Cylinder,
+			view_weight: 1.0,
+			..ChunkLoadingComponent::new(11)
+		});
 	}
 }
 
@@ -148,6 +160,8 @@ pub fn dependencies() -> Vec<String> {
 #[systems]
 pub fn systems(loader: &mut ExtensionSystemsLoader) {	
 	loader.system("client_tick", "chunk_loading_system", chunk_loading_system);
+
+	loader.system("client_tick", "block_texture_array_system", block_texture_array_system);
 	
 	loader.system("client_tick", "player_chunk_loader", player_chunk_loader)
 		.run_after("player_spawn")
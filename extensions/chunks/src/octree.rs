@@ -0,0 +1,403 @@
+use glam::{IVec3, UVec3};
+use crate::array_volume::{ArrayVolume, VoxelVolume};
+
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OctreeNode<V> {
+	Empty,
+	// Every voxel in this octant is the same
+	Leaf(V),
+	Branch(Box<[OctreeNode<V>; 8]>),
+}
+
+
+// Octant of a position in a node whose octants have this edge length, and the position within that octant
+fn octant(position: UVec3, half: u32) -> (usize, UVec3) {
+	let upper = position.cmpge(UVec3::splat(half));
+	let o = UVec3::new(upper.test(0) as u32, upper.test(1) as u32, upper.test(2) as u32);
+	((o.x * 4 + o.y * 2 + o.z) as usize, position - o * half)
+}
+
+
+fn octant_origin(octant: usize, half: u32) -> UVec3 {
+	let octant = octant as u32;
+	UVec3::new(octant >> 2 & 1, octant >> 1 & 1, octant & 1) * half
+}
+
+
+/// A sparse voxel octree, loosely based on [Efficient Sparse Voxel Octrees](https://research.nvidia.com/sites/default/files/pubs/2010-02_Efficient-Sparse-Voxel/laine2010tr1_paper.pdf).
+///
+/// Octants are numbered x * 4 + y * 2 + z, where each is 1 if in the upper half.
+/// Uniform regions are stored as a single leaf, so mostly empty or mostly solid chunks are small.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Octree<V> {
+	depth: u32,
+	root: OctreeNode<V>,
+}
+impl<V: Clone + PartialEq> Octree<V> {
+	/// Creates an empty octree with an edge length of 2^depth.
+	pub fn new(depth: u32) -> Self {
+		Self { depth, root: OctreeNode::Empty, }
+	}
+
+	pub fn depth(&self) -> u32 {
+		self.depth
+	}
+
+	pub fn edge_length(&self) -> u32 {
+		1 << self.depth
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.root == OctreeNode::Empty
+	}
+
+	pub fn root(&self) -> &OctreeNode<V> {
+		&self.root
+	}
+
+	pub fn in_bounds(&self, position: UVec3) -> bool {
+		position.cmplt(UVec3::splat(self.edge_length())).all()
+	}
+
+	pub fn get(&self, position: UVec3) -> Option<&V> {
+		if !self.in_bounds(position) {
+			return None;
+		}
+		let mut node = &self.root;
+		let mut position = position;
+		let mut half = self.edge_length() / 2;
+		loop {
+			match node {
+				OctreeNode::Empty => return None,
+				OctreeNode::Leaf(v) => return Some(v),
+				OctreeNode::Branch(children) => {
+					let (o, p) = octant(position, half);
+					node = &children[o];
+					position = p;
+					half /= 2;
+				},
+			}
+		}
+	}
+
+	/// Sets a voxel, merging octants that become uniform.
+	/// Panics if the position is out of bounds.
+	pub fn set(&mut self, position: UVec3, data: Option<V>) {
+		assert!(self.in_bounds(position), "Position {position} is outside of the octree");
+		let half = self.edge_length() / 2;
+		Self::set_in(&mut self.root, position, half, data);
+	}
+
+	fn set_in(node: &mut OctreeNode<V>, position: UVec3, half: u32, data: Option<V>) {
+		match (&*node, &data) {
+			(OctreeNode::Empty, None) => return,
+			(OctreeNode::Leaf(v), Some(d)) if v == d => return,
+			_ => {},
+		}
+		// A single voxel
+		if half == 0 {
+			*node = data.map_or(OctreeNode::Empty, OctreeNode::Leaf);
+			return;
+		}
+		// Split so that one octant can change
+		if !matches!(node, OctreeNode::Branch(_)) {
+			let fill = node.clone();
+			*node = OctreeNode::Branch(Box::new(std::array::from_fn(|_| fill.clone())));
+		}
+		let OctreeNode::Branch(children) = node else { unreachable!() };
+		let (o, p) = octant(position, half);
+		Self::set_in(&mut children[o], p, half / 2, data);
+
+		if !matches!(children[0], OctreeNode::Branch(_)) && children.iter().all(|c| c == &children[0]) {
+			*node = children[0].clone();
+		}
+	}
+
+	/// Number of nodes, including leaves and empty octants.
+	pub fn node_count(&self) -> usize {
+		fn count<V>(node: &OctreeNode<V>) -> usize {
+			match node {
+				OctreeNode::Branch(children) => 1 + children.iter().map(count).sum::<usize>(),
+				_ => 1,
+			}
+		}
+		count(&self.root)
+	}
+
+	/// Flattens this into the format read by the octree chunks shader.
+	///
+	/// Each node is a u32 with its leaf mask in the top byte, its node mask in the next byte,
+	/// and in the lower two bytes the offset from the word after it to the start of its children.
+	/// Masks have octant 0 in their highest bit.
+	/// A node's children are stored together in octant order.
+	/// Each has its leaf value if it is a leaf or its node if it is a branch.
+	/// The root node is at index 0 and its children follow it directly.
+	///
+	/// Panics if an offset does not fit in two bytes, which can't happen for a chunk.
+	pub fn flatten(&self, leaf: impl Fn(&V) -> u32) -> Vec<u32> {
+		let mut data = vec![0];
+		match &self.root {
+			OctreeNode::Empty => {},
+			// The root is always a node, so a full octree is stored as eight full octants
+			OctreeNode::Leaf(v) => {
+				data[0] = 0xFF << 24;
+				data.extend(std::iter::repeat(leaf(v)).take(8));
+			},
+			OctreeNode::Branch(children) => Self::flatten_branch(children, 0, &mut data, &leaf),
+		}
+		data
+	}
+
+	fn flatten_branch(children: &[OctreeNode<V>; 8], node: usize, data: &mut Vec<u32>, leaf: &impl Fn(&V) -> u32) {
+		let content = data.len() - node - 1;
+		assert!(content <= u16::MAX as usize, "Octree is too large to flatten");
+		let mut leaves = 0;
+		let mut nodes = 0;
+		let mut branches = Vec::new();
+		for (octant, child) in children.iter().enumerate() {
+			let bit = 1 << (7 - octant);
+			match child {
+				OctreeNode::Empty => {},
+				OctreeNode::Leaf(v) => {
+					leaves |= bit;
+					data.push(leaf(v));
+				},
+				OctreeNode::Branch(c) => {
+					nodes |= bit;
+					branches.push((data.len(), c));
+					// Written when it is flattened
+					data.push(0);
+				},
+			}
+		}
+		data[node] = (leaves << 24) | (nodes << 16) | content as u32;
+		for (i, c) in branches {
+			Self::flatten_branch(c, i, data, leaf);
+		}
+	}
+
+	pub fn from_array_volume(volume: &ArrayVolume<V>) -> Self where V: std::fmt::Debug {
+		let size = volume.size;
+		assert!(size.x == size.y && size.y == size.z && size.x.is_power_of_two(), "Octrees must be cubes with a power of two edge length");
+		let mut octree = Self::new(size.x.trailing_zeros());
+		for (p, v) in VoxelVolume::iter(volume) {
+			octree.insert(p, v.clone());
+		}
+		octree
+	}
+
+	pub fn to_array_volume(&self) -> ArrayVolume<V> where V: std::fmt::Debug {
+		let mut volume = ArrayVolume::new(UVec3::splat(self.edge_length()));
+		for (p, v) in VoxelVolume::iter(self) {
+			volume.insert(p, v.clone());
+		}
+		volume
+	}
+}
+impl<V: Clone + PartialEq> VoxelVolume<V> for Octree<V> {
+	fn extent(&self) -> UVec3 {
+		UVec3::splat(self.edge_length())
+	}
+
+	fn get(&self, position: UVec3) -> Option<&V> {
+		Octree::get(self, position)
+	}
+
+	fn insert(&mut self, position: UVec3, data: V) {
+		self.set(position, Some(data));
+	}
+
+	fn remove(&mut self, position: UVec3) {
+		self.set(position, None);
+	}
+
+	fn iter<'a>(&'a self) -> impl Iterator<Item = (UVec3, &'a V)> + 'a where V: 'a {
+		// Uniform octants are expanded into their voxels
+		fn visit<'a, V>(node: &'a OctreeNode<V>, origin: UVec3, size: u32, out: &mut Vec<(UVec3, &'a V)>) {
+			match node {
+				OctreeNode::Empty => {},
+				OctreeNode::Leaf(v) => out.extend(crate::cube_iterator_xyz_uvec(UVec3::splat(size)).map(|p| (origin + p, v))),
+				OctreeNode::Branch(children) => for (o, c) in children.iter().enumerate() {
+					visit(c, origin + octant_origin(o, size / 2), size / 2, out);
+				},
+			}
+		}
+		let mut out = Vec::new();
+		visit(&self.root, UVec3::ZERO, self.edge_length(), &mut out);
+		out.into_iter()
+	}
+}
+
+
+/// Information about an [OctreeScene] for the octree chunks shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OctreeSceneInfo {
+	// Chunks along each edge of the grid
+	pub extent: u32,
+	// Index of the grid in the data
+	pub chunk_st: u32,
+}
+impl OctreeSceneInfo {
+	/// Padded to the sixteen bytes that uniform buffers want.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		[self.extent, self.chunk_st, 0, 0].iter().flat_map(|v| v.to_ne_bytes()).collect()
+	}
+}
+
+
+/// Flattened chunk octrees arranged for the octree chunks shader.
+///
+/// The octrees come first and are followed by a grid of extent^3 chunks centred on the viewer's chunk.
+/// Each grid cell is zero if it has no chunk or one more than the index of its chunk's octree.
+#[derive(Debug, Clone)]
+pub struct OctreeScene {
+	extent: u32,
+	octrees: Vec<u32>,
+	grid: Vec<u32>,
+}
+impl OctreeScene {
+	/// The extent should be odd so that the grid has a centre.
+	pub fn new(extent: u32) -> Self {
+		Self { extent, octrees: Vec::new(), grid: vec![0; extent.pow(3) as usize], }
+	}
+
+	fn grid_index(&self, offset: IVec3) -> Option<usize> {
+		let h = (self.extent / 2) as i32;
+		if offset.abs().cmpgt(IVec3::splat(h)).any() {
+			return None;
+		}
+		let [x, y, z] = (offset + h).as_uvec3().to_array();
+		Some((x * self.extent * self.extent + y * self.extent + z) as usize)
+	}
+
+	/// Adds a flattened octree for the chunk at this offset from the centre.
+	/// Returns false if it is outside of the grid.
+	pub fn insert(&mut self, offset: IVec3, flattened: &[u32]) -> bool {
+		let Some(i) = self.grid_index(offset) else {
+			return false;
+		};
+		self.grid[i] = self.octrees.len() as u32 + 1;
+		self.octrees.extend_from_slice(flattened);
+		true
+	}
+
+	/// Size of the data in bytes.
+	pub fn size(&self) -> usize {
+		(self.octrees.len() + self.grid.len()) * std::mem::size_of::<u32>()
+	}
+
+	pub fn info(&self) -> OctreeSceneInfo {
+		OctreeSceneInfo { extent: self.extent, chunk_st: self.octrees.len() as u32, }
+	}
+
+	pub fn data(&self) -> Vec<u32> {
+		self.octrees.iter().chain(self.grid.iter()).copied().collect()
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.octrees.iter().chain(self.grid.iter()).flat_map(|v| v.to_ne_bytes()).collect()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cube_iterator_xyz_uvec;
+
+	// Does what the octree chunks shader does in octree_get
+	fn shader_get(data: &[u32], edge_length: u32, mut index: usize, position: UVec3) -> Option<u32> {
+		let to_node = |d: u32| ((d & 0xFFFF) as usize, (d >> 16) & 0xFF, d >> 24);
+		let octant_offset = |octant: u32, leaf: u32, node: u32| {
+			let preceding = 255 - (0xFF >> octant);
+			((leaf & preceding).count_ones() + (node & preceding).count_ones()) as usize
+		};
+		// Root content is always 0
+		let (_, mut node, mut leaf) = to_node(data[index]);
+		let mut content = 0;
+		let mut half = edge_length / 2;
+		let mut position = position;
+		let mut has_leaf;
+		let mut has_node;
+		loop {
+			let upper = position.cmpge(UVec3::splat(half));
+			let o = UVec3::new(upper.test(0) as u32, upper.test(1) as u32, upper.test(2) as u32);
+			position -= o * half;
+			let octant = o.x * 4 + o.y * 2 + o.z;
+			let mask = 1 << (7 - octant);
+			has_leaf = leaf & mask != 0;
+			has_node = node & mask != 0;
+			index += content + 1 + octant_offset(octant, leaf, node);
+			if !has_node || half == 1 {
+				break;
+			}
+			index += has_leaf as usize;
+			(content, node, leaf) = to_node(data[index]);
+			half /= 2;
+		}
+		has_leaf.then(|| data[index])
+	}
+
+	fn test_octree() -> Octree<u32> {
+		let mut octree = Octree::new(4);
+		for p in cube_iterator_xyz_uvec(UVec3::new(16, 4, 16)) {
+			octree.insert(p, 1);
+		}
+		octree.insert(UVec3::new(3, 4, 5), 2);
+		octree.insert(UVec3::new(15, 15, 15), 3);
+		octree.remove(UVec3::new(0, 0, 0));
+		octree
+	}
+
+	#[test]
+	fn test_octree_get_set() {
+		let mut octree = test_octree();
+		assert_eq!(Some(&1), octree.get(UVec3::new(9, 2, 9)));
+		assert_eq!(Some(&2), octree.get(UVec3::new(3, 4, 5)));
+		assert_eq!(None, octree.get(UVec3::new(0, 0, 0)));
+		assert_eq!(None, octree.get(UVec3::new(8, 8, 8)));
+		assert_eq!(None, octree.get(UVec3::new(16, 0, 0)));
+		assert_eq!(16 * 4 * 16 + 1, VoxelVolume::iter(&octree).count());
+
+		// Uniform octants merge back together
+		let n = octree.node_count();
+		octree.insert(UVec3::new(0, 0, 0), 1);
+		assert!(octree.node_count() < n);
+		for p in cube_iterator_xyz_uvec(UVec3::splat(16)) {
+			octree.remove(p);
+		}
+		assert!(octree.is_empty());
+		assert_eq!(1, octree.node_count());
+	}
+
+	#[test]
+	fn test_octree_array_volume() {
+		let octree = test_octree();
+		let volume = octree.to_array_volume();
+		assert_eq!(Some(&3), volume.get(UVec3::new(15, 15, 15)));
+		assert_eq!(octree, Octree::from_array_volume(&volume));
+	}
+
+	#[test]
+	fn test_octree_flatten() {
+		let octree = test_octree();
+		let data = octree.flatten(|&v| v * 10);
+		for p in cube_iterator_xyz_uvec(UVec3::splat(16)) {
+			assert_eq!(octree.get(p).map(|v| v * 10), shader_get(&data, 16, 0, p), "at {p}");
+		}
+
+		// Octrees can be read where they are in a scene
+		let mut scene = OctreeScene::new(3);
+		assert!(scene.insert(IVec3::ZERO, &Octree::<u32>::new(4).flatten(|&v| v)));
+		assert!(scene.insert(IVec3::new(1, -1, 0), &data));
+		assert!(!scene.insert(IVec3::new(2, 0, 0), &data));
+		let info = scene.info();
+		let scene = scene.data();
+		// Cells of the scene are indexed by their (x, y, z) within it
+		let cell = |p: UVec3| (p.x * 9 + p.y * 3 + p.z) as usize;
+		let start = scene[info.chunk_st as usize + cell(UVec3::new(2, 0, 1))] - 1;
+		assert_eq!(Some(20), shader_get(&scene, 16, start as usize, UVec3::new(3, 4, 5)));
+	}
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use glam::{IVec3, Mat3, Quat, Vec3};
+use serde::{Serialize, Deserialize};
+
+
+
+/// A property that a block's state can have.
+/// Facing, HorizontalFacing, and Axis properties also orient the block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockPropertySpecification {
+	// Any of the six directions, chosen from the face that the block was placed against
+	Facing,
+	// A direction in the xz plane, chosen so that the block faces whoever placed it
+	HorizontalFacing,
+	// x, y, or z, chosen from the face that the block was placed against
+	Axis,
+	Bool,
+	Values(Vec<String>),
+}
+impl BlockPropertySpecification {
+	/// Possible values.
+	/// The first is the default and for orientations it is the unrotated one.
+	pub fn values(&self) -> Vec<String> {
+		match self {
+			Self::Facing => ["zp", "zn", "xp", "xn", "yp", "yn"].map(String::from).to_vec(),
+			Self::HorizontalFacing => ["zp", "xp", "zn", "xn"].map(String::from).to_vec(),
+			Self::Axis => ["y", "x", "z"].map(String::from).to_vec(),
+			Self::Bool => ["false", "true"].map(String::from).to_vec(),
+			Self::Values(v) => v.clone(),
+		}
+	}
+}
+
+
+/// An index into the combinations of a block's property values.
+/// Zero is the state with every property at its default value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct BlockState(pub u16);
+
+
+/// Maps directions in the world to the faces of a rotated block.
+/// Directions are indexed xp, xn, yp, yn, zp, zn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation([u8; 6]);
+impl Orientation {
+	pub const IDENTITY: Self = Self([0, 1, 2, 3, 4, 5]);
+
+	const DIRECTIONS: [IVec3; 6] = [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z];
+
+	pub fn direction_index(direction: IVec3) -> Option<usize> {
+		Self::DIRECTIONS.iter().position(|&d| d == direction)
+	}
+
+	pub fn direction_name(direction: IVec3) -> Option<&'static str> {
+		Self::direction_index(direction).map(|i| ["xp", "xn", "yp", "yn", "zp", "zn"][i])
+	}
+
+	/// Creates an orientation from a rotation of block space into world space.
+	fn from_rotation(rotation: impl Fn(IVec3) -> IVec3) -> Self {
+		let mut faces = [0; 6];
+		for (local, &d) in Self::DIRECTIONS.iter().enumerate() {
+			let world = Self::direction_index(rotation(d)).unwrap();
+			faces[world] = local as u8;
+		}
+		Self(faces)
+	}
+
+	/// The orientation that turns a block's zp face towards this direction.
+	pub fn facing(direction: &str) -> Self {
+		match direction {
+			"zn" => Self::from_rotation(|v| IVec3::new(-v.x, v.y, -v.z)),
+			"xp" => Self::from_rotation(|v| IVec3::new(v.z, v.y, -v.x)),
+			"xn" => Self::from_rotation(|v| IVec3::new(-v.z, v.y, v.x)),
+			"yp" => Self::from_rotation(|v| IVec3::new(v.x, v.z, -v.y)),
+			"yn" => Self::from_rotation(|v| IVec3::new(v.x, -v.z, v.y)),
+			_ => Self::IDENTITY,
+		}
+	}
+
+	/// The orientation that turns a block's y axis onto this axis.
+	pub fn axis(axis: &str) -> Self {
+		match axis {
+			"x" => Self::from_rotation(|v| IVec3::new(v.y, -v.x, v.z)),
+			"z" => Self::from_rotation(|v| IVec3::new(v.x, -v.z, v.y)),
+			_ => Self::IDENTITY,
+		}
+	}
+
+	/// The block face that is seen from this world direction.
+	pub fn local_face(&self, world_direction: usize) -> usize {
+		self.0[world_direction] as usize
+	}
+
+	/// The rotation of block space into world space, for things like models that can't be reordered.
+	pub fn rotation(&self) -> Quat {
+		// Where each of the block's positive axes ends up
+		let [x, y, z] = [0, 2, 4].map(|local| {
+			let world = self.0.iter().position(|&l| l == local).unwrap();
+			Self::DIRECTIONS[world].as_vec3()
+		});
+		Quat::from_mat3(&Mat3::from_cols(x, y, z))
+	}
+
+	/// Reorders a block's face table so that it is indexed by world direction.
+	pub fn rotate<T: Copy>(&self, faces: [T; 6]) -> [T; 6] {
+		std::array::from_fn(|i| faces[self.local_face(i)])
+	}
+}
+
+
+/// The states of a block.
+#[derive(Debug, Clone, Default)]
+pub struct BlockStates {
+	// Name, kind, values
+	// The first property varies fastest in the state id
+	properties: Vec<(String, BlockPropertySpecification, Vec<String>)>,
+}
+impl BlockStates {
+	pub fn new(properties: &BTreeMap<String, BlockPropertySpecification>) -> Self {
+		Self {
+			properties: properties.iter()
+				.map(|(name, p)| (name.clone(), p.clone(), p.values()))
+				.collect(),
+		}
+	}
+
+	pub fn count(&self) -> usize {
+		self.properties.iter().map(|(_, _, v)| v.len()).product()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.properties.is_empty()
+	}
+
+	pub fn properties(&self) -> impl Iterator<Item = (&str, &BlockPropertySpecification)> {
+		self.properties.iter().map(|(n, p, _)| (n.as_str(), p))
+	}
+
+	/// Finds the state with these values.
+	/// Unmentioned properties take their default values.
+	pub fn state<'a>(&self, values: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<BlockState> {
+		let mut indices = vec![0; self.properties.len()];
+		for (name, value) in values {
+			let i = self.properties.iter().position(|(n, _, _)| n == name)?;
+			indices[i] = self.properties[i].2.iter().position(|v| v == value)?;
+		}
+		let mut id = 0;
+		let mut stride = 1;
+		for (i, (_, _, values)) in indices.into_iter().zip(self.properties.iter()) {
+			id += i * stride;
+			stride *= values.len();
+		}
+		Some(BlockState(id as u16))
+	}
+
+	/// The values of every property in a state.
+	pub fn values(&self, state: BlockState) -> Vec<(&str, &str)> {
+		let mut id = state.0 as usize;
+		self.properties.iter().map(|(name, _, values)| {
+			let v = &values[id % values.len()];
+			id /= values.len();
+			(name.as_str(), v.as_str())
+		}).collect()
+	}
+
+	pub fn value(&self, state: BlockState, property: &str) -> Option<&str> {
+		self.values(state).into_iter().find(|&(n, _)| n == property).map(|(_, v)| v)
+	}
+
+	/// Finds the orientation of a state from its first orienting property.
+	pub fn orientation(&self, state: BlockState) -> Orientation {
+		for ((_, property, _), (_, value)) in self.properties.iter().zip(self.values(state)) {
+			match property {
+				BlockPropertySpecification::Facing | BlockPropertySpecification::HorizontalFacing => return Orientation::facing(value),
+				BlockPropertySpecification::Axis => return Orientation::axis(value),
+				_ => {},
+			}
+		}
+		Orientation::IDENTITY
+	}
+
+	/// Chooses a state for a block placed against a face with this normal by someone looking in this direction.
+	pub fn placement_state(&self, normal: IVec3, look: Vec3) -> BlockState {
+		let horizontal = if look.x.abs() > look.z.abs() {
+			if look.x > 0.0 { "xn" } else { "xp" }
+		} else {
+			if look.z > 0.0 { "zn" } else { "zp" }
+		};
+		let axis = if normal.x != 0 { "x" } else if normal.z != 0 { "z" } else { "y" };
+		let facing = Orientation::direction_name(normal).unwrap_or("zp");
+
+		let values = self.properties.iter().filter_map(|(name, property, _)| match property {
+			BlockPropertySpecification::Facing => Some((name.as_str(), facing)),
+			BlockPropertySpecification::HorizontalFacing => Some((name.as_str(), horizontal)),
+			BlockPropertySpecification::Axis => Some((name.as_str(), axis)),
+			_ => None,
+		}).collect::<Vec<_>>();
+		self.state(values).unwrap_or_default()
+	}
+
+	/// Formats a state like "[axis=x,lit=true]".
+	/// Gives an empty string for blocks without properties.
+	pub fn describe(&self, state: BlockState) -> String {
+		if self.is_empty() {
+			return String::new();
+		}
+		let values = self.values(state).into_iter()
+			.map(|(n, v)| format!("{n}={v}"))
+			.collect::<Vec<_>>();
+		format!("[{}]", values.join(","))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_states() -> BlockStates {
+		BlockStates::new(&BTreeMap::from([
+			("facing".to_string(), BlockPropertySpecification::HorizontalFacing),
+			("lit".to_string(), BlockPropertySpecification::Bool),
+		]))
+	}
+
+	#[test]
+	fn test_state_ids() {
+		let states = test_states();
+		assert_eq!(8, states.count());
+		assert_eq!(Some(BlockState(0)), states.state([]));
+		for id in 0..states.count() as u16 {
+			let values = states.values(BlockState(id));
+			assert_eq!(Some(BlockState(id)), states.state(values));
+		}
+		assert_eq!(None, states.state([("lit", "maybe")]));
+		assert_eq!("[facing=zp,lit=true]", states.describe(states.state([("lit", "true")]).unwrap()));
+	}
+
+	#[test]
+	fn test_orientation() {
+		// Facing zp is unrotated
+		assert_eq!(Orientation::IDENTITY, Orientation::facing("zp"));
+		// Facing xp shows the block's front (zp) to xp and its back to xn
+		let o = Orientation::facing("xp");
+		assert_eq!(4, o.local_face(0));
+		assert_eq!(5, o.local_face(1));
+		// Top stays on top
+		assert_eq!(2, o.local_face(2));
+		// An x-axis log shows its top and bottom along x
+		let o = Orientation::axis("x");
+		assert_eq!(2, o.local_face(0));
+		assert_eq!(3, o.local_face(1));
+		assert_eq!(1, o.local_face(2));
+	}
+
+	#[test]
+	fn test_orientation_rotation() {
+		for o in ["zp", "zn", "xp", "xn", "yp", "yn"].map(Orientation::facing).into_iter().chain(["x", "z"].map(Orientation::axis)) {
+			let r = o.rotation();
+			for (world, &d) in Orientation::DIRECTIONS.iter().enumerate() {
+				let local = Orientation::DIRECTIONS[o.local_face(world)];
+				assert_eq!(d, (r * local.as_vec3()).round().as_ivec3());
+			}
+		}
+	}
+
+	#[test]
+	fn test_placement_state() {
+		let states = test_states();
+		// Looking along +z, so the block should face -z, back towards us
+		let s = states.placement_state(IVec3::Y, Vec3::new(0.1, -0.5, 1.0));
+		assert_eq!(Some("zn"), states.value(s, "facing"));
+		assert_eq!(Some("false"), states.value(s, "lit"));
+	}
+}
@@ -0,0 +1,60 @@
+//! Helpers for tests of chunks and the extensions that build on it.
+//! Other extensions use these by enabling the "test_util" feature in their dev-dependency on chunks.
+
+use glam::{IVec3, UVec3};
+use crate::{blocks::{BlockEntry, BlockKey, BlockManager, BlockRenderType, BlockSpecification}, chunks::{ChunkKey, Chunks}, cube_iterator_xyz_uvec, states::BlockStates};
+
+
+
+/// Adds a block without sounds or events.
+/// Extra fields are added to its specification, like "light_emission: Some((15, 0, 8)),".
+pub fn block(blocks: &mut BlockManager, name: &str, render_type: BlockRenderType, covering: bool, extra: &str) -> BlockKey {
+	block_with_events(blocks, name, render_type, covering, BlockEvents::default(), extra)
+}
+
+
+/// Which events a block from [block_with_events] has.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockEvents {
+	pub on_place: bool,
+	pub on_interact: bool,
+	pub on_break: bool,
+}
+
+
+/// Adds a block without sounds, with these events.
+pub fn block_with_events(blocks: &mut BlockManager, name: &str, render_type: BlockRenderType, covering: bool, events: BlockEvents, extra: &str) -> BlockKey {
+	let BlockEvents { on_place, on_interact, on_break } = events;
+	let specification = ron::de::from_str::<BlockSpecification>(&format!(r#"(
+		name: "{name}",
+		render_type: Colour,
+		floats: {{}},
+		sounds: {{}},
+		on_place: {on_place},
+		on_interact: {on_interact},
+		on_break: {on_break},
+		{extra}
+	)"#)).unwrap();
+	blocks.insert(BlockEntry {
+		specification,
+		path: None,
+		render_type,
+		covering,
+		states: BlockStates::default(),
+	})
+}
+
+
+/// Adds a plain covering block, like stone.
+pub fn colour_block(blocks: &mut BlockManager, name: &str) -> BlockKey {
+	block(blocks, name, BlockRenderType::Colour, true, "")
+}
+
+
+/// Loads the chunks from min to max inclusive, giving their positions and keys.
+pub fn load_chunks(chunks: &mut Chunks, min: IVec3, max: IVec3) -> Vec<(IVec3, ChunkKey)> {
+	cube_iterator_xyz_uvec((max - min + IVec3::ONE).as_uvec3())
+		.map(|p: UVec3| min + p.as_ivec3())
+		.map(|position| (position, chunks.load(position)))
+		.collect()
+}
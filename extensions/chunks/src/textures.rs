@@ -0,0 +1,182 @@
+//! Block face textures are layers of one array texture so that chunks can be drawn with a single material.
+//! Faces find their layer through their material, whose "texture" mapping is the layer's image.
+//! Layer 0 is a placeholder for faces whose texture has no layer.
+
+use std::{collections::{HashMap, HashSet}, path::PathBuf};
+use eeks::prelude::*;
+use krender::{prelude::{GlobalResourceIdentifier, MaterialManager, MaterialResourceLocation, Texture, TextureFormat}, MaterialKey};
+use pinecore::render::TextureResource;
+use crate::blocks::BlockResource;
+
+
+
+/// The label of the block texture array. 
+pub const BLOCK_TEXTURES: &str = "block textures";
+
+
+#[derive(Debug, Default)]
+pub struct BlockTextures {
+	// Image paths of the layers after the missing layer
+	layers: Vec<PathBuf>,
+	layer_by_path: HashMap<PathBuf, u32>,
+	layer_by_material: HashMap<MaterialKey, u32>,
+	// Materials that have been warned about for having no layer
+	missing: HashSet<MaterialKey>,
+	// The number of layers in the array texture when it was last built
+	built: usize,
+}
+impl BlockTextures {
+	/// The layer drawn for faces whose texture has no layer. 
+	pub const MISSING_LAYER: u32 = 0;
+
+	/// Gives a material's texture a layer if it doesn't have one yet. 
+	/// Materials that don't map a texture by path have no layer, which is warned about once. 
+	pub fn insert(&mut self, material: MaterialKey, materials: &MaterialManager) -> Option<u32> {
+		if let Some(&layer) = self.layer_by_material.get(&material) {
+			return Some(layer);
+		}
+		let path = match materials.get(material).map(|e| (e, e.specification.mappings.get("texture"))) {
+			Some((_, Some(MaterialResourceLocation::Global(GlobalResourceIdentifier::Path(path))))) => path,
+			Some((entry, _)) => {
+				if self.missing.insert(material) {
+					warn!("Block material '{}' has no texture path, so it is drawn with the missing texture", entry.specification.name);
+				}
+				return None;
+			},
+			None => {
+				if self.missing.insert(material) {
+					warn!("Block material {material:?} doesn't exist, so it is drawn with the missing texture");
+				}
+				return None;
+			},
+		};
+		let layer = *self.layer_by_path.entry(path.clone()).or_insert_with(|| {
+			self.layers.push(path.clone());
+			self.layers.len() as u32
+		});
+		self.layer_by_material.insert(material, layer);
+		Some(layer)
+	}
+
+	/// The layer of a material's texture, or None if it doesn't have one. 
+	pub fn layer(&self, material: MaterialKey) -> Option<u32> {
+		self.layer_by_material.get(&material).copied()
+	}
+
+	/// The image paths of the layers after the missing layer. 
+	pub fn layers(&self) -> &[PathBuf] {
+		&self.layers
+	}
+
+	// Has a layer been added since the array texture was built? 
+	fn outdated(&self) -> bool {
+		self.layers.len() != self.built
+	}
+
+	/// Reads every layer's image, resized to the size of the first, after a magenta and black missing layer. 
+	/// Images that can't be read are left transparent. 
+	pub fn read(&self, format: TextureFormat) -> Option<(u32, u32, Vec<u8>)> {
+		let images = self.layers.iter()
+			.map(|path| image::open(path).map_err(|e| error!("Failed to read block texture {path:?} - {e}")).ok())
+			.collect::<Vec<_>>();
+		let (width, height) = images.iter().flatten().next().map(|i| (i.width(), i.height()))?;
+
+		let layer_bytes = (format.bytes_per_element() * width * height) as usize;
+		let mut data = Vec::with_capacity(layer_bytes * (images.len() + 1));
+		let missing = image::RgbaImage::from_fn(width, height, |x, y| {
+			if (x * 2 / width + y * 2 / height) % 2 == 0 { image::Rgba([255, 0, 255, 255]) } else { image::Rgba([0, 0, 0, 255]) }
+		});
+		data.extend(format.image_bytes(&missing.into()));
+		for (path, image) in self.layers.iter().zip(images) {
+			match image {
+				Some(i) if (i.width(), i.height()) == (width, height) => data.extend(format.image_bytes(&i)),
+				Some(i) => {
+					warn!("Block texture {path:?} is resized from {}x{} to {width}x{height}", i.width(), i.height());
+					data.extend(format.image_bytes(&i.resize_exact(width, height, image::imageops::FilterType::Nearest)));
+				},
+				None => data.resize(data.len() + layer_bytes, 0),
+			}
+		}
+		Some((width, height, data))
+	}
+}
+
+
+/// Builds the block texture array once blocks have been loaded, and again if more are added. 
+pub fn block_texture_array_system(
+	blocks: Res<BlockResource>,
+	mut textures: ResMut<TextureResource>,
+) {
+	if !blocks.read().textures.outdated() {
+		return;
+	}
+	let mut blocks = blocks.write();
+	let block_textures = &mut blocks.textures;
+	block_textures.built = block_textures.layers.len();
+
+	let format = TextureFormat::Rgba8Unorm;
+	let Some((width, height, data)) = block_textures.read(format) else {
+		error!("No block textures could be read");
+		return;
+	};
+	let layers = block_textures.layers.len() as u32 + 1;
+	info!("Build block texture array with {layers} {width}x{height} layers");
+
+	// Materials keep the same texture, only its contents change
+	match textures.key_by_name(&BLOCK_TEXTURES.to_string()) {
+		Some(key) => {
+			let texture = textures.get_mut(key).unwrap();
+			texture.set_size(width, height, layers);
+			texture.set_full_mips();
+			texture.set_data(data);
+		},
+		None => {
+			let texture = Texture::new_d2_array(BLOCK_TEXTURES, format, width, height, layers, data)
+				.with_full_mips();
+			textures.insert(texture);
+		},
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use krender::prelude::MaterialSpecification;
+
+	fn material(materials: &mut MaterialManager, name: &str, texture: Option<&str>) -> MaterialKey {
+		let mut mappings = HashMap::new();
+		if let Some(texture) = texture {
+			mappings.insert("texture".to_string(), MaterialResourceLocation::Global(GlobalResourceIdentifier::Path(texture.into())));
+		}
+		materials.insert(MaterialSpecification {
+			name: name.to_string(),
+			shader: PathBuf::new(),
+			mappings,
+			array_mappings: HashMap::new(),
+		})
+	}
+
+	#[test]
+	fn test_texture_layers() {
+		let mut materials = MaterialManager::new();
+		let stone = material(&mut materials, "stone", Some("stone.png"));
+		let dirt = material(&mut materials, "dirt", Some("dirt.png"));
+		// Another material with the same texture
+		let path = material(&mut materials, "path", Some("dirt.png"));
+		let colour = material(&mut materials, "colour", None);
+
+		let mut textures = BlockTextures::default();
+		assert_eq!(Some(1), textures.insert(stone, &materials));
+		assert_eq!(Some(2), textures.insert(dirt, &materials));
+		assert_eq!(Some(2), textures.insert(path, &materials));
+		assert_eq!(Some(1), textures.insert(stone, &materials));
+		assert_eq!(None, textures.insert(colour, &materials));
+		assert_eq!(None, textures.insert(colour, &materials));
+
+		assert_eq!(Some(2), textures.layer(path));
+		assert_eq!(None, textures.layer(colour));
+		assert!(textures.missing.contains(&colour));
+		assert_eq!(&[PathBuf::from("stone.png"), PathBuf::from("dirt.png")], textures.layers());
+	}
+}
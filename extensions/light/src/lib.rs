@@ -3,9 +3,9 @@ pub mod sun;
 
 use pinecore::controls::ControlMap;
 use eeks::prelude::*;
-use light::{torchlight_chunk_init_system, torchlight_debug_place_system, torchlight_update_system, TorchLightChunksResource, TorchLightModifierComponent};
+use light::{torchlight_chunk_init_system, torchlight_debug_place_system, torchlight_saving_system, torchlight_update_system, TorchLightChunksResource, TorchLightModifierComponent};
 use pinecore::player::PlayerSpawnResource;
-use sun::{sun_buffer_system, sun_render_system, SunResource};
+use sun::{sun_buffer_system, sun_render_system, sunlight_update_system, SunChunksResource, SunResource};
 
 #[macro_use]
 extern crate log;
@@ -41,6 +41,11 @@ pub fn systems(loader: &mut ExtensionSystemsLoader) {
 	loader.system("client_tick", "torchlight_update_system", torchlight_update_system)
 		.run_after("torchlight_chunk_init_system");
 
+	loader.system("client_shutdown", "torchlight_saving_system", torchlight_saving_system);
+
+	loader.system("client_tick", "sunlight_update_system", sunlight_update_system)
+		.run_after("chunk_loading_system");
+
 	loader.system("client_tick", "torchlight_debug_place_system", torchlight_debug_place_system)
 		.run_before("torchlight_update_system");
 
@@ -59,5 +64,6 @@ pub fn systems(loader: &mut ExtensionSystemsLoader) {
 pub fn load(storages: &mut eeks::ExtensionStorageLoader) {
 	storages.resource(TorchLightChunksResource::default());
 	storages.resource(SunResource::new());
+	storages.resource(SunChunksResource::default());
 	storages.component::<TorchLightModifierComponent>();
 }
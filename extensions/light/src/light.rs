@@ -1,11 +1,11 @@
-use std::{sync::Arc, num::NonZeroU16, time::{Instant, Duration}};
-use chunks::{array_volume::ArrayVolume, chunk_of_point, chunk_of_voxel, chunks::{ChunkKey, ChunksResource}, fvt::FVTIterator, generation::KGeneration, voxel_relative_to_chunk, CHUNK_SIZE};
+use std::{collections::{HashMap, VecDeque}, num::NonZeroU16, path::{Path, PathBuf}, sync::Arc, time::{Instant, Duration}};
+use chunks::{array_volume::ArrayVolume, blocks::{BlockKey, BlockManager, BlockResource}, chunk_of_point, chunk_of_voxel, chunks::{ChunkEvent, ChunkKey, Chunks, ChunksResource}, cube_iterator_xyz_uvec, generation::{ChangeHistory, KGeneration}, voxel_relative_to_chunk, CHUNK_SIZE};
 use pinecore::controls::{ControlComponent, ControlKey, ControlMap, KeyCode, KeyCombo, KeyKey, KeyModifiers};
 use eeks::prelude::*;
 use glam::{IVec3, UVec3, Vec3, Vec4};
 use parking_lot::RwLock;
 use slotmap::SecondaryMap;
-use terrain::terrain::TerrainResource;
+use terrain::{raycast::{Ray, RaycastFilter}, terrain::{TerrainChunk, TerrainResource}};
 use pinecore::transform::TransformComponent;
 
 
@@ -63,8 +63,14 @@ use pinecore::transform::TransformComponent;
 const U4_MAX_U16: u16 = 2_u16.pow(4) - 1;
 const U4_MAX_F32: f32 = U4_MAX_U16 as f32;
 
+const NEIGHBOURS: [IVec3; 6] = [
+	IVec3::X, IVec3::NEG_X, 
+	IVec3::Y, IVec3::NEG_Y, 
+	IVec3::Z, IVec3::NEG_Z, 
+];
 
-#[derive(Debug, Clone, Copy)]
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct LightRGBA {
 	pub r: u16,
 	pub g: u16,
@@ -72,6 +78,27 @@ pub struct LightRGBA {
 	pub a: u16, // Scaling for all channels
 }
 impl LightRGBA {
+	pub const ZERO: Self = Self { r: 0, g: 0, b: 0, a: 0, };
+
+	/// Light given off by a block with this emission. 
+	/// Alpha is the brightest channel. 
+	pub fn from_emission(emission: [u16; 3]) -> Self {
+		let [r, g, b] = emission.map(|c| c.min(U4_MAX_U16));
+		Self { r, g, b, a: r.max(g).max(b), }
+	}
+
+	pub fn to_array(self) -> [u16; 4] {
+		[self.r, self.g, self.b, self.a]
+	}
+
+	pub fn from_array([r, g, b, a]: [u16; 4]) -> Self {
+		Self { r, g, b, a, }
+	}
+
+	pub fn is_dark(&self) -> bool {
+		*self == Self::ZERO
+	}
+
 	pub fn into_vec4(self) -> Vec4 {
 		Vec4::new(
 			self.r as f32 / U4_MAX_F32, 
@@ -81,6 +108,30 @@ impl LightRGBA {
 		)
 	}
 
+	/// Multiplies each colour channel by a filter colour.
+	pub fn filtered(mut self, filter: [f32; 3]) -> Self {
+		let f = |c: u16, f: f32| ((c as f32 * f.clamp(0.0, 1.0)).round() as u16).min(U4_MAX_U16);
+		self.r = f(self.r, filter[0]);
+		self.g = f(self.g, filter[1]);
+		self.b = f(self.b, filter[2]);
+		self
+	}
+
+	/// Channel-wise maximum.
+	pub fn max(self, other: Self) -> Self {
+		Self {
+			r: self.r.max(other.r),
+			g: self.g.max(other.g),
+			b: self.b.max(other.b),
+			a: self.a.max(other.a),
+		}
+	}
+
+	/// Is any channel brighter than in the other light?
+	pub fn any_brighter(&self, other: &Self) -> bool {
+		self.r > other.r || self.g > other.g || self.b > other.b || self.a > other.a
+	}
+
 	// Todo: SIMD
 	#[inline]
 	pub fn dec(mut self) -> Self {
@@ -130,23 +181,61 @@ impl From<Option<PackedLightRGBA>> for LightRGBA {
 pub struct LightChunk {
 	volume: ArrayVolume<PackedLightRGBA>,
 	pub generation: KGeneration, // Generation of light
+	changes: ChangeHistory,
+	// Voxels that give off light and how much
+	pub sources: HashMap<UVec3, LightRGBA>,
+	// The terrain this was lit with, None until the chunk's terrain is complete
+	// Changes are found by comparing it to the current terrain
+	pub terrain: Option<Arc<TerrainChunk>>,
 	
 	// Relight neighbour if outgoing lights changed
 	// pub outgoing_lights: Vec<(IVec3, DefaultKey)>,
 	// pub incoming_lights: RwLock<(SlotMap<DefaultKey, (IVec3, bool)>, KGeneration)>,
 }
+impl Default for LightChunk {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 impl LightChunk {
 	pub fn new() -> Self {
 		Self {
 			volume: ArrayVolume::new(UVec3::splat(CHUNK_SIZE)),
 			generation: KGeneration::new(),
+			changes: ChangeHistory::new(),
+			sources: HashMap::new(),
+			terrain: None,
 		}
 	}
+
+	/// Increments the generation and remembers which voxels were changed. 
+	pub fn record_change(&mut self, min: UVec3, max: UVec3) {
+		self.changes.record(&mut self.generation, min, max);
+	}
+
+	/// The bounds of the voxels changed since a generation, or None if it is current. 
+	pub fn changed_since(&self, generation: KGeneration) -> Option<(UVec3, UVec3)> {
+		self.changes.changed_since(self.generation, generation)
+	}
+
+	/// Sources that were placed rather than coming from the blocks of its terrain. 
+	pub fn placed_sources(&self, blocks: &BlockManager) -> HashMap<UVec3, LightRGBA> {
+		let emission = |p: &UVec3| self.terrain.as_ref()
+			.and_then(|t| t.get(*p))
+			.and_then(|b| blocks.get(b))
+			.and_then(|e| e.specification.light_emission)
+			.map(LightRGBA::from_emission);
+		self.sources.iter()
+			.filter(|&(p, &l)| emission(p) != Some(l))
+			.map(|(&p, &l)| (p, l))
+			.collect()
+	}
 }
 impl std::fmt::Debug for LightChunk {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		f.debug_struct("LightChunk")
 			.field("generation", &self.generation)
+			.field("sources", &self.sources.len())
 			.finish()
 	}
 }
@@ -164,167 +253,450 @@ impl std::ops::DerefMut for LightChunk {
 
 
 
+/// How light is changed by entering a voxel containing this block.
+/// Gives None if light cannot enter it.
+pub fn light_filter(blocks: &BlockManager, block: Option<BlockKey>) -> Option<[f32; 3]> {
+	match block.map(|key| blocks.get(key)) {
+		None => Some([1.0; 3]),
+		Some(Some(e)) if e.transmits_light() => Some(e.specification.light_filter.unwrap_or([1.0; 3])),
+		// Unknown blocks are assumed to be opaque
+		_ => None,
+	}
+}
+
+
 #[derive(Debug, Default, Resource)]
 pub struct TorchLightChunksResource {
 	pub chunks: Arc<RwLock<SecondaryMap<ChunkKey, LightChunk>>>,
+	// Light sources to create, kept until their chunk has been lit
 	pub add_lights: Vec<(IVec3, LightRGBA)>,
+	// Voxels whose light (and whatever spread from it) should be removed
 	pub del_lights: Vec<IVec3>, 
 }
 
 
-/// Creates torchlight storage for existing chunks. 
-/// Removes torchlight storage for non-existing chunks. 
-pub fn torchlight_chunk_init_system(
-	chunks: Res<ChunksResource>,
-	torchlight_chunks: ResMut<TorchLightChunksResource>,
-) {
-	let chunks = chunks.read();
-	let mut torchlight_chunks = torchlight_chunks.chunks.write();
+/// Where the placed light sources of a chunk are saved, next to its saved terrain. 
+pub fn saved_sources_path(directory: impl AsRef<Path>, position: IVec3) -> PathBuf {
+	directory.as_ref().join(format!("{}_{}_{}.light", position.x, position.y, position.z))
+}
 
-	{ // Insert new
-		for (key, &pos) in chunks.chunks.iter() {
-			if !torchlight_chunks.contains_key(key) {
-				debug!("Add torchlight for chunk {}", pos);
-				torchlight_chunks.insert(key, LightChunk::new());
-			}
-		}
-	}
-	
-	{ // Remove old
-		torchlight_chunks.retain(|key, _| chunks.chunks.contains_key(key));
+
+pub fn read_saved_sources(path: impl AsRef<Path>) -> anyhow::Result<HashMap<UVec3, LightRGBA>> {
+	let b = std::fs::read(path.as_ref())?;
+	let sources = bincode::deserialize::<Vec<([u32; 3], [u16; 4])>>(b.as_slice())?;
+	Ok(sources.into_iter()
+		.map(|(p, [r, g, b, a])| (UVec3::from_array(p), LightRGBA { r, g, b, a, }))
+		.collect())
+}
+
+
+pub fn write_saved_sources(path: impl AsRef<Path>, sources: &HashMap<UVec3, LightRGBA>) -> anyhow::Result<()> {
+	let path = path.as_ref();
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
 	}
+	let mut sources = sources.iter()
+		.map(|(p, l)| (p.to_array(), [l.r, l.g, l.b, l.a]))
+		.collect::<Vec<_>>();
+	sources.sort();
+	std::fs::write(path, bincode::serialize(&sources)?)?;
+	Ok(())
 }
 
 
-/// Relights chunks
-pub fn torchlight_update_system(
-	chunks: Res<ChunksResource>,
-	terrain: Res<TerrainResource>,
-	mut torchlight: ResMut<TorchLightChunksResource>,
-) {
-	// Idea: only do a few of these each frame, inserion sorted by distance to player
+/// Spreads and removes torchlight across chunks. 
+/// Chunks that have not been lit with their terrain yet are treated as opaque, 
+/// light spreads into them once they are. 
+/// 
+/// Light is kept for each channel separately, so coloured lights mix. 
+/// The voxels changed in each chunk are only recorded, as one change, when [Self::finish] is called. 
+/// Meshing does not see changes that are never recorded. 
+#[must_use = "changes are only recorded by finish"]
+pub struct Torchlight<'a> {
+	pub chunks: &'a Chunks,
+	pub blocks: &'a BlockManager,
+	pub light: &'a mut SecondaryMap<ChunkKey, LightChunk>,
+	// Bounds of the voxels changed in each chunk
+	changed: HashMap<ChunkKey, (UVec3, UVec3)>,
+}
+impl<'a> Torchlight<'a> {
+	pub fn new(chunks: &'a Chunks, blocks: &'a BlockManager, light: &'a mut SecondaryMap<ChunkKey, LightChunk>) -> Self {
+		Self { chunks, blocks, light, changed: HashMap::new(), }
+	}
 
-	// I've set it up this way because I may wish to parallelize it later
-	let mut to_remove = Vec::new();
-	let mut to_propagate = Vec::new();
-	for pos in torchlight.del_lights.iter().copied() {
-		debug!("Remove light at {:?}", pos);
-		let torchlight_chunks = torchlight.chunks.read();
-		let terrain_chunks = terrain.chunks.read();
-		let mut queue = Vec::new();
-
-		let chunk_pos = chunk_of_voxel(pos);
-		let pos_in_chunk = voxel_relative_to_chunk(pos, chunk_pos).as_uvec3();
-		let chunk = chunks.read().get_position(chunk_pos).unwrap();
-		let torch = torchlight_chunks.get(chunk).unwrap();
-		let light: LightRGBA = torch.get(pos_in_chunk).copied().into();
-		queue.push((pos, light));
-		to_remove.push((chunk, pos_in_chunk));
-
-		while let Some((this_pos, this_light)) = queue.pop() {
-			for offs in [
-				IVec3::X, IVec3::NEG_X, 
-				// IVec3::Y, IVec3::NEG_Y, 
-				IVec3::Z, IVec3::NEG_Z, 
-			] {
-				let neighbour_pos = this_pos + offs;
-				let neighbour_chunk_pos = chunk_of_voxel(neighbour_pos);
-				let neighbour_pos_in_chunk = voxel_relative_to_chunk(neighbour_pos, neighbour_chunk_pos).as_uvec3();
-
-				let neighbour_chunk = chunks.read().get_position(neighbour_chunk_pos).unwrap();
-				let neighbour_torch = torchlight_chunks.get(neighbour_chunk).unwrap();
-				let neighbour_terrain = terrain_chunks.get(neighbour_chunk).unwrap().complete_ref().unwrap();
-				
-				if neighbour_terrain.get(neighbour_pos_in_chunk).is_some() {
-					continue
-				}
+	fn locate(&self, voxel: IVec3) -> Option<(ChunkKey, UVec3)> {
+		let chunk = chunk_of_voxel(voxel);
+		let key = self.chunks.get_position(chunk)?;
+		self.light.get(key).filter(|l| l.terrain.is_some())?;
+		Some((key, voxel_relative_to_chunk(voxel, chunk).as_uvec3()))
+	}
 
-				let neighbour_light: LightRGBA = neighbour_torch.get(neighbour_pos_in_chunk).copied().into();
-				if neighbour_light.r >= this_light.r {
-					if neighbour_light.r != 0 {
-						to_propagate.push((neighbour_pos, neighbour_light));
-					}
-				} else {
-					to_remove.push((neighbour_chunk, neighbour_pos_in_chunk));
-					queue.push((neighbour_pos, neighbour_light));
+	pub fn get(&self, voxel: IVec3) -> LightRGBA {
+		self.locate(voxel)
+			.map(|(key, p)| self.light[key].get(p).copied().into())
+			.unwrap_or_default()
+	}
+
+	// None if light can't enter this voxel
+	fn filter(&self, voxel: IVec3) -> Option<[f32; 3]> {
+		let (key, p) = self.locate(voxel)?;
+		light_filter(self.blocks, self.light[key].terrain.as_ref()?.get(p))
+	}
+
+	fn set(&mut self, key: ChunkKey, p: UVec3, light: LightRGBA) {
+		let chunk = &mut self.light[key];
+		match light.into() {
+			Some(packed) => chunk.insert(p, packed),
+			None => chunk.remove(p),
+		}
+		let bounds = self.changed.entry(key).or_insert((p, p));
+		*bounds = (bounds.0.min(p), bounds.1.max(p));
+	}
+
+	/// Spreads light outward from these voxels. 
+	pub fn propagate(&mut self, seeds: impl IntoIterator<Item = IVec3>) {
+		let mut queue = seeds.into_iter().collect::<VecDeque<_>>();
+		while let Some(pos) = queue.pop_front() {
+			let light = self.get(pos);
+			if light.is_dark() {
+				continue
+			}
+			for offs in NEIGHBOURS {
+				let neighbour_pos = pos + offs;
+				// Light passes through blocks that don't fill their voxel, and is tinted by them
+				let Some(filter) = self.filter(neighbour_pos) else {
+					continue
+				};
+				let spread = light.dec().filtered(filter);
+				let neighbour_light = self.get(neighbour_pos);
+				if !spread.any_brighter(&neighbour_light) {
+					continue
 				}
+				let (key, p) = self.locate(neighbour_pos).unwrap();
+				self.set(key, p, spread.max(neighbour_light));
+				queue.push_back(neighbour_pos);
 			}
 		}
 	}
-	// This is where one would deduplicate (if one wanted to bother)
-	{
-		if to_remove.len() > 0 {
-			let n = to_remove.len();
-			debug!("Removed {} lights", n);
-			to_remove.sort_unstable_by_key(|&(k, p)| (k, p.x, p.y, p.z));
-			to_remove.dedup();
-			if n != to_remove.len() {
-				warn!("Deduplicated to {} lights", to_remove.len());
+
+	/// Removes the light in these voxels and whatever light spread from them. 
+	/// Sources that are reached light themselves again. 
+	/// Returns voxels that light should be propagated from to fill in what was wrongly removed. 
+	pub fn remove(&mut self, voxels: impl IntoIterator<Item = IVec3>) -> Vec<IVec3> {
+		let mut queue = VecDeque::new();
+		let mut refill = Vec::new();
+		for voxel in voxels {
+			let Some((key, p)) = self.locate(voxel) else {
+				continue
+			};
+			let light = self.get(voxel);
+			if !light.is_dark() {
+				self.clear(key, p, voxel, LightRGBA::ZERO, &mut refill);
+				queue.push_back((voxel, light));
 			}
 		}
-		let mut torchlight_chunks = torchlight.chunks.write();
-		for (key, pos) in to_remove {
-			let torch = torchlight_chunks.get_mut(key).unwrap();
-			torch.remove(pos);
-			torch.generation.increment();
+		self.unspread(queue, refill)
+	}
+
+	// Removes light that spread from these voxels with these lights, the voxels themselves are not changed
+	fn unspread(&mut self, mut queue: VecDeque<(IVec3, LightRGBA)>, mut refill: Vec<IVec3>) -> Vec<IVec3> {
+		// Each channel is removed on its own 
+		// A neighbour dimmer than the removed light may have been lit by it, a brighter one was lit by something else
+		while let Some((pos, removed)) = queue.pop_front() {
+			let removed = removed.to_array();
+			for offs in NEIGHBOURS {
+				let neighbour_pos = pos + offs;
+				let Some((key, p)) = self.locate(neighbour_pos) else {
+					continue
+				};
+				let mut remaining = self.get(neighbour_pos).to_array();
+				let mut cleared = [0; 4];
+				let mut lit_elsewhere = false;
+				for c in 0..4 {
+					if removed[c] == 0 || remaining[c] == 0 {
+						continue
+					}
+					if remaining[c] < removed[c] {
+						cleared[c] = remaining[c];
+						remaining[c] = 0;
+					} else {
+						lit_elsewhere = true;
+					}
+				}
+				if cleared != [0; 4] {
+					self.clear(key, p, neighbour_pos, LightRGBA::from_array(remaining), &mut refill);
+					queue.push_back((neighbour_pos, LightRGBA::from_array(cleared)));
+				}
+				if lit_elsewhere {
+					refill.push(neighbour_pos);
+				}
+			}
 		}
+		refill
 	}
-	// torchlight.add_lights.extend(to_propagate);
 
-	// We cannot delay setting here, because otherwise we can loop infinitely 
-	// Maybe we could track what was set, but it's more work so no 
-	for (pos, light) in torchlight.add_lights.iter().copied() {
-		debug!("Create light at {:?}", pos);
-		let mut torchlight_chunks = torchlight.chunks.write();
-		let terrain_chunks = terrain.chunks.read();
-		let mut queue = Vec::new();
-
-		let chunk_pos = chunk_of_voxel(pos);
-		let pos_in_chunk = voxel_relative_to_chunk(pos, chunk_pos).as_uvec3();
-		let chunk = chunks.read().get_position(chunk_pos).unwrap();
-		queue.push((pos, light));
-		let torch = torchlight_chunks.get_mut(chunk).unwrap();
-		let packed: Option<PackedLightRGBA> = light.into();
-		if let Some(packed) = packed {
-			torch.insert(pos_in_chunk, packed);
-			torch.generation.increment();
+	// Sets a voxel's light to what is left after removal, which is brought back up if it is a source
+	fn clear(&mut self, key: ChunkKey, p: UVec3, voxel: IVec3, remaining: LightRGBA, refill: &mut Vec<IVec3>) {
+		match self.light[key].sources.get(&p).copied() {
+			Some(source) => {
+				self.set(key, p, remaining.max(source));
+				refill.push(voxel);
+			},
+			None => self.set(key, p, remaining),
 		}
+	}
 
-		while let Some((this_pos, this_light)) = queue.pop() {
-			let this_light_dec = this_light.dec();
+	/// Makes a voxel give off light. 
+	/// Returns false if its chunk has not been lit yet. 
+	pub fn add_source(&mut self, voxel: IVec3, light: LightRGBA) -> bool {
+		let Some((key, p)) = self.locate(voxel) else {
+			return false
+		};
+		self.light[key].sources.insert(p, light);
+		let current = self.get(voxel);
+		self.set(key, p, current.max(light));
+		self.propagate([voxel]);
+		true
+	}
 
-			for offs in [
-				IVec3::X, IVec3::NEG_X, 
-				// IVec3::Y, IVec3::NEG_Y, 
-				IVec3::Z, IVec3::NEG_Z, 
-			] {
-				let neighbour_pos = this_pos + offs;
-				let neighbour_chunk_pos = chunk_of_voxel(neighbour_pos);
-				let neighbour_pos_in_chunk = voxel_relative_to_chunk(neighbour_pos, neighbour_chunk_pos).as_uvec3();
+	/// Removes the light in a voxel, and its source if it has one. 
+	/// Light from elsewhere is spread back in. 
+	/// Returns false if its chunk has not been lit yet. 
+	pub fn remove_source(&mut self, voxel: IVec3) -> bool {
+		let Some((key, p)) = self.locate(voxel) else {
+			return false
+		};
+		self.light[key].sources.remove(&p);
+		let refill = self.remove([voxel]);
+		self.propagate(refill);
+		true
+	}
 
-				let neighbour_chunk = chunks.read().get_position(neighbour_chunk_pos).unwrap();
-				let neighbour_torch = torchlight_chunks.get(neighbour_chunk).unwrap();
-				let neighbour_terrain = terrain_chunks.get(neighbour_chunk).unwrap().complete_ref().unwrap();
+	/// Takes out a chunk's light, first removing the light that spread from it into its neighbours. 
+	/// This leaves the same light as if the chunk had never been lit. 
+	/// Returns the sources that were placed rather than coming from its blocks, 
+	/// which would be lost if the chunk were loaded again. 
+	/// The chunk might already be gone from [Chunks], so its position is given. 
+	pub fn unload(&mut self, key: ChunkKey, position: IVec3) -> HashMap<UVec3, LightRGBA> {
+		let Some(chunk) = self.light.remove(key) else {
+			return HashMap::new()
+		};
+		let origin = position * CHUNK_SIZE as i32;
+		let s = CHUNK_SIZE as i32;
+		let mut queue = VecDeque::new();
+		for a in 0..s {
+			for b in 0..s {
+				for p in [
+					IVec3::new(0, a, b), IVec3::new(s - 1, a, b), 
+					IVec3::new(a, 0, b), IVec3::new(a, s - 1, b), 
+					IVec3::new(a, b, 0), IVec3::new(a, b, s - 1), 
+				] {
+					let light: LightRGBA = chunk.get(p.as_uvec3()).copied().into();
+					if !light.is_dark() {
+						queue.push_back((origin + p, light));
+					}
+				}
+			}
+		}
+		let refill = self.unspread(queue, Vec::new());
+		self.propagate(refill);
+		chunk.placed_sources(self.blocks)
+	}
 
-				if neighbour_terrain.get(neighbour_pos_in_chunk).is_some() {
-					continue
+	/// Lights a chunk with new terrain. 
+	/// The first time this lights the chunk's sources, finds more in its blocks, and lets in light from its neighbours. 
+	/// After that it only relights the voxels that differ from the last terrain. 
+	pub fn update_terrain(&mut self, key: ChunkKey, terrain: Arc<TerrainChunk>) {
+		let (Some(&position), Some(light)) = (self.chunks.chunks.get(key), self.light.get_mut(key)) else {
+			return
+		};
+		let previous = light.terrain.replace(terrain.clone());
+		let origin = position * CHUNK_SIZE as i32;
+		let blocks = self.blocks;
+		let emission = |p: UVec3| terrain.get(p)
+			.and_then(|b| blocks.get(b))
+			.and_then(|e| e.specification.light_emission)
+			.map(LightRGBA::from_emission);
+
+		let mut seeds = Vec::new();
+		match previous {
+			None => {
+				for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)) {
+					if let Some(l) = emission(p) {
+						self.light[key].sources.insert(p, l);
+					}
 				}
-				let neighbour_light: LightRGBA = neighbour_torch.get(neighbour_pos_in_chunk).copied().into();
-				if neighbour_light.r >= this_light_dec.r {
-					continue
+				let sources = self.light[key].sources.iter().map(|(&p, &l)| (p, l)).collect::<Vec<_>>();
+				for (p, l) in sources {
+					let current = self.get(origin + p.as_ivec3());
+					self.set(key, p, current.max(l));
+					seeds.push(origin + p.as_ivec3());
 				}
-				queue.push((neighbour_pos, this_light_dec));
-				let torch = torchlight_chunks.get_mut(neighbour_chunk).unwrap();
-				let packed: Option<PackedLightRGBA> = this_light_dec.into();
-				if let Some(packed) = packed {
-					torch.insert(neighbour_pos_in_chunk, packed);
-					torch.generation.increment();
+				// Light can now spread in from neighbouring chunks
+				let s = CHUNK_SIZE as i32;
+				for a in 0..s {
+					for b in 0..s {
+						seeds.extend([
+							IVec3::new(-1, a, b), IVec3::new(s, a, b), 
+							IVec3::new(a, -1, b), IVec3::new(a, s, b), 
+							IVec3::new(a, b, -1), IVec3::new(a, b, s), 
+						].map(|p| origin + p));
+					}
+				}
+			},
+			Some(old) => {
+				if Arc::ptr_eq(&old, &terrain) {
+					return
+				}
+				let changed = cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE))
+					.filter(|&p| old.get(p) != terrain.get(p))
+					.collect::<Vec<_>>();
+				trace!("Relight {} changed voxels in chunk {position}", changed.len());
+				for p in changed.iter() {
+					self.light[key].sources.remove(p);
 				}
+				seeds = self.remove(changed.iter().map(|p| origin + p.as_ivec3()));
+				for p in changed {
+					let voxel = origin + p.as_ivec3();
+					if let Some(l) = emission(p) {
+						self.light[key].sources.insert(p, l);
+						let current = self.get(voxel);
+						self.set(key, p, current.max(l));
+					}
+					seeds.push(voxel);
+					seeds.extend(NEIGHBOURS.map(|o| voxel + o));
+				}
+			},
+		}
+		self.propagate(seeds);
+	}
+
+	/// Records where the light of each chunk changed, so that meshing sees the changes. 
+	pub fn finish(self) {
+		for (key, (min, max)) in self.changed {
+			if let Some(chunk) = self.light.get_mut(key) {
+				chunk.record_change(min, max);
 			}
 		}
 	}
-	torchlight.add_lights.clear();
-	torchlight.del_lights.clear();
+}
+
+
+/// Creates torchlight storage for loaded chunks, with the placed sources that were saved with their terrain. 
+/// Removes torchlight storage for unloaded chunks along with the light that spread from them, 
+/// saving their placed sources if terrain is being saved. 
+/// Chunks are relit by [torchlight_update_system] once their terrain is complete, 
+/// which finds the sources of their blocks again. 
+pub fn torchlight_chunk_init_system(
+	chunks: Res<ChunksResource>,
+	blocks: Res<BlockResource>,
+	terrain: Res<TerrainResource>,
+	mut torchlight: ResMut<TorchLightChunksResource>,
+) {
+	let chunks = chunks.read();
+	let blocks = blocks.read();
+	let mut torchlight_chunks = torchlight.chunks.write();
+
+	for event in chunks.events.iter().copied() {
+		match event {
+			ChunkEvent::Loaded(key, pos) => if !torchlight_chunks.contains_key(key) {
+				debug!("Add torchlight for chunk {}", pos);
+				let mut light = LightChunk::new();
+				let saved = terrain.save_directory.as_ref()
+					.map(|d| saved_sources_path(d, pos))
+					.filter(|p| p.exists());
+				if let Some(path) = saved {
+					match read_saved_sources(&path) {
+						Ok(sources) => light.sources = sources,
+						Err(e) => error!("Failed to load light sources of chunk {pos} from {path:?}: {e}"),
+					}
+				}
+				torchlight_chunks.insert(key, light);
+			},
+			ChunkEvent::Unloaded(key, pos) => if torchlight_chunks.contains_key(key) {
+				let mut world = Torchlight::new(&chunks, &blocks, &mut torchlight_chunks);
+				let sources = world.unload(key, pos);
+				world.finish();
+				if let Some(directory) = terrain.save_directory.as_ref() {
+					save_sources(directory, pos, &sources);
+				}
+			},
+		}
+	}
+}
+
+
+fn save_sources(directory: &Path, position: IVec3, sources: &HashMap<UVec3, LightRGBA>) {
+	let path = saved_sources_path(directory, position);
+	let saved = if sources.is_empty() {
+		// They were all removed
+		match std::fs::remove_file(&path) {
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			r => r.map_err(anyhow::Error::from),
+		}
+	} else {
+		write_saved_sources(&path, sources)
+	};
+	if let Err(e) = saved {
+		error!("Failed to save light sources of chunk {position} to {path:?}: {e}");
+	}
+}
+
+
+/// Saves the placed sources of chunks that are still loaded, which would otherwise only be saved when unloaded. 
+pub fn torchlight_saving_system(
+	chunks: Res<ChunksResource>,
+	blocks: Res<BlockResource>,
+	terrain: Res<TerrainResource>,
+	torchlight: Res<TorchLightChunksResource>,
+) {
+	let Some(directory) = terrain.save_directory.as_ref() else { return };
+	let chunks = chunks.read();
+	let blocks = blocks.read();
+	for (key, light) in torchlight.chunks.read().iter() {
+		if let Some(&position) = chunks.chunks.get(key) {
+			save_sources(directory, position, &light.placed_sources(&blocks));
+		}
+	}
+}
+
+
+/// Relights chunks whose terrain has changed and applies queued light changes. 
+pub fn torchlight_update_system(
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	blocks: Res<BlockResource>,
+	mut torchlight: ResMut<TorchLightChunksResource>,
+) {
+	let blocks = blocks.read();
+	let chunks = chunks.read();
+	let terrain_chunks = terrain.chunks.read();
+	let torchlight = &mut *torchlight;
+	let mut light_chunks = torchlight.chunks.write();
+
+	// Idea: only do a few of these each frame, inserion sorted by distance to player
+	let updated = light_chunks.iter()
+		.filter_map(|(key, light)| {
+			let terrain = terrain_chunks.get(key)?.complete_ref()?;
+			let current = light.terrain.as_ref().is_some_and(|t| Arc::ptr_eq(t, terrain));
+			(!current).then(|| (key, terrain.clone()))
+		})
+		.collect::<Vec<_>>();
+
+	let mut world = Torchlight::new(&chunks, &blocks, &mut light_chunks);
+	for (key, terrain) in updated {
+		world.update_terrain(key, terrain);
+	}
+
+	torchlight.del_lights.retain(|&pos| {
+		debug!("Remove light at {:?}", pos);
+		!world.remove_source(pos)
+	});
+	torchlight.add_lights.retain(|&(pos, light)| {
+		debug!("Create light at {:?}", pos);
+		!world.add_source(pos, light)
+	});
+	world.finish();
 }
 
 
@@ -417,18 +789,16 @@ pub fn torchlight_debug_place_system(
 			let mut tlc = torchlight.chunks.write();
 			let lc = tlc.get_mut(k).unwrap();
 			lc.volume.fill_with(PackedLightRGBA(NonZeroU16::MAX));
-			lc.generation.increment();
+			lc.record_change(UVec3::ZERO, UVec3::splat(CHUNK_SIZE - 1));
 		}
 
 		if control.last_tick_pressed(modifier.place) && modifier.last_modification.and_then(|i| Some(i.elapsed() > Duration::from_secs_f32(0.1))).unwrap_or(true) {
 			modifier.last_modification = Some(Instant::now());
 
 			// Find the first filled voxel
-			let v = FVTIterator::new(
-				transform.translation, 
-				transform.rotation.mul_vec3(Vec3::Z), 
-				0.0, 10.0, 1.0,
-			).find(|r| terrain.get_voxel(&chunks, r.voxel).is_some());
+			let ray = Ray::new(transform.translation, transform.rotation.mul_vec3(Vec3::Z));
+			let v = terrain.raycast(&chunks, ray, 10.0, RaycastFilter::default())
+				.filter(|v| v.normal != IVec3::ZERO);
 
 			if let Some(v) = v {
 				let position = v.voxel + v.normal;
@@ -447,11 +817,9 @@ pub fn torchlight_debug_place_system(
 			modifier.last_modification = Some(Instant::now());
 
 			// Find the first filled voxel
-			let v = FVTIterator::new(
-				transform.translation, 
-				transform.rotation.mul_vec3(Vec3::Z), 
-				0.0, 10.0, 1.0,
-			).find(|r| terrain.get_voxel(&chunks, r.voxel).is_some());
+			let ray = Ray::new(transform.translation, transform.rotation.mul_vec3(Vec3::Z));
+			let v = terrain.raycast(&chunks, ray, 10.0, RaycastFilter::default())
+				.filter(|v| v.normal != IVec3::ZERO);
 
 			if let Some(v) = v {
 				let position = v.voxel + v.normal;
@@ -513,3 +881,266 @@ impl TorchlightContents {
 		base
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{blocks::BlockRenderType, test_util::{block, colour_block, load_chunks}};
+	use terrain::terrain::TerrainContents;
+
+	// The eight chunks from -1 to 0
+	struct World {
+		chunks: Chunks,
+		blocks: BlockManager,
+		light: SecondaryMap<ChunkKey, LightChunk>,
+		stone: BlockKey,
+		glass: BlockKey,
+		lamp: BlockKey,
+	}
+	impl World {
+		fn new() -> Self {
+			let mut blocks = BlockManager::new();
+			let stone = colour_block(&mut blocks, "stone");
+			let glass = block(&mut blocks, "glass", BlockRenderType::Colour, false, "light_filter: Some((0.5, 1.0, 1.0)),");
+			let lamp = block(&mut blocks, "lamp", BlockRenderType::Colour, true, "light_emission: Some((15, 0, 8)),");
+			let mut chunks = Chunks::new();
+			let mut light = SecondaryMap::new();
+			for (_, key) in load_chunks(&mut chunks, IVec3::NEG_ONE, IVec3::ZERO) {
+				light.insert(key, LightChunk::new());
+			}
+			Self { chunks, blocks, light, stone, glass, lamp, }
+		}
+
+		fn torchlight(&mut self) -> Torchlight {
+			Torchlight::new(&self.chunks, &self.blocks, &mut self.light)
+		}
+
+		// Gives new terrain to the chunks containing these voxels, or empty terrain to chunks that have none
+		fn set_terrain(&mut self, voxels: &[(IVec3, Option<BlockKey>)]) {
+			let keys = self.light.keys().collect::<Vec<_>>();
+			for key in keys {
+				let position = self.chunks.chunks[key];
+				let changes = voxels.iter().filter(|(v, _)| chunk_of_voxel(*v) == position).collect::<Vec<_>>();
+				let previous = self.light[key].terrain.clone();
+				if previous.is_some() && changes.is_empty() {
+					continue
+				}
+				let mut terrain = previous.map(|t| (*t).clone()).unwrap_or_else(|| TerrainChunk::new(TerrainContents::new()));
+				for &&(v, b) in changes.iter() {
+					let p = voxel_relative_to_chunk(v, position).as_uvec3();
+					match b {
+						Some(b) => terrain.insert(p, b),
+						None => terrain.remove(p),
+					}
+				}
+				self.torchlight().update_terrain(key, Arc::new(terrain));
+			}
+		}
+
+		// Unloads a chunk like the init system does, giving its sources and terrain
+		fn unload(&mut self, position: IVec3) -> (HashMap<UVec3, LightRGBA>, Arc<TerrainChunk>) {
+			let key = self.chunks.get_position(position).unwrap();
+			let terrain = self.light[key].terrain.clone().unwrap();
+			self.chunks.unload(key);
+			(self.torchlight().unload(key, position), terrain)
+		}
+
+		fn load(&mut self, position: IVec3, sources: HashMap<UVec3, LightRGBA>, terrain: Arc<TerrainChunk>) {
+			let key = self.chunks.load(position);
+			let mut light = LightChunk::new();
+			light.sources = sources;
+			self.light.insert(key, light);
+			self.torchlight().update_terrain(key, terrain);
+		}
+
+		// By chunk position, as keys change when chunks are reloaded
+		fn snapshot(&self) -> Vec<LightRGBA> {
+			let mut keys = self.light.keys().collect::<Vec<_>>();
+			keys.sort_by_key(|&k| self.chunks.chunks[k].to_array());
+			keys.into_iter()
+				.flat_map(|k| cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)).map(move |p| self.light[k].get(p).copied().into()))
+				.collect()
+		}
+	}
+
+	#[test]
+	fn test_torchlight_channels() {
+		let mut world = World::new();
+		let glass = world.glass;
+		world.set_terrain(&[(IVec3::new(6, 0, 0), Some(glass))]);
+		let mut torchlight = world.torchlight();
+
+		assert!(torchlight.add_source(IVec3::ZERO, LightRGBA::from_emission([15, 8, 0])));
+		// Upward, downward into the next chunk, and sideways into another
+		assert_eq!(LightRGBA { r: 10, g: 3, b: 0, a: 10 }, torchlight.get(IVec3::new(0, 5, 0)));
+		assert_eq!(LightRGBA { r: 12, g: 5, b: 0, a: 12 }, torchlight.get(IVec3::new(0, -3, 0)));
+		assert_eq!(LightRGBA { r: 11, g: 4, b: 0, a: 11 }, torchlight.get(IVec3::new(-2, 0, -2)));
+
+		// Glass halves red
+		assert!(torchlight.add_source(IVec3::new(5, 0, 0), LightRGBA::from_emission([15, 15, 15])));
+		assert_eq!(LightRGBA { r: 7, g: 14, b: 14, a: 14 }, torchlight.get(IVec3::new(6, 0, 0)));
+		// More red goes around it
+		assert_eq!(LightRGBA { r: 11, g: 13, b: 13, a: 13 }, torchlight.get(IVec3::new(7, 0, 0)));
+	}
+
+	#[test]
+	fn test_torchlight_remove_readd() {
+		let mut world = World::new();
+		let (stone, glass) = (world.stone, world.glass);
+		let mut walls = Vec::new();
+		for i in -4..4 {
+			walls.push((IVec3::new(0, i, 2), Some(stone)));
+			walls.push((IVec3::new(i, 1, -1), Some(glass)));
+		}
+		world.set_terrain(&walls);
+		let a = (IVec3::new(-1, 2, 3), LightRGBA::from_emission([12, 4, 9]));
+		let b = (IVec3::new(2, -2, -1), LightRGBA::from_emission([3, 14, 6]));
+
+		let mut torchlight = world.torchlight();
+		torchlight.add_source(a.0, a.1);
+		torchlight.add_source(b.0, b.1);
+		torchlight.finish();
+		let both = world.snapshot();
+
+		world.torchlight().remove_source(a.0);
+		let only_b = world.snapshot();
+		assert_ne!(both, only_b);
+		assert_eq!(0, world.torchlight().get(a.0).r);
+
+		world.torchlight().add_source(a.0, a.1);
+		assert_eq!(both, world.snapshot());
+
+		// Order doesn't matter
+		let mut torchlight = world.torchlight();
+		torchlight.remove_source(a.0);
+		torchlight.remove_source(b.0);
+		torchlight.finish();
+		assert!(world.snapshot().iter().all(|l| l.is_dark()));
+		world.torchlight().add_source(b.0, b.1);
+		assert_eq!(only_b, world.snapshot());
+		world.torchlight().add_source(a.0, a.1);
+		assert_eq!(both, world.snapshot());
+	}
+
+	#[test]
+	fn test_torchlight_terrain_changes() {
+		let mut world = World::new();
+		let (stone, lamp) = (world.stone, world.lamp);
+		// Chunks are dark until their terrain is known
+		assert!(!world.torchlight().add_source(IVec3::ZERO, LightRGBA::from_emission([15; 3])));
+
+		world.set_terrain(&[(IVec3::new(1, 1, 1), Some(lamp))]);
+		let lamp_light = LightRGBA::from_emission([15, 0, 8]);
+		assert_eq!(lamp_light, world.torchlight().get(IVec3::new(1, 1, 1)));
+		assert_eq!(LightRGBA { r: 12, g: 0, b: 5, a: 12 }, world.torchlight().get(IVec3::new(1, -2, 1)));
+		let lit = world.snapshot();
+
+		// Stone blocks light, which goes around it
+		world.set_terrain(&[(IVec3::new(1, 0, 1), Some(stone))]);
+		assert!(world.torchlight().get(IVec3::new(1, 0, 1)).is_dark());
+		assert_eq!(LightRGBA { r: 11, g: 0, b: 4, a: 11 }, world.torchlight().get(IVec3::new(1, -1, 1)));
+		world.set_terrain(&[(IVec3::new(1, 0, 1), None)]);
+		assert_eq!(lit, world.snapshot());
+
+		world.set_terrain(&[(IVec3::new(1, 1, 1), None)]);
+		assert!(world.snapshot().iter().all(|l| l.is_dark()));
+	}
+
+	#[test]
+	fn test_torchlight_changes() {
+		let mut world = World::new();
+		world.set_terrain(&[]);
+		let key = world.chunks.get_position(IVec3::ZERO).unwrap();
+		let other = world.chunks.get_position(IVec3::NEG_ONE).unwrap();
+		let (start, other_start) = (world.light[key].generation, world.light[other].generation);
+
+		// A light spreading in one chunk is one change covering where it reached
+		let mut torchlight = world.torchlight();
+		assert!(torchlight.add_source(IVec3::new(8, 8, 8), LightRGBA::from_emission([3, 3, 3])));
+		torchlight.finish();
+		assert_eq!(Some((UVec3::splat(6), UVec3::splat(10))), world.light[key].changed_since(start));
+		assert_eq!(None, world.light[other].changed_since(other_start));
+	}
+
+	#[test]
+	fn test_torchlight_late_chunk() {
+		let mut world = World::new();
+		let key = world.chunks.get_position(IVec3::ZERO).unwrap();
+		world.torchlight().update_terrain(key, Arc::new(TerrainChunk::new(TerrainContents::new())));
+		assert!(world.torchlight().add_source(IVec3::new(0, 3, 3), LightRGBA::from_emission([9, 9, 9])));
+		assert!(world.torchlight().get(IVec3::new(-1, 3, 3)).is_dark());
+
+		// Light spreads into it once it has terrain
+		world.set_terrain(&[]);
+		assert_eq!(LightRGBA::from_emission([8, 8, 8]), world.torchlight().get(IVec3::new(-1, 3, 3)));
+		assert_eq!(LightRGBA::from_emission([4, 4, 4]), world.torchlight().get(IVec3::new(-1, 1, 1)));
+	}
+
+	#[test]
+	fn test_torchlight_unload_reload() {
+		let mut world = World::new();
+		let lamp = world.lamp;
+		world.set_terrain(&[(IVec3::new(-3, 2, 2), Some(lamp))]);
+		let mut torchlight = world.torchlight();
+		torchlight.add_source(IVec3::new(-30, 4, 5), LightRGBA::from_emission([6, 12, 3]));
+		torchlight.add_source(IVec3::new(2, -2, -2), LightRGBA::from_emission([3, 3, 14]));
+		torchlight.finish();
+		let lit = world.snapshot();
+
+		// Light from the chunk goes with it
+		let position = IVec3::new(-1, 0, 0);
+		let (sources, terrain) = world.unload(position);
+		// The lamp is found again in the terrain, so only the placed light is kept
+		assert_eq!(vec![(UVec3::new(2, 4, 5), LightRGBA::from_emission([6, 12, 3]))], sources.clone().into_iter().collect::<Vec<_>>());
+		assert_eq!(0, world.torchlight().get(IVec3::new(0, 2, 2)).r);
+
+		// The lamp and placed light come back
+		world.load(position, sources, terrain);
+		assert_eq!(lit, world.snapshot());
+	}
+
+	#[test]
+	fn test_saved_sources_round_trip() {
+		// Unique so that concurrent test runs don't delete each other's saves
+		let directory = std::env::temp_dir().join(format!("pinefruit_test_saved_sources_round_trip_{}", std::process::id()));
+		let path = saved_sources_path(&directory, IVec3::new(-1, 2, 3));
+		let sources = HashMap::from([
+			(UVec3::new(2, 4, 5), LightRGBA::from_emission([6, 12, 3])),
+			(UVec3::new(0, 0, 31), LightRGBA::from_emission([15, 0, 0])),
+		]);
+		write_saved_sources(&path, &sources).unwrap();
+		assert_eq!(sources, read_saved_sources(&path).unwrap());
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_torchlight_removed_while_unloaded() {
+		let a = IVec3::new(1, 1, 1);
+		let b = IVec3::new(-20, -20, 20);
+		// A floor with a hole above the chunk at -1, -1, 0, so light from a can only get below through it
+		let mut world = World::new();
+		let floor = (-32..32)
+			.flat_map(|x| (-32..32).map(move |z| IVec3::new(x, 0, z)))
+			.filter(|v| !(v.x < 0 && v.z >= 0))
+			.map(|v| (v, Some(world.stone)))
+			.collect::<Vec<_>>();
+		world.set_terrain(&floor);
+		let mut torchlight = world.torchlight();
+		torchlight.add_source(a, LightRGBA::from_emission([15, 15, 0]));
+		torchlight.add_source(b, LightRGBA::from_emission([0, 5, 5]));
+		torchlight.finish();
+		assert_ne!(0, world.torchlight().get(IVec3::new(0, -1, 1)).r);
+
+		// Removing a can't reach the light that went through the unloaded chunk
+		let position = IVec3::new(-1, -1, 0);
+		let (sources, terrain) = world.unload(position);
+		world.torchlight().remove_source(a);
+		world.load(position, sources, terrain);
+
+		let mut expected = World::new();
+		expected.set_terrain(&floor);
+		expected.torchlight().add_source(b, LightRGBA::from_emission([0, 5, 5]));
+		assert_eq!(expected.snapshot(), world.snapshot());
+	}
+}
@@ -1,14 +1,15 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
 
-use chunks::{chunk_of_voxel, chunks::{ChunkKey, ChunksResource}, generation::KGeneration, voxel_relative_to_chunk, CHUNK_SIZE};
+use chunks::{blocks::{BlockManager, BlockResource}, chunk_of_voxel, chunks::{ChunkEvent, ChunkKey, Chunks, ChunksResource}, cube_iterator_xyz_uvec, generation::{ChangeHistory, KGeneration}, voxel_relative_to_chunk, CHUNK_SIZE};
 use eeks::prelude::*;
 use glam::{IVec3, Mat4, UVec3};
 use parking_lot::RwLock;
 use pinecore::render::{AbstractRenderTarget, Buffer, BufferKey, BufferResource, MaterialResource, QueueResource, RenderFrame, RRID};
 use slotmap::SecondaryMap;
 use splines::Spline;
-use terrain::terrain::TerrainResource;
+use terrain::terrain::{TerrainChunk, TerrainResource};
 use pinecore::time::TimeResource;
+use crate::light::light_filter;
 
 
 
@@ -20,14 +21,27 @@ const LIGHT_MIN: u16 = 0;
 fn get_r(v: u16) -> u16 { (v & 0b0011110000000000) >> 10 }
 fn get_g(v: u16) -> u16 { (v & 0b0000000111100000) >> 5 }
 fn get_b(v: u16) -> u16 { (v & 0b0000000000001111) >> 0 }
-fn set_r(v: u16, r: u16) -> u16 { (v & !0b0011110000000000) & (r << 10) }
-fn set_g(v: u16, g: u16) -> u16 { (v & !0b0000000111100000) & (g << 5) }
-fn set_b(v: u16, b: u16) -> u16 { (v & !0b0000000000001111) & (b << 0) }
-fn is_tinted(v: u16) -> bool { untinted(v).is_some() }
+fn set_r(v: u16, r: u16) -> u16 { (v & !0b0011110000000000) | (r << 10) }
+fn set_g(v: u16, g: u16) -> u16 { (v & !0b0000000111100000) | (g << 5) }
+fn set_b(v: u16, b: u16) -> u16 { (v & !0b0000000000001111) | (b << 0) }
+fn is_tinted(v: u16) -> bool { untinted(v).is_none() }
 fn untinted(v: u16) -> Option<u16> { 
 	(get_r(v) == get_g(v) && get_r(v) == get_b(v)).then_some(get_r(v))
 }
 fn splat(v: u16) -> u16 { (v << 10) | (v << 5) | (v << 0) }
+pub fn pack(r: u16, g: u16, b: u16) -> u16 { (r << 10) | (g << 5) | (b << 0) }
+pub fn unpack(v: u16) -> [u16; 3] { [get_r(v), get_g(v), get_b(v)] }
+// Multiplies each channel by a filter colour
+fn tint(v: u16, filter: [f32; 3]) -> u16 {
+	let f = |c: u16, f: f32| ((c as f32 * f.clamp(0.0, 1.0)).round() as u16).min(LIGHT_MAX);
+	pack(f(get_r(v), filter[0]), f(get_g(v), filter[1]), f(get_b(v), filter[2]))
+}
+fn channel_max(a: u16, b: u16) -> u16 {
+	pack(get_r(a).max(get_r(b)), get_g(a).max(get_g(b)), get_b(a).max(get_b(b)))
+}
+fn any_brighter(a: u16, b: u16) -> bool {
+	get_r(a) > get_r(b) || get_g(a) > get_g(b) || get_b(a) > get_b(b)
+}
 fn simd_dec(v: u16) -> u16 {
 	v.saturating_sub(0b00000010000100001) & 0b0011110111101111
 }
@@ -60,11 +74,11 @@ pub enum SunlightVolume {
 	// Sunlight is falling off, but it is still the same colour
 	// We can actually use only 4 bits per value, but this is left for later 
 	// (Access by halving index and applying bit offset determined by modulo value) 
-	// min value count, max value count  
+	// max value count, min value count  
 	Gradient((Box<[u8]>, u32, u32)),
 	// Sunlight has been tinted by glass or something 
 	// Should use u24, but it's (probably) not worth the hassle 
-	// min value count, max value count, tinted value count 
+	// max value count, min value count, tinted value count 
 	Tinted((Box<[u16]>, u32, u32, u32)),
 }
 impl SunlightVolume {
@@ -83,9 +97,9 @@ impl SunlightVolume {
 	pub fn get(&self, pos: UVec3) -> u16 {
 		let i = Self::index_of(pos);
 		match self {
-			Self::Light => LIGHT_MAX,
+			Self::Light => splat(LIGHT_MAX),
 			Self::Dark => LIGHT_MIN,
-			Self::Gradient((contents, _, _)) => contents[i] as u16,
+			Self::Gradient((contents, _, _)) => splat(contents[i] as u16),
 			Self::Tinted((contents, _, _, _)) => contents[i],
 		}
 	}
@@ -96,29 +110,28 @@ impl SunlightVolume {
 			Self::Light => if !is_max(value) {
 				if is_tinted(value) {
 					trace!("Light sun chunk becomes Tinted");
-					*self = Self::Tinted((vec![splat(LIGHT_MAX); CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, 0, 0));
+					*self = Self::Tinted((vec![splat(LIGHT_MAX); CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), CHUNK_SIZE.pow(3), 0, 0));
 				} else {
-					trace!("Lgiht sun chunk becomes Gradient");
-					*self = Self::Gradient((vec![LIGHT_MAX as u8; CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, 0));
+					trace!("Light sun chunk becomes Gradient");
+					*self = Self::Gradient((vec![LIGHT_MAX as u8; CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), CHUNK_SIZE.pow(3), 0));
 				}
 				self.set(pos, value);
 			},
 			Self::Dark => if !is_min(value) {
 				if is_tinted(value) {
 					trace!("Dark sun chunk becomes Tinted");
-					*self = Self::Tinted((vec![splat(LIGHT_MIN); CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, 0, 0));
+					*self = Self::Tinted((vec![splat(LIGHT_MIN); CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, CHUNK_SIZE.pow(3), 0));
 				} else {
 					trace!("Dark sun chunk becomes Gradient");
-					*self = Self::Gradient((vec![LIGHT_MIN as u8; CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, 0));
+					*self = Self::Gradient((vec![LIGHT_MIN as u8; CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0, CHUNK_SIZE.pow(3)));
 				}
 				self.set(pos, value);
 			},
 			Self::Gradient((contents, maxc, minc)) => {
 				if is_tinted(value) {
-					let s = contents.into_iter().map(|&v| splat(v as u16)).collect::<Vec<_>>();
-					let tinc = s.iter().filter(|&&v| is_tinted(v)).count() as u32;
+					let s = contents.iter().map(|&v| splat(v as u16)).collect::<Vec<_>>();
 					trace!("Gradient sun chunk becomes Tinted");
-					*self = Self::Tinted((s.into_boxed_slice(), *maxc, *minc, tinc));
+					*self = Self::Tinted((s.into_boxed_slice(), *maxc, *minc, 0));
 					self.set(pos, value);
 					return;
 				}
@@ -147,20 +160,18 @@ impl SunlightVolume {
 			Self::Tinted((contents, maxc, minc, tinc)) => {
 				let replaced = contents[i];
 				contents[i] = value;
-				match replaced {
-					LIGHT_MAX => *maxc -= 1,
-					LIGHT_MIN => *minc -= 1,
-					_ => {},
-				}
-				if is_tinted(replaced) { 
+				if is_max(replaced) {
+					*maxc -= 1;
+				} else if is_min(replaced) {
+					*minc -= 1;
+				} else if is_tinted(replaced) { 
 					*tinc -= 1;
 				}
-				match value {
-					LIGHT_MAX => *maxc += 1,
-					LIGHT_MIN => *minc += 1,
-					_ => {},
-				}
-				if is_tinted(value) { 
+				if is_max(value) {
+					*maxc += 1;
+				} else if is_min(value) {
+					*minc += 1;
+				} else if is_tinted(value) { 
 					*tinc += 1;
 				}
 
@@ -172,7 +183,7 @@ impl SunlightVolume {
 					*self = Self::Light;					
 				} else if *tinc == 0 {
 					trace!("Tinted sun chunk becomes Gradient");
-					let s = contents.into_iter().map(|&v| v as u8).collect::<Vec<_>>();
+					let s = contents.iter().map(|&v| untinted(v).unwrap() as u8).collect::<Vec<_>>();
 					*self = Self::Gradient((s.into_boxed_slice(), *maxc, *minc));
 				}
 			},
@@ -193,7 +204,39 @@ impl SunlightVolume {
 #[derive(Debug)]
 pub struct SunlightChunk {
 	contents: SunlightVolume,
-	generation: KGeneration,
+	pub generation: KGeneration,
+	changes: ChangeHistory,
+	// The terrain this was lit with, None until the chunk's terrain is complete
+	// Changes are found by comparing it to the current terrain
+	pub terrain: Option<Arc<TerrainChunk>>,
+	// Was the top lit as if open to the sky because the chunk above was not lit? 
+	sky: bool,
+}
+impl Default for SunlightChunk {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl SunlightChunk {
+	pub fn new() -> Self {
+		Self {
+			contents: SunlightVolume::new(),
+			generation: KGeneration::new(),
+			changes: ChangeHistory::new(),
+			terrain: None,
+			sky: false,
+		}
+	}
+
+	/// Increments the generation and remembers which voxels were changed. 
+	pub fn record_change(&mut self, min: UVec3, max: UVec3) {
+		self.changes.record(&mut self.generation, min, max);
+	}
+
+	/// The bounds of the voxels changed since a generation, or None if it is current. 
+	pub fn changed_since(&self, generation: KGeneration) -> Option<(UVec3, UVec3)> {
+		self.changes.changed_since(self.generation, generation)
+	}
 }
 impl std::ops::Deref for SunlightChunk {
 	type Target = SunlightVolume;
@@ -208,10 +251,15 @@ impl std::ops::DerefMut for SunlightChunk {
 }
 
 
-#[derive(Debug, Resource)]
+#[derive(Debug, Default, Resource)]
 pub struct SunChunksResource {
 	pub chunks: Arc<RwLock<SecondaryMap<ChunkKey, SunlightChunk>>>,
+	// Light that has reached loaded chunks which are not lit yet
+	// By chunk position and then chunk-relative position, it is filtered when applied
+	pub pending: HashMap<IVec3, HashMap<UVec3, u16>>,
+	// Light to set, kept until its chunk has been lit
 	pub add_lights: Vec<(IVec3, u16)>,
+	// Voxels whose light (and whatever spread from it) should be removed
 	pub del_lights: Vec<IVec3>, 
 }
 impl SunChunksResource {
@@ -227,91 +275,438 @@ impl SunChunksResource {
 }
 
 
-pub fn sunlight_update_system(
-	chunks: Res<ChunksResource>,
-	terrain: Res<TerrainResource>,
-	mut sun: ResMut<SunChunksResource>,
-) {
-	// // let mut remove_queue = Vec::new();
-	
-	// for pos in sun.del_lights.iter().copied() {
+const NEIGHBOURS: [IVec3; 6] = [
+	IVec3::X, IVec3::NEG_X, 
+	IVec3::Y, IVec3::NEG_Y, 
+	IVec3::Z, IVec3::NEG_Z, 
+];
 
-	// }
-	// sun.del_lights.clear();
+// Light moving in this direction from a voxel with this light
+// Full sunlight goes straight down without falling off
+fn spread(light: u16, direction: IVec3) -> u16 {
+	if direction == IVec3::NEG_Y && is_max(light) {
+		light
+	} else {
+		simd_dec(light)
+	}
+}
 
-	// let mut sun_chunks = sun.chunks.write();
-	let terrain_chunks = terrain.chunks.read();
-	let chunks = chunks.read();
+fn filtered(light: u16, filter: [f32; 3]) -> u16 {
+	if filter == [1.0; 3] {
+		light
+	} else {
+		tint(light, filter)
+	}
+}
 
-	let mut prop_queue = Vec::new();
 
-	// Insert lights if their chunks are loaded 
-	for (pos, lvl) in sun.add_lights.iter().copied() {
-		let mut sun_chunks = sun.chunks.write();
+/// Spreads and removes sunlight across chunks. 
+/// 
+/// Chunks that have not been lit with their terrain yet are treated as opaque. 
+/// Light that reaches them is kept in `pending` and applied when they are lit. 
+/// A chunk lit before the chunk above it is lit from the top as if open to the sky, 
+/// which is undone when the chunk above is lit. 
+/// The voxels changed in each chunk are only recorded, as one change, when [Self::finish] is called. 
+/// Meshing does not see changes that are never recorded. 
+#[must_use = "changes are only recorded by finish"]
+pub struct Sunlight<'a> {
+	pub chunks: &'a Chunks,
+	pub blocks: &'a BlockManager,
+	pub sun: &'a mut SecondaryMap<ChunkKey, SunlightChunk>,
+	pub pending: &'a mut HashMap<IVec3, HashMap<UVec3, u16>>,
+	// Bounds of the voxels changed in each chunk
+	changed: HashMap<ChunkKey, (UVec3, UVec3)>,
+}
+impl<'a> Sunlight<'a> {
+	pub fn new(
+		chunks: &'a Chunks, 
+		blocks: &'a BlockManager, 
+		sun: &'a mut SecondaryMap<ChunkKey, SunlightChunk>, 
+		pending: &'a mut HashMap<IVec3, HashMap<UVec3, u16>>,
+	) -> Self {
+		Self { chunks, blocks, sun, pending, changed: HashMap::new(), }
+	}
+
+	fn locate(&self, voxel: IVec3) -> Option<(ChunkKey, UVec3)> {
+		let chunk = chunk_of_voxel(voxel);
+		let key = self.chunks.get_position(chunk)?;
+		self.sun.get(key).filter(|s| s.terrain.is_some())?;
+		Some((key, voxel_relative_to_chunk(voxel, chunk).as_uvec3()))
+	}
+
+	fn is_lit(&self, chunk: IVec3) -> bool {
+		self.chunks.get_position(chunk)
+			.and_then(|key| self.sun.get(key))
+			.is_some_and(|s| s.terrain.is_some())
+	}
+
+	pub fn get(&self, voxel: IVec3) -> u16 {
+		self.locate(voxel)
+			.map(|(key, p)| self.sun[key].get(p))
+			.unwrap_or(LIGHT_MIN)
+	}
 
-		let chunk_pos = chunk_of_voxel(pos);
-		let pos_in_chunk = voxel_relative_to_chunk(pos, chunk_pos).as_uvec3();
-		if let Some(chunk) = chunks.get_position(chunk_pos) {
-			prop_queue.push((pos, lvl));
-			let sun = sun_chunks.get_mut(chunk).unwrap();
-			sun.set(pos_in_chunk, lvl);
-			sun.generation.increment();
-		} else {
-			// This will cause errors 
-			// You must find a way to retain entries in this case 
-			warn!("Chunk for sunlgiht is not loaded, skipping");
+	// None if light can't enter this voxel
+	fn filter(&self, voxel: IVec3) -> Option<[f32; 3]> {
+		let (key, p) = self.locate(voxel)?;
+		light_filter(self.blocks, self.sun[key].terrain.as_ref()?.get(p))
+	}
+
+	// The light a voxel gets from the sky, if it is at the top of a chunk that is open to it
+	fn sky(&self, voxel: IVec3) -> Option<u16> {
+		let (key, p) = self.locate(voxel)?;
+		if !self.sun[key].sky || p.y != CHUNK_SIZE - 1 {
+			return None;
 		}
+		self.filter(voxel).map(|f| filtered(splat(LIGHT_MAX), f))
 	}
-	sun.add_lights.clear();
 
-	let mut sun_chunks = sun.chunks.write();
-	while let Some((this_pos, this_light)) = prop_queue.pop() {
-		for offs in [
-			IVec3::X, IVec3::NEG_X, 
-			IVec3::Y, IVec3::NEG_Y, 
-			IVec3::Z, IVec3::NEG_Z, 
-		] {
-			let this_light_dec = if offs.y == -1 && is_max(this_light) {
-				this_light
-			} else {
-				simd_dec(this_light)
-			};
+	fn set(&mut self, key: ChunkKey, p: UVec3, light: u16) {
+		let chunk = &mut self.sun[key];
+		chunk.set(p, light);
+		let bounds = self.changed.entry(key).or_insert((p, p));
+		*bounds = (bounds.0.min(p), bounds.1.max(p));
+	}
+
+	// Brightens a voxel, giving true if anything changed
+	fn brighten(&mut self, voxel: IVec3, light: u16) -> bool {
+		let Some((key, p)) = self.locate(voxel) else {
+			return false
+		};
+		let current = self.sun[key].get(p);
+		if !any_brighter(light, current) {
+			return false
+		}
+		self.set(key, p, channel_max(light, current));
+		true
+	}
 
-			let neighbour_pos = this_pos + offs;
-			let neighbour_chunk_pos = chunk_of_voxel(neighbour_pos);
-			let neighbour_pos_in_chunk = voxel_relative_to_chunk(neighbour_pos, neighbour_chunk_pos).as_uvec3();
+	// Keeps light for a loaded chunk that is not lit yet
+	fn defer(&mut self, voxel: IVec3, light: u16) {
+		let chunk = chunk_of_voxel(voxel);
+		if self.chunks.get_position(chunk).is_none() {
+			// It will gather light from its neighbours when loaded
+			return
+		}
+		let p = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
+		let pending = self.pending.entry(chunk).or_default().entry(p).or_insert(LIGHT_MIN);
+		*pending = channel_max(*pending, light);
+	}
 
-			let neighbour_chunk = chunks.get_position(neighbour_chunk_pos);
-			if neighbour_chunk.is_none() {
-				// If chunk is not loaded, then push light into the queue for later
-				// This can/will build up over time, but idk what to do about it 
-				// sun.add_lights.push((neighbour_pos, this_light_dec));
+	/// Spreads light outward from these voxels. 
+	pub fn propagate(&mut self, seeds: impl IntoIterator<Item = IVec3>) {
+		let mut queue = seeds.into_iter().collect::<VecDeque<_>>();
+		while let Some(pos) = queue.pop_front() {
+			let light = self.get(pos);
+			if is_min(light) {
 				continue
 			}
-			let neighbour_chunk = neighbour_chunk.unwrap();
-			let neighbour_sun = sun_chunks.get(neighbour_chunk).unwrap();
-			let neighbour_terrain = terrain_chunks.get(neighbour_chunk);
-			if neighbour_terrain.is_none() {
-				// sun.add_lights.push((neighbour_pos, this_light_dec));
-				continue
+			for offs in NEIGHBOURS {
+				let neighbour_pos = pos + offs;
+				if !self.is_lit(chunk_of_voxel(neighbour_pos)) {
+					self.defer(neighbour_pos, spread(light, offs));
+					continue
+				}
+				// Light passes through blocks that don't fill their voxel, and is tinted by them
+				let Some(filter) = self.filter(neighbour_pos) else {
+					continue
+				};
+				if self.brighten(neighbour_pos, filtered(spread(light, offs), filter)) {
+					queue.push_back(neighbour_pos);
+				}
 			}
-			let neighbour_terrain = neighbour_terrain.unwrap().complete_ref().unwrap();
+		}
+	}
 
-			if neighbour_terrain.get(neighbour_pos_in_chunk).is_some() {
+	/// Removes the light in these voxels and whatever light spread from them. 
+	/// Returns voxels that light should be propagated from to fill in what was wrongly removed. 
+	pub fn remove(&mut self, voxels: impl IntoIterator<Item = IVec3>) -> Vec<IVec3> {
+		let mut queue = VecDeque::new();
+		let refill = Vec::new();
+		for voxel in voxels {
+			let Some((key, p)) = self.locate(voxel) else {
 				continue
+			};
+			let light = self.sun[key].get(p);
+			if !is_min(light) {
+				self.set(key, p, LIGHT_MIN);
+				queue.push_back((voxel, light));
 			}
-			let neighbour_light = neighbour_sun.get(neighbour_pos_in_chunk);
-			if get_r(neighbour_light) >= get_r(this_light_dec) {
-				continue
+		}
+		self.unspread(queue, refill)
+	}
+
+	// Removes light that spread from these voxels with these lights, the voxels themselves are not changed
+	fn unspread(&mut self, mut queue: VecDeque<(IVec3, u16)>, mut refill: Vec<IVec3>) -> Vec<IVec3> {
+		while let Some((pos, removed)) = queue.pop_front() {
+			for offs in NEIGHBOURS {
+				let neighbour_pos = pos + offs;
+				let Some((key, p)) = self.locate(neighbour_pos) else {
+					if let Some(sky) = (offs == IVec3::Y).then(|| self.sky(pos)).flatten() {
+						// The sky lights it again
+						if self.brighten(pos, sky) {
+							refill.push(pos);
+						}
+					} else if self.take_pending(neighbour_pos) {
+						// Some of that might have come from elsewhere
+						refill.extend(NEIGHBOURS.map(|o| neighbour_pos + o).into_iter().filter(|&v| v != pos));
+					}
+					continue
+				};
+				let remaining = self.sun[key].get(p);
+				if is_min(remaining) {
+					continue
+				}
+
+				// Everything under full sunlight came from above
+				let (cleared, remaining, lit_elsewhere) = if offs == IVec3::NEG_Y && is_max(removed) {
+					(remaining, LIGHT_MIN, false)
+				} else {
+					// Each channel is removed on its own
+					// A neighbour dimmer than the removed light may have been lit by it, a brighter one was lit by something else
+					let mut cleared = LIGHT_MIN;
+					let mut kept = remaining;
+					let mut lit_elsewhere = false;
+					for (get, set) in [(get_r as fn(u16) -> u16, set_r as fn(u16, u16) -> u16), (get_g, set_g), (get_b, set_b)] {
+						let (r, n) = (get(removed), get(remaining));
+						if r == 0 || n == 0 {
+							continue
+						}
+						if n < r {
+							cleared = set(cleared, n);
+							kept = set(kept, 0);
+						} else {
+							lit_elsewhere = true;
+						}
+					}
+					(cleared, kept, lit_elsewhere)
+				};
+				if !is_min(cleared) {
+					self.set(key, p, remaining);
+					queue.push_back((neighbour_pos, cleared));
+				}
+				if lit_elsewhere {
+					refill.push(neighbour_pos);
+				}
+			}
+		}
+		refill
+	}
+
+	// Forgets light that was waiting for this voxel's chunk, giving true if there was any
+	fn take_pending(&mut self, voxel: IVec3) -> bool {
+		let chunk = chunk_of_voxel(voxel);
+		let p = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
+		self.pending.get_mut(&chunk).and_then(|pending| pending.remove(&p)).is_some()
+	}
+
+	/// Brightens a voxel and spreads from it. 
+	/// Returns false if its chunk has not been lit yet. 
+	pub fn add_light(&mut self, voxel: IVec3, light: u16) -> bool {
+		if self.locate(voxel).is_none() {
+			return false
+		}
+		if self.brighten(voxel, light) {
+			self.propagate([voxel]);
+		}
+		true
+	}
+
+	/// Removes the light in a voxel and spreads light from elsewhere back in. 
+	/// Returns false if its chunk has not been lit yet. 
+	pub fn remove_light(&mut self, voxel: IVec3) -> bool {
+		if self.locate(voxel).is_none() {
+			return false
+		}
+		let refill = self.remove([voxel]);
+		self.propagate(refill);
+		true
+	}
+
+	/// Takes out a chunk's sunlight, first removing the light that spread from it into its neighbours. 
+	/// The chunk below is open to the sky again, 
+	/// which leaves the same light as if the chunk had never been lit. 
+	/// The chunk might already be gone from [Chunks], so its position is given. 
+	pub fn unload(&mut self, key: ChunkKey, position: IVec3) {
+		self.pending.remove(&position);
+		let Some(chunk) = self.sun.remove(key).filter(|s| s.terrain.is_some()) else {
+			return
+		};
+		let origin = position * CHUNK_SIZE as i32;
+		let s = CHUNK_SIZE as i32;
+		let mut queue = VecDeque::new();
+		for a in 0..s {
+			for b in 0..s {
+				for p in [
+					IVec3::new(0, a, b), IVec3::new(s - 1, a, b), 
+					IVec3::new(a, 0, b), IVec3::new(a, s - 1, b), 
+					IVec3::new(a, b, 0), IVec3::new(a, b, s - 1), 
+				] {
+					let light = chunk.get(p.as_uvec3());
+					if !is_min(light) {
+						queue.push_back((origin + p, light));
+					}
+				}
+			}
+		}
+		let mut refill = self.unspread(queue, Vec::new());
+
+		let below = self.chunks.get_position(position - IVec3::Y)
+			.filter(|&k| self.sun.get(k).is_some_and(|s| s.terrain.is_some()));
+		if let Some(below) = below {
+			self.sun[below].sky = true;
+			for x in 0..s {
+				for z in 0..s {
+					let voxel = origin + IVec3::new(x, -1, z);
+					if let Some(sky) = self.sky(voxel) {
+						self.brighten(voxel, sky);
+						refill.push(voxel);
+					}
+				}
+			}
+		}
+		self.propagate(refill);
+	}
+
+	/// Collects light from lit neighbours into `pending` for a newly loaded chunk. 
+	pub fn gather(&mut self, chunk: IVec3) {
+		let origin = chunk * CHUNK_SIZE as i32;
+		let s = CHUNK_SIZE as i32;
+		for a in 0..s {
+			for b in 0..s {
+				for (inside, offs) in [
+					(IVec3::new(0, a, b), IVec3::NEG_X), (IVec3::new(s - 1, a, b), IVec3::X), 
+					(IVec3::new(a, 0, b), IVec3::NEG_Y), (IVec3::new(a, s - 1, b), IVec3::Y), 
+					(IVec3::new(a, b, 0), IVec3::NEG_Z), (IVec3::new(a, b, s - 1), IVec3::Z), 
+				] {
+					let voxel = origin + inside;
+					let light = self.get(voxel + offs);
+					if !is_min(light) {
+						self.defer(voxel, spread(light, -offs));
+					}
+				}
 			}
-			prop_queue.push((neighbour_pos, this_light_dec));
-			
-			let sun = sun_chunks.get_mut(neighbour_chunk).unwrap();
-			sun.set(neighbour_pos_in_chunk, this_light_dec);
-			sun.generation.increment();	
 		}
 	}
-		
+
+	/// Lights a chunk with new terrain. 
+	/// The first time this applies pending light and light from the sky. 
+	/// After that it only relights the voxels that differ from the last terrain. 
+	pub fn update_terrain(&mut self, key: ChunkKey, terrain: Arc<TerrainChunk>) {
+		let (Some(&position), Some(sun)) = (self.chunks.chunks.get(key), self.sun.get_mut(key)) else {
+			return
+		};
+		let origin = position * CHUNK_SIZE as i32;
+		let previous = sun.terrain.replace(terrain.clone());
+
+		let seeds = match previous {
+			None => {
+				let mut seeds = Vec::new();
+				if !self.is_lit(position + IVec3::Y) {
+					self.sun[key].sky = true;
+					for x in 0..CHUNK_SIZE {
+						for z in 0..CHUNK_SIZE {
+							let voxel = origin + UVec3::new(x, CHUNK_SIZE - 1, z).as_ivec3();
+							if let Some(sky) = self.sky(voxel) {
+								self.brighten(voxel, sky);
+								seeds.push(voxel);
+							}
+						}
+					}
+				}
+
+				for (p, light) in self.pending.remove(&position).unwrap_or_default() {
+					let voxel = origin + p.as_ivec3();
+					if let Some(filter) = self.filter(voxel) {
+						if self.brighten(voxel, filtered(light, filter)) {
+							seeds.push(voxel);
+						}
+					}
+				}
+
+				// The chunk below was lit as if open to the sky, but it is under this one
+				let below = self.chunks.get_position(position - IVec3::Y)
+					.filter(|&k| self.sun.get(k).is_some_and(|s| s.terrain.is_some() && s.sky));
+				if let Some(below) = below {
+					self.sun[below].sky = false;
+					let top = (0..CHUNK_SIZE).flat_map(|x| (0..CHUNK_SIZE).map(move |z| origin + IVec3::new(x as i32, -1, z as i32)));
+					seeds.extend(self.remove(top.collect::<Vec<_>>()));
+				}
+				seeds
+			},
+			Some(old) => {
+				if Arc::ptr_eq(&old, &terrain) {
+					return
+				}
+				let changed = cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE))
+					.filter(|&p| old.get(p) != terrain.get(p))
+					.map(|p| origin + p.as_ivec3())
+					.collect::<Vec<_>>();
+				trace!("Relight {} changed voxels in chunk {position}", changed.len());
+				let mut seeds = self.remove(changed.iter().copied());
+				for voxel in changed {
+					if let Some(sky) = self.sky(voxel) {
+						self.brighten(voxel, sky);
+					}
+					seeds.push(voxel);
+					seeds.extend(NEIGHBOURS.map(|o| voxel + o));
+				}
+				seeds
+			},
+		};
+		self.propagate(seeds);
+	}
+
+	/// Records where the sunlight of each chunk changed, so that meshing sees the changes. 
+	pub fn finish(self) {
+		for (key, (min, max)) in self.changed {
+			if let Some(chunk) = self.sun.get_mut(key) {
+				chunk.record_change(min, max);
+			}
+		}
+	}
+}
+
+
+/// Lights chunks with sunlight as their terrain is generated or changed. 
+pub fn sunlight_update_system(
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	blocks: Res<BlockResource>,
+	mut sun: ResMut<SunChunksResource>,
+) {
+	let blocks = blocks.read();
+	let chunks = chunks.read();
+	let terrain_chunks = terrain.chunks.read();
+	let sun = &mut *sun;
+	let mut sun_chunks = sun.chunks.write();
+	let mut world = Sunlight::new(&chunks, &blocks, &mut sun_chunks, &mut sun.pending);
+
+	for event in chunks.events.iter().copied() {
+		match event {
+			ChunkEvent::Loaded(key, position) => if !world.sun.contains_key(key) {
+				world.sun.insert(key, SunlightChunk::new());
+				world.gather(position);
+			},
+			ChunkEvent::Unloaded(key, position) => world.unload(key, position),
+		}
+	}
+
+	// Idea: only do a few of these each frame, inserion sorted by distance to player
+	let updated = world.sun.iter()
+		.filter_map(|(key, sun)| {
+			let terrain = terrain_chunks.get(key)?.complete_ref()?;
+			let current = sun.terrain.as_ref().is_some_and(|t| Arc::ptr_eq(t, terrain));
+			(!current).then(|| (key, terrain.clone()))
+		})
+		.collect::<Vec<_>>();
+	for (key, terrain) in updated {
+		world.update_terrain(key, terrain);
+	}
+
+	sun.del_lights.retain(|&pos| !world.remove_light(pos));
+	sun.add_lights.retain(|&(pos, light)| !world.add_light(pos, light));
+	world.finish();
 }
 
 
@@ -418,3 +813,265 @@ pub fn sun_render_system(
 			.with_depth(RRID::context("depth")))
 		.pass(sunbox_mtl, Entity::default());	
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{blocks::{BlockKey, BlockRenderType}, test_util::{block, colour_block, load_chunks}};
+	use terrain::terrain::TerrainContents;
+
+	// The eight chunks from -1 to 0, loaded but not lit
+	struct World {
+		chunks: Chunks,
+		blocks: BlockManager,
+		sun: SecondaryMap<ChunkKey, SunlightChunk>,
+		pending: HashMap<IVec3, HashMap<UVec3, u16>>,
+		terrain: HashMap<ChunkKey, TerrainChunk>,
+		stone: BlockKey,
+		glass: BlockKey,
+	}
+	impl World {
+		fn new() -> Self {
+			let mut blocks = BlockManager::new();
+			let stone = colour_block(&mut blocks, "stone");
+			let glass = block(&mut blocks, "glass", BlockRenderType::Colour, false, "light_filter: Some((0.5, 1.0, 1.0)),");
+			let mut chunks = Chunks::new();
+			let mut sun = SecondaryMap::new();
+			let mut terrain = HashMap::new();
+			for (_, key) in load_chunks(&mut chunks, IVec3::NEG_ONE, IVec3::ZERO) {
+				sun.insert(key, SunlightChunk::new());
+				terrain.insert(key, TerrainChunk::new(TerrainContents::new()));
+			}
+			Self { chunks, blocks, sun, pending: HashMap::new(), terrain, stone, glass, }
+		}
+
+		fn sunlight(&mut self) -> Sunlight {
+			Sunlight::new(&self.chunks, &self.blocks, &mut self.sun, &mut self.pending)
+		}
+
+		// Changes the terrain, relighting chunks that are already lit
+		fn set(&mut self, voxels: &[(IVec3, Option<BlockKey>)]) {
+			let mut changed = Vec::new();
+			for &(v, b) in voxels {
+				let c = chunk_of_voxel(v);
+				let key = self.chunks.get_position(c).unwrap();
+				let p = voxel_relative_to_chunk(v, c).as_uvec3();
+				let terrain = self.terrain.get_mut(&key).unwrap();
+				match b {
+					Some(b) => terrain.insert(p, b),
+					None => terrain.remove(p),
+				}
+				changed.push(c);
+			}
+			let lit = changed.into_iter().filter(|&c| self.sunlight().is_lit(c)).collect::<Vec<_>>();
+			self.light(&lit);
+		}
+
+		fn light(&mut self, positions: &[IVec3]) {
+			for &position in positions {
+				let key = self.chunks.get_position(position).unwrap();
+				let terrain = Arc::new(self.terrain[&key].clone());
+				self.sunlight().update_terrain(key, terrain);
+			}
+		}
+
+		fn light_all(&mut self, top_first: bool) {
+			let mut positions = cube_iterator_xyz_uvec(UVec3::splat(2))
+				.map(|p| p.as_ivec3() - IVec3::ONE)
+				.collect::<Vec<_>>();
+			positions.sort_by_key(|p| if top_first { -p.y } else { p.y });
+			self.light(&positions);
+		}
+
+		fn get(&mut self, voxel: IVec3) -> u16 {
+			self.sunlight().get(voxel)
+		}
+
+		fn volume(&self, chunk: IVec3) -> &SunlightVolume {
+			&self.sun[self.chunks.get_position(chunk).unwrap()]
+		}
+
+		// Unloads a chunk, giving its terrain
+		fn unload(&mut self, position: IVec3) -> TerrainChunk {
+			let key = self.chunks.get_position(position).unwrap();
+			self.chunks.unload(key);
+			self.sunlight().unload(key, position);
+			self.terrain.remove(&key).unwrap()
+		}
+
+		fn load(&mut self, position: IVec3, terrain: TerrainChunk) {
+			let key = self.chunks.load(position);
+			self.sun.insert(key, SunlightChunk::new());
+			self.terrain.insert(key, terrain);
+			self.sunlight().gather(position);
+			self.light(&[position]);
+		}
+
+		// By chunk position, as keys change when chunks are reloaded
+		fn snapshot(&self) -> Vec<u16> {
+			let mut keys = self.sun.keys().collect::<Vec<_>>();
+			keys.sort_by_key(|&k| self.chunks.chunks[k].to_array());
+			keys.into_iter()
+				.flat_map(|k| cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)).map(move |p| self.sun[k].get(p)))
+				.collect()
+		}
+	}
+
+	#[test]
+	fn test_channel_helpers() {
+		let v = pack(1, 2, 3);
+		assert_eq!(pack(9, 2, 3), set_r(v, 9));
+		assert_eq!(pack(1, 9, 3), set_g(v, 9));
+		assert_eq!(pack(1, 2, 9), set_b(v, 9));
+		assert_eq!(pack(0, 1, 2), simd_dec(v));
+		assert_eq!(pack(8, 15, 15), tint(splat(LIGHT_MAX), [0.5, 1.0, 1.0]));
+		assert_eq!(Some(4), untinted(splat(4)));
+		assert!(is_tinted(v));
+	}
+
+	#[test]
+	fn test_sunlight_volume_representations() {
+		let mut volume = SunlightVolume::new();
+		assert!(matches!(volume, SunlightVolume::Dark));
+		volume.set(UVec3::ZERO, splat(7));
+		assert!(matches!(volume, SunlightVolume::Gradient(_)));
+		assert_eq!(splat(7), volume.get(UVec3::ZERO));
+		volume.set(UVec3::ONE, pack(1, 2, 3));
+		assert!(matches!(volume, SunlightVolume::Tinted(_)));
+		assert_eq!(splat(7), volume.get(UVec3::ZERO));
+		volume.set(UVec3::ONE, splat(LIGHT_MAX));
+		assert!(matches!(volume, SunlightVolume::Gradient(_)));
+		assert_eq!(splat(LIGHT_MAX), volume.get(UVec3::ONE));
+		volume.set(UVec3::ONE, LIGHT_MIN);
+		volume.set(UVec3::ZERO, LIGHT_MIN);
+		assert!(matches!(volume, SunlightVolume::Dark));
+
+		for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)) {
+			volume.set(p, splat(LIGHT_MAX));
+		}
+		assert!(matches!(volume, SunlightVolume::Light));
+		volume.set(UVec3::ZERO, pack(15, 15, 3));
+		assert!(matches!(volume, SunlightVolume::Tinted(_)));
+		volume.set(UVec3::ZERO, splat(LIGHT_MAX));
+		assert!(matches!(volume, SunlightVolume::Light));
+		volume.set(UVec3::ZERO, splat(2));
+		assert!(matches!(volume, SunlightVolume::Gradient(_)));
+		assert_eq!(splat(LIGHT_MAX), volume.get(UVec3::ONE));
+	}
+
+	#[test]
+	fn test_sunlight_columns() {
+		let mut world = World::new();
+		let (stone, glass) = (world.stone, world.glass);
+		world.set(&[(IVec3::new(3, -5, 3), Some(stone)), (IVec3::new(-4, 20, -4), Some(glass))]);
+		world.light_all(true);
+
+		assert_eq!(splat(LIGHT_MAX), world.get(IVec3::new(3, 10, 3)));
+		assert_eq!(splat(LIGHT_MAX), world.get(IVec3::new(3, -4, 3)));
+		assert_eq!(LIGHT_MIN, world.get(IVec3::new(3, -5, 3)));
+		assert_eq!(splat(14), world.get(IVec3::new(3, -6, 3)));
+		assert_eq!(splat(14), world.get(IVec3::new(3, -32, 3)));
+		// Tinted light isn't full so it falls off, and full light from the sides is brighter
+		assert_eq!(pack(8, 15, 15), world.get(IVec3::new(-4, 20, -4)));
+		assert_eq!(splat(14), world.get(IVec3::new(-4, 19, -4)));
+
+		assert!(matches!(world.volume(IVec3::new(0, 0, 0)), SunlightVolume::Light));
+		assert!(matches!(world.volume(IVec3::new(-1, 0, -1)), SunlightVolume::Tinted(_)));
+		assert!(matches!(world.volume(IVec3::new(0, -1, 0)), SunlightVolume::Gradient(_)));
+	}
+
+	#[test]
+	fn test_sunlight_pending_and_sky() {
+		let mut a = World::new();
+		let mut b = World::new();
+		for world in [&mut a, &mut b] {
+			let stone = world.stone;
+			let roof = cube_iterator_xyz_uvec(UVec3::new(6, 1, 6))
+				.map(|p| (p.as_ivec3() + IVec3::new(-3, 2, -3), Some(stone)))
+				.collect::<Vec<_>>();
+			world.set(&roof);
+		}
+
+		// Light waits for chunks that are not lit
+		assert!(!b.sunlight().add_light(IVec3::ZERO, splat(3)));
+		b.light(&[IVec3::new(0, -1, 0)]);
+		assert!(b.pending.contains_key(&IVec3::new(-1, -1, 0)));
+		assert!(b.pending.contains_key(&IVec3::new(0, 0, 0)));
+		// Lit as if nothing was above it
+		assert_eq!(splat(LIGHT_MAX), b.get(IVec3::new(0, -1, 0)));
+
+		a.light_all(true);
+		b.light_all(false);
+		assert!(a.pending.is_empty());
+		assert!(b.pending.is_empty());
+		assert!(a.snapshot() == b.snapshot());
+		// Shaded by the roof
+		assert_eq!(splat(12), b.get(IVec3::new(-1, -1, -1)));
+	}
+
+	#[test]
+	fn test_sunlight_block_changes() {
+		let mut world = World::new();
+		world.light_all(true);
+		let lit = world.snapshot();
+		let (stone, glass) = (world.stone, world.glass);
+
+		world.set(&[(IVec3::new(0, -3, 0), Some(stone))]);
+		assert_eq!(LIGHT_MIN, world.get(IVec3::new(0, -3, 0)));
+		assert_eq!(splat(14), world.get(IVec3::new(0, -4, 0)));
+		assert_eq!(splat(14), world.get(IVec3::new(0, -30, 0)));
+		world.set(&[(IVec3::new(0, -3, 0), None)]);
+		assert!(world.snapshot() == lit);
+
+		// A roof across chunk boundaries
+		let roof = cube_iterator_xyz_uvec(UVec3::new(6, 1, 6))
+			.map(|p| p.as_ivec3() + IVec3::new(-3, -2, -3))
+			.collect::<Vec<_>>();
+		world.set(&roof.iter().map(|&v| (v, Some(stone))).collect::<Vec<_>>());
+		assert_eq!(splat(12), world.get(IVec3::new(-1, -3, -1)));
+		assert_eq!(splat(LIGHT_MAX), world.get(IVec3::new(-1, -1, -1)));
+		world.set(&roof.iter().map(|&v| (v, None)).collect::<Vec<_>>());
+		assert!(world.snapshot() == lit);
+
+		world.set(&[(IVec3::new(5, 5, 5), Some(glass))]);
+		assert!(matches!(world.volume(IVec3::ZERO), SunlightVolume::Tinted(_)));
+		world.set(&[(IVec3::new(5, 5, 5), None)]);
+		assert!(matches!(world.volume(IVec3::ZERO), SunlightVolume::Light));
+		assert!(world.snapshot() == lit);
+	}
+
+	#[test]
+	fn test_sunlight_blocked_while_unloaded() {
+		// A floor with a hole above the chunk at 0, -1, 0, so sunlight can only get below through it
+		let floor = |world: &World, hole: bool| (-32..32)
+			.flat_map(|x| (-32..32).map(move |z| IVec3::new(x, 0, z)))
+			.filter(|v| !(hole && v.x >= 0 && v.z >= 0))
+			.map(|v| (v, Some(world.stone)))
+			.collect::<Vec<_>>();
+		let mut world = World::new();
+		world.set(&floor(&world, true));
+		world.light_all(true);
+		assert_eq!(splat(13), world.get(IVec3::new(-2, -1, 0)));
+
+		// Closing the hole can't reach the light that went through the unloaded chunk
+		let position = IVec3::new(0, -1, 0);
+		let terrain = world.unload(position);
+		world.set(&floor(&world, false));
+		world.load(position, terrain);
+
+		let mut expected = World::new();
+		expected.set(&floor(&expected, false));
+		expected.light_all(true);
+		assert_eq!(LIGHT_MIN, world.get(IVec3::new(-2, -1, 0)));
+		assert!(world.pending.is_empty());
+		assert!(expected.snapshot() == world.snapshot());
+
+		// The chunk below is open to the sky while the one above it is unloaded
+		let position = IVec3::new(0, 0, 0);
+		let terrain = world.unload(position);
+		assert_eq!(splat(LIGHT_MAX), world.get(IVec3::new(0, -1, 0)));
+		world.load(position, terrain);
+		assert!(expected.snapshot() == world.snapshot());
+	}
+}
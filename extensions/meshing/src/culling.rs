@@ -0,0 +1,238 @@
+//! Chunks are culled if they are outside of the view frustum or if they can't be seen through the chunks in between.
+//! The second part is like cave culling, which looks for paths from the camera through faces connected by open space.
+
+use std::collections::{HashSet, VecDeque};
+use chunks::{blocks::BlockManager, CHUNK_SIZE};
+use glam::{IVec3, Mat4, UVec3, Vec3, Vec4, Vec4Swizzles};
+use terrain::terrain::TerrainChunk;
+
+
+
+/// Six planes facing inwards.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+	planes: [Vec4; 6],
+}
+impl Frustum {
+	/// From a projection * view matrix with depth from 0 to 1.
+	pub fn from_view_projection(m: Mat4) -> Self {
+		let [r0, r1, r2, r3] = [m.row(0), m.row(1), m.row(2), m.row(3)];
+		let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2]
+			.map(|p| p / p.xyz().length());
+		Self { planes }
+	}
+
+	/// Is any of this box within the frustum?
+	/// Boxes near corners can pass without being inside, which is fine.
+	pub fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+		self.planes.iter().all(|p| {
+			// The corner furthest along the plane's normal
+			let corner = Vec3::select(p.xyz().cmpge(Vec3::ZERO), max, min);
+			p.xyz().dot(corner) + p.w >= 0.0
+		})
+	}
+
+	pub fn intersects_chunk(&self, chunk: IVec3) -> bool {
+		let min = (chunk * CHUNK_SIZE as i32).as_vec3();
+		self.intersects_aabb(min, min + Vec3::splat(CHUNK_SIZE as f32))
+	}
+}
+
+
+/// Which faces of a chunk can be seen from which others through its open space.
+/// Indexed by direction, each is a mask of the directions it connects to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkVisibility(pub [u8; 6]);
+impl ChunkVisibility {
+	pub const ALL: Self = Self([0b111111; 6]);
+	pub const NONE: Self = Self([0; 6]);
+
+	/// Flood fills the voxels that don't hide what is behind them.
+	pub fn new(blocks: &BlockManager, chunk: &TerrainChunk) -> Self {
+		const S: i32 = CHUNK_SIZE as i32;
+		let index = |p: IVec3| (p.x * S * S + p.y * S + p.z) as usize;
+		let mut closed = vec![false; (S * S * S) as usize];
+		for x in 0..S {
+			for y in 0..S {
+				for z in 0..S {
+					let p = UVec3::new(x as u32, y as u32, z as u32);
+					let state = chunk.get_state(p);
+					closed[index(p.as_ivec3())] = chunk.get(p)
+						.and_then(|b| blocks.get(b))
+						.is_some_and(|e| (0..6).all(|d| e.covers(state, d)));
+				}
+			}
+		}
+
+		let mut visibility = Self::NONE;
+		let mut queue = Vec::new();
+		for start in 0..closed.len() {
+			if closed[start] {
+				continue
+			}
+			// Visited voxels are closed so that they aren't filled again
+			closed[start] = true;
+			queue.push(IVec3::new(start as i32 / (S * S), start as i32 / S % S, start as i32 % S));
+			let mut faces = 0;
+			while let Some(p) = queue.pop() {
+				for direction in 0..6 {
+					let n = p + direction_offset(direction);
+					if n.cmplt(IVec3::ZERO).any() || n.cmpge(IVec3::splat(S)).any() {
+						faces |= 1 << direction;
+						continue
+					}
+					if !closed[index(n)] {
+						closed[index(n)] = true;
+						queue.push(n);
+					}
+				}
+			}
+			for direction in 0..6 {
+				if faces & 1 << direction != 0 {
+					visibility.0[direction] |= faces;
+				}
+			}
+		}
+		visibility
+	}
+
+	pub fn connected(&self, a: usize, b: usize) -> bool {
+		self.0[a] & 1 << b != 0
+	}
+}
+impl Default for ChunkVisibility {
+	// Chunks we know nothing about must not hide anything
+	fn default() -> Self {
+		Self::ALL
+	}
+}
+
+
+fn direction_offset(direction: usize) -> IVec3 {
+	let axis = IVec3::AXES[direction / 2];
+	if direction % 2 == 0 { axis } else { -axis }
+}
+
+
+/// Finds chunks that might be seen from a camera.
+/// Paths start at the camera's chunk and go through chunks in the frustum,
+/// entering and leaving by faces that are connected and never turning back on an axis.
+/// `visibility` gives None for chunks that paths can't go through, like those that aren't loaded.
+pub fn visible_chunks(
+	camera: IVec3,
+	frustum: &Frustum,
+	visibility: impl Fn(IVec3) -> Option<ChunkVisibility>,
+) -> HashSet<IVec3> {
+	let mut visible = HashSet::from([camera]);
+	// Chunk, the face it was entered by, and the directions that were taken to get there
+	let mut queue = VecDeque::from([(camera, None, 0u8)]);
+	while let Some((chunk, entered, travelled)) = queue.pop_front() {
+		let Some(v) = visibility(chunk) else { continue };
+		for direction in 0..6 {
+			let back = direction ^ 1;
+			if travelled & 1 << back != 0 {
+				continue
+			}
+			if entered.is_some_and(|e| !v.connected(e, direction)) {
+				continue
+			}
+			let next = chunk + direction_offset(direction);
+			if visible.contains(&next) || !frustum.intersects_chunk(next) {
+				continue
+			}
+			visible.insert(next);
+			queue.push_back((next, Some(back), travelled | 1 << direction));
+		}
+	}
+	visible
+}
+
+
+/// What was drawn in the last frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CullingCounters {
+	pub drawn: u64,
+	pub frustum_culled: u64,
+	pub occlusion_culled: u64,
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{blocks::BlockRenderType, cube_iterator_xyz_uvec, test_util::block};
+	use krender::MaterialKey;
+	use slotmap::SlotMap;
+	use terrain::terrain::TerrainContents;
+
+	fn stone(blocks: &mut BlockManager) -> chunks::blocks::BlockKey {
+		let material = SlotMap::<MaterialKey, ()>::with_key().insert(());
+		block(blocks, "stone", BlockRenderType::Cube([material; 6]), true, "opacity: Opaque,")
+	}
+
+	fn visibility(f: impl Fn(UVec3) -> bool) -> ChunkVisibility {
+		let mut blocks = BlockManager::new();
+		let stone = stone(&mut blocks);
+		let mut contents = TerrainContents::new();
+		for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)).filter(|&p| f(p)) {
+			contents.insert(p, stone);
+		}
+		ChunkVisibility::new(&blocks, &TerrainChunk::new(contents))
+	}
+
+	#[test]
+	fn test_chunk_visibility() {
+		assert_eq!(ChunkVisibility::ALL, visibility(|_| false));
+		assert_eq!(ChunkVisibility::NONE, visibility(|_| true));
+
+		// A wall across x
+		let wall = visibility(|p| p.x == 16);
+		assert!(!wall.connected(0, 1) && !wall.connected(1, 0));
+		assert!(wall.connected(2, 0) && wall.connected(2, 1) && wall.connected(4, 5));
+
+		// A wall with a hole in it
+		let holed = visibility(|p| p.x == 16 && p.y != 5);
+		assert!(holed.connected(0, 1));
+
+		// Air along one side doesn't reach the other
+		let slab = visibility(|p| p.x != 0);
+		assert_eq!(0b111110, slab.0[1]);
+		assert_eq!(0, slab.0[0]);
+	}
+
+	// Looking along z from the middle of chunk 0 with a 90 degree field of view
+	fn frustum() -> Frustum {
+		let view = Mat4::from_translation(Vec3::splat(16.0)).inverse();
+		Frustum::from_view_projection(Mat4::perspective_lh(90.0_f32.to_radians(), 1.0, 0.1, 500.0) * view)
+	}
+
+	#[test]
+	fn test_frustum() {
+		let frustum = frustum();
+		assert!(frustum.intersects_chunk(IVec3::ZERO));
+		assert!(frustum.intersects_chunk(IVec3::new(0, 0, 3)));
+		assert!(frustum.intersects_chunk(IVec3::new(2, -2, 3)));
+		assert!(!frustum.intersects_chunk(IVec3::new(0, 0, -2)));
+		assert!(!frustum.intersects_chunk(IVec3::new(5, 0, 2)));
+		// Beyond the far plane
+		assert!(!frustum.intersects_chunk(IVec3::new(0, 0, 20)));
+	}
+
+	#[test]
+	fn test_visible_chunks() {
+		let frustum = frustum();
+		let loaded = |p: IVec3| p.abs().max_element() <= 4;
+		let open = visible_chunks(IVec3::ZERO, &frustum, |p| loaded(p).then_some(ChunkVisibility::ALL));
+		assert!(open.contains(&IVec3::new(0, 0, 2)));
+		assert!(!open.contains(&IVec3::new(0, 0, -1)));
+		assert!(open.iter().all(|&p| frustum.intersects_chunk(p)));
+
+		// Nothing can be seen through a solid chunk, and paths can't turn back to get behind it
+		let walled = visible_chunks(IVec3::ZERO, &frustum, |p| {
+			loaded(p).then_some(if p == IVec3::Z { ChunkVisibility::NONE } else { ChunkVisibility::ALL })
+		});
+		assert!(walled.contains(&IVec3::Z));
+		assert!(!walled.contains(&IVec3::new(0, 0, 2)));
+		assert!(walled.contains(&IVec3::new(1, 0, 2)));
+	}
+}
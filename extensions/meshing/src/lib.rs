@@ -1,20 +1,31 @@
+#![feature(test)]
+
+pub mod culling;
+pub mod lod;
 pub mod model;
+pub mod octree;
+pub mod packed;
+pub mod shading;
 
 use eeks::prelude::*;
-use model::{chunk_bounds_rendering_system, map_modelling_system, map_rendering_system, model_wipe_system, MapMeshingComponent, MapModelResource};
+use model::{chunk_bounds_rendering_system, map_modelling_system, map_rendering_system, model_wipe_system, translucent_sorting_system, MapMeshingComponent, MapModelResource};
+use octree::{octree_chunks_rendering_system, octree_chunks_system, OctreeChunksResource};
 use pinecore::player::PlayerSpawnResource;
 
 
 #[macro_use]
 extern crate log;
 
+extern crate test;
+
 
 fn player_meshing_component(
 	psr: Res<PlayerSpawnResource>,
 	mut meshings: CompMut<MapMeshingComponent>,
 ) {
 	for entity in psr.entities.iter().copied() {
-		meshings.insert(entity, MapMeshingComponent::new(4, 2));
+		// Chunks are loaded a little further than this so that the outermost ones can be shaded
+		meshings.insert(entity, MapMeshingComponent::new(4, 2).with_lod_radii([6, 8, 10]));
 	}
 }
 
@@ -36,15 +47,24 @@ pub fn systems(loader: &mut ExtensionSystemsLoader) {
 
 	loader.system("client_tick", "map_modelling_system", map_modelling_system)
 		.run_after("terrain_loading_system")
-		.run_after("torchlight_update_system");
+		.run_after("terrain_modification_application")
+		.run_after("torchlight_update_system")
+		.run_after("sunlight_update_system");
+
+	loader.system("client_tick", "octree_chunks_system", octree_chunks_system)
+		.run_after("map_modelling_system");
 
-	loader.system("render", "map_rendering_system", map_rendering_system);
+	loader.system("render", "translucent_sorting_system", translucent_sorting_system);
+	loader.system("render", "map_rendering_system", map_rendering_system)
+		.run_after("translucent_sorting_system");
 	loader.system("render", "chunk_bounds_rendering_system", chunk_bounds_rendering_system);
+	loader.system("render", "octree_chunks_rendering_system", octree_chunks_rendering_system);
 }
 
 
 #[load]
 pub fn load(storages: &mut eeks::ExtensionStorageLoader) {
 	storages.resource(MapModelResource::new(8));
+	storages.resource(OctreeChunksResource::new(15, 32 * 1024 * 1024));
 	storages.component::<MapMeshingComponent>();
 }
@@ -0,0 +1,231 @@
+use chunks::{blocks::{BlockEntry, BlockKey, BlockManager}, states::BlockState, CHUNK_SIZE};
+use glam::{IVec3, UVec2, UVec3, Vec3};
+use smallvec::SmallVec;
+use terrain::terrain::TerrainChunk;
+use crate::{model::{quad_axes, quads_greedy, sort_translucent, ChunkMeshData, ChunkQuad, MapMeshingComponent}, shading::{front_voxel, ChunkSurroundings}};
+
+
+
+/// The coarsest level of detail, where cells are 2^3 voxels along each axis.
+pub const MAX_LOD: u32 = 3;
+
+
+/// How a chunk should be meshed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChunkDetail {
+	// Voxels are merged into cells of 2^lod along each axis
+	pub lod: u32,
+	// By direction, closed borders get faces even if there is terrain on the other side
+	// Borders with chunks of another level don't line up, so both sides are closed to hide the cracks
+	pub seams: [bool; 6],
+}
+impl ChunkDetail {
+	/// The finest detail that any loader wants for a chunk, or None if none of them want it.
+	pub fn of(loaders: &[(&MapMeshingComponent, IVec3)], position: IVec3) -> Option<Self> {
+		let lod = |p| loaders.iter().filter_map(|&(l, c)| l.lod(c, p)).min();
+		let own = lod(position)?;
+		let seams = std::array::from_fn(|direction| {
+			let axis = IVec3::AXES[direction / 2];
+			let neighbour = if direction % 2 == 0 { position + axis } else { position - axis };
+			lod(neighbour).is_some_and(|l| l != own)
+		});
+		Some(Self { lod: own, seams })
+	}
+
+	/// Does meshing at this detail read any voxels within these bounds of the neighbour at this offset?
+	/// Cells that touch the border and the voxels around faces are read, everything else isn't.
+	pub fn reads(&self, offset: IVec3, (min, max): (UVec3, UVec3)) -> bool {
+		let depth = 1 << self.lod;
+		(0..3).all(|axis| {
+			let (lo, hi) = match offset[axis] {
+				-1 => (CHUNK_SIZE - depth, CHUNK_SIZE - 1),
+				1 => (0, depth - 1),
+				_ => (0, CHUNK_SIZE - 1),
+			};
+			min[axis] <= hi && max[axis] >= lo
+		})
+	}
+}
+
+
+/// The most common block in a cell of `scale` voxels along each axis starting at `origin`.
+/// Blocks that aren't drawn as cubes count as empty, and the cell is only empty
+/// if there is more empty space than any block, so that thin layers survive.
+fn downsample(blocks: &BlockManager, chunk: &TerrainChunk, origin: UVec3, scale: u32) -> Option<(BlockKey, BlockState)> {
+	let mut counts: SmallVec<[((BlockKey, BlockState), u32); 4]> = SmallVec::new();
+	let mut empty = 0;
+	for x in 0..scale {
+		for y in 0..scale {
+			for z in 0..scale {
+				let p = origin + UVec3::new(x, y, z);
+				let Some(b) = chunk.get(p).filter(|&b| blocks.get(b).is_some_and(|e| e.face_materials(BlockState::default()).is_some())) else {
+					empty += 1;
+					continue
+				};
+				let block = (b, chunk.get_state(p));
+				match counts.iter_mut().find(|(c, _)| *c == block) {
+					Some((_, n)) => *n += 1,
+					None => counts.push((block, 1)),
+				}
+			}
+		}
+	}
+	// The first most common, so that the result doesn't depend on iteration order of ties
+	let (block, n) = counts.iter().copied()
+		.fold(None, |a: Option<((BlockKey, BlockState), u32)>, c| match a {
+			Some(a) if a.1 >= c.1 => Some(a),
+			_ => Some(c),
+		})?;
+	(n >= empty).then_some(block)
+}
+
+
+/// Cells of a chunk and those of its face neighbours that touch it.
+struct Cells {
+	// Cells along each axis of the chunk
+	n: i32,
+	// From -1 to n on each axis, edges and corners are left empty
+	cells: Vec<Option<(BlockKey, BlockState)>>,
+}
+impl Cells {
+	fn new(blocks: &BlockManager, surroundings: &ChunkSurroundings, scale: u32, seams: [bool; 6]) -> Self {
+		let n = (CHUNK_SIZE / scale) as i32;
+		let e = n + 2;
+		let mut cells = vec![None; (e * e * e) as usize];
+		for x in -1..=n {
+			for y in -1..=n {
+				for z in -1..=n {
+					let cell = IVec3::new(x, y, z);
+					let offset = cell.div_euclid(IVec3::splat(n));
+					// Only the face neighbours are needed
+					if offset.abs().element_sum() > 1 {
+						continue
+					}
+					if let Some(direction) = (0..6).find(|&d| offset == Self::direction_offset(d)) {
+						if seams[direction] {
+							continue
+						}
+					}
+					let origin = cell.rem_euclid(IVec3::splat(n)).as_uvec3() * scale;
+					cells[Self::index(n, cell)] = downsample(blocks, surroundings.chunk(offset), origin, scale);
+				}
+			}
+		}
+		Self { n, cells }
+	}
+
+	fn direction_offset(direction: usize) -> IVec3 {
+		let axis = IVec3::AXES[direction / 2];
+		if direction % 2 == 0 { axis } else { -axis }
+	}
+
+	fn index(n: i32, cell: IVec3) -> usize {
+		let e = n + 2;
+		let [x, y, z] = (cell + IVec3::ONE).to_array();
+		(x * e * e + y * e + z) as usize
+	}
+
+	fn get(&self, cell: IVec3) -> Option<(BlockKey, BlockState)> {
+		self.cells[Self::index(self.n, cell)]
+	}
+}
+
+
+/// Meshes a chunk at a reduced level of detail.
+/// Cells are meshed like voxels and then greedily merged.
+/// Model blocks are left out because they would be too small to see.
+pub fn chunk_quads_lod(
+	blocks: &BlockManager,
+	surroundings: &ChunkSurroundings,
+	lod: u32,
+	seams: [bool; 6],
+	viewer: Vec3,
+) -> ChunkMeshData {
+	assert!(lod > 0 && lod <= MAX_LOD);
+	let scale = 1 << lod;
+	let cells = Cells::new(blocks, surroundings, scale, seams);
+	let n = cells.n;
+
+	let mut quads = Vec::new();
+	let mut translucent_quads = Vec::new();
+	// Position is in cells
+	let mut push_quad = |e: &BlockEntry, state: BlockState, position: IVec3, direction: u32| {
+		let Some(material) = e.face_materials(state).map(|faces| faces[direction as usize]) else { return };
+		let [u_axis, v_axis] = quad_axes(direction);
+		let (u, v) = (IVec3::AXES[u_axis], IVec3::AXES[v_axis]);
+		let front = front_voxel((position * scale as i32).as_uvec3(), direction);
+		let shade = surroundings.shade_coarse(front, u, v, scale as i32);
+		let quad = ChunkQuad {
+			position: position.as_uvec3(),
+			direction,
+			extent: UVec2::ONE,
+			material,
+			vertices: [shade; 4],
+		};
+		if e.is_translucent() {
+			translucent_quads.push(quad);
+		} else {
+			quads.push(quad);
+		}
+	};
+
+	for axis in 0..3 {
+		let step = IVec3::AXES[axis];
+		for x in 0..n {
+			for y in 0..n {
+				for z in 0..=n {
+					// z is the position along this axis
+					let cell = match axis {
+						0 => IVec3::new(z, x, y),
+						1 => IVec3::new(x, z, y),
+						_ => IVec3::new(x, y, z),
+					};
+					// Faces on the positive border are made by that neighbour unless it is closed
+					if z == n && !seams[axis * 2] {
+						continue
+					}
+
+					let p = cells.get(cell);
+					let ne = cells.get(cell - step);
+					if p.is_some() && p.map(|(k, _)| k) == ne.map(|(k, _)| k) {
+						continue
+					}
+					let pe = p.and_then(|(k, s)| blocks.get(k).map(|e| (e, s)));
+					let nee = ne.and_then(|(k, s)| blocks.get(k).map(|e| (e, s)));
+					// The positive face of the negative cell
+					if let Some((e, s)) = nee {
+						if !pe.is_some_and(|(pe, ps)| pe.covers(ps, axis * 2 + 1)) {
+							push_quad(e, s, cell, axis as u32 * 2);
+						}
+					}
+					// The negative face of this cell
+					if let Some((e, s)) = pe {
+						if !nee.is_some_and(|(ne, ns)| ne.covers(ns, axis * 2)) {
+							push_quad(e, s, cell, axis as u32 * 2 + 1);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	let scale_quad = |mut q: ChunkQuad| {
+		q.position *= scale;
+		q.extent *= scale;
+		q
+	};
+	let quads = quads_greedy(quads).into_iter().map(scale_quad).collect();
+	let mut translucent_quads = translucent_quads.into_iter().map(scale_quad).collect::<Vec<_>>();
+	sort_translucent(&mut translucent_quads, surroundings.position, viewer);
+
+	ChunkMeshData {
+		quads, translucent_quads,
+		sorted_from: viewer,
+		instances: Vec::new(),
+		terrain_dependencies: surroundings.terrain_dependencies.clone(),
+		missing_neighbours: surroundings.missing.clone(),
+		torchlight_dependencies: surroundings.torchlight_dependencies.clone(),
+		sunlight_dependencies: surroundings.sunlight_dependencies.clone(),
+		..Default::default()
+	}
+}
@@ -1,32 +1,125 @@
-use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
-use arrayvec::ArrayVec;
-use chunks::{blocks::{BlockEntry, BlockManager, BlockRenderType, BlockResource}, chunk_of_point, chunks::{ChunkKey, ChunksResource}, generation::KGeneration, VoxelCube, CHUNK_SIZE};
+use std::{collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+use chunks::{blocks::{BlockEntry, BlockKey, BlockManager, BlockRenderType, BlockResource, PathOrLabel}, chunk_of_point, chunks::{ChunkEvent, ChunkKey, ChunksResource}, generation::KGeneration, states::BlockState, textures::{BlockTextures, BLOCK_TEXTURES}, VoxelCube, CHUNK_SIZE};
 use pinecore::controls::ControlComponent;
 use crossbeam_channel::{Receiver, Sender};
 use eeks::prelude::*;
-use glam::{IVec3, UVec3, Vec2, Vec3};
-use krender::{prelude::{AbstractRenderTarget, Mesh, RRID}, MaterialKey, MeshKey};
-use light::light::{LightRGBA, TorchLightChunksResource, TorchLightModifierComponent};
-use parking_lot::RwLock;
-use pinecore::render::{MaterialResource, MeshResource, RenderFrame};
+use glam::{IVec3, Mat4, UVec2, UVec3, Vec2, Vec3};
+use krender::{prelude::{AbstractRenderTarget, Buffer, Mesh, RRID}, BufferKey, MaterialKey, MeshKey};
+use light::{light::{TorchLightChunksResource, TorchLightModifierComponent}, sun::SunChunksResource};
+use pinecore::render::{context_aspect_ratio, BufferResource, CameraComponent, ContextResource, MaterialResource, MeshResource, QueueResource, RenderFrame, TextureResource};
 use slotmap::SecondaryMap;
-use smallvec::{smallvec, SmallVec};
-use terrain::terrain::{TerrainChunk, TerrainEntry, TerrainResource};
+use smallvec::SmallVec;
+use terrain::terrain::{TerrainEntry, TerrainResource};
 use pinecore::transform::TransformComponent;
+use crate::{culling::{visible_chunks, ChunkVisibility, CullingCounters, Frustum}, lod::{chunk_quads_lod, ChunkDetail, MAX_LOD}, packed::{PackedAllocation, PackedChunkQuads}, shading::{flip_quad, front_voxel, ChunkSurroundings, VertexShade}};
 
 
 
 #[derive(Debug)]
 pub struct MapModelEntry {
-	// A model will (almost (if not full of model blocks)) always depend on itself and its negative neighbours
-	// It can also depend on any other number of chunks, so a smallvec is necessary
+	// A model depends on itself and all of its neighbours, which are used for shading
 	pub terrain_dependencies: SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>, 
-	// A model will always depend on light from itself
-	// It might also depend on its negative neighbours
-	pub light_dependencies: ArrayVec<(IVec3, ChunkKey, KGeneration), 4>,
-	pub models: Vec<(MaterialKey, MeshKey)>, // renderable things
+	// Vertices are lit by the light of the chunk and its neighbours
+	pub torchlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	pub sunlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	// Neighbours that were meshed as empty because they weren't loaded
+	pub missing_neighbours: Vec<IVec3>,
+	// Opaque quads, which are drawn together with those of other chunks
+	pub packed: Option<PackedAllocation>,
+	// Opaque quads if they couldn't be packed
+	pub model: Option<MeshKey>,
+	// Drawn after everything else
+	pub translucent_model: Option<MeshKey>,
+	// Kept so that they can be sorted again when the camera moves
+	pub translucent_quads: Vec<ChunkQuad>,
+	pub sorted_from: Vec3,
+	// Model blocks, grouped so that each model is drawn once
+	pub instances: Vec<ModelBatch>,
 	pub entity: Entity,
+	pub detail: ChunkDetail,
+	pub visibility: ChunkVisibility,
+	// Size of the chunk's own meshes, instance buffers, and packed quads
+	pub bytes: u64,
 	pub outdated: bool,
+	// Outdated because the terrain was edited, these are remeshed first
+	pub edited: bool,
+}
+
+
+/// The output of a chunk meshing job.
+#[derive(Debug, Default)]
+pub struct ChunkMeshData {
+	pub quads: Vec<ChunkQuad>,
+	// Sorted back to front as seen from where the viewer was when meshing began
+	pub translucent_quads: Vec<ChunkQuad>,
+	pub sorted_from: Vec3,
+	pub instances: Vec<ModelInstance>,
+	pub terrain_dependencies: SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>,
+	pub torchlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	pub sunlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	pub missing_neighbours: Vec<IVec3>,
+	pub detail: ChunkDetail,
+	pub visibility: ChunkVisibility,
+}
+
+
+/// A rectangle of voxel faces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkQuad {
+	// Chunk-relative, the corner with the lowest coordinates
+	pub position: UVec3,
+	// 000 xp 001 xn 010 yp 011 yn 100 zp 101 zn
+	pub direction: u32,
+	// Faces along u and v, which are z and y for x quads, x and z for y quads, and x and y for z quads
+	pub extent: UVec2,
+	pub material: MaterialKey,
+	// In the same order as the positions
+	pub vertices: [VertexShade; 4],
+}
+impl ChunkQuad {
+	/// A single face, shaded by what is around it.
+	pub fn face(surroundings: &ChunkSurroundings, position: UVec3, direction: u32, material: MaterialKey) -> Self {
+		let [u_axis, v_axis] = quad_axes(direction);
+		let vertices = surroundings.shade(front_voxel(position, direction), IVec3::AXES[u_axis], IVec3::AXES[v_axis]);
+		Self { position, direction, extent: UVec2::ONE, material, vertices, }
+	}
+
+	/// The number of voxel faces covered.
+	pub fn area(&self) -> u32 {
+		self.extent.x * self.extent.y
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshingMode {
+	// One quad for every visible face
+	Simple,
+	// Coplanar opaque faces with the same material and evenly shaded vertices are merged
+	Greedy,
+}
+
+
+/// A voxel whose block is drawn with a model.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelInstance {
+	// Chunk-relative
+	pub position: UVec3,
+	pub block: BlockKey,
+	pub state: BlockState,
+}
+
+
+/// The model blocks of a chunk that have the same material and mesh.
+/// They are drawn together from their own instance buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelBatch {
+	pub material: MaterialKey,
+	pub mesh: MeshKey,
+	// A model matrix for each block
+	pub instances: BufferKey,
+	// One draw command for all of them
+	pub indirect: BufferKey,
 }
 
 
@@ -49,6 +142,21 @@ impl MapModelState {
 
 
 
+/// Running totals of what chunk models have made and freed.
+/// Anything made but neither freed nor in a model has leaked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MapModelCounters {
+	pub meshes_made: u64,
+	pub meshes_freed: u64,
+	pub buffers_made: u64,
+	pub buffers_freed: u64,
+	pub entities_spawned: u64,
+	pub entities_freed: u64,
+	// Results of jobs for chunks that were pruned while they were meshed
+	pub discarded_jobs: u64,
+}
+
+
 #[derive(Debug, Resource)]
 #[sda(commands = true)]
 pub struct MapModelResource {
@@ -59,21 +167,23 @@ pub struct MapModelResource {
 	pub sender: Sender<(
 		ChunkKey, 
 		IVec3, 
-		Result<(
-			Vec<(UVec3, u32, MaterialKey)>, 
-			SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>,
-		), MeshingError>,
+		Result<ChunkMeshData, MeshingError>,
 	)>, 
 	pub receiver: Receiver<(
 		ChunkKey, 
 		IVec3, 
-		Result<(
-			Vec<(UVec3, u32, MaterialKey)>, 
-			SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>,
-		), MeshingError>,
+		Result<ChunkMeshData, MeshingError>,
 	)>,
 	pub max_meshing_jobs: u8,
 	pub cur_meshing_jobs: u8,
+	pub mode: MeshingMode,
+	pub counters: MapModelCounters,
+	pub packed: PackedChunkQuads,
+	// Skip chunks that can't be seen by the camera
+	pub culling: bool,
+	pub culled: CullingCounters,
+	// Packed, opaque, and translucent chunk materials, read once they are first drawn
+	pub voxel_materials: Option<(MaterialKey, MaterialKey, MaterialKey)>,
 }
 impl MapModelResource {
 	pub fn new(max_meshing_jobs: u8) -> Self {
@@ -85,145 +195,329 @@ impl MapModelResource {
 			receiver,
 			max_meshing_jobs,
 			cur_meshing_jobs: 0,
+			mode: MeshingMode::Greedy,
+			counters: MapModelCounters::default(),
+			packed: PackedChunkQuads::new(),
+			culling: true,
+			culled: CullingCounters::default(),
+			voxel_materials: None,
+		}
+	}
+
+	/// Stops tracking a chunk and frees its model if it has one.
+	/// A job that is still meshing it will have its result discarded.
+	pub fn remove(
+		&mut self, 
+		key: ChunkKey,
+		meshes: &mut MeshResource,
+		buffers: &mut BufferResource,
+		entities: &mut EntitiesMut,
+		transforms: &mut CompMut<TransformComponent>, 
+	) {
+		if let Some((_, _, MapModelState::Complete(entry))) = self.chunks.remove(key) {
+			self.free(entry, meshes, buffers, entities, transforms);
+		}
+	}
+
+	/// Removes every chunk and frees their models.
+	pub fn clear(
+		&mut self, 
+		meshes: &mut MeshResource,
+		buffers: &mut BufferResource,
+		entities: &mut EntitiesMut,
+		transforms: &mut CompMut<TransformComponent>, 
+	) {
+		for key in self.chunks.keys().collect::<Vec<_>>() {
+			self.remove(key, meshes, buffers, entities, transforms);
+		}
+	}
+
+	fn free(
+		&mut self, 
+		entry: MapModelEntry,
+		meshes: &mut MeshResource,
+		buffers: &mut BufferResource,
+		entities: &mut EntitiesMut,
+		transforms: &mut CompMut<TransformComponent>, 
+	) {
+		trace!("Free model with entity {:?}", entry.entity);
+		transforms.remove(entry.entity);
+		entities.remove(entry.entity);
+		self.counters.entities_freed += 1;
+
+		if let Some(allocation) = entry.packed {
+			self.packed.remove(allocation);
+		}
+		for key in entry.model.into_iter().chain(entry.translucent_model) {
+			if meshes.remove(key).is_some() {
+				self.counters.meshes_freed += 1;
+			} else {
+				warn!("Chunk mesh {key:?} was already removed");
+			}
+		}
+		// Block meshes are shared, so only the instance buffers are removed
+		for batch in entry.instances {
+			for key in [batch.instances, batch.indirect] {
+				if buffers.remove(key).is_some() {
+					self.counters.buffers_freed += 1;
+				} else {
+					warn!("Model instance buffer {key:?} was already removed");
+				}
+			}
+		}
+	}
+
+	/// Meshes, buffers, bytes, and entities of all models.
+	pub fn live(&self) -> (u64, u64, u64, u64) {
+		self.chunks.values()
+			.filter_map(|(_, _, s)| s.ref_complete())
+			.fold((0, 0, 0, 0), |(m, i, b, e), entry| (
+				m + entry.model.iter().chain(entry.translucent_model.iter()).count() as u64,
+				i + 2 * entry.instances.len() as u64,
+				b + entry.bytes,
+				e + 1,
+			))
+	}
+
+	/// Finds or reads the mesh of a model block.
+	fn block_mesh(meshes: &mut MeshResource, mesh: &PathOrLabel) -> Option<MeshKey> {
+		match mesh {
+			PathOrLabel::Label(l) => meshes.key_from_label(l),
+			PathOrLabel::Path(p) => Some(meshes.key_from_path(p)
+				.unwrap_or_else(|| meshes.insert(Mesh::read_obj(p)))),
 		}
 	}
 
 	pub fn receive_jobs(
 		&mut self, 
 		meshes: &mut MeshResource,
+		buffers: &mut BufferResource,
 		entities: &mut EntitiesMut,
 		transforms: &mut CompMut<TransformComponent>, 
-		torchlight: &TorchLightChunksResource,
+		blocks: &BlockManager,
 	) {
-		let torchlight_chunks = torchlight.chunks.read();
+		for (key, position, r) in self.receiver.try_iter().collect::<Vec<_>>() {
+			self.cur_meshing_jobs -= 1;
+			// The chunk was pruned (and maybe marked again) while this was meshing
+			if !matches!(self.chunks.get(key), Some(&(p, true, _)) if p == position) {
+				trace!("Discard model for pruned chunk {position}");
+				self.counters.discarded_jobs += 1;
+				continue
+			}
 
-		for (key, position, r) in self.receiver.try_iter() {
 			match r {
-				Ok((quads, terrain_dependencies)) => {
+				Ok(data) => {
 					debug!("Received chunk model for {}", position);
 
-					trace!("Contains {} quads", quads.len());
+					trace!("Contains {} quads and {} translucent quads", data.quads.len(), data.translucent_quads.len());
 
-					// Group quads by material
-					let quads_by_key = quads.iter().fold(HashMap::new(), |mut a: HashMap<MaterialKey, Vec<(UVec3, u32, MaterialKey)>>, &v| {
-						if let Some(c) = a.get_mut(&v.2) {
-							c.push(v);
-						} else {
-							a.insert(v.2, vec![v]);
-						}
-						a
-					});
-					trace!("Contains {} materials", quads_by_key.len());
-
-					let torchlight_c = torchlight_chunks.get(key)
-						.expect("Torchlight entry not exists!");
-					
-					// let mut torchlight_cxn = None;
-
-					let mut light_dependencies = ArrayVec::new();
-					light_dependencies.push((position, key, torchlight_c.generation));
-					
-					// Construct meshes
-					let mut models = Vec::with_capacity(quads_by_key.len());
-					for g in quads_by_key.values() {
-						let mut positions = Vec::with_capacity(g.len() * 4);
-						let mut lights = Vec::with_capacity(g.len() * 4);
-						let mut uvs = Vec::with_capacity(g.len() * 4);
-						let mut indices = Vec::with_capacity(g.len() * 6);
-						for &(position, direction, _) in g {
-							indices.extend_from_slice(quad_indices(direction).map(|i| i + positions.len() as u32).as_slice());
-							uvs.extend_from_slice(quad_uvs().as_slice());
-							positions.extend_from_slice(quad_positions(position, direction).as_slice());
-
-							// Find the voxel this face is facing, then get light data from it
-							// If on edge and facing negative (to neighbouring chunk)
-							let light: f32 = if position.to_array().iter().any(|&p| p == 0) && ((direction & 0b001) != 0) {
-								// if position.x == 0 {
-								// 	// get cxn 16-1, y, z
-								// 	// How do we know the key??
-								//  // Maybe deps[2]?
-								// 	let cxn = torchlight_cxn.get_or_insert_with(|| torchlight_chunks.get(key))
-								// } else if position.y == 0 {
-
-								// } else {
-
-								// }
-								1.0
-							} else {
-								let offs = match direction {
-									// Every positive face is just zero
-									0b000 => UVec3::ZERO,
-									0b001 => UVec3::X,
-									0b010 => UVec3::ZERO,
-									0b011 => UVec3::Y,
-									0b100 => UVec3::ZERO,
-									0b101 => UVec3::Z,
-									_ => unreachable!(),
-								};
-								let p = position - offs;
-								let l: LightRGBA = torchlight_c.get(p).copied().into();
-
-								if l.r != 0 {
-									error!("{p}: {}", l.r);
-								}
-
-								l.into_vec4().x
-							};
-							lights.extend_from_slice(&[light; 4]);
-						}
+					// Faces are drawn with the layer of their texture in the block texture array
+					let layer = |material| blocks.textures.layer(material).unwrap_or(BlockTextures::MISSING_LAYER);
 
-						let mesh = Mesh::new(format!("Chunk {position} material {:?}", g[0].2))
-							.with_data("positions", positions.as_slice())
-							.with_data("lights", lights.as_slice())
-							.with_data("uvs", uvs.as_slice())
-							.with_vertex_count(positions.len() as u32)
-							.with_indices(indices);
-						let key = meshes.insert(mesh);
-
-						models.push((g[0].2, key));
-					}
-					trace!("Made {} models", models.len());
+					// Opaque quads are packed unless there is no space left for them
+					let packed = self.packed.insert(position, &data.quads, layer);
+					let model = if packed.is_none() && !data.quads.is_empty() {
+						warn!("Failed to pack quads for chunk {position}, using a mesh instead");
+						quad_mesh(meshes, position, "", &data.quads, layer)
+					} else {
+						None
+					};
+					let translucent_model = quad_mesh(meshes, position, "translucent ", &data.translucent_quads, layer);
+					let mut bytes = model.iter().chain(translucent_model.iter())
+						.filter_map(|&key| meshes.get(key))
+						.map(mesh_bytes)
+						.sum::<u64>() + packed.as_ref().map_or(0, |p| p.allocation.size());
+					self.counters.meshes_made += model.is_some() as u64 + translucent_model.is_some() as u64;
 
 					// Make entity
 					let world_position = (position * CHUNK_SIZE as i32).as_vec3();
 					trace!("Spawning chunk entity with position {world_position}");
 					let entity = entities.spawn();
 					transforms.insert(entity, TransformComponent::new().with_position(world_position));
+					self.counters.entities_spawned += 1;
+
+					// Model blocks with the same material and mesh are drawn together
+					let mut matrices = BTreeMap::<(MaterialKey, MeshKey), Vec<[f32; 16]>>::new();
+					for instance in data.instances.iter() {
+						let Some(entry) = blocks.get(instance.block) else { continue };
+						let BlockRenderType::Model { mesh, material, .. } = &entry.render_type else { continue };
+						let Some(mesh) = Self::block_mesh(meshes, mesh) else {
+							warn!("Failed to find mesh {mesh:?} for block '{}'", entry.specification.name);
+							continue
+						};
+						// Rotate about the centre of the voxel
+						let rotation = entry.states.orientation(instance.state).rotation();
+						let centre = world_position + instance.position.as_vec3() + Vec3::splat(0.5);
+						let matrix = Mat4::from_rotation_translation(rotation, centre - rotation * Vec3::splat(0.5));
+						matrices.entry((*material, mesh)).or_default().push(matrix.to_cols_array());
+					}
+					let instances = matrices.into_iter().enumerate().map(|(i, ((material, mesh), matrices))| {
+						let count = matrices.len() as u32;
+						let m = meshes.get(mesh).unwrap();
+						// The indexed form has a base vertex before the first instance
+						let command = match m.indices.as_ref() {
+							Some(indices) => vec![indices.len() as u32, count, 0, 0, 0],
+							None => vec![m.n_vertices, count, 0, 0],
+						};
+						let instances = Buffer::new_init(format!("chunk {position} model {i} instances"), bytemuck::cast_slice(&matrices), false, false, false)
+							.with_usages(wgpu::BufferUsages::VERTEX);
+						let indirect = Buffer::new_init(format!("chunk {position} model {i} indirect"), bytemuck::cast_slice(&command), false, false, false)
+							.with_usages(wgpu::BufferUsages::INDIRECT);
+						bytes += (matrices.len() * 64 + command.len() * 4) as u64;
+						self.counters.buffers_made += 2;
+						ModelBatch { material, mesh, instances: buffers.insert(instances), indirect: buffers.insert(indirect), }
+					}).collect::<Vec<_>>();
+					trace!("Made {} model instances in {} batches", data.instances.len(), instances.len());
 
 					let entry = MapModelEntry {
-						terrain_dependencies, light_dependencies, models, entity, outdated: false,
+						terrain_dependencies: data.terrain_dependencies,
+						torchlight_dependencies: data.torchlight_dependencies,
+						sunlight_dependencies: data.sunlight_dependencies,
+						missing_neighbours: data.missing_neighbours,
+						packed, model, translucent_model, instances, entity,
+						translucent_quads: data.translucent_quads,
+						sorted_from: data.sorted_from,
+						detail: data.detail,
+						visibility: data.visibility,
+						bytes,
+						outdated: false,
+						edited: false,
 					};
 
 					trace!("Insert with key {:?}", key);
 
 					// Todo: make sure it has higher generations than existing
 
-					// Insert and (maybe) unload
+					// Insert and replace the old model
 					if let Some((_, _, MapModelState::Complete(e))) = self.chunks.insert(key, (position, false, MapModelState::Complete(entry))) {
-						let entity = e.entity;
-						warn!("Remove entity {entity:?}");
-						transforms.remove(entity);
-						entities.remove(entity);
-						
-						for (_, key) in e.models {
-							warn!("Remove mesh {key:?}");
-							meshes.remove(key);
-						}
+						self.free(e, meshes, buffers, entities, transforms);
 					}
 				},
 				Err(e) => {
 					warn!("Modelling failed for {position} - {e}");
-					self.chunks.insert(key, (position, false, MapModelState::Failed(e)));
+					if let Some((_, _, MapModelState::Complete(e))) = self.chunks.insert(key, (position, false, MapModelState::Failed(e))) {
+						self.free(e, meshes, buffers, entities, transforms);
+					}
 				},
 			}
-			self.cur_meshing_jobs -= 1;
+		}
+	}
+
+	/// Sorts the translucent quads of models again if the viewer has moved far from where they were sorted. 
+	/// Chunks further away can wait longer before their order is wrong. 
+	pub fn sort_translucent_models(
+		&mut self, 
+		meshes: &mut MeshResource,
+		blocks: &BlockManager,
+		viewer: Vec3,
+	) {
+		for (position, _, state) in self.chunks.values_mut() {
+			let MapModelState::Complete(entry) = state else { continue };
+			let Some(old) = entry.translucent_model else { continue };
+			let moved = entry.sorted_from.distance(viewer);
+			if moved < RESORT_DISTANCE.max(chunk_centre(*position).distance(viewer) / 8.0) {
+				continue
+			}
+			trace!("Sort translucent quads of chunk {position} again");
+			sort_translucent(&mut entry.translucent_quads, *position, viewer);
+			entry.sorted_from = viewer;
+			let layer = |material| blocks.textures.layer(material).unwrap_or(BlockTextures::MISSING_LAYER);
+			entry.translucent_model = quad_mesh(meshes, *position, "translucent ", &entry.translucent_quads, layer);
+			meshes.remove(old);
+			self.counters.meshes_made += 1;
+			self.counters.meshes_freed += 1;
 		}
 	}
 }
+
+
+/// How far the viewer must move before translucent quads are sorted again.
+const RESORT_DISTANCE: f32 = 1.0;
+
+
+fn chunk_centre(position: IVec3) -> Vec3 {
+	(position * CHUNK_SIZE as i32).as_vec3() + Vec3::splat(CHUNK_SIZE as f32 / 2.0)
+}
+
+
+/// The size of a mesh's vertex and index data.
+fn mesh_bytes(mesh: &Mesh) -> u64 {
+	let vertices = mesh.data.values().map(|d| d.len() as u64).sum::<u64>();
+	let indices = mesh.indices.as_ref().map_or(0, |i| i.len() as u64 * 4);
+	vertices + indices
+}
+
+
+/// Makes one mesh of these quads, or None if there aren't any.
+/// Quads keep their order, and each vertex has the texture layer of its quad.
+fn quad_mesh(
+	meshes: &mut MeshResource,
+	position: IVec3,
+	label: &str,
+	quads: &[ChunkQuad],
+	layer: impl Fn(MaterialKey) -> u32,
+) -> Option<MeshKey> {
+	if quads.is_empty() {
+		return None;
+	}
+
+	let mut positions = Vec::with_capacity(quads.len() * 4);
+	let mut torchlights = Vec::with_capacity(quads.len() * 4);
+	let mut sunlights = Vec::with_capacity(quads.len() * 4);
+	let mut occlusions = Vec::with_capacity(quads.len() * 4);
+	let mut uvs = Vec::with_capacity(quads.len() * 4);
+	let mut layers = Vec::with_capacity(quads.len() * 4);
+	let mut indices = Vec::with_capacity(quads.len() * 6);
+	for quad in quads {
+		indices.extend_from_slice(quad_indices(quad.direction, flip_quad(&quad.vertices)).map(|i| i + positions.len() as u32).as_slice());
+		uvs.extend_from_slice(quad_uvs(quad.extent).as_slice());
+		positions.extend_from_slice(quad_positions(quad.position, quad.direction, quad.extent).as_slice());
+		torchlights.extend(quad.vertices.iter().map(|v| v.torchlight));
+		sunlights.extend(quad.vertices.iter().map(|v| v.sunlight));
+		occlusions.extend(quad.vertices.iter().map(|v| v.occlusion));
+		layers.extend_from_slice(&[layer(quad.material); 4]);
+	}
+
+	let mesh = Mesh::new(format!("Chunk {position} {label}mesh"))
+		.with_data("positions", positions.as_slice())
+		.with_data("torchlights", torchlights.as_slice())
+		.with_data("sunlights", sunlights.as_slice())
+		.with_data("occlusions", occlusions.as_slice())
+		.with_data("uvs", uvs.as_slice())
+		.with_data("layers", layers.as_slice())
+		.with_vertex_count(positions.len() as u32)
+		.with_indices(indices);
+	Some(meshes.insert(mesh))
+}
+
+
 impl StorageCommandExpose for MapModelResource {
 	// resource MapModelResource set max_jobs 32
+	// resource MapModelResource set mode greedy
+	// resource MapModelResource stats
+	// resource MapModelResource culled
+	// resource MapModelResource set culling false
 	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
 		match command[0] {
+			"stats" => {
+				let (meshes, buffers, bytes, entities) = self.live();
+				let c = self.counters;
+				// Signed so that freeing too much shows up instead of overflowing
+				let leaked = |made: u64, freed: u64, live: u64| made as i64 - freed as i64 - live as i64;
+				Ok(format!(
+					"{} models, {} meshes ({} bytes), {} buffers, {} entities, {} leaked meshes, {} leaked buffers, {} leaked entities, {} discarded jobs",
+					self.chunks.values().filter(|(_, _, s)| s.ref_complete().is_some()).count(),
+					meshes, bytes, buffers, entities,
+					leaked(c.meshes_made, c.meshes_freed, meshes),
+					leaked(c.buffers_made, c.buffers_freed, buffers),
+					leaked(c.entities_spawned, c.entities_freed, entities),
+					c.discarded_jobs,
+				))
+			},
+			"culled" => {
+				let c = self.culled;
+				Ok(format!("{} drawn, {} outside frustum, {} occluded", c.drawn, c.frustum_culled, c.occlusion_culled))
+			},
 			"set" => match command[1] {
 				"max_jobs" => if let Some(v) = command.get(2) {
 						let v = v.parse::<u8>()?;
@@ -232,6 +526,29 @@ impl StorageCommandExpose for MapModelResource {
 					} else {
 						Err(anyhow::anyhow!("Give a set value"))
 					},
+				"mode" => if let Some(&v) = command.get(2) {
+						self.mode = match v {
+							"simple" => MeshingMode::Simple,
+							"greedy" => MeshingMode::Greedy,
+							_ => return Err(anyhow::anyhow!("Unknown mode (simple or greedy)")),
+						};
+						// Remesh everything so that the change can be seen
+						for entry in self.chunks.values_mut().filter_map(|(_, _, s)| match s {
+							MapModelState::Complete(e) => Some(e),
+							_ => None,
+						}) {
+							entry.outdated = true;
+						}
+						Ok(format!("MapModelResource mode {:?}", self.mode))
+					} else {
+						Err(anyhow::anyhow!("Give a set value"))
+					},
+				"culling" => if let Some(v) = command.get(2) {
+						self.culling = v.parse::<bool>()?;
+						Ok(format!("MapModelResource culling {}", self.culling))
+					} else {
+						Err(anyhow::anyhow!("Give a set value"))
+					},
 				_ => Err(anyhow::anyhow!("Unknown field")),
 			}
 			_ => Err(anyhow::anyhow!("Unknown command")),
@@ -242,23 +559,47 @@ impl StorageCommandExpose for MapModelResource {
 
 #[derive(Debug, Component)]
 pub struct MapMeshingComponent {
+	// Chunks within this are meshed at full detail
 	pub radius: i32,
 	pub tolerence: i32,
+	// The outer radius of each coarser level of detail, increasing
+	pub lod_radii: Vec<i32>,
 }
 impl MapMeshingComponent {
 	pub fn new(radius: i32, tolerence: i32) -> Self {
 		assert!(radius >= 0);
 		assert!(tolerence >= 0);
-		Self { radius, tolerence, }
+		Self { radius, tolerence, lod_radii: Vec::new(), }
+	}
+
+	pub fn with_lod_radii(self, lod_radii: impl Into<Vec<i32>>) -> Self {
+		let lod_radii = lod_radii.into();
+		assert!(lod_radii.len() <= MAX_LOD as usize, "at most {MAX_LOD} levels of detail");
+		assert!(lod_radii.iter().try_fold(self.radius, |last, &r| (r > last).then_some(r)).is_some(), "radii must increase");
+		Self { lod_radii, ..self }
+	}
+
+	// Chunks out to here are meshed at some level of detail
+	fn outer_radius(&self) -> i32 {
+		self.lod_radii.last().copied().unwrap_or(self.radius)
 	}
 
 	pub fn loading_volume(&self, transform: TransformComponent) -> VoxelCube {
-		VoxelCube::new(chunk_of_point(transform.translation), UVec3::splat(self.radius as u32))
+		VoxelCube::new(chunk_of_point(transform.translation), UVec3::splat(self.outer_radius() as u32))
 	}
 
 	// Volume but expanded by tolerence
 	pub fn un_loading_volume(&self, transform: TransformComponent) -> VoxelCube {
-		VoxelCube::new(chunk_of_point(transform.translation), UVec3::splat((self.radius + self.tolerence) as u32))
+		VoxelCube::new(chunk_of_point(transform.translation), UVec3::splat((self.outer_radius() + self.tolerence) as u32))
+	}
+
+	/// The level of detail for a chunk, 0 being full detail, or None if it is too far away.
+	pub fn lod(&self, loader_chunk: IVec3, chunk: IVec3) -> Option<u32> {
+		let d = (chunk - loader_chunk).abs().max_element();
+		if d <= self.radius {
+			return Some(0);
+		}
+		self.lod_radii.iter().position(|&r| d <= r).map(|i| i as u32 + 1)
 	}
 }
 
@@ -268,13 +609,17 @@ pub fn model_wipe_system(
 	controls: Comp<ControlComponent>,
 	mut modifiers: CompMut<TorchLightModifierComponent>,
 	mut models: ResMut<MapModelResource>,
+	mut meshes: ResMut<MeshResource>,
+	mut buffers: ResMut<BufferResource>,
+	mut entities: EntitiesMut,
+	mut transforms: CompMut<TransformComponent>,
 ) {
 	for (control, modifier) in (&controls, &mut modifiers).iter() {
-		// Only for testing, doesn't wipe jobs in progress
+		// Only for testing, jobs in progress are discarded when they finish
 		if control.last_tick_pressed(modifier.wipe) && modifier.last_modification.and_then(|i| Some(i.elapsed() > Duration::from_secs_f32(0.1))).unwrap_or(true) {
 			modifier.last_modification = Some(Instant::now());
 
-			models.chunks.clear();
+			models.clear(&mut meshes, &mut buffers, &mut entities, &mut transforms);
 		}
 	}
 }
@@ -285,10 +630,12 @@ pub fn map_modelling_system(
 	chunks: Res<ChunksResource>,
 	terrain: Res<TerrainResource>,
 	torchlight: Res<TorchLightChunksResource>,
+	sunlight: Res<SunChunksResource>,
 	mut models: ResMut<MapModelResource>,
 	loaders: Comp<MapMeshingComponent>,
 	mut transforms: CompMut<TransformComponent>,
-	mut meshes: ResMut<MeshResource>,
+	// Grouped because systems can only take ten arguments
+	(mut meshes, mut buffers): (ResMut<MeshResource>, ResMut<BufferResource>),
 	blocks: Res<BlockResource>,
 ) {
 	let loading_volumes = (&loaders, &transforms).iter()
@@ -299,6 +646,14 @@ pub fn map_modelling_system(
 		.map(|(l, t)| l.un_loading_volume(*t))
 		.collect::<Vec<_>>();
 
+	let viewers = (&loaders, &transforms).iter()
+		.map(|(_, t)| t.translation)
+		.collect::<Vec<_>>();
+
+	let detail_loaders = (&loaders, &transforms).iter()
+		.map(|(l, t)| (l, chunk_of_point(t.translation)))
+		.collect::<Vec<_>>();
+
 	{
 		// profiling::scope!("Mark");
 		let chunks_chunks = chunks.read();
@@ -314,21 +669,23 @@ pub fn map_modelling_system(
 
 	{
 		// profiling::scope!("Prune");
+		for event in chunks.read().events.iter() {
+			if let &ChunkEvent::Unloaded(key, _) = event {
+				models.remove(key, &mut meshes, &mut buffers, &mut entities, &mut transforms);
+			}
+		}
 		let g = models.chunks.iter()
 			.map(|(key, (pos, _, _))| (key, *pos))
 			.collect::<Vec<_>>();
 		for (key, pos) in g {
 			if !un_loading_volumes.iter().any(|lv| lv.contains(pos)) {
-				if let Some((_, _, MapModelState::Complete(_))) = models.chunks.remove(key) {
-					// trace!("Unloading model for chunk {}", pos);
-					// Remove meshes
-					// Remove entity
-				}
+				// trace!("Unloading model for chunk {}", pos);
+				models.remove(key, &mut meshes, &mut buffers, &mut entities, &mut transforms);
 			}
 		}
 	}
 
-	models.receive_jobs(&mut meshes, &mut entities, &mut transforms, &torchlight);
+	models.receive_jobs(&mut meshes, &mut buffers, &mut entities, &mut transforms, &blocks.read());
 	// let n = chunks.read().chunks.len();
 	// let n_loaded = models.chunks.values().filter(|(_, w, s)| {
 	// 	(!w) && s.ref_complete().is_some()
@@ -339,12 +696,13 @@ pub fn map_modelling_system(
 		// profiling::scope!("Check for remesh viability");
 		let chunks_chunks = chunks.read();
 		let terrain_chunks = terrain.chunks.read();
-		let light_chunks = torchlight.chunks.read();
+		let torchlight_chunks = torchlight.chunks.read();
+		let sunlight_chunks = sunlight.chunks.read();
 		// Check for model validity
 		// let mut n_outdated = 0;
 		// let mut n_failed = 0;
 		// let mut n_waiting = 0;
-		for (_, modelling, state) in models.chunks.values_mut() {
+		for (position, modelling, state) in models.chunks.values_mut() {
 			// Don't check if we're already trying to fix the issue
 			if !*modelling { 
 				match state {
@@ -354,24 +712,20 @@ pub fn map_modelling_system(
 							continue;
 						}
 
-						entry.outdated = entry.terrain_dependencies.iter().any(|(_, key, gen)| {
-							match terrain_chunks.get(*key) {
-								Some(TerrainEntry::Complete(g)) => g.generation != *gen,
-								_ => false,
-							}
+						entry.edited = terrain_edited(&entry.detail, *position, &mut entry.terrain_dependencies, &terrain_chunks);
+						entry.outdated = entry.edited;
+						entry.outdated |= dependencies_changed(&entry.detail, *position, &mut entry.torchlight_dependencies, |key, gen| {
+							torchlight_chunks.get(key).and_then(|c| c.changed_since(gen).map(|bounds| (c.generation, bounds)))
 						});
-						entry.outdated |= entry.light_dependencies.iter().any(|(p, key, gen)| {
-							match light_chunks.get(*key) {
-								Some(c) => {
-									let g = c.generation != *gen;
-									if g {
-										warn!("{p}: {:?} != {:?}", c.generation, gen);
-									}
-									g
-								},
-								_ => false,
-							}
+						entry.outdated |= dependencies_changed(&entry.detail, *position, &mut entry.sunlight_dependencies, |key, gen| {
+							sunlight_chunks.get(key).and_then(|c| c.changed_since(gen).map(|bounds| (c.generation, bounds)))
 						});
+						// Remesh when a neighbour that was missing has been generated
+						entry.outdated |= entry.missing_neighbours.iter().any(|&p| chunks_chunks.get_position(p)
+							.and_then(|k| terrain_chunks.get(k))
+							.is_some_and(|e| e.complete_ref().is_some()));
+						// Remesh when the viewer moves to another ring or a neighbour does
+						entry.outdated |= ChunkDetail::of(&detail_loaders, *position).is_some_and(|d| d != entry.detail);
 						
 						// if entry.outdated {
 						// 	n_outdated += 1;
@@ -397,6 +751,54 @@ pub fn map_modelling_system(
 		// debug!("{} outdated, {} failed, {} retry", n_outdated, n_failed, n_waiting);
 	}
 
+	// Starts a job to mesh a chunk
+	let begin = |models: &mut MapModelResource, key: ChunkKey| {
+		let Some((position, working, _)) = models.chunks.get_mut(key) else { return };
+		let position = *position;
+		*working = true;
+		let sender = models.sender.clone();
+		let terrain_chunks = terrain.chunks.clone();
+		let torchlight_chunks = torchlight.chunks.clone();
+		let sunlight_chunks = sunlight.chunks.clone();
+		let blocks = blocks.blocks.clone();
+		let chunks = chunks.clone();
+		let mode = models.mode;
+		let detail = ChunkDetail::of(&detail_loaders, position).unwrap_or_default();
+		let centre = chunk_centre(position);
+		let viewer = viewers.iter().copied()
+			.min_by(|a, b| a.distance_squared(centre).total_cmp(&b.distance_squared(centre)))
+			.unwrap_or(centre);
+		rayon::spawn(move || {
+			let blocks = blocks.read();
+			let mesh_res = ChunkSurroundings::new(&blocks, &chunks, &terrain_chunks, &torchlight_chunks, &sunlight_chunks, position)
+				.map(|surroundings| {
+					let data = match (detail.lod, mode) {
+						(0, MeshingMode::Simple) => chunk_quads_simple(&blocks, &surroundings, detail.seams, viewer),
+						(0, MeshingMode::Greedy) => chunk_quads_greedy(&blocks, &surroundings, detail.seams, viewer),
+						(lod, _) => chunk_quads_lod(&blocks, &surroundings, lod, detail.seams, viewer),
+					};
+					let visibility = ChunkVisibility::new(&blocks, surroundings.chunk(IVec3::ZERO));
+					ChunkMeshData { detail, visibility, ..data }
+				});
+			sender.send((key, position, mesh_res)).unwrap();
+		});
+		models.cur_meshing_jobs += 1;
+	};
+
+	// Edited chunks go first and may use as many jobs again so that edits are seen quickly
+	let edited = models.chunks.iter()
+		.filter(|(_, (_, working, s))| !working && s.ref_complete().is_some_and(|e| e.edited))
+		.map(|(key, _)| key)
+		.collect::<Vec<_>>();
+	for key in edited {
+		if models.cur_meshing_jobs >= models.max_meshing_jobs.saturating_mul(2) {
+			trace!("Reached maxium chunk meshing jobs for edits");
+			break;
+		}
+		trace!("Begin modeling edited chunk {}", models.chunks[key].0);
+		begin(&mut models, key);
+	}
+
 	if models.cur_meshing_jobs < models.max_meshing_jobs {
 		let terrain_chunks = terrain.chunks.read();
 		for &(key, d) in chunks.read().chunks_by_distance.iter() {
@@ -408,25 +810,15 @@ pub fn map_modelling_system(
 
 			// let position = chunks.chunks[key];
 			// if loading_volumes.iter().any(|lv| lv.contains(p)) && !models.chunks.contains_key(k) 
-			if let Some((position, working, entry)) = models.chunks.get_mut(key) {
-				if match &entry {
+			// Chunks that are being meshed are left alone until their jobs finish
+			if let Some(&(position, false, ref entry)) = models.chunks.get(key) {
+				if match entry {
 					MapModelState::Complete(e) => e.outdated,
 					MapModelState::Failed(_) => false,
 					MapModelState::Waiting => true,
 				} {
-					let position = *position;
-					*working = true;
 					trace!("Begin modeling chunk {position} (distance {d})");
-					let sender = models.sender.clone();
-					let terrain_chunks = terrain.chunks.clone();
-					let blocks = blocks.blocks.clone();
-					let chunks = chunks.clone();
-					rayon::spawn(move || {
-						let blocks = blocks.read();
-						let mesh_res = chunk_quads_simple(&blocks, &chunks, &terrain_chunks, position);
-						sender.send((key, position, mesh_res)).unwrap();
-					});
-					models.cur_meshing_jobs += 1;
+					begin(&mut models, key);
 				}
 			}
 
@@ -439,6 +831,43 @@ pub fn map_modelling_system(
 }
 
 
+/// Has the terrain that a chunk model was meshed from been edited where it was read? 
+/// Neighbours only matter if the voxels that were read from them have changed, 
+/// so dependencies on those that were changed elsewhere are brought up to date. 
+pub fn terrain_edited(
+	detail: &ChunkDetail,
+	position: IVec3,
+	dependencies: &mut [(IVec3, ChunkKey, KGeneration)],
+	terrain_chunks: &SecondaryMap<ChunkKey, TerrainEntry>,
+) -> bool {
+	dependencies_changed(detail, position, dependencies, |key, gen| match terrain_chunks.get(key) {
+		Some(TerrainEntry::Complete(g)) => g.changed_since(gen).map(|bounds| (g.generation, bounds)),
+		_ => None,
+	})
+}
+
+
+/// Like [terrain_edited], but for any chunk data that records its changes. 
+/// `changes` gives the current generation of a chunk and the bounds changed since a generation, if any were. 
+pub fn dependencies_changed(
+	detail: &ChunkDetail,
+	position: IVec3,
+	dependencies: &mut [(IVec3, ChunkKey, KGeneration)],
+	changes: impl Fn(ChunkKey, KGeneration) -> Option<(KGeneration, (UVec3, UVec3))>,
+) -> bool {
+	let mut changed = false;
+	for (p, key, gen) in dependencies.iter_mut() {
+		let Some((current, bounds)) = changes(*key, *gen) else { continue };
+		if detail.reads(*p - position, bounds) {
+			changed = true;
+		} else {
+			*gen = current;
+		}
+	}
+	changed
+}
+
+
 #[derive(Debug, thiserror::Error)]
 pub enum MeshingError {
 	#[error("this depends on chunk {0}, which isn't loaded")]
@@ -448,221 +877,266 @@ pub enum MeshingError {
 
 fn chunk_quads_simple(
 	blocks: &BlockManager,
-	chunks: &ChunksResource, 
-	terrain_chunks: &Arc<RwLock<SecondaryMap<ChunkKey, TerrainEntry>>>,
-	position: IVec3,
-) -> Result<(
-	// Direction - 000 xp 001 xn 010 yp 011 100 zp 101 zn
-	Vec<(UVec3, u32, MaterialKey)>, 
-	SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>,
-), MeshingError> {
-	fn get_chunk(
-		chunks: &ChunksResource, 
-		terrain_chunks: &Arc<RwLock<SecondaryMap<ChunkKey, TerrainEntry>>>,
-		pos: IVec3,
-	) -> Result<(ChunkKey, Arc<TerrainChunk>), MeshingError> {
-		let key = chunks.read().get_position(pos).ok_or(MeshingError::ChunkUnloaded(pos))?;
-		let chunk = terrain_chunks.read().get(key).ok_or(MeshingError::ChunkUnloaded(pos))?
-			.complete_ref().ok_or(MeshingError::ChunkUnloaded(pos))?
-			.clone();
-		Ok((key, chunk))
-	}
-
-	let (chunk_key, chunk) = get_chunk(chunks, terrain_chunks, position)?;
-	
-	let mut deps = smallvec![(position, chunk_key, chunk.generation)];
-	let mut cxn = None;
-	let mut cyn = None;
-	let mut czn = None;
+	surroundings: &ChunkSurroundings,
+	// Closed borders are meshed as if nothing were on the other side
+	seams: [bool; 6],
+	// World-relative, used to sort translucent quads
+	viewer: Vec3,
+) -> ChunkMeshData {
+	let chunk = surroundings.chunk(IVec3::ZERO);
+	let cxn = surroundings.chunk(IVec3::NEG_X);
+	let cyn = surroundings.chunk(IVec3::NEG_Y);
+	let czn = surroundings.chunk(IVec3::NEG_Z);
 
 	let mut quads = Vec::new();
+	let mut translucent_quads = Vec::new();
+	let mut push_quad = |e: &BlockEntry, position, direction, material| {
+		let quad = ChunkQuad::face(surroundings, position, direction, material);
+		if e.is_translucent() {
+			translucent_quads.push(quad);
+		} else {
+			quads.push(quad);
+		}
+	};
+	let mut instances = Vec::new();
 	for x in 0..CHUNK_SIZE {
 		for y in 0..CHUNK_SIZE {
 			for z in 0..CHUNK_SIZE {
 				let b = chunk.get(UVec3::new(x, y, z));
 				let pe = b.and_then(|key| blocks.get(key));
-
-				// Returns (positive face?), (negative face?)
-				let faces = |pe: Option<&BlockEntry>, ne: Option<&BlockEntry>| {
-					// Has face iff is some and other is not covering
-					// We might be able to reduce this to one (bool, bool) match but I don't trust myself
-					let p_face: Option<bool> = pe.and_then(|e| Some(e.covering));
-					let n_face = ne.and_then(|e| Some(e.covering));
-					match (n_face, p_face) {
-						// empty | empty
-						(None, None) => (false, false),
-						// empty | anything (negative face)
-						(None, Some(_)) => (false, true),
-						// anything | empty (positive face)
-						(Some(_), None) => (true, false),
-						(Some(nc), Some(pc)) => match (nc, pc) {
-							// glass | water
-							// or glass | glass
-							// face generation depends on: 
-							// if same type
-							//   if faces self
-							//     two faces
-							//   else
-							//     no faces
-							// else 
-							//   two faces
-							(false, false) => todo!(), 
-							// glass | stone (two faces)
-							(false, true) => (true, true),
-							// stone | glass (two faces)
-							(true, false) => (true, true),
-							// stone | stone (no faces)
-							(true, true) => (false, false),
-						},
+				let ps = chunk.get_state(UVec3::new(x, y, z));
+
+				// Returns (positive face?), (negative face?) for the faces between this voxel and its negative neighbour on an axis
+				// A face is hidden if the voxel in front of it covers that side
+				// Nothing is seen between two voxels of the same block
+				let faces = |pe: Option<&BlockEntry>, ne: Option<(BlockKey, BlockState)>, axis: usize| {
+					if b.is_some() && b == ne.map(|(k, _)| k) {
+						return (false, false);
 					}
+					let ns = ne.map(|(_, s)| s).unwrap_or_default();
+					let ne = ne.and_then(|(k, _)| blocks.get(k));
+					let positive_face = ne.is_some() && !pe.is_some_and(|e| e.covers(ps, axis * 2 + 1));
+					let negative_face = pe.is_some() && !ne.is_some_and(|e| e.covers(ns, axis * 2));
+					(positive_face, negative_face)
 				};
 
+				if let Some(BlockRenderType::Model { .. }) = pe.map(|e| &e.render_type) {
+					instances.push(ModelInstance { position: UVec3::new(x, y, z), block: b.unwrap(), state: ps, });
+				}
+
 				// Look at xn
-				let xn = if x == 0 {
+				let xn = if x == 0 && seams[1] {
+					None
+				} else if x == 0 {
 					// Access the adjacent chunk
-					if cxn.is_none() {
-						let pxn = position - IVec3::X;
-						let (k, e) = get_chunk(chunks, terrain_chunks, pxn)?;
-						deps.push((pxn, k, e.generation));
-						cxn = Some(e);
-					}
-					let cxn = cxn.as_mut().unwrap();
-					cxn.get(UVec3::new(CHUNK_SIZE-1, y, z))
+					cxn.get(UVec3::new(CHUNK_SIZE-1, y, z)).map(|key| (key, cxn.get_state(UVec3::new(CHUNK_SIZE-1, y, z))))
 				} else {
-					chunk.get(UVec3::new(x-1, y, z))
+					chunk.get(UVec3::new(x-1, y, z)).map(|key| (key, chunk.get_state(UVec3::new(x-1, y, z))))
 				}; 
 				// Get entries
-				let xne = xn.and_then(|key| blocks.get(key));
-				let (positive_face, negative_face) = faces(pe, xne);
+				let xne = xn.and_then(|(key, _)| blocks.get(key));
+				let xns = xn.map(|(_, state)| state).unwrap_or_default();
+				let (positive_face, negative_face) = faces(pe, xn, 0);
 				if positive_face {
-					let m = match xne.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[0]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 0, m));
+					let e = xne.unwrap();
+					if let Some(m) = e.face_materials(xns).map(|faces| faces[0]) {
+						push_quad(e, UVec3::new(x, y, z), 0, m);
 					}
 				}
 				if negative_face {
-					let m = match pe.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[1]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 1, m));
+					let e = pe.unwrap();
+					if let Some(m) = e.face_materials(ps).map(|faces| faces[1]) {
+						push_quad(e, UVec3::new(x, y, z), 1, m);
 					}
 				}
 
 				// Look at yn
-				let yn = if y == 0 {
-					if cyn.is_none() {
-						let pyn = position - IVec3::Y;
-						let (k, e) = get_chunk(chunks, terrain_chunks, pyn)?;
-						deps.push((pyn, k, e.generation));
-						cyn = Some(e);
-					}
-					let cyn = cyn.as_mut().unwrap();
-					cyn.get(UVec3::new(x, CHUNK_SIZE-1, z))
+				let yn = if y == 0 && seams[3] {
+					None
+				} else if y == 0 {
+					cyn.get(UVec3::new(x, CHUNK_SIZE-1, z)).map(|key| (key, cyn.get_state(UVec3::new(x, CHUNK_SIZE-1, z))))
 				} else {
-					chunk.get(UVec3::new(x, y-1, z))
+					chunk.get(UVec3::new(x, y-1, z)).map(|key| (key, chunk.get_state(UVec3::new(x, y-1, z))))
 				}; 
 				// Get entries
-				let yne = yn.and_then(|key| blocks.get(key));
-				let (positive_face, negative_face) = faces(pe, yne);
+				let yne = yn.and_then(|(key, _)| blocks.get(key));
+				let yns = yn.map(|(_, state)| state).unwrap_or_default();
+				let (positive_face, negative_face) = faces(pe, yn, 1);
 				if positive_face {
-					let m = match yne.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[2]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 2, m));
+					let e = yne.unwrap();
+					if let Some(m) = e.face_materials(yns).map(|faces| faces[2]) {
+						push_quad(e, UVec3::new(x, y, z), 2, m);
 					}
 				}
 				if negative_face {
-					let m = match pe.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[3]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 3, m));
+					let e = pe.unwrap();
+					if let Some(m) = e.face_materials(ps).map(|faces| faces[3]) {
+						push_quad(e, UVec3::new(x, y, z), 3, m);
 					}
 				}
 
 				// Look at zn
-				let zn = if z == 0 {
-					if czn.is_none() {
-						let pzn = position - IVec3::Z;
-						let (k, e) = get_chunk(chunks, terrain_chunks, pzn)?;
-						deps.push((pzn, k, e.generation));
-						czn = Some(e);
-					}
-					let czn = czn.as_mut().unwrap();
-					czn.get(UVec3::new(x, y, CHUNK_SIZE-1))
+				let zn = if z == 0 && seams[5] {
+					None
+				} else if z == 0 {
+					czn.get(UVec3::new(x, y, CHUNK_SIZE-1)).map(|key| (key, czn.get_state(UVec3::new(x, y, CHUNK_SIZE-1))))
 				} else {
-					chunk.get(UVec3::new(x, y, z-1))
+					chunk.get(UVec3::new(x, y, z-1)).map(|key| (key, chunk.get_state(UVec3::new(x, y, z-1))))
 				}; 
 				// Get entries
-				let zne = zn.and_then(|key| blocks.get(key));
-				let (positive_face, negative_face) = faces(pe, zne);
+				let zne = zn.and_then(|(key, _)| blocks.get(key));
+				let zns = zn.map(|(_, state)| state).unwrap_or_default();
+				let (positive_face, negative_face) = faces(pe, zn, 2);
 				if positive_face {
-					let m = match zne.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[4]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 4, m));
+					let e = zne.unwrap();
+					if let Some(m) = e.face_materials(zns).map(|faces| faces[4]) {
+						push_quad(e, UVec3::new(x, y, z), 4, m);
 					}
 				}
 				if negative_face {
-					let m = match pe.as_ref().unwrap().render_type {
-						BlockRenderType::Cube(faces) => Some(faces[5]),
-						_ => None,
-					};
-					if let Some(m) = m {
-						quads.push((UVec3::new(x, y, z), 5, m));
+					let e = pe.unwrap();
+					if let Some(m) = e.face_materials(ps).map(|faces| faces[5]) {
+						push_quad(e, UVec3::new(x, y, z), 5, m);
 					}
 				}
 			}
 		}
 	}
 
-	Ok((quads, deps))
-}
-
-
-// fn quads_greedy(
-// 	blocks: &BlockManager,
-// 	chunk: &Chunk,
-// ) -> Vec<(IVec3, IVec3, bool, MaterialKey)> {
-// 	let mut quads = Vec::new();
-// 	for pass in 0..3 {
-// 		for mut x in 0..CHUNK_SIZE {
-// 			for mut y in 0..CHUNK_SIZE {
-// 				for mut z in 0..CHUNK_SIZE {
-// 					// Re-order to fit the pass
-// 					match pass {
-// 						0 => {},
-// 						1 => {
-// 							let b = z;
-// 							z = y;
-// 							y = x;
-// 							x = b;
-// 						},
-// 						2 => {
-// 							let b = y;
-// 							z = x;
-// 							y = z;
-// 							x = b;
-// 						},
-// 						_ => unreachable!(),
-// 					}
-// 					println!("get {} {} {}", x, y, z);
-// 				}
-// 			}
-// 		}
-// 	}
-// 	quads
-// }
+	// Faces on the positive borders usually belong to the neighbours
+	for axis in 0..3 {
+		if !seams[axis * 2] {
+			continue
+		}
+		for a in 0..CHUNK_SIZE {
+			for b in 0..CHUNK_SIZE {
+				let mut p = UVec3::ZERO;
+				let [u_axis, v_axis] = quad_axes(axis as u32 * 2);
+				p[axis] = CHUNK_SIZE - 1;
+				p[u_axis] = a;
+				p[v_axis] = b;
+				let Some(e) = chunk.get(p).and_then(|key| blocks.get(key)) else { continue };
+				if let Some(m) = e.face_materials(chunk.get_state(p)).map(|faces| faces[axis * 2]) {
+					let mut position = p;
+					position[axis] = CHUNK_SIZE;
+					push_quad(e, position, axis as u32 * 2, m);
+				}
+			}
+		}
+	}
+
+	sort_translucent(&mut translucent_quads, surroundings.position, viewer);
+
+	ChunkMeshData {
+		quads, translucent_quads, instances,
+		sorted_from: viewer,
+		terrain_dependencies: surroundings.terrain_dependencies.clone(),
+		missing_neighbours: surroundings.missing.clone(),
+		torchlight_dependencies: surroundings.torchlight_dependencies.clone(),
+		sunlight_dependencies: surroundings.sunlight_dependencies.clone(),
+		..Default::default()
+	}
+}
+
+
+/// Sorts quads back to front as seen from a world-relative viewer.
+pub(crate) fn sort_translucent(quads: &mut [ChunkQuad], chunk: IVec3, viewer: Vec3) {
+	let viewer = viewer - (chunk * CHUNK_SIZE as i32).as_vec3();
+	let distance = |q: &ChunkQuad| {
+		let centre = quad_positions(q.position, q.direction, q.extent).iter().sum::<Vec3>() / 4.0;
+		centre.distance_squared(viewer)
+	};
+	quads.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+}
+
+
+/// Sorts things in chunks back to front as seen from a world-relative viewer.
+pub(crate) fn sort_chunks_back_to_front<T>(items: &mut [T], position: impl Fn(&T) -> IVec3, viewer: Vec3) {
+	let distance = |t: &T| chunk_centre(position(t)).distance_squared(viewer);
+	items.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+}
+
+
+/// Meshes like [chunk_quads_simple] and then merges the opaque quads.
+fn chunk_quads_greedy(
+	blocks: &BlockManager,
+	surroundings: &ChunkSurroundings,
+	seams: [bool; 6],
+	viewer: Vec3,
+) -> ChunkMeshData {
+	let mut data = chunk_quads_simple(blocks, surroundings, seams, viewer);
+	data.quads = quads_greedy(data.quads);
+	data
+}
+
+
+/// Merges faces into rectangles, first along u and then along v.
+/// Faces are only merged with others that have the same direction and material 
+/// and the same shading at every vertex, so that the result looks the same. 
+/// Translucent quads should not be given to this because they need to be sorted. 
+pub(crate) fn quads_greedy(mut quads: Vec<ChunkQuad>) -> Vec<ChunkQuad> {
+	const S: usize = CHUNK_SIZE as usize;
+	let slice = |q: &ChunkQuad| q.position[(q.direction >> 1) as usize];
+	quads.sort_unstable_by_key(|q| (q.direction, slice(q)));
+
+	let mut merged = Vec::new();
+	// Faces of the current slice, indexed by u + v * S
+	let mut mask: Vec<Option<(MaterialKey, VertexShade)>> = vec![None; S * S];
+	for group in quads.chunk_by(|a, b| (a.direction, slice(a)) == (b.direction, slice(b))) {
+		let direction = group[0].direction;
+		let [u_axis, v_axis] = quad_axes(direction);
+		for q in group {
+			// Vertices that differ would be interpolated differently after merging
+			if q.vertices.iter().all(|v| *v == q.vertices[0]) {
+				mask[q.position[u_axis] as usize + q.position[v_axis] as usize * S] = Some((q.material, q.vertices[0]));
+			} else {
+				merged.push(*q);
+			}
+		}
+
+		for v in 0..S {
+			for u in 0..S {
+				let i = u + v * S;
+				let Some(face) = mask[i] else { continue };
+
+				let mut width = 1;
+				while u + width < S && mask[i + width] == Some(face) {
+					width += 1;
+				}
+				let mut height = 1;
+				while v + height < S && mask[i + height * S..i + height * S + width].iter().all(|&f| f == Some(face)) {
+					height += 1;
+				}
+				// Merged faces are removed so that they won't be used again
+				for dv in 0..height {
+					mask[i + dv * S..i + dv * S + width].fill(None);
+				}
+
+				let mut position = group[0].position;
+				position[u_axis] = u as u32;
+				position[v_axis] = v as u32;
+				merged.push(ChunkQuad {
+					position, direction,
+					extent: UVec2::new(width as u32, height as u32),
+					material: face.0,
+					vertices: [face.1; 4],
+				});
+			}
+		}
+	}
+	merged
+}
+
+
+/// The (u, v) axes of quads in this direction.
+pub(crate) fn quad_axes(direction: u32) -> [usize; 2] {
+	match direction & 0b110 {
+		0b000 => [2, 1],
+		0b010 => [0, 2],
+		0b100 => [0, 1],
+		_ => unreachable!(),
+	}
+}
 
 
 // u=x
@@ -689,41 +1163,108 @@ const Z_QUAD_POSITIONS: [Vec3; 4] = [
 ];
 
 
-fn quad_positions(position: UVec3, direction: u32) -> [Vec3; 4] {
-	match direction & 0b110 {
+fn quad_positions(position: UVec3, direction: u32, extent: UVec2) -> [Vec3; 4] {
+	let positions = match direction & 0b110 {
 		0b000 => X_QUAD_POSITIONS,
 		0b010 => Y_QUAD_POSITIONS,
 		0b100 => Z_QUAD_POSITIONS,
 		_ => unreachable!(),
-	}.map(|v| v + position.as_vec3())
+	};
+	let [u_axis, v_axis] = quad_axes(direction);
+	let mut scale = Vec3::ONE;
+	scale[u_axis] = extent.x as f32;
+	scale[v_axis] = extent.y as f32;
+	positions.map(|v| v * scale + position.as_vec3())
 }
 
 
-fn quad_uvs() -> [Vec2; 4] {
+// Textures repeat across merged quads
+fn quad_uvs(extent: UVec2) -> [Vec2; 4] {
+	let [u, v] = extent.as_vec2().to_array();
 	[
-		Vec2::new(0.0, 1.0), // 00 -> 01
-		Vec2::new(1.0, 1.0), // 10 -> 11
-		Vec2::new(1.0, 0.0), // 11 -> 10
+		Vec2::new(0.0, v), // 00 -> 01
+		Vec2::new(u, v), // 10 -> 11
+		Vec2::new(u, 0.0), // 11 -> 10
 		Vec2::new(0.0, 0.0), // 01 -> 00
 	]
 }
 
 
-fn quad_indices(direction: u32) -> [u32; 6] {
-	if direction & 0b1 == 0 {
-		[0, 1, 2, 2, 3, 0] // positive
-	} else {
-		[0, 3, 2, 2, 1, 0] // negative
+// Flipped quads are split between the second and fourth vertices instead of the first and third
+fn quad_indices(direction: u32, flipped: bool) -> [u32; 6] {
+	match (direction & 0b1 == 0, flipped) {
+		(true, false) => [0, 1, 2, 2, 3, 0], // positive
+		(false, false) => [0, 3, 2, 2, 1, 0], // negative
+		(true, true) => [1, 2, 3, 3, 0, 1],
+		(false, true) => [1, 0, 3, 3, 2, 1],
 	}
 }
 
 
+/// Keeps translucent quads sorted for the camera that they are drawn for.
+pub fn translucent_sorting_system(
+	mut models: ResMut<MapModelResource>,
+	mut meshes: ResMut<MeshResource>,
+	blocks: Res<BlockResource>,
+	contexts: Res<ContextResource>,
+	transforms: Comp<TransformComponent>,
+	input: Res<RenderFrame>,
+) {
+	let Some(transform) = contexts.get(input.context)
+		.and_then(|c| c.entity)
+		.and_then(|e| transforms.get(e)) else { return };
+	models.sort_translucent_models(&mut meshes, &blocks.read(), transform.translation);
+}
+
+
 pub fn map_rendering_system(
 	// context: Res<ActiveContextResource>,
 	// mut contexts: ResMut<ContextResource>, 
-	models: Res<MapModelResource>,
+	mut models: ResMut<MapModelResource>,
+	mut materials: ResMut<MaterialResource>,
+	mut buffers: ResMut<BufferResource>,
+	queue: Res<QueueResource>,
+	contexts: Res<ContextResource>,
+	textures: Res<TextureResource>,
+	cameras: Comp<CameraComponent>,
+	transforms: Comp<TransformComponent>,
 	mut input: ResMut<RenderFrame>,
 ) {
+	let models = &mut *models;
+
+	// Everything is drawn if there is no camera
+	let camera = contexts.get(input.context)
+		.and_then(|c| Some((c.entity?, context_aspect_ratio(c, &textures)?)))
+		.and_then(|(e, aspect)| Some((cameras.get(e)?, transforms.get(e)?, aspect)));
+	let culling = camera.filter(|_| models.culling).map(|(camera, transform, aspect)| {
+		let frustum = Frustum::from_view_projection(camera.view_projection(transform, aspect));
+		let visibilities = models.chunks.values()
+			.map(|(p, _, s)| (*p, s.ref_complete().map_or(ChunkVisibility::ALL, |e| e.visibility)))
+			.collect::<HashMap<_, _>>();
+		let visible = visible_chunks(chunk_of_point(transform.translation), &frustum, |p| visibilities.get(&p).copied());
+		(frustum, visible)
+	});
+	let drawn = |position: IVec3| culling.as_ref().map_or(true, |(_, visible)| visible.contains(&position));
+
+	let mut counters = CullingCounters::default();
+	let entries = models.chunks.values()
+		.filter_map(|(p, _, g)| g.ref_complete().map(|e| (*p, e)))
+		.filter(|&(p, _)| {
+			if drawn(p) {
+				counters.drawn += 1;
+			} else if culling.as_ref().is_some_and(|(f, _)| f.intersects_chunk(p)) {
+				counters.occlusion_culled += 1;
+			} else {
+				counters.frustum_culled += 1;
+			}
+			drawn(p)
+		})
+		.collect::<Vec<_>>();
+	models.culled = counters;
+	profiling::scope!("Draw chunks", format!("{} drawn, {} outside frustum, {} occluded", counters.drawn, counters.frustum_culled, counters.occlusion_culled).as_str());
+
+	let indirect = models.packed.flush(&mut buffers, &queue);
+	let commands = models.packed.write_commands(&mut buffers, &queue, entries.iter().filter_map(|(_, e)| e.packed.as_ref()));
 
 	let target = AbstractRenderTarget::new()
 		.with_colour(RRID::context("albedo"), None)
@@ -732,9 +1273,50 @@ pub fn map_rendering_system(
 		.stage("models")
 		.target(target);
 
-	for entry in models.chunks.values().filter_map(|(_, _, g)| g.ref_complete()) {
-		for &(material, mesh) in entry.models.iter() {
-			items.mesh(material, mesh, entry.entity);
+	// Chunk faces sample the block texture array, which is made once blocks are loaded
+	let voxel_materials = textures.key_by_name(&BLOCK_TEXTURES.to_string()).map(|_| *models.voxel_materials.get_or_insert_with(|| (
+		materials.read("resources/materials/voxel_packed.ron"),
+		materials.read("resources/materials/voxel.ron"),
+		materials.read("resources/materials/voxel_translucent.ron"),
+	)));
+
+	if let Some((packed, voxel, _)) = voxel_materials {
+		// Every chunk's quads are drawn at once
+		if !commands.is_empty() {
+			items.multi_indirect(packed, indirect, commands);
+		}
+		for (_, entry) in entries.iter() {
+			if let Some(mesh) = entry.model {
+				items.mesh(voxel, mesh, entry.entity);
+			}
+		}
+	}
+
+	for (_, entry) in entries.iter() {
+		for batch in entry.instances.iter() {
+			items.indirect_mesh(batch.material, batch.mesh, batch.indirect, batch.instances);
+		}
+	}
+
+	let target = AbstractRenderTarget::new()
+		.with_colour(RRID::context("albedo"), None)
+		.with_depth(RRID::context("depth"));
+	let mut items = input
+		.stage("translucent_models")
+		.run_after("models")
+		.ordered()
+		.target(target);
+
+	if let Some((_, _, translucent)) = voxel_materials {
+		// Blending needs chunks to be drawn back to front too
+		let mut translucent_entries = entries.iter()
+			.filter_map(|(p, e)| Some((*p, e.translucent_model?, e.entity)))
+			.collect::<Vec<_>>();
+		if let Some((_, transform, _)) = camera {
+			sort_chunks_back_to_front(&mut translucent_entries, |&(p, _, _)| p, transform.translation);
+		}
+		for (_, mesh, entity) in translucent_entries {
+			items.mesh(translucent, mesh, entity);
 		}
 	}
 }
@@ -792,3 +1374,697 @@ pub fn chunk_bounds_rendering_system(
 		items.mesh(material, mesh, entry.entity);
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{collections::{HashMap, HashSet}, sync::Arc};
+	use chunks::{chunk_of_voxel, cube_iterator_xyz_uvec, test_util::block, voxel_relative_to_chunk};
+	use light::{light::{torchlight_chunk_init_system, torchlight_update_system, LightChunk, LightRGBA}, sun::{self, sunlight_update_system, SunlightChunk}};
+	use krender::prelude::BufferManager;
+	use slotmap::SlotMap;
+	use terrain::{events::BlockEventResource, generator::{InteroplatedGeneratorNoise, RawFbmSettings}, liquids::LiquidsResource, modification::{terrain_modification_application, VoxelModification}, terrain::{TerrainChunk, TerrainContents}};
+	use test::Bencher;
+
+	struct World {
+		blocks: BlockManager,
+		materials: SlotMap<MaterialKey, ()>,
+		chunks: ChunksResource,
+		terrain: TerrainResource,
+		torchlight: TorchLightChunksResource,
+		sunlight: SunChunksResource,
+	}
+	impl World {
+		fn new() -> Self {
+			Self {
+				blocks: BlockManager::new(),
+				materials: SlotMap::with_key(),
+				chunks: ChunksResource::new(),
+				terrain: TerrainResource::default(),
+				torchlight: TorchLightChunksResource::default(),
+				sunlight: SunChunksResource::default(),
+			}
+		}
+
+		// A cube with a different material on each face
+		fn cube(&mut self, name: &str, opacity: &str) -> BlockKey {
+			let covering = opacity == "Opaque";
+			let faces = [(); 6].map(|_| self.materials.insert(()));
+			block(&mut self.blocks, name, BlockRenderType::Cube(faces), covering, &format!("opacity: {opacity},"))
+		}
+
+		// Loads a chunk and the neighbours it needs for meshing
+		fn load(&mut self, position: IVec3, f: impl Fn(IVec3) -> Option<BlockKey>) {
+			for c in cube_iterator_xyz_uvec(UVec3::splat(3)).map(|o| position + o.as_ivec3() - IVec3::ONE) {
+				let mut contents = TerrainContents::new();
+				for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)) {
+					if let Some(b) = f(c * CHUNK_SIZE as i32 + p.as_ivec3()) {
+						contents.insert(p, b);
+					}
+				}
+				let key = self.chunks.write().load(c);
+				self.terrain.chunks.write().insert(key, TerrainEntry::Complete(Arc::new(TerrainChunk::new(contents))));
+			}
+		}
+
+		fn locate(&self, voxel: IVec3) -> (ChunkKey, UVec3) {
+			let c = chunk_of_voxel(voxel);
+			(self.chunks.read().get_position(c).unwrap(), voxel_relative_to_chunk(voxel, c).as_uvec3())
+		}
+
+		fn set_torchlight(&self, voxel: IVec3, light: LightRGBA) {
+			let (key, p) = self.locate(voxel);
+			let mut chunks = self.torchlight.chunks.write();
+			let chunk = chunks.entry(key).unwrap().or_insert_with(LightChunk::new);
+			match light.into() {
+				Some(packed) => chunk.insert(p, packed),
+				None => chunk.remove(p),
+			}
+		}
+
+		fn set_sunlight(&self, voxel: IVec3, [r, g, b]: [u16; 3]) {
+			let (key, p) = self.locate(voxel);
+			let mut chunks = self.sunlight.chunks.write();
+			chunks.entry(key).unwrap().or_insert_with(SunlightChunk::new).set(p, sun::pack(r, g, b));
+		}
+
+		// Sets a voxel as an edit would
+		fn edit(&self, voxel: IVec3, block: Option<BlockKey>) {
+			let (key, p) = self.locate(voxel);
+			let mut chunks = self.terrain.chunks.write();
+			let Some(TerrainEntry::Complete(chunk)) = chunks.get_mut(key) else { panic!() };
+			let inner = Arc::make_mut(chunk);
+			match block {
+				Some(b) => inner.insert(p, b),
+				None => inner.remove(p),
+			}
+			inner.record_change(p, p);
+		}
+
+		fn surroundings(&self, position: IVec3) -> Result<ChunkSurroundings, MeshingError> {
+			ChunkSurroundings::new(&self.blocks, &self.chunks, &self.terrain.chunks, &self.torchlight.chunks, &self.sunlight.chunks, position)
+		}
+
+		fn simple(&self, position: IVec3) -> ChunkMeshData {
+			chunk_quads_simple(&self.blocks, &self.surroundings(position).unwrap(), [false; 6], Vec3::ZERO)
+		}
+
+		fn greedy(&self, position: IVec3) -> ChunkMeshData {
+			chunk_quads_greedy(&self.blocks, &self.surroundings(position).unwrap(), [false; 6], Vec3::ZERO)
+		}
+
+		fn lod(&self, position: IVec3, lod: u32, seams: [bool; 6]) -> ChunkMeshData {
+			let surroundings = self.surroundings(position).unwrap();
+			if lod == 0 {
+				chunk_quads_greedy(&self.blocks, &surroundings, seams, Vec3::ZERO)
+			} else {
+				chunk_quads_lod(&self.blocks, &surroundings, lod, seams, Vec3::ZERO)
+			}
+		}
+	}
+
+	// Hashes a voxel for pseudo-random terrain
+	fn noise(v: IVec3, seed: u32) -> u32 {
+		let h = (v.x as u32).wrapping_mul(73856093) ^ (v.y as u32).wrapping_mul(19349663) ^ (v.z as u32).wrapping_mul(83492791) ^ seed;
+		h.wrapping_mul(2654435761) >> 8
+	}
+
+	// Rolling hills with a grass surface
+	fn generated_world() -> World {
+		let settings = RawFbmSettings {
+			seed: 42,
+			freq: 1.0 / 50.0,
+			lacunarity: 2.0,
+			gain: 0.5,
+			octaves: 3,
+		};
+		let scale = settings.compute_scale();
+		let (origin, extent) = (-(CHUNK_SIZE as i32), CHUNK_SIZE * 3);
+		// One more above so that surfaces can be found
+		let densities = InteroplatedGeneratorNoise::generate(
+			settings,
+			origin, extent, 4,
+			origin, extent + 1, 8,
+			origin, extent, 4,
+		);
+		let solid = move |v: IVec3| {
+			let p = (v - IVec3::splat(origin)).as_uvec3();
+			let d = (densities[(p.x * (extent + 1) * extent + p.y * extent + p.z) as usize] * scale + 1.0) / 2.0;
+			d + (16 - v.y) as f32 / 24.0 >= 0.5
+		};
+
+		let mut world = World::new();
+		let grass = world.cube("grass", "Opaque");
+		let dirt = world.cube("dirt", "Opaque");
+		let stone = world.cube("stone", "Opaque");
+		world.load(IVec3::ZERO, |v| {
+			if !solid(v) {
+				None
+			} else if !solid(v + IVec3::Y) {
+				Some(grass)
+			} else if (1..4).all(|i| solid(v + IVec3::Y * i)) {
+				Some(stone)
+			} else {
+				Some(dirt)
+			}
+		});
+		world
+	}
+
+	// Every face of every quad
+	fn faces(quad: &ChunkQuad) -> impl Iterator<Item = (UVec3, u32, MaterialKey)> + '_ {
+		let [u_axis, v_axis] = quad_axes(quad.direction);
+		(0..quad.extent.y).flat_map(move |dv| (0..quad.extent.x).map(move |du| {
+			let mut position = quad.position;
+			position[u_axis] += du;
+			position[v_axis] += dv;
+			(position, quad.direction, quad.material)
+		}))
+	}
+
+	// Greedy meshing should cover exactly the faces that simple meshing does, and shade them the same
+	fn assert_same_faces(world: &World) -> (usize, usize) {
+		let simple = world.simple(IVec3::ZERO);
+		let greedy = world.greedy(IVec3::ZERO);
+
+		let area = greedy.quads.iter().map(|q| q.area() as usize).sum::<usize>();
+		assert_eq!(simple.quads.len(), area, "surface area differs");
+		let expected = simple.quads.iter().map(|q| ((q.position, q.direction, q.material), q.vertices)).collect::<HashMap<_, _>>();
+		let merged = greedy.quads.iter().flat_map(|q| faces(q).map(|f| (f, q.vertices))).collect::<Vec<_>>();
+		assert_eq!(expected.keys().collect::<HashSet<_>>(), merged.iter().map(|(f, _)| f).collect::<HashSet<_>>());
+		for (face, vertices) in merged {
+			assert_eq!(expected[&face], vertices, "{face:?} is shaded differently");
+		}
+
+		assert_eq!(simple.translucent_quads, greedy.translucent_quads);
+		(simple.quads.len(), greedy.quads.len())
+	}
+
+	#[test]
+	fn test_greedy_same_faces() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		let dirt = world.cube("dirt", "Opaque");
+		let glass = world.cube("glass", "Translucent");
+		world.load(IVec3::ZERO, |v| {
+			let height = 8 + (v.x / 5 + v.z / 7).rem_euclid(4);
+			if noise(v, 1) % 13 == 0 {
+				None
+			} else if v.y < height - 3 {
+				Some(stone)
+			} else if v.y < height {
+				Some(dirt)
+			} else if v.y == height && noise(v, 2) % 5 == 0 {
+				Some(glass)
+			} else {
+				None
+			}
+		});
+
+		let (simple, greedy) = assert_same_faces(&world);
+		assert!(greedy < simple);
+
+		for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)).filter(|p| p.x < 16) {
+			let l = noise(p.as_ivec3(), 3) % 3;
+			world.set_torchlight(p.as_ivec3(), LightRGBA::from_emission([l as u16, 0, 0]));
+		}
+		let (_, greedy_lit) = assert_same_faces(&world);
+		assert!(greedy < greedy_lit, "light should split quads");
+
+		let (simple, greedy) = assert_same_faces(&generated_world());
+		assert!(greedy < simple, "only {simple} to {greedy} quads");
+	}
+
+	#[test]
+	fn test_greedy_floor() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		// Other chunks are empty
+		world.load(IVec3::ZERO, |v| (v.y >= 0 && v.y < 4 && (0..CHUNK_SIZE as i32).contains(&v.x) && (0..CHUNK_SIZE as i32).contains(&v.z)).then_some(stone));
+
+		// Top, bottom, and the sides against the negative neighbours
+		assert_eq!(1024 * 2 + 128 * 2, world.simple(IVec3::ZERO).quads.len());
+		let greedy = world.greedy(IVec3::ZERO);
+		assert_eq!(4, greedy.quads.len());
+		let top = greedy.quads.iter().find(|q| q.direction == 0b010).unwrap();
+		assert_eq!((UVec3::new(0, 4, 0), UVec2::splat(32)), (top.position, top.extent));
+		assert_eq!([
+			Vec3::new(0.0, 4.0, 0.0),
+			Vec3::new(32.0, 4.0, 0.0),
+			Vec3::new(32.0, 4.0, 32.0),
+			Vec3::new(0.0, 4.0, 32.0),
+		], quad_positions(top.position, top.direction, top.extent));
+		let side = greedy.quads.iter().find(|q| q.direction == 0b001).unwrap();
+		assert_eq!((UVec3::ZERO, UVec2::new(32, 4)), (side.position, side.extent));
+
+		// Light is smoothed into the faces around a lit voxel, which can't be merged
+		world.set_torchlight(IVec3::new(5, 4, 5), LightRGBA::from_emission([15, 0, 0]));
+		let greedy = world.greedy(IVec3::ZERO);
+		// Four around the lit faces, the unevenly lit faces, and the evenly lit one in the middle
+		let top = greedy.quads.iter().filter(|q| q.direction == 0b010).collect::<Vec<_>>();
+		assert_eq!(4 + 8 + 1, top.len());
+		let middle = top.iter().find(|q| q.position == UVec3::new(5, 4, 5)).unwrap();
+		assert_eq!(UVec2::ONE, middle.extent);
+		assert_eq!([Vec3::new(0.25, 0.0, 0.0); 4], middle.vertices.map(|v| v.torchlight));
+	}
+
+	#[test]
+	fn test_ambient_occlusion() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		let walls = [IVec3::new(5, 4, 5), IVec3::new(6, 4, 4), IVec3::new(-1, 4, 10)];
+		world.load(IVec3::ZERO, |v| (v.y < 4 || walls.contains(&v)).then_some(stone));
+		world.set_torchlight(IVec3::new(6, 4, 5), LightRGBA::from_emission([12, 0, 0]));
+		world.set_sunlight(IVec3::new(6, 4, 5), [15, 15, 15]);
+		let data = world.simple(IVec3::ZERO);
+		let top = |x, z| data.quads.iter().find(|q| q.direction == 0b010 && q.position == UVec3::new(x, 4, z)).unwrap();
+
+		// Walls on both sides of the first vertex
+		let q = top(6, 5);
+		assert_eq!([0.0, 2.0 / 3.0, 1.0, 2.0 / 3.0], q.vertices.map(|v| v.occlusion));
+		assert!(flip_quad(&q.vertices));
+		assert_eq!([1, 2, 3, 3, 0, 1], quad_indices(q.direction, true));
+		// Light is only averaged from voxels that it can be in
+		assert_eq!([12.0 / 15.0, 4.0 / 15.0, 3.0 / 15.0, 4.0 / 15.0], q.vertices.map(|v| v.torchlight.x));
+		assert_eq!(Vec3::ONE, q.vertices[0].sunlight);
+
+		// Only a corner
+		let q = top(7, 5);
+		assert_eq!([2.0 / 3.0, 1.0, 1.0, 1.0], q.vertices.map(|v| v.occlusion));
+		// Nothing
+		let q = top(8, 8);
+		assert_eq!([1.0; 4], q.vertices.map(|v| v.occlusion));
+		assert!(!flip_quad(&q.vertices));
+
+		// A wall in the neighbouring chunk
+		let q = top(0, 10);
+		assert_eq!([2.0 / 3.0, 1.0, 1.0, 2.0 / 3.0], q.vertices.map(|v| v.occlusion));
+	}
+
+	#[test]
+	fn test_surroundings_unloaded() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		let corner = IVec3::splat(2 * CHUNK_SIZE as i32 - 1);
+		world.load(IVec3::ZERO, |v| (v == corner).then_some(stone));
+		assert!(world.surroundings(IVec3::ZERO).unwrap().missing.is_empty());
+		// Its positive neighbours are not loaded but it can be meshed anyway
+		assert_eq!(19, world.surroundings(IVec3::ONE).unwrap().missing.len());
+		assert_eq!(3, world.simple(IVec3::ONE).quads.len());
+		assert!(matches!(world.surroundings(IVec3::splat(2)), Err(MeshingError::ChunkUnloaded(_))));
+	}
+
+	#[test]
+	fn test_meshing_mode_command() {
+		let mut models = MapModelResource::new(1);
+		assert_eq!(MeshingMode::Greedy, models.mode);
+		models.command(&["set", "mode", "simple"]).unwrap();
+		assert_eq!(MeshingMode::Simple, models.mode);
+		assert!(models.command(&["set", "mode", "fancy"]).is_err());
+		assert_eq!(MeshingMode::Simple, models.mode);
+	}
+
+	#[test]
+	fn test_lod_floor() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		world.load(IVec3::ZERO, |v| (v.y < 5).then_some(stone));
+
+		// Cells that are half full are kept, so the surface rounds up or down to the cell size
+		for (lod, height) in [(1, 6), (2, 4), (3, 8)] {
+			let data = world.lod(IVec3::ZERO, lod, [false; 6]);
+			assert_eq!(1, data.quads.len(), "lod {lod}");
+			let top = data.quads[0];
+			assert_eq!((UVec3::new(0, height, 0), 0b010, UVec2::splat(32)), (top.position, top.direction, top.extent), "lod {lod}");
+		}
+	}
+
+	#[test]
+	fn test_lod_seams() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		world.load(IVec3::ZERO, |v| (v.y < 5).then_some(stone));
+
+		let side = |data: &ChunkMeshData, direction| data.quads.iter()
+			.find(|q| q.direction == direction)
+			.map(|q| (q.position, q.extent));
+
+		// Closed borders are walled off from their neighbours
+		let data = world.lod(IVec3::ZERO, 0, [true; 6]);
+		let wall = data.quads.iter().filter(|q| q.direction == 0b000).collect::<Vec<_>>();
+		assert!(wall.iter().all(|q| q.position.x == 32));
+		assert_eq!(32 * 5, wall.iter().map(|q| q.area()).sum::<u32>());
+		for (lod, height) in [(1, 6), (2, 4)] {
+			let data = world.lod(IVec3::ZERO, lod, [true; 6]);
+			assert_eq!(6, data.quads.len(), "lod {lod}");
+			assert_eq!(Some((UVec3::new(32, 0, 0), UVec2::new(32, height))), side(&data, 0b000), "lod {lod}");
+			assert_eq!(Some((UVec3::ZERO, UVec2::new(32, height))), side(&data, 0b001), "lod {lod}");
+			assert_eq!(Some((UVec3::ZERO, UVec2::splat(32))), side(&data, 0b011), "lod {lod}");
+			assert_eq!(Some((UVec3::new(0, 0, 32), UVec2::new(32, height))), side(&data, 0b100), "lod {lod}");
+		}
+
+		// Only the closed one
+		let data = world.lod(IVec3::ZERO, 1, [false, true, false, false, false, false]);
+		assert_eq!(2, data.quads.len());
+		assert_eq!(Some((UVec3::ZERO, UVec2::new(32, 6))), side(&data, 0b001));
+	}
+
+	#[test]
+	fn test_chunk_detail() {
+		let near = MapMeshingComponent::new(1, 2).with_lod_radii([2, 4]);
+		assert_eq!(
+			[Some(0), Some(0), Some(1), Some(2), Some(2), None],
+			[0, 1, 2, 3, 4, 5].map(|x| near.lod(IVec3::ZERO, IVec3::new(-x, 0, 1))),
+		);
+
+		let loaders = [(&near, IVec3::ZERO)];
+		let detail = |x| ChunkDetail::of(&loaders, IVec3::new(x, 0, 0));
+		assert_eq!(Some(ChunkDetail { lod: 0, seams: [true, false, false, false, false, false] }), detail(1));
+		assert_eq!(Some(ChunkDetail { lod: 1, seams: [true, true, false, false, false, false] }), detail(2));
+		// Nothing is meshed past the edge
+		assert_eq!(Some(ChunkDetail { lod: 2, seams: [false; 6] }), detail(4));
+		assert_eq!(None, detail(5));
+
+		// The finest detail wins
+		let far = MapMeshingComponent::new(0, 2).with_lod_radii([1]);
+		let loaders = [(&near, IVec3::ZERO), (&far, IVec3::new(4, 0, 0))];
+		assert_eq!(Some(0), ChunkDetail::of(&loaders, IVec3::new(4, 0, 0)).map(|d| d.lod));
+		assert_eq!(Some(ChunkDetail { lod: 1, seams: [true, false, false, false, false, false] }), ChunkDetail::of(&loaders, IVec3::new(3, 0, 0)));
+	}
+
+	#[test]
+	fn test_lod_fewer_quads() {
+		let world = generated_world();
+		let mut last = world.greedy(IVec3::ZERO).quads.len();
+		for lod in 1..=MAX_LOD {
+			let n = world.lod(IVec3::ZERO, lod, [false; 6]).quads.len();
+			assert!(n < last, "lod {lod} has {n} quads, more than {last}");
+			last = n;
+		}
+	}
+
+	#[test]
+	fn test_model_lifecycle() {
+		let mut ecs = eeks::prelude::World::new();
+		ecs.register_component::<TransformComponent>();
+		let chunks = ChunksResource::new();
+		let (a, b) = {
+			let mut chunks = chunks.write();
+			(chunks.load(IVec3::ZERO), chunks.load(IVec3::X))
+		};
+		let material = SlotMap::<MaterialKey, ()>::with_key().insert(());
+		let quad = ChunkQuad { position: UVec3::ZERO, direction: 0b010, extent: UVec2::ONE, material, vertices: Default::default() };
+		let data = || Ok(ChunkMeshData { quads: vec![quad], translucent_quads: vec![quad], ..Default::default() });
+		// The translucent mesh has four vertices with positions, lights, occlusion, uvs, and layers, and six indices
+		// The opaque quad takes a slab of packed quads
+		let bytes = 4 * (12 + 12 + 12 + 4 + 8 + 4) + 6 * 4 + crate::packed::SLAB_BYTES;
+
+		let mut models = MapModelResource::new(8);
+		let mut meshes = MeshResource::default();
+		let mut buffers = BufferResource(BufferManager::new());
+		let blocks = BlockManager::new();
+		ecs.run(|mut entities: EntitiesMut, mut transforms: CompMut<TransformComponent>| {
+			// As if jobs had been started for both
+			for (key, position) in [(a, IVec3::ZERO), (b, IVec3::X)] {
+				models.chunks.insert(key, (position, true, MapModelState::Waiting));
+				models.cur_meshing_jobs += 1;
+			}
+			// One is pruned before its job finishes
+			models.remove(b, &mut meshes, &mut buffers, &mut entities, &mut transforms);
+			models.sender.send((a, IVec3::ZERO, data())).unwrap();
+			models.sender.send((b, IVec3::X, data())).unwrap();
+			models.receive_jobs(&mut meshes, &mut buffers, &mut entities, &mut transforms, &blocks);
+			assert_eq!(0, models.cur_meshing_jobs);
+			assert_eq!(1, models.counters.discarded_jobs);
+			assert!(!models.chunks.contains_key(b));
+			assert_eq!((1, 0, bytes, 1), models.live());
+			assert_eq!(crate::packed::SLAB_BYTES, models.packed.bytes());
+
+			let old = models.chunks[a].2.ref_complete().and_then(|e| Some((e.entity, e.translucent_model?))).unwrap();
+			assert!(models.chunks[a].2.ref_complete().is_some_and(|e| e.model.is_none() && e.packed.is_some()));
+			assert!(transforms.contains(old.0));
+			assert!(meshes.get(old.1).is_some());
+
+			// Remeshing replaces the old model
+			models.chunks[a].1 = true;
+			models.cur_meshing_jobs += 1;
+			models.sender.send((a, IVec3::ZERO, data())).unwrap();
+			models.receive_jobs(&mut meshes, &mut buffers, &mut entities, &mut transforms, &blocks);
+			assert_eq!((1, 0, bytes, 1), models.live());
+			assert_eq!(crate::packed::SLAB_BYTES, models.packed.bytes());
+			assert!(!transforms.contains(old.0));
+			assert!(meshes.get(old.1).is_none());
+
+			models.remove(a, &mut meshes, &mut buffers, &mut entities, &mut transforms);
+			assert_eq!((0, 0, 0, 0), models.live());
+			assert_eq!(0, models.packed.bytes());
+			assert_eq!(
+				"0 models, 0 meshes (0 bytes), 0 buffers, 0 entities, 0 leaked meshes, 0 leaked buffers, 0 leaked entities, 1 discarded jobs",
+				models.command(&["stats"]).unwrap(),
+			);
+		});
+	}
+
+	#[test]
+	fn test_model_batches() {
+		let mut ecs = eeks::prelude::World::new();
+		ecs.register_component::<TransformComponent>();
+		let chunks = ChunksResource::new();
+		let key = chunks.write().load(IVec3::ZERO);
+		let mut materials = SlotMap::<MaterialKey, ()>::with_key();
+		let (m0, m1) = (materials.insert(()), materials.insert(()));
+
+		let mut meshes = MeshResource::default();
+		let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+		meshes.insert(Mesh::new("slab").with_data("positions", &positions).with_vertex_count(3).with_indices(vec![0, 1, 2]));
+		let mut blocks = BlockManager::new();
+		let model = |mesh: &str, material| BlockRenderType::Model { mesh: PathOrLabel::Label(mesh.into()), material, covers: [false; 6] };
+		let slab = block(&mut blocks, "slab", model("slab", m0), false, "");
+		let red_slab = block(&mut blocks, "red slab", model("slab", m1), false, "");
+		let instance = |x, block| ModelInstance { position: UVec3::new(x, 0, 0), block, state: BlockState::default() };
+		// Two slabs share a batch
+		let instances = vec![instance(0, slab), instance(1, red_slab), instance(2, slab)];
+
+		let mut models = MapModelResource::new(8);
+		let mut buffers = BufferResource(BufferManager::new());
+		ecs.run(|mut entities: EntitiesMut, mut transforms: CompMut<TransformComponent>| {
+			models.chunks.insert(key, (IVec3::ZERO, true, MapModelState::Waiting));
+			models.cur_meshing_jobs += 1;
+			models.sender.send((key, IVec3::ZERO, Ok(ChunkMeshData { instances, ..Default::default() }))).unwrap();
+			models.receive_jobs(&mut meshes, &mut buffers, &mut entities, &mut transforms, &blocks);
+			let batches = models.chunks[key].2.ref_complete().unwrap().instances.clone();
+			assert_eq!(vec![m0, m1], batches.iter().map(|b| b.material).collect::<Vec<_>>());
+			// Only the chunk has an entity, each batch has an instance and an indirect buffer
+			// Two and one model matrices, and an indexed draw command each
+			assert_eq!((0, 4, 3 * 64 + 2 * 20, 1), models.live());
+
+			models.remove(key, &mut meshes, &mut buffers, &mut entities, &mut transforms);
+			assert_eq!((0, 0, 0, 0), models.live());
+			assert!(batches.iter().all(|b| buffers.get(b.instances).is_none() && buffers.get(b.indirect).is_none()));
+			// The block mesh is shared, so it is kept
+			assert!(meshes.key_from_label("slab").is_some());
+		});
+	}
+
+	#[test]
+	fn test_translucent_chunk_order() {
+		let mut chunks = vec![IVec3::ZERO, IVec3::X * 2, IVec3::X];
+		let s = CHUNK_SIZE as f32;
+		sort_chunks_back_to_front(&mut chunks, |&p| p, Vec3::new(2.5 * s, 0.5 * s, 0.5 * s));
+		assert_eq!(vec![IVec3::ZERO, IVec3::X, IVec3::X * 2], chunks);
+		// The order follows the camera
+		sort_chunks_back_to_front(&mut chunks, |&p| p, Vec3::new(-0.5 * s, 0.5 * s, 0.5 * s));
+		assert_eq!(vec![IVec3::X * 2, IVec3::X, IVec3::ZERO], chunks);
+	}
+
+	#[test]
+	fn test_translucent_resort() {
+		let mut ecs = eeks::prelude::World::new();
+		ecs.register_component::<TransformComponent>();
+		let chunks = ChunksResource::new();
+		let key = chunks.write().load(IVec3::ZERO);
+		let material = SlotMap::<MaterialKey, ()>::with_key().insert(());
+		let quad = |x| ChunkQuad { position: UVec3::new(x, 0, 0), direction: 0b010, extent: UVec2::ONE, material, vertices: Default::default() };
+		let mut translucent_quads = vec![quad(0), quad(15)];
+		let viewer = Vec3::new(20.0, 0.0, 0.0);
+		// Sorted from beyond the far quad
+		sort_translucent(&mut translucent_quads, IVec3::ZERO, viewer);
+		assert_eq!(vec![quad(0), quad(15)], translucent_quads);
+
+		let mut models = MapModelResource::new(8);
+		let mut meshes = MeshResource::default();
+		let mut buffers = BufferResource(BufferManager::new());
+		let blocks = BlockManager::new();
+		ecs.run(|mut entities: EntitiesMut, mut transforms: CompMut<TransformComponent>| {
+			models.chunks.insert(key, (IVec3::ZERO, true, MapModelState::Waiting));
+			models.cur_meshing_jobs += 1;
+			models.sender.send((key, IVec3::ZERO, Ok(ChunkMeshData { translucent_quads, sorted_from: viewer, ..Default::default() }))).unwrap();
+			models.receive_jobs(&mut meshes, &mut buffers, &mut entities, &mut transforms, &blocks);
+		});
+		let translucent = |models: &MapModelResource| models.chunks[key].2.ref_complete()
+			.map(|e| (e.translucent_model.unwrap(), e.translucent_quads.clone())).unwrap();
+		let (old, _) = translucent(&models);
+
+		// A small step is not enough
+		models.sort_translucent_models(&mut meshes, &blocks, viewer + Vec3::X * 0.5);
+		assert_eq!((old, vec![quad(0), quad(15)]), translucent(&models));
+
+		// Moving to the other side reverses the order and replaces the mesh
+		models.sort_translucent_models(&mut meshes, &blocks, Vec3::new(-4.0, 0.0, 0.0));
+		let (new, quads) = translucent(&models);
+		assert_eq!(vec![quad(15), quad(0)], quads);
+		assert_ne!(old, new);
+		assert!(meshes.get(old).is_none());
+		assert!(meshes.get(new).is_some());
+		assert_eq!(models.counters.meshes_made, models.counters.meshes_freed + 1);
+	}
+
+	#[test]
+	fn test_terrain_edited() {
+		let mut world = World::new();
+		let stone = world.cube("stone", "Opaque");
+		world.load(IVec3::ZERO, |v| (v.y < 4).then_some(stone));
+		let data = world.simple(IVec3::ZERO);
+		let edited = |detail: ChunkDetail, dependencies: &mut [(IVec3, ChunkKey, KGeneration)]| {
+			terrain_edited(&detail, IVec3::ZERO, dependencies, &world.terrain.chunks.read())
+		};
+		let lod = |lod| ChunkDetail { lod, ..Default::default() };
+
+		let mut dependencies = data.terrain_dependencies.clone();
+		assert!(!edited(lod(0), &mut dependencies));
+
+		// Deep within the positive neighbour, so it isn't read
+		world.edit(IVec3::new(CHUNK_SIZE as i32 + 5, 3, 7), None);
+		assert!(!edited(lod(0), &mut dependencies));
+		// The dependency was brought up to date and won't be checked again
+		assert_eq!(None, dependencies.iter().find_map(|&(p, key, gen)| {
+			(p == IVec3::X).then(|| world.terrain.chunks.read()[key].complete_ref().unwrap().changed_since(gen)).flatten()
+		}));
+		// Coarser cells reach further
+		let mut coarse = data.terrain_dependencies.clone();
+		assert!(edited(lod(3), &mut coarse));
+
+		// On the border
+		world.edit(IVec3::new(CHUNK_SIZE as i32, 3, 7), None);
+		assert!(edited(lod(0), &mut dependencies));
+		// Anywhere within the chunk itself
+		let mut dependencies = world.simple(IVec3::ZERO).terrain_dependencies;
+		world.edit(IVec3::new(16, 16, 16), Some(stone));
+		assert!(edited(lod(0), &mut dependencies));
+		// A diagonal neighbour is only read along its edge
+		let mut dependencies = world.simple(IVec3::ZERO).terrain_dependencies;
+		world.edit(IVec3::new(-1, -5, 10), None);
+		assert!(!edited(lod(0), &mut dependencies));
+		world.edit(IVec3::new(-1, -1, 10), None);
+		assert!(edited(lod(0), &mut dependencies));
+	}
+
+	// Moves a world into resources for the systems that an edit passes through, viewed from the middle chunk
+	fn ticking(world: World) -> eeks::prelude::World {
+		let mut ecs = eeks::prelude::World::new();
+		ecs.register_component::<TransformComponent>();
+		ecs.register_component::<MapMeshingComponent>();
+		ecs.insert_resource(world.chunks);
+		ecs.insert_resource(world.terrain);
+		ecs.insert_resource(world.torchlight);
+		ecs.insert_resource(world.sunlight);
+		ecs.insert_resource(BlockResource { blocks: Arc::new(parking_lot::RwLock::new(world.blocks)) });
+		ecs.insert_resource(LiquidsResource::new());
+		ecs.insert_resource(BlockEventResource::default());
+		ecs.insert_resource(MapModelResource::new(8));
+		ecs.insert_resource(MeshResource::default());
+		ecs.insert_resource(BufferResource(BufferManager::new()));
+		let centre = Vec3::splat(CHUNK_SIZE as f32 / 2.0);
+		ecs.spawn()
+			.with(TransformComponent::new().with_position(centre))
+			.with(MapMeshingComponent::new(1, 0))
+			.finish();
+		ecs
+	}
+
+	// Runs each system once, then waits for the meshing jobs that were started as if they took less than a frame
+	fn tick(ecs: &eeks::prelude::World) {
+		ecs.run(terrain_modification_application);
+		ecs.run(torchlight_chunk_init_system);
+		ecs.run(torchlight_update_system);
+		ecs.run(sunlight_update_system);
+		ecs.run(map_modelling_system);
+		let models = ecs.resource_ref::<MapModelResource>();
+		while models.receiver.len() < models.cur_meshing_jobs as usize {
+			std::thread::yield_now();
+		}
+	}
+
+	// Is the model of this chunk made from its current terrain? 
+	fn visible(ecs: &eeks::prelude::World, position: IVec3) -> bool {
+		let key = ecs.resource_ref::<ChunksResource>().read().get_position(position).unwrap();
+		let generation = ecs.resource_ref::<TerrainResource>().chunks.read()[key].complete_ref().unwrap().generation;
+		let models = ecs.resource_ref::<MapModelResource>();
+		models.chunks.get(key).is_some_and(|(_, working, state)| !working && state.ref_complete().is_some_and(|e| {
+			!e.outdated && e.terrain_dependencies.iter().any(|&(_, k, g)| k == key && g == generation)
+		}))
+	}
+
+	// Ticks until a chunk's model is current, giving the number of ticks
+	fn ticks_until_visible(ecs: &eeks::prelude::World, position: IVec3) -> usize {
+		(1..=100).find(|_| {
+			tick(ecs);
+			visible(ecs, position)
+		}).expect("chunk was never remeshed")
+	}
+
+	// Queues an edit as a player would
+	fn queue_edit(ecs: &eeks::prelude::World, voxel: IVec3, set_to: Option<BlockKey>) {
+		let (c, modification) = VoxelModification { position: voxel, set_to, state: BlockState::default(), priority: 0, entity: None, emit_events: false }.as_chunk_relative();
+		ecs.resource_ref::<TerrainResource>().block_mods.write().entry(c).or_default().push(modification);
+	}
+
+	#[test]
+	fn test_edit_latency() {
+		let world = generated_world();
+		let stone = world.blocks.key_by_name(&"stone".to_string()).unwrap();
+		let ecs = ticking(world);
+		ticks_until_visible(&ecs, IVec3::ZERO);
+
+		// The edit is applied, relit, and meshed in one tick, and its model is received in the next
+		for set_to in [Some(stone), None] {
+			queue_edit(&ecs, IVec3::new(8, 15, 8), set_to);
+			assert_eq!(2, ticks_until_visible(&ecs, IVec3::ZERO));
+		}
+	}
+
+	// Time from an edit to having the chunk's new mesh data
+	// This is most of the latency, the rest is waiting for the next tick to upload it
+	#[bench]
+	fn bench_edit_latency(b: &mut Bencher) {
+		let world = generated_world();
+		let stone = world.blocks.key_by_name(&"stone".to_string()).unwrap();
+		let ecs = ticking(world);
+		ticks_until_visible(&ecs, IVec3::ZERO);
+		let mut placed = false;
+		b.iter(|| {
+			placed = !placed;
+			queue_edit(&ecs, IVec3::new(8, 15, 8), placed.then_some(stone));
+			ticks_until_visible(&ecs, IVec3::ZERO)
+		});
+	}
+
+	#[bench]
+	fn bench_simple_meshing(b: &mut Bencher) {
+		let world = generated_world();
+		b.iter(|| world.simple(IVec3::ZERO));
+	}
+
+	#[bench]
+	fn bench_greedy_meshing(b: &mut Bencher) {
+		let world = generated_world();
+		b.iter(|| world.greedy(IVec3::ZERO));
+	}
+
+	#[bench]
+	fn bench_lod_meshing(b: &mut Bencher) {
+		let world = generated_world();
+		b.iter(|| world.lod(IVec3::ZERO, 1, [false; 6]));
+	}
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use chunks::{blocks::{BlockKey, BlockResource}, chunk_of_point, chunks::{ChunkEvent, ChunkKey, ChunksResource}, generation::KGeneration, octree::OctreeScene};
+use eeks::prelude::*;
+use glam::IVec3;
+use pinecore::render::{AbstractRenderTarget, Buffer, BufferKey, BufferResource, MaterialResource, QueueResource, RenderFrame, RRID};
+use pinecore::transform::TransformComponent;
+use slotmap::SecondaryMap;
+use terrain::terrain::TerrainResource;
+use crate::model::MapMeshingComponent;
+
+
+
+// The colours buffer can't be resized, so it has room for this many blocks
+const MAX_BLOCK_COLOURS: usize = 1024;
+
+
+/// Renders chunks around the viewer by ray tracing their octrees.
+/// This is meant for distant chunks, so it is drawn before meshed models.
+#[derive(Debug, Resource)]
+#[sda(commands = true)]
+pub struct OctreeChunksResource {
+	pub enabled: bool,
+	// Chunks along each edge of the grid, should be odd
+	pub extent: u32,
+	// Bytes, the scene is not updated if it would not fit
+	// The buffer is made with this size, so changing it later does nothing
+	pub capacity: u64,
+	// Flattened octrees and the terrain generation that they were made from
+	octrees: SecondaryMap<ChunkKey, (KGeneration, Vec<u32>)>,
+	centre: Option<IVec3>,
+	colour_indices: HashMap<BlockKey, u32>,
+	scene_buffer: Option<BufferKey>,
+	info_buffer: Option<BufferKey>,
+	colours_buffer: Option<BufferKey>,
+}
+impl OctreeChunksResource {
+	pub fn new(extent: u32, capacity: u64) -> Self {
+		Self {
+			enabled: false,
+			extent,
+			capacity,
+			octrees: SecondaryMap::new(),
+			centre: None,
+			colour_indices: HashMap::new(),
+			scene_buffer: None,
+			info_buffer: None,
+			colours_buffer: None,
+		}
+	}
+}
+impl StorageCommandExpose for OctreeChunksResource {
+	// resource OctreeChunksResource set enabled true
+	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
+		match command[0] {
+			"set" => {
+				let v = command.get(2).ok_or_else(|| anyhow::anyhow!("Give a set value"))?;
+				match command[1] {
+					"enabled" => self.enabled = v.parse()?,
+					"extent" => self.extent = v.parse()?,
+					_ => return Err(anyhow::anyhow!("Unknown field")),
+				}
+				// Rebuild the scene
+				self.centre = None;
+				Ok(format!("OctreeChunksResource {} {}", command[1], v))
+			},
+			"stats" => Ok(format!(
+				"{} octrees, {} bytes",
+				self.octrees.len(),
+				self.octrees.values().map(|(_, d)| d.len() * 4).sum::<usize>(),
+			)),
+			_ => Err(anyhow::anyhow!("Unknown command")),
+		}
+	}
+}
+
+
+fn write_buffer(buffers: &mut BufferResource, queue: &QueueResource, key: &mut Option<BufferKey>, name: &str, size: u64, bytes: &[u8]) {
+	let key = *key.get_or_insert_with(|| buffers.insert(Buffer::new(name, size, false, true, false)));
+	buffers.get_mut(key).unwrap().write(queue, 0, bytes);
+}
+
+
+/// Keeps the octree scene buffer up to date with the terrain around the first [MapMeshingComponent].
+pub fn octree_chunks_system(
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	blocks: Res<BlockResource>,
+	loaders: Comp<MapMeshingComponent>,
+	transforms: Comp<TransformComponent>,
+	mut octrees: ResMut<OctreeChunksResource>,
+	mut buffers: ResMut<BufferResource>,
+	queue: Res<QueueResource>,
+) {
+	if !octrees.enabled {
+		return;
+	}
+	let Some(centre) = (&loaders, &transforms).iter().map(|(_, t)| chunk_of_point(t.translation)).next() else {
+		return;
+	};
+	let octrees = &mut *octrees;
+	let chunks = chunks.read();
+
+	let mut changed = octrees.centre != Some(centre);
+	octrees.centre = Some(centre);
+
+	{ // Block colours, which leaves are indices into
+		let blocks = blocks.read();
+		if octrees.colour_indices.len() != blocks.blocks.len() {
+			if blocks.blocks.len() > MAX_BLOCK_COLOURS {
+				warn!("Only {MAX_BLOCK_COLOURS} blocks can be ray traced, there are {}", blocks.blocks.len());
+			}
+			octrees.colour_indices = blocks.blocks.keys()
+				.take(MAX_BLOCK_COLOURS)
+				.enumerate()
+				.map(|(i, k)| (k, i as u32))
+				.collect();
+			let colours = blocks.blocks.values()
+				.take(MAX_BLOCK_COLOURS)
+				.flat_map(|e| {
+					let [r, g, b, a] = e.colour().map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u32);
+					((r << 24) | (g << 16) | (b << 8) | a).to_ne_bytes()
+				})
+				.collect::<Vec<_>>();
+			write_buffer(&mut buffers, &queue, &mut octrees.colours_buffer, "block colours buffer", (MAX_BLOCK_COLOURS * 4) as u64, &colours);
+			// Their leaves are now wrong
+			octrees.octrees.clear();
+			changed = true;
+		}
+	}
+
+	for event in chunks.events.iter() {
+		if let &ChunkEvent::Unloaded(key, _) = event {
+			changed |= octrees.octrees.remove(key).is_some();
+		}
+	}
+
+	{ // Flatten chunks whose terrain has changed
+		let h = (octrees.extent / 2) as i32;
+		let terrain_chunks = terrain.chunks.read();
+		for (key, &position) in chunks.chunks.iter() {
+			if (position - centre).abs().cmpgt(IVec3::splat(h)).any() {
+				continue
+			}
+			let Some(chunk) = terrain_chunks.get(key).and_then(|t| t.complete_ref()) else {
+				continue
+			};
+			if octrees.octrees.get(key).is_some_and(|(g, _)| *g == chunk.generation) {
+				continue
+			}
+			let indices = &octrees.colour_indices;
+			let flattened = chunk.to_octree().flatten(|k| indices.get(k).copied().unwrap_or(0));
+			octrees.octrees.insert(key, (chunk.generation, flattened));
+			changed = true;
+		}
+	}
+
+	if !changed {
+		return;
+	}
+	let mut scene = OctreeScene::new(octrees.extent);
+	for (key, (_, flattened)) in octrees.octrees.iter() {
+		if let Some(&position) = chunks.chunks.get(key) {
+			scene.insert(position - centre, flattened);
+		}
+	}
+	if scene.size() as u64 > octrees.capacity {
+		warn!("Octree scene needs {} bytes but only has {}", scene.size(), octrees.capacity);
+		return;
+	}
+	trace!("Octree scene is {} bytes", scene.size());
+	write_buffer(&mut buffers, &queue, &mut octrees.scene_buffer, "voxel scene data", octrees.capacity, &scene.to_bytes());
+	write_buffer(&mut buffers, &queue, &mut octrees.info_buffer, "voxel scene uniform", 16, &scene.info().to_bytes());
+}
+
+
+pub fn octree_chunks_rendering_system(
+	octrees: Res<OctreeChunksResource>,
+	mut materials: ResMut<MaterialResource>,
+	mut input: ResMut<RenderFrame>,
+) {
+	if !octrees.enabled || octrees.scene_buffer.is_none() {
+		return;
+	}
+	let material = materials.read("resources/materials/octree_chunks.ron");
+	input.stage("octree_chunks")
+		.run_after("skybox")
+		.run_before("models")
+		.target(AbstractRenderTarget::new()
+			.with_colour(RRID::context("albedo"), None))
+		.pass(material, Entity::default());
+}
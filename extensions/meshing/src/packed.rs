@@ -0,0 +1,300 @@
+//! Opaque chunk quads are packed into one big buffer so that every chunk can be drawn with a few indirect draws.
+//! The shader (resources/shaders/packed_chunks) makes vertices from the quads using the vertex index.
+
+use std::ops::Range;
+use glam::{IVec3, IVec4, UVec2, UVec3, Vec3};
+use krender::{allocator::{BufferAllocator, SlabAllocationKey, SlabBufferAllocator}, prelude::Buffer, BufferKey, MaterialKey};
+use pinecore::render::BufferResource;
+use crate::{model::ChunkQuad, shading::{flip_quad, VertexShade}};
+
+
+
+/// Bytes in a [PackedQuad].
+pub const QUAD_BYTES: u64 = 24;
+// Quads in each slab, most chunks need only a few slabs
+const SLAB_QUADS: u64 = 64;
+pub(crate) const SLAB_BYTES: u64 = SLAB_QUADS * QUAD_BYTES;
+// 64MiB of quads
+const SLAB_COUNT: u64 = (64 << 20) / SLAB_BYTES;
+/// Chunk slots are 16 bits.
+pub const MAX_CHUNKS: u32 = 1 << 16;
+/// Texture layers are 15 bits.
+pub const MAX_LAYERS: u32 = 1 << 15;
+// Draw commands in the indirect buffer, one for each chunk
+const MAX_COMMANDS: u64 = 1 << 16;
+
+
+/// A quad in six words.
+///
+/// - Position 3x6 bits, because closed seams put faces at 32
+/// - Direction 3 bits
+/// - Extent minus one 2x5 bits
+/// - Chunk slot 16 bits, texture layer 15 bits, flipped 1 bit
+/// - For each vertex, torchlight 3x5 bits, sunlight 3x5 bits, and occlusion 2 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackedQuad(pub [u32; 6]);
+impl PackedQuad {
+	pub fn new(quad: &ChunkQuad, slot: u32, layer: u32) -> Self {
+		assert!(quad.position.max_element() <= 32);
+		assert!(quad.extent.min_element() >= 1 && quad.extent.max_element() <= 32);
+		assert!(slot < MAX_CHUNKS && layer < MAX_LAYERS);
+		let [x, y, z] = quad.position.to_array();
+		let [u, v] = (quad.extent - UVec2::ONE).to_array();
+		let w0 = x | y << 6 | z << 12 | quad.direction << 18 | u << 21 | v << 26;
+		let w1 = slot | layer << 16 | (flip_quad(&quad.vertices) as u32) << 31;
+		let [a, b, c, d] = quad.vertices.map(|v| Self::pack_shade(&v));
+		Self([w0, w1, a, b, c, d])
+	}
+
+	fn pack_shade(shade: &VertexShade) -> u32 {
+		let channels = |c: Vec3| {
+			let [r, g, b] = (c.clamp(Vec3::ZERO, Vec3::ONE) * 31.0).round().as_uvec3().to_array();
+			r | g << 5 | b << 10
+		};
+		let occlusion = (shade.occlusion.clamp(0.0, 1.0) * 3.0).round() as u32;
+		channels(shade.torchlight) | channels(shade.sunlight) << 15 | occlusion << 30
+	}
+
+	pub fn position(&self) -> UVec3 {
+		let w = self.0[0];
+		UVec3::new(w & 63, w >> 6 & 63, w >> 12 & 63)
+	}
+
+	pub fn direction(&self) -> u32 {
+		self.0[0] >> 18 & 7
+	}
+
+	pub fn extent(&self) -> UVec2 {
+		let w = self.0[0];
+		UVec2::new((w >> 21 & 31) + 1, (w >> 26 & 31) + 1)
+	}
+
+	pub fn slot(&self) -> u32 {
+		self.0[1] & 0xFFFF
+	}
+
+	pub fn layer(&self) -> u32 {
+		self.0[1] >> 16 & 0x7FFF
+	}
+
+	pub fn flipped(&self) -> bool {
+		self.0[1] >> 31 != 0
+	}
+
+	/// The shading of a vertex after it has lost precision.
+	pub fn vertex(&self, i: usize) -> VertexShade {
+		let w = self.0[2 + i];
+		let channels = |w: u32| Vec3::new((w & 31) as f32, (w >> 5 & 31) as f32, (w >> 10 & 31) as f32) / 31.0;
+		VertexShade {
+			torchlight: channels(w),
+			sunlight: channels(w >> 15),
+			occlusion: (w >> 30) as f32 / 3.0,
+		}
+	}
+}
+
+
+/// Where a chunk's quads are in the buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackedAllocation {
+	pub allocation: SlabAllocationKey,
+	pub slot: u32,
+	// First quad and quad count
+	pub range: (u32, u32),
+}
+
+
+/// The buffers that the packed quads are drawn from.
+#[derive(Debug, Clone, Copy)]
+struct PackedBuffers {
+	quads: BufferKey,
+	origins: BufferKey,
+	indirect: BufferKey,
+}
+
+
+/// Tracks the contents of the packed quad buffer.
+/// Changes are kept until they can be written to the GPU.
+#[derive(Debug)]
+pub struct PackedChunkQuads {
+	allocator: SlabBufferAllocator,
+	// Slots hold the positions of chunks because quad positions are chunk-relative
+	free_slots: Vec<u32>,
+	next_slot: u32,
+	// Byte offset and data
+	quad_writes: Vec<(u64, Vec<u8>)>,
+	origin_writes: Vec<(u64, Vec<u8>)>,
+	// Allocated bytes
+	bytes: u64,
+	buffers: Option<PackedBuffers>,
+}
+impl Default for PackedChunkQuads {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl PackedChunkQuads {
+	pub fn new() -> Self {
+		Self {
+			allocator: SlabBufferAllocator::new(SLAB_BYTES as u32, SLAB_COUNT as u32, true),
+			free_slots: Vec::new(),
+			next_slot: 0,
+			quad_writes: Vec::new(),
+			origin_writes: Vec::new(),
+			bytes: 0,
+			buffers: None,
+		}
+	}
+
+	/// Bytes that are allocated to chunks.
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+
+	/// Packs the quads of a chunk, or gives None if there is no space for them.
+	/// Each quad's material is replaced with the layer of its texture in the block texture array.
+	pub fn insert(&mut self, position: IVec3, quads: &[ChunkQuad], layer: impl Fn(MaterialKey) -> u32) -> Option<PackedAllocation> {
+		if quads.is_empty() {
+			return None;
+		}
+		let slot = self.free_slots.pop().or_else(|| {
+			(self.next_slot < MAX_CHUNKS).then(|| {
+				self.next_slot += 1;
+				self.next_slot - 1
+			})
+		})?;
+		let Some(allocation) = self.allocator.alloc(quads.len() as u64 * QUAD_BYTES) else {
+			self.free_slots.push(slot);
+			return None;
+		};
+
+		let first = (allocation.start / QUAD_BYTES) as u32;
+		let words = quads.iter()
+			.flat_map(|q| PackedQuad::new(q, slot, layer(q.material)).0)
+			.collect::<Vec<_>>();
+		self.quad_writes.push((allocation.start, bytemuck::cast_slice(&words).to_vec()));
+		let origin = IVec4::new(position.x, position.y, position.z, 0);
+		self.origin_writes.push((slot as u64 * 16, bytemuck::cast_slice(&origin.to_array()).to_vec()));
+		self.bytes += allocation.size();
+
+		Some(PackedAllocation { allocation, slot, range: (first, quads.len() as u32), })
+	}
+
+	/// Frees the space of a chunk, its data is left to be overwritten.
+	pub fn remove(&mut self, allocation: PackedAllocation) {
+		self.bytes -= allocation.allocation.size();
+		self.allocator.free(allocation.allocation);
+		self.free_slots.push(allocation.slot);
+	}
+
+	/// Makes the buffers if they don't exist and writes any changes.
+	/// Gives the indirect buffer.
+	pub(crate) fn flush(&mut self, buffers: &mut BufferResource, queue: &wgpu::Queue) -> BufferKey {
+		let b = *self.buffers.get_or_insert_with(|| {
+			info!("Initialize packed chunk quad buffers");
+			PackedBuffers {
+				quads: buffers.insert(Buffer::new("chunk quads", SLAB_COUNT * SLAB_BYTES, false, true, true)),
+				origins: buffers.insert(Buffer::new("chunk origins", MAX_CHUNKS as u64 * 16, false, true, true)),
+				indirect: buffers.insert(Buffer::new("chunk quads indirect", MAX_COMMANDS * 16, false, true, false)
+					.with_usages(wgpu::BufferUsages::INDIRECT)),
+			}
+		});
+		let quads = buffers.get_mut(b.quads).unwrap();
+		for (offset, data) in self.quad_writes.drain(..) {
+			quads.write(queue, offset, &data);
+		}
+		let origins = buffers.get_mut(b.origins).unwrap();
+		for (offset, data) in self.origin_writes.drain(..) {
+			origins.write(queue, offset, &data);
+		}
+		b.indirect
+	}
+
+	/// Writes a draw command for each of these chunks.
+	/// Gives the range of commands to draw.
+	pub(crate) fn write_commands<'a>(
+		&self,
+		buffers: &mut BufferResource,
+		queue: &wgpu::Queue,
+		allocations: impl Iterator<Item = &'a PackedAllocation>,
+	) -> Range<u32> {
+		// Vertex count, instance count, first vertex, first instance
+		let mut commands = allocations
+			.map(|a| [a.range.1 * 6, 1, a.range.0 * 6, 0])
+			.collect::<Vec<_>>();
+		if commands.len() > MAX_COMMANDS as usize {
+			warn!("Too many chunk draw commands, {} are skipped", commands.len() - MAX_COMMANDS as usize);
+			commands.truncate(MAX_COMMANDS as usize);
+		}
+
+		if let Some(b) = self.buffers {
+			buffers.get_mut(b.indirect).unwrap().write(queue, 0, bytemuck::cast_slice(&commands));
+		}
+		0..commands.len() as u32
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slotmap::SlotMap;
+
+	#[test]
+	fn test_pack_roundtrip() {
+		let material = SlotMap::<MaterialKey, ()>::with_key().insert(());
+		let shade = |t: f32, o: f32| VertexShade { torchlight: Vec3::new(t, 0.0, 1.0), sunlight: Vec3::splat(0.5), occlusion: o };
+		let quad = ChunkQuad {
+			position: UVec3::new(32, 0, 17),
+			direction: 0b000,
+			extent: UVec2::new(32, 3),
+			material,
+			vertices: [shade(0.0, 0.0), shade(1.0, 1.0), shade(0.0, 0.0), shade(1.0, 1.0)],
+		};
+		let packed = PackedQuad::new(&quad, MAX_CHUNKS - 1, 7);
+		assert_eq!(quad.position, packed.position());
+		assert_eq!(quad.direction, packed.direction());
+		assert_eq!(quad.extent, packed.extent());
+		assert_eq!(MAX_CHUNKS - 1, packed.slot());
+		assert_eq!(7, packed.layer());
+		assert_eq!(flip_quad(&quad.vertices), packed.flipped());
+		for i in 0..4 {
+			let v = packed.vertex(i);
+			assert!(v.torchlight.abs_diff_eq(quad.vertices[i].torchlight, 1.0 / 62.0));
+			assert!(v.sunlight.abs_diff_eq(quad.vertices[i].sunlight, 1.0 / 62.0));
+			assert_eq!(quad.vertices[i].occlusion, v.occlusion);
+		}
+	}
+
+	#[test]
+	fn test_pack_chunks() {
+		let mut keys = SlotMap::<MaterialKey, ()>::with_key();
+		let (a, b) = (keys.insert(()), keys.insert(()));
+		let layer = |material| if material == a { 3 } else { 5 };
+		let quad = |material| ChunkQuad { position: UVec3::ZERO, direction: 0b010, extent: UVec2::ONE, material, vertices: Default::default() };
+		let mut packed = PackedChunkQuads::new();
+		assert_eq!(None, packed.insert(IVec3::ZERO, &[], layer));
+
+		// Quads of every material are together, each with the layer of its texture
+		let first = packed.insert(IVec3::ZERO, &[quad(b), quad(a), quad(b)], layer).unwrap();
+		assert_eq!((0, 3), first.range);
+		let words = &packed.quad_writes[0].1;
+		let layers = words.chunks(QUAD_BYTES as usize)
+			.map(|q| PackedQuad(bytemuck::pod_read_unaligned::<[u32; 6]>(q)).layer())
+			.collect::<Vec<_>>();
+		assert_eq!(vec![5, 3, 5], layers);
+		// Big chunks take more slabs
+		let second = packed.insert(IVec3::X, &vec![quad(a); SLAB_QUADS as usize + 1], layer).unwrap();
+		assert_ne!(first.slot, second.slot);
+		assert_eq!((SLAB_QUADS as u32, SLAB_QUADS as u32 + 1), second.range);
+		assert_eq!(SLAB_BYTES * 3, packed.bytes());
+
+		// Freed space and slots are reused
+		let slot = first.slot;
+		packed.remove(first);
+		let third = packed.insert(IVec3::Y, &[quad(a)], layer).unwrap();
+		assert_eq!(slot, third.slot);
+		assert_eq!((0, 1), third.range);
+		assert_eq!(SLAB_BYTES * 3, packed.bytes());
+	}
+}
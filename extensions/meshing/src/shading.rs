@@ -0,0 +1,211 @@
+use std::sync::Arc;
+use chunks::{blocks::BlockManager, chunks::{ChunkKey, ChunksResource}, generation::KGeneration, CHUNK_SIZE};
+use glam::{IVec3, UVec3, Vec3};
+use light::{light::{LightChunk, LightRGBA}, sun::{self, SunlightChunk}};
+use parking_lot::RwLock;
+use slotmap::SecondaryMap;
+use smallvec::SmallVec;
+use terrain::terrain::{TerrainChunk, TerrainContents, TerrainEntry};
+use crate::model::MeshingError;
+
+
+
+/// How a vertex of a chunk quad is lit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VertexShade {
+	// Each channel is in [0, 1]
+	pub torchlight: Vec3,
+	pub sunlight: Vec3,
+	// 0 if the vertex is in a corner, 1 if nothing is around it
+	pub occlusion: f32,
+}
+
+
+/// Should a quad be split along the diagonal from its second to its fourth vertex?
+/// Splitting between the less occluded pair keeps a dark corner from spreading across the whole quad.
+pub fn flip_quad(vertices: &[VertexShade; 4]) -> bool {
+	vertices[0].occlusion + vertices[2].occlusion < vertices[1].occlusion + vertices[3].occlusion
+}
+
+
+/// A copy of the terrain and light in and around a chunk.
+/// Faces on the edge of a chunk are shaded using their neighbours.
+/// Neighbours that are not loaded are treated as empty and dark.
+pub struct ChunkSurroundings {
+	pub position: IVec3,
+	// By offset, x then y then z from -1 to 1
+	terrain: Vec<Arc<TerrainChunk>>,
+	// Voxels from -1 to CHUNK_SIZE on each axis
+	occluding: Vec<bool>,
+	torchlight: Vec<LightRGBA>,
+	sunlight: Vec<[u16; 3]>,
+	pub terrain_dependencies: SmallVec<[(IVec3, ChunkKey, KGeneration); 4]>,
+	// Chunks without light are treated as dark
+	pub torchlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	pub sunlight_dependencies: Vec<(IVec3, ChunkKey, KGeneration)>,
+	// Neighbours whose terrain was not complete, which should be meshed again once they are
+	pub missing: Vec<IVec3>,
+}
+impl ChunkSurroundings {
+	const EXTENT: i32 = CHUNK_SIZE as i32 + 2;
+
+	pub fn new(
+		blocks: &BlockManager,
+		chunks: &ChunksResource,
+		terrain_chunks: &Arc<RwLock<SecondaryMap<ChunkKey, TerrainEntry>>>,
+		torchlight_chunks: &Arc<RwLock<SecondaryMap<ChunkKey, LightChunk>>>,
+		sunlight_chunks: &Arc<RwLock<SecondaryMap<ChunkKey, SunlightChunk>>>,
+		position: IVec3,
+	) -> Result<Self, MeshingError> {
+		let offsets = (-1..=1).flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| IVec3::new(x, y, z))));
+
+		let mut keys = Vec::with_capacity(27);
+		let mut terrain = Vec::with_capacity(27);
+		let mut terrain_dependencies = SmallVec::new();
+		let mut missing = Vec::new();
+		let empty = Arc::new(TerrainChunk::new(TerrainContents::new()));
+		{
+			let chunks = chunks.read();
+			let terrain_chunks = terrain_chunks.read();
+			for offset in offsets {
+				let p = position + offset;
+				let loaded = chunks.get_position(p).and_then(|key| terrain_chunks.get(key)
+					.and_then(|e| e.complete_ref())
+					.map(|chunk| (key, chunk.clone())));
+				match loaded {
+					Some((key, chunk)) => {
+						terrain_dependencies.push((p, key, chunk.generation));
+						keys.push((p, Some(key)));
+						terrain.push(chunk);
+					},
+					// Only the chunk itself is needed
+					None if offset == IVec3::ZERO => return Err(MeshingError::ChunkUnloaded(p)),
+					None => {
+						missing.push(p);
+						keys.push((p, None));
+						terrain.push(empty.clone());
+					},
+				}
+			}
+		}
+
+		let torchlight_chunks = torchlight_chunks.read();
+		let sunlight_chunks = sunlight_chunks.read();
+		let torch = keys.iter().map(|&(_, key)| key.and_then(|key| torchlight_chunks.get(key))).collect::<Vec<_>>();
+		let sun = keys.iter().map(|&(_, key)| key.and_then(|key| sunlight_chunks.get(key))).collect::<Vec<_>>();
+		let torchlight_dependencies = keys.iter().zip(torch.iter())
+			.filter_map(|(&(p, key), c)| Some((p, key?, c.as_ref()?.generation)))
+			.collect();
+		let sunlight_dependencies = keys.iter().zip(sun.iter())
+			.filter_map(|(&(p, key), c)| Some((p, key?, c.as_ref()?.generation)))
+			.collect();
+
+		let volume = (Self::EXTENT * Self::EXTENT * Self::EXTENT) as usize;
+		let mut occluding = vec![false; volume];
+		let mut torchlight = vec![LightRGBA::ZERO; volume];
+		let mut sunlight = vec![[0; 3]; volume];
+		let edge = -1..=CHUNK_SIZE as i32;
+		for x in edge.clone() {
+			for y in edge.clone() {
+				for z in edge.clone() {
+					let v = IVec3::new(x, y, z);
+					let c = Self::chunk_index(v.div_euclid(IVec3::splat(CHUNK_SIZE as i32)));
+					let p = v.rem_euclid(IVec3::splat(CHUNK_SIZE as i32)).as_uvec3();
+					let i = Self::index(v);
+					occluding[i] = terrain[c].get(p)
+						.and_then(|b| blocks.get(b))
+						.is_some_and(|e| e.covering);
+					torchlight[i] = torch[c].map_or(LightRGBA::ZERO, |l| l.get(p).copied().into());
+					sunlight[i] = sun[c].map_or([0; 3], |l| sun::unpack(l.get(p)));
+				}
+			}
+		}
+
+		Ok(Self {
+			position, terrain, occluding, torchlight, sunlight,
+			terrain_dependencies, torchlight_dependencies, sunlight_dependencies, missing,
+		})
+	}
+
+	fn chunk_index(offset: IVec3) -> usize {
+		let [x, y, z] = (offset + IVec3::ONE).to_array();
+		(x * 9 + y * 3 + z) as usize
+	}
+
+	fn index(voxel: IVec3) -> usize {
+		let [x, y, z] = (voxel + IVec3::ONE).to_array();
+		(x * Self::EXTENT * Self::EXTENT + y * Self::EXTENT + z) as usize
+	}
+
+	/// The terrain of this chunk or one of its neighbours.
+	pub fn chunk(&self, offset: IVec3) -> &TerrainChunk {
+		&self.terrain[Self::chunk_index(offset)]
+	}
+
+	/// Shades the vertices of a face that looks into this (chunk-relative) voxel.
+	/// The face is seen along u and v, and vertices are given in the order
+	/// (0, 0), (1, 0), (1, 1), (0, 1) to match the quad positions.
+	pub fn shade(&self, front: IVec3, u: IVec3, v: IVec3) -> [VertexShade; 4] {
+		let occludes = |voxel: IVec3| self.occluding[Self::index(voxel)];
+		[(-1, -1), (1, -1), (1, 1), (-1, 1)].map(|(su, sv)| {
+			let side_u = front + u * su;
+			let side_v = front + v * sv;
+			let corner = side_u + v * sv;
+			let (a, b) = (occludes(side_u), occludes(side_v));
+			// Light can't reach the corner past both sides
+			let c = (a && b) || occludes(corner);
+			let level = if a && b { 0 } else { 3 - a as u32 - b as u32 - c as u32 };
+
+			// Average over what light can be in
+			let mut torchlight = Vec3::ZERO;
+			let mut sunlight = Vec3::ZERO;
+			let mut n = 0.0;
+			for (voxel, occluded) in [(front, false), (side_u, a), (side_v, b), (corner, c)] {
+				if !occluded {
+					let l = self.torchlight[Self::index(voxel)];
+					torchlight += Vec3::new(l.r as f32, l.g as f32, l.b as f32);
+					sunlight += Vec3::from_array(self.sunlight[Self::index(voxel)].map(|c| c as f32));
+					n += 1.0;
+				}
+			}
+
+			VertexShade {
+				torchlight: torchlight / (n * 15.0),
+				sunlight: sunlight / (n * 15.0),
+				occlusion: level as f32 / 3.0,
+			}
+		})
+	}
+
+	/// Evenly shades a face that covers `size` voxels along u and v.
+	/// The brightest of the voxels in front of it is used so that faces
+	/// lying partly against terrain aren't darkened, and nothing is occluded.
+	pub fn shade_coarse(&self, front: IVec3, u: IVec3, v: IVec3, size: i32) -> VertexShade {
+		let mut torchlight = Vec3::ZERO;
+		let mut sunlight = Vec3::ZERO;
+		for du in 0..size {
+			for dv in 0..size {
+				let i = Self::index(front + u * du + v * dv);
+				let l = self.torchlight[i];
+				torchlight = torchlight.max(Vec3::new(l.r as f32, l.g as f32, l.b as f32));
+				sunlight = sunlight.max(Vec3::from_array(self.sunlight[i].map(|c| c as f32)));
+			}
+		}
+		VertexShade {
+			torchlight: torchlight / 15.0,
+			sunlight: sunlight / 15.0,
+			occlusion: 1.0,
+		}
+	}
+}
+
+
+/// The chunk-relative voxel that a face looks into.
+pub fn front_voxel(position: UVec3, direction: u32) -> IVec3 {
+	let axis = (direction >> 1) as usize;
+	if direction & 0b001 == 0 {
+		position.as_ivec3()
+	} else {
+		position.as_ivec3() - IVec3::AXES[axis]
+	}
+}
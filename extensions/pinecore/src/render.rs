@@ -161,6 +161,17 @@ impl CameraComponent {
 		}
 	}
 
+	pub fn projection(&self, aspect_ratio: f32) -> Mat4 {
+		// opengl_wgpu_matrix * 
+		Mat4::perspective_lh(self.fovy, aspect_ratio, self.near, self.far)
+	}
+
+	/// Projection * view for a camera at this transform. 
+	pub fn view_projection(&self, transform: &TransformComponent, aspect_ratio: f32) -> Mat4 {
+		let view = Mat4::from_rotation_translation(transform.rotation, transform.translation).inverse();
+		self.projection(aspect_ratio) * view
+	}
+
 	fn near_from_fovy_degrees(fovy: f32) -> f32 {
 		1.0 / (fovy.to_radians() / 2.0).tan()
 	}
@@ -172,6 +183,15 @@ impl CameraComponent {
 }
 
 
+/// The aspect ratio of a context's output texture. 
+pub fn context_aspect_ratio(context: &RenderContext, textures: &TextureManager) -> Option<f32> {
+	let size = context.textures.get("output_texture")
+		.and_then(|&k| textures.get(k))
+		.map(|t| t.size)?;
+	Some(size.width as f32 / size.height as f32)
+}
+
+
 /// Writes camera buffer for the active context. 
 pub fn context_camera_system(
 	frame: Res<RenderFrame>,
@@ -214,17 +234,9 @@ pub fn context_camera_system(
 		let c = cameras.get(entity).unwrap();
 		let t = transforms.get(entity).cloned().unwrap_or_default();
 		
-		let aspect_ratio = {
-			let size = context.textures.get("output_texture")
-				.cloned()
-				.and_then(|k| textures.get(k))
-				.and_then(|t| Some(t.size))
-				.unwrap();
-			size.width as f32 / size.height as f32
-		};
+		let aspect_ratio = context_aspect_ratio(context, &textures).unwrap();
 
-		// opengl_wgpu_matrix * 
-		let projection = Mat4::perspective_lh(c.fovy, aspect_ratio, c.near, c.far);
+		let projection = c.projection(aspect_ratio);
 		let view = Mat4::from_rotation_translation(t.rotation, t.translation).inverse();
 		let uniform = CameraUniformData {
 			near: c.near,
@@ -0,0 +1,250 @@
+use std::{ffi::OsStr, path::Path};
+use chunks::blocks::{BlockKey, BlockManager};
+use splines::Spline;
+use crate::generator::GenerationError;
+
+
+
+/// A biome as it is described on disk.
+///
+/// Biomes are chosen by their distance to a column's climate.
+/// The climate is a point in (temperature, humidity) space, both of which are in [0.0, 1.0].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BiomeSpecification {
+	pub name: String,
+	pub temperature: f32,
+	pub humidity: f32,
+	// Block names, these are looked up when generation begins
+	pub surface: String,
+	pub subsurface: String,
+	pub subsurface_depth: u32,
+	// Maps raw height noise -> intended world terrain height
+	pub height_spline: Spline<f32, f32>,
+	// Structures that may be placed in this biome, by name
+	// There is no structure pass yet, but we want the biome files to be ready for it
+	#[serde(default)]
+	pub structures: Vec<String>,
+}
+impl BiomeSpecification {
+	pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+		let b = std::fs::read(path.as_ref())?;
+		let s: Self = ron::de::from_bytes(b.as_slice())?;
+		s.check_height_spline()?;
+		Ok(s)
+	}
+
+	// Generation samples the height spline everywhere, so it must not fail
+	// Sampling between keys is what can fail, at the keys and in the middle of each span is enough to find it
+	fn check_height_spline(&self) -> anyhow::Result<()> {
+		let keys = self.height_spline.keys();
+		anyhow::ensure!(!keys.is_empty(), "biome '{}' has no height spline keys", self.name);
+		let samples = keys.iter().map(|k| k.t)
+			.chain(keys.windows(2).map(|w| (w[0].t + w[1].t) / 2.0));
+		for t in samples {
+			anyhow::ensure!(self.height_spline.clamped_sample(t).is_some(), "biome '{}' height spline can't be sampled at {}", self.name, t);
+		}
+		Ok(())
+	}
+
+	fn climate_distance(&self, temperature: f32, humidity: f32) -> f32 {
+		((self.temperature - temperature).powi(2) + (self.humidity - humidity).powi(2)).sqrt()
+	}
+}
+
+
+/// The block keys for a biome.
+/// These are indexed in the same order as the biomes of a [BiomeManager].
+#[derive(Debug, Clone, Copy)]
+pub struct BiomeBlocks {
+	pub surface: BlockKey,
+	pub subsurface: BlockKey,
+	pub subsurface_depth: u32,
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct BiomeManager {
+	pub biomes: Vec<BiomeSpecification>,
+	// Biomes within this climate distance of the closest biome will have their
+	// height splines blended together
+	pub blend_distance: f32,
+}
+impl BiomeManager {
+	pub fn new(blend_distance: f32) -> Self {
+		Self {
+			biomes: Vec::new(),
+			blend_distance,
+		}
+	}
+
+	pub fn read_directory(directory: impl AsRef<Path>, blend_distance: f32) -> anyhow::Result<Self> {
+		let directory = directory.as_ref().canonicalize()?;
+		anyhow::ensure!(directory.is_dir(), "{:?} is not a directory", directory);
+
+		// Sorted so that biome indices do not depend on the file system
+		let mut files = directory
+			.read_dir()?
+			.map(|f| f.map(|f| f.path()))
+			.collect::<Result<Vec<_>, _>>()?;
+		files.retain(|p| p.extension() == Some(OsStr::new("ron")));
+		files.sort();
+
+		let mut s = Self::new(blend_distance);
+		for file in files {
+			let biome = BiomeSpecification::read(&file)?;
+			trace!("Read biome '{}' from {:?}", biome.name, file);
+			s.biomes.push(biome);
+		}
+		Ok(s)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.biomes.is_empty()
+	}
+
+	pub fn get(&self, index: usize) -> Option<&BiomeSpecification> {
+		self.biomes.get(index)
+	}
+
+	/// Finds the index of the biome closest to this climate.
+	pub fn closest(&self, temperature: f32, humidity: f32) -> Option<usize> {
+		self.biomes.iter()
+			.map(|b| b.climate_distance(temperature, humidity))
+			.enumerate()
+			.reduce(|a, v| if v.1 < a.1 { v } else { a })
+			.map(|(i, _)| i)
+	}
+
+	/// Gives (biome index, weight) pairs for this climate.
+	/// Weights sum to 1.
+	/// The closest biome is always included, others are included if they are
+	/// within [Self::blend_distance] of being the closest.
+	pub fn weights(&self, temperature: f32, humidity: f32) -> Vec<(usize, f32)> {
+		let distances = self.biomes.iter()
+			.map(|b| b.climate_distance(temperature, humidity))
+			.collect::<Vec<_>>();
+		let Some(closest) = distances.iter().copied().reduce(f32::min) else {
+			return Vec::new();
+		};
+
+		let mut weights = distances.iter().copied().enumerate()
+			.map(|(i, d)| (i, if self.blend_distance > 0.0 {
+				(1.0 - (d - closest) / self.blend_distance).max(0.0)
+			} else if d == closest {
+				1.0
+			} else {
+				0.0
+			}))
+			.filter(|&(_, w)| w > 0.0)
+			.collect::<Vec<_>>();
+
+		let total = weights.iter().map(|&(_, w)| w).sum::<f32>();
+		weights.iter_mut().for_each(|(_, w)| *w /= total);
+		weights
+	}
+
+	/// Samples the height splines of every nearby biome and blends the results.
+	pub fn height(&self, temperature: f32, humidity: f32, height_noise: f32) -> Option<f32> {
+		let weights = self.weights(temperature, humidity);
+		if weights.is_empty() {
+			return None;
+		}
+		Some(weights.into_iter()
+			.map(|(i, w)| self.biomes[i].height_spline.clamped_sample(height_noise).unwrap() * w)
+			.sum())
+	}
+
+	/// Looks up the blocks used by each biome.
+	pub fn block_keys(&self, blocks: &BlockManager) -> Result<Vec<BiomeBlocks>, GenerationError> {
+		let key = |name: &String| blocks.key_by_name(name)
+			.ok_or_else(|| GenerationError::BlockNotFoundError(name.clone()));
+		self.biomes.iter().map(|b| Ok(BiomeBlocks {
+			surface: key(&b.surface)?,
+			subsurface: key(&b.subsurface)?,
+			subsurface_depth: b.subsurface_depth,
+		})).collect()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use splines::{Interpolation, Key};
+
+	fn flat_biome(name: &str, temperature: f32, humidity: f32, height: f32) -> BiomeSpecification {
+		BiomeSpecification {
+			name: name.into(),
+			temperature,
+			humidity,
+			surface: "grass".into(),
+			subsurface: "dirt".into(),
+			subsurface_depth: 3,
+			height_spline: Spline::from_vec(vec![
+				Key::new(0.0, height, Interpolation::Linear),
+				Key::new(1.0, height, Interpolation::Linear),
+			]),
+			structures: Vec::new(),
+		}
+	}
+
+	fn test_biomes() -> BiomeManager {
+		let mut biomes = BiomeManager::new(0.1);
+		biomes.biomes.push(flat_biome("cold", 0.0, 0.5, 0.0));
+		biomes.biomes.push(flat_biome("hot", 1.0, 0.5, 100.0));
+		biomes
+	}
+
+	#[test]
+	fn test_biome_closest() {
+		let biomes = test_biomes();
+		assert_eq!(Some(0), biomes.closest(0.2, 0.5));
+		assert_eq!(Some(1), biomes.closest(0.8, 0.5));
+		assert_eq!(None, BiomeManager::new(0.1).closest(0.5, 0.5));
+	}
+
+	/// Far from a border only one biome should contribute
+	#[test]
+	fn test_biome_weights_interior() {
+		let biomes = test_biomes();
+		let w = biomes.weights(0.1, 0.5);
+		assert_eq!(1, w.len());
+		assert_eq!((0, 1.0), w[0]);
+		assert_eq!(Some(0.0), biomes.height(0.1, 0.5, 0.5));
+	}
+
+	/// Height should be continuous across a border and monotonic between the two biomes
+	#[test]
+	fn test_biome_height_blending() {
+		let biomes = test_biomes();
+		assert!((biomes.height(0.5, 0.5, 0.5).unwrap() - 50.0).abs() < 0.001);
+
+		let mut last = f32::MIN;
+		for i in 0..=100 {
+			let t = 0.4 + 0.2 * i as f32 / 100.0;
+			let h = biomes.height(t, 0.5, 0.5).unwrap();
+			assert!(h >= last, "height decreased from {last} to {h} at t={t}");
+			assert!(h - last.max(0.0) < 10.0, "height jumped from {last} to {h} at t={t}");
+			last = h;
+		}
+	}
+
+	/// Biomes whose height can't be sampled are rejected when they are read
+	#[test]
+	fn test_biome_height_spline_checked() {
+		let mut biome = flat_biome("flat", 0.5, 0.5, 10.0);
+		assert!(biome.check_height_spline().is_ok());
+		biome.height_spline = Spline::from_vec(Vec::new());
+		assert!(biome.check_height_spline().is_err());
+		// Catmull-Rom needs a key on either side
+		biome.height_spline = Spline::from_vec(vec![
+			Key::new(0.0, 0.0, Interpolation::CatmullRom),
+			Key::new(1.0, 1.0, Interpolation::CatmullRom),
+		]);
+		assert!(biome.check_height_spline().is_err());
+
+		let biomes = BiomeManager::read_directory("../../resources/biomes", 0.05).unwrap();
+		assert!(!biomes.is_empty());
+		assert!(BiomeManager::read_directory("../../resources/biomes/plains.ron", 0.05).is_err());
+	}
+}
@@ -0,0 +1,586 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use chunks::{blocks::{BlockKey, BlockResource}, chunk_of_voxel, chunks::ChunksResource, states::BlockState, VoxelSphere, CHUNK_SIZE};
+use eeks::prelude::*;
+use glam::IVec3;
+use crate::{events::BlockEventResource, modification::VoxelModification, terrain::{TerrainEntry, TerrainResource}};
+
+
+
+/// A region of voxels to be edited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditShape {
+	// Inclusive on both ends
+	Box(IVec3, IVec3),
+	Sphere(IVec3, i32),
+	// Base centre, radius, height
+	// Extends upward from the base
+	Cylinder(IVec3, i32, i32),
+	Line(IVec3, IVec3),
+	// Every voxel connected to this one with the same block, up to some limit
+	Flood(IVec3, usize),
+}
+impl EditShape {
+	/// The positions within this shape.
+	/// Flood fills depend on terrain and give only their start position.
+	pub fn positions(&self) -> Vec<IVec3> {
+		match *self {
+			Self::Box(a, b) => {
+				let min = a.min(b);
+				let max = a.max(b);
+				(min.x..=max.x).flat_map(|x|
+					(min.y..=max.y).flat_map(move |y|
+						(min.z..=max.z).map(move |z| IVec3::new(x, y, z))))
+					.collect()
+			},
+			Self::Sphere(centre, radius) => VoxelSphere::new(centre, radius).iter().into_iter().collect(),
+			Self::Cylinder(base, radius, height) => (-radius..=radius).flat_map(|x|
+				(0..height).flat_map(move |y|
+					(-radius..=radius).map(move |z| IVec3::new(x, y, z))))
+				.filter(|p| p.x.pow(2) + p.z.pow(2) <= radius.pow(2))
+				.map(|p| base + p)
+				.collect(),
+			Self::Line(start, end) => {
+				let d = end - start;
+				let steps = d.abs().max_element();
+				if steps == 0 {
+					return vec![start];
+				}
+				(0..=steps).map(|i| {
+					let t = i as f32 / steps as f32;
+					(start.as_vec3() + d.as_vec3() * t).round().as_ivec3()
+				}).collect()
+			},
+			Self::Flood(start, _) => vec![start],
+		}
+	}
+
+	/// An upper bound on the number of voxels in this shape.
+	/// Saturates rather than overflowing, so that huge shapes can be rejected.
+	pub fn volume(&self) -> usize {
+		// Voxels from a to b inclusive, found in i64 so that distant corners can't overflow
+		let extent = |a: i32, b: i32| (a as i64 - b as i64).unsigned_abs() as usize + 1;
+		let diameter = |radius: i32| (radius.max(0) as usize).saturating_mul(2).saturating_add(1);
+		match *self {
+			Self::Box(a, b) => (0..3).fold(1_usize, |v, i| v.saturating_mul(extent(a[i], b[i]))),
+			Self::Sphere(_, radius) => diameter(radius).saturating_pow(3),
+			Self::Cylinder(_, radius, height) => diameter(radius).saturating_pow(2).saturating_mul(height.max(0) as usize),
+			Self::Line(start, end) => (0..3).map(|i| extent(start[i], end[i])).max().unwrap(),
+			Self::Flood(_, limit) => limit,
+		}
+	}
+}
+
+
+/// The contents of a voxel.
+pub type EditVoxel = Option<(BlockKey, BlockState)>;
+
+
+/// The previous and new contents of every voxel changed by an edit.
+#[derive(Debug, Clone, Default)]
+pub struct EditTransaction {
+	// Position, before, after
+	pub changes: Vec<(IVec3, EditVoxel, EditVoxel)>,
+}
+impl EditTransaction {
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.changes.is_empty()
+	}
+
+	/// A transaction that reverses this one.
+	pub fn inverse(&self) -> Self {
+		Self {
+			changes: self.changes.iter().rev()
+				.map(|&(p, before, after)| (p, after, before))
+				.collect(),
+		}
+	}
+}
+
+
+/// Bounded undo and redo stacks.
+#[derive(Debug)]
+pub struct EditHistory {
+	undo: VecDeque<EditTransaction>,
+	redo: Vec<EditTransaction>,
+	capacity: usize,
+}
+impl EditHistory {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			undo: VecDeque::with_capacity(capacity),
+			redo: Vec::new(),
+			capacity,
+		}
+	}
+
+	/// Records a new transaction, forgetting anything that could be redone.
+	pub fn push(&mut self, transaction: EditTransaction) {
+		if transaction.is_empty() {
+			return;
+		}
+		self.redo.clear();
+		self.undo.push_back(transaction);
+		self.forget_excess();
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Changes how many edits can be undone, forgetting the oldest if there are too many. 
+	pub fn set_capacity(&mut self, capacity: usize) {
+		self.capacity = capacity;
+		self.forget_excess();
+	}
+
+	fn forget_excess(&mut self) {
+		while self.undo.len() > self.capacity {
+			self.undo.pop_front();
+		}
+		// The bottom of the redo stack is the furthest from the present
+		let excess = self.redo.len().saturating_sub(self.capacity);
+		self.redo.drain(..excess);
+	}
+
+	/// Gives the transaction that would undo the last edit.
+	pub fn undo(&mut self) -> Option<EditTransaction> {
+		let t = self.undo.pop_back()?;
+		let inverse = t.inverse();
+		self.redo.push(t);
+		Some(inverse)
+	}
+
+	/// Gives the transaction that would redo the last undone edit.
+	pub fn redo(&mut self) -> Option<EditTransaction> {
+		let t = self.redo.pop()?;
+		let redo = t.clone();
+		self.undo.push_back(t);
+		Some(redo)
+	}
+
+	pub fn undo_len(&self) -> usize {
+		self.undo.len()
+	}
+
+	pub fn redo_len(&self) -> usize {
+		self.redo.len()
+	}
+}
+
+
+/// Applies shapes to the terrain as single transactions.
+///
+/// Edits are queued as [VoxelModification]s, so they are not visible in the terrain until
+/// `terrain_modification_application` runs.
+/// The editor remembers what it has queued so that edits in the same tick see each other.
+pub struct TerrainEditor<'a> {
+	chunks: &'a ChunksResource,
+	terrain: &'a TerrainResource,
+	pending: HashMap<IVec3, EditVoxel>,
+	// Who is making the edits
+	pub entity: Option<Entity>,
+}
+impl<'a> TerrainEditor<'a> {
+	pub fn new(chunks: &'a ChunksResource, terrain: &'a TerrainResource) -> Self {
+		Self { chunks, terrain, pending: HashMap::new(), entity: None, }
+	}
+
+	fn is_loaded(&self, position: IVec3) -> bool {
+		let terrain_chunks = self.terrain.chunks.read();
+		self.chunks.read().get_position(chunk_of_voxel(position))
+			.and_then(|k| terrain_chunks.get(k))
+			.is_some_and(|e| matches!(e, TerrainEntry::Complete(_)))
+	}
+
+	fn get(&self, position: IVec3) -> EditVoxel {
+		match self.pending.get(&position) {
+			Some(&b) => b,
+			None => self.terrain.get_voxel_state(self.chunks, position),
+		}
+	}
+
+	fn flood(&self, start: IVec3, limit: usize) -> Vec<IVec3> {
+		if !self.is_loaded(start) {
+			return Vec::new();
+		}
+		// Any state of the same block is replaced
+		let target = self.get(start).map(|(k, _)| k);
+		let mut seen = HashSet::from([start]);
+		let mut queue = VecDeque::from([start]);
+		let mut found = Vec::new();
+		while let Some(p) = queue.pop_front() {
+			found.push(p);
+			if found.len() >= limit {
+				break;
+			}
+			for d in [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z] {
+				let n = p + d;
+				if !seen.contains(&n) && self.is_loaded(n) && self.get(n).map(|(k, _)| k) == target {
+					seen.insert(n);
+					queue.push_back(n);
+				}
+			}
+		}
+		found
+	}
+
+	/// Sets every voxel in a shape.
+	/// Voxels in chunks that are not loaded are skipped, as we can't know what we would be replacing.
+	pub fn apply(&mut self, shape: &EditShape, set_to: EditVoxel) -> EditTransaction {
+		let positions = match *shape {
+			EditShape::Flood(start, limit) => self.flood(start, limit),
+			_ => shape.positions(),
+		};
+		let changes = positions.into_iter()
+			.filter(|&p| self.is_loaded(p))
+			.map(|p| (p, self.get(p), set_to))
+			.filter(|&(_, before, after)| before != after)
+			.collect::<Vec<_>>();
+		let transaction = EditTransaction { changes, };
+		self.queue(&transaction);
+		transaction
+	}
+
+	/// Queues the changes of a transaction, such as one from [EditHistory::undo].
+	pub fn queue(&mut self, transaction: &EditTransaction) {
+		let modifications = transaction.changes.iter().map(|&(position, _, set_to)| {
+			self.pending.insert(position, set_to);
+			VoxelModification {
+				position,
+				set_to: set_to.map(|(k, _)| k),
+				state: set_to.map(|(_, s)| s).unwrap_or_default(),
+				priority: 0,
+				entity: self.entity,
+				emit_events: true,
+			}
+		}).collect::<Vec<_>>();
+		self.terrain.modify_voxels(&modifications);
+	}
+}
+
+
+/// Something for the editor to do.
+/// Block names are kept as strings until they can be looked up.
+#[derive(Debug, Clone)]
+pub enum EditCommand {
+	Apply(EditShape, String),
+	Undo,
+	Redo,
+}
+
+
+/// Holds queued edits and an edit history for each editor.
+/// Edits from the console use the history for `None`.
+#[derive(Debug, Resource)]
+#[sda(commands = true)]
+pub struct TerrainEditorResource {
+	pub pending: Vec<(Option<Entity>, EditCommand)>,
+	pub histories: HashMap<Option<Entity>, EditHistory>,
+	// Edits that have been queued but may yet be cancelled or transformed by block events
+	pub unresolved: Vec<(Option<Entity>, EditTransaction)>,
+	pub history_length: usize,
+	// Shapes bigger than this are rejected
+	pub max_volume: usize,
+	pub flood_limit: usize,
+}
+impl Default for TerrainEditorResource {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl TerrainEditorResource {
+	pub fn new() -> Self {
+		Self {
+			pending: Vec::new(),
+			histories: HashMap::new(),
+			unresolved: Vec::new(),
+			history_length: 64,
+			max_volume: 64 * 64 * 64,
+			flood_limit: 4096,
+		}
+	}
+
+	pub fn edit(&mut self, editor: Option<Entity>, command: EditCommand) {
+		self.pending.push((editor, command));
+	}
+
+	/// Records unresolved edits in their editor's history once none of their voxels are waiting to change.
+	/// Changes are recorded as they turned out, so those that were cancelled are left out.
+	pub fn resolve(&mut self, pending: impl Fn(IVec3) -> bool, current: impl Fn(IVec3) -> EditVoxel) {
+		let history_length = self.history_length;
+		let histories = &mut self.histories;
+		self.unresolved.retain(|(entity, transaction)| {
+			if transaction.changes.iter().any(|&(p, _, _)| pending(p)) {
+				return true;
+			}
+			let changes = transaction.changes.iter()
+				.map(|&(p, before, _)| (p, before, current(p)))
+				.filter(|&(_, before, after)| before != after)
+				.collect();
+			histories.entry(*entity)
+				.or_insert_with(|| EditHistory::new(history_length))
+				.push(EditTransaction { changes, });
+			false
+		});
+	}
+}
+impl StorageCommandExpose for TerrainEditorResource {
+	// Also given commands that start with "terrain"
+	// terrain fill 0 0 0 4 4 4 stone
+	// terrain sphere 0 10 0 3 air
+	// terrain cylinder 0 0 0 3 8 dirt
+	// terrain line 0 0 0 10 5 0 stone
+	// terrain replace 0 0 0 sand
+	// terrain undo
+	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
+		let int = |i: usize| -> anyhow::Result<i32> {
+			Ok(command.get(i).ok_or_else(|| anyhow::anyhow!("Expected a number at position {i}"))?.parse::<i32>()?)
+		};
+		// Radii, heights, and counts can't be negative
+		let size = |i: usize| -> anyhow::Result<i32> {
+			let v = int(i)?;
+			anyhow::ensure!(v >= 0, "Expected a non-negative number at position {i}, got {v}");
+			Ok(v)
+		};
+		let count = |i: usize| -> anyhow::Result<usize> {
+			Ok(size(i)? as usize)
+		};
+		let ivec = |i: usize| -> anyhow::Result<IVec3> {
+			Ok(IVec3::new(int(i)?, int(i+1)?, int(i+2)?))
+		};
+		let block = |i: usize| -> anyhow::Result<String> {
+			Ok(command.get(i).ok_or_else(|| anyhow::anyhow!("Give a block name"))?.to_string())
+		};
+
+		let shape = match command[0] {
+			"fill" => (EditShape::Box(ivec(1)?, ivec(4)?), block(7)?),
+			"sphere" => (EditShape::Sphere(ivec(1)?, size(4)?), block(5)?),
+			"cylinder" => (EditShape::Cylinder(ivec(1)?, size(4)?, size(5)?), block(6)?),
+			"line" => (EditShape::Line(ivec(1)?, ivec(4)?), block(7)?),
+			"replace" => (EditShape::Flood(ivec(1)?, self.flood_limit), block(4)?),
+			"undo" => {
+				self.edit(None, EditCommand::Undo);
+				return Ok("Queued undo".into());
+			},
+			"redo" => {
+				self.edit(None, EditCommand::Redo);
+				return Ok("Queued redo".into());
+			},
+			"history" => return Ok(match self.histories.get(&None) {
+				Some(h) => format!("undo: {}, redo: {}", h.undo_len(), h.redo_len()),
+				None => "No history".into(),
+			}),
+			"set" => return match command.get(1).copied() {
+				Some("history_length") => {
+					self.history_length = count(2)?;
+					for history in self.histories.values_mut() {
+						history.set_capacity(self.history_length);
+					}
+					Ok(format!("TerrainEditorResource history_length {}", self.history_length))
+				},
+				Some("max_volume") => {
+					self.max_volume = count(2)?;
+					Ok(format!("TerrainEditorResource max_volume {}", self.max_volume))
+				},
+				Some("flood_limit") => {
+					self.flood_limit = count(2)?;
+					Ok(format!("TerrainEditorResource flood_limit {}", self.flood_limit))
+				},
+				_ => Err(anyhow::anyhow!("Unknown field")),
+			},
+			_ => return Err(anyhow::anyhow!("Unknown command")),
+		};
+
+		if shape.0.volume() > self.max_volume {
+			return Err(anyhow::anyhow!("Shape volume {} exceeds max_volume {}", shape.0.volume(), self.max_volume));
+		}
+		self.edit(None, EditCommand::Apply(shape.0, shape.1));
+		Ok("Queued edit".into())
+	}
+}
+
+
+/// Applies queued edits, which are recorded in their editor's history by [terrain_editor_history_system].
+pub fn terrain_editor_system(
+	chunks: Res<ChunksResource>,
+	blocks: Res<BlockResource>,
+	terrain: Res<TerrainResource>,
+	mut editor: ResMut<TerrainEditorResource>,
+) {
+	let pending = std::mem::take(&mut editor.pending);
+	if pending.is_empty() {
+		return;
+	}
+	let blocks = blocks.read();
+	let mut terrain_editor = TerrainEditor::new(&chunks, &terrain);
+
+	let history_length = editor.history_length;
+	for (entity, command) in pending {
+		terrain_editor.entity = entity;
+		let editor = &mut *editor;
+		let history = editor.histories.entry(entity)
+			.or_insert_with(|| EditHistory::new(history_length));
+		match command {
+			EditCommand::Apply(shape, block) => {
+				let set_to = if block == "air" {
+					None
+				} else if let Some(b) = blocks.parse_block_state(&block) {
+					Some(b)
+				} else {
+					warn!("Edit uses unknown block '{block}'");
+					continue
+				};
+				let t = terrain_editor.apply(&shape, set_to);
+				debug!("Edit {:?} changes {} voxels", shape, t.len());
+				if !t.is_empty() {
+					editor.unresolved.push((entity, t));
+				}
+			},
+			EditCommand::Undo => if let Some(t) = history.undo() {
+				debug!("Undo {} voxels", t.len());
+				terrain_editor.queue(&t);
+			},
+			EditCommand::Redo => if let Some(t) = history.redo() {
+				debug!("Redo {} voxels", t.len());
+				terrain_editor.queue(&t);
+			},
+		}
+	}
+}
+
+
+/// Records edits in their editor's history after their block events are resolved and they are applied.
+pub fn terrain_editor_history_system(
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	events: Res<BlockEventResource>,
+	mut editor: ResMut<TerrainEditorResource>,
+) {
+	if editor.unresolved.is_empty() {
+		return;
+	}
+	let block_mods = terrain.block_mods.read();
+	let pending = |p: IVec3| {
+		let c = chunk_of_voxel(p);
+		let r = p - c * CHUNK_SIZE as i32;
+		events.is_deferred(p) || block_mods.get(&c).is_some_and(|mods| mods.iter().any(|m| m.position == r))
+	};
+	editor.resolve(pending, |p| terrain.get_voxel_state(&chunks, p));
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slotmap::KeyData;
+
+	#[test]
+	fn test_shape_positions() {
+		assert_eq!(27, EditShape::Box(IVec3::ONE, IVec3::NEG_ONE).positions().len());
+		assert_eq!(27, EditShape::Box(IVec3::ONE, IVec3::NEG_ONE).volume());
+
+		let line = EditShape::Line(IVec3::ZERO, IVec3::new(10, 5, -3)).positions();
+		assert_eq!(11, line.len());
+		assert_eq!(IVec3::ZERO, line[0]);
+		assert_eq!(IVec3::new(10, 5, -3), line[10]);
+
+		let cylinder = EditShape::Cylinder(IVec3::new(0, 10, 0), 2, 3).positions();
+		assert!(cylinder.iter().all(|p| p.y >= 10 && p.y < 13));
+		assert_eq!(13 * 3, cylinder.len());
+
+		// Huge shapes saturate instead of overflowing
+		assert_eq!(usize::MAX, EditShape::Sphere(IVec3::ZERO, i32::MAX).volume());
+		assert_eq!(3, EditShape::Cylinder(IVec3::ZERO, -5, 3).volume());
+		let far = EditShape::Line(IVec3::splat(i32::MIN), IVec3::splat(i32::MAX));
+		assert_eq!(u32::MAX as usize + 1, far.volume());
+		assert!(EditShape::Box(IVec3::splat(i32::MIN), IVec3::splat(i32::MAX)).volume() > 64 * 64 * 64);
+	}
+
+	#[test]
+	fn test_command_rejects_negatives() {
+		let mut editor = TerrainEditorResource::new();
+		assert!(editor.command(&["sphere", "0", "0", "0", "-3", "stone"]).is_err());
+		assert!(editor.command(&["cylinder", "0", "0", "0", "2", "-8", "stone"]).is_err());
+		assert!(editor.command(&["set", "max_volume", "-1"]).is_err());
+		assert!(editor.command(&["set", "flood_limit", "-1"]).is_err());
+		assert!(editor.command(&["set", "history_length", "-1"]).is_err());
+		assert!(editor.command(&["sphere", "0", "0", "0", "2147483647", "stone"]).is_err());
+		assert!(editor.pending.is_empty());
+
+		assert!(editor.command(&["sphere", "0", "0", "0", "3", "stone"]).is_ok());
+		assert_eq!(1, editor.pending.len());
+	}
+
+	#[test]
+	fn test_history_undo_redo() {
+		let a = Some((BlockKey::from(KeyData::from_ffi(1)), BlockState::default()));
+		let b = Some((BlockKey::from(KeyData::from_ffi(2)), BlockState(1)));
+		let mut history = EditHistory::new(2);
+		history.push(EditTransaction { changes: vec![(IVec3::ZERO, None, a)], });
+		history.push(EditTransaction { changes: vec![(IVec3::ZERO, a, b)], });
+
+		let undo = history.undo().unwrap();
+		assert_eq!(vec![(IVec3::ZERO, b, a)], undo.changes);
+		let redo = history.redo().unwrap();
+		assert_eq!(vec![(IVec3::ZERO, a, b)], redo.changes);
+		assert!(history.redo().is_none());
+
+		// Capacity is respected
+		history.push(EditTransaction { changes: vec![(IVec3::ONE, None, a)], });
+		assert_eq!(2, history.undo_len());
+
+		// New edits clear redo
+		history.undo();
+		assert_eq!(1, history.redo_len());
+		history.push(EditTransaction { changes: vec![(IVec3::ONE, None, b)], });
+		assert_eq!(0, history.redo_len());
+	}
+
+	#[test]
+	fn test_history_length_command() {
+		let a = Some((BlockKey::from(KeyData::from_ffi(1)), BlockState::default()));
+		let mut editor = TerrainEditorResource::new();
+		let history = editor.histories.entry(None).or_insert_with(|| EditHistory::new(64));
+		for i in 0..4 {
+			history.push(EditTransaction { changes: vec![(IVec3::splat(i), None, a)], });
+		}
+		history.undo();
+
+		// Existing histories forget their oldest edits
+		editor.command(&["set", "history_length", "2"]).unwrap();
+		let history = editor.histories.get_mut(&None).unwrap();
+		assert_eq!((2, 2, 1), (history.capacity(), history.undo_len(), history.redo_len()));
+		assert_eq!(vec![(IVec3::splat(2), a, None)], history.undo().unwrap().changes);
+		assert!(history.undo().is_some());
+		assert!(history.undo().is_none());
+	}
+
+	#[test]
+	fn test_history_resolved() {
+		let a = Some((BlockKey::from(KeyData::from_ffi(1)), BlockState::default()));
+		let b = Some((BlockKey::from(KeyData::from_ffi(2)), BlockState::default()));
+		let mut editor = TerrainEditorResource::new();
+		let placed = EditTransaction { changes: vec![(IVec3::ZERO, None, a), (IVec3::X, None, a)], };
+		editor.unresolved.push((None, placed.clone()));
+
+		// Nothing is recorded while a voxel is waiting for its events
+		editor.resolve(|p| p == IVec3::X, |_| a);
+		assert_eq!(1, editor.unresolved.len());
+		assert!(editor.histories.is_empty());
+
+		// One placement was cancelled and the other was transformed
+		editor.resolve(|_| false, |p| if p == IVec3::X { None } else { b });
+		assert!(editor.unresolved.is_empty());
+		let history = editor.histories.get_mut(&None).unwrap();
+		assert_eq!(vec![(IVec3::ZERO, b, None)], history.undo().unwrap().changes);
+
+		// Edits that were entirely cancelled aren't recorded, so they don't clear redo
+		editor.unresolved.push((None, placed));
+		editor.resolve(|_| false, |_| None);
+		assert_eq!(1, editor.histories[&None].redo_len());
+	}
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use chunks::{blocks::{BlockKey, BlockResource}, states::BlockState};
+use eeks::prelude::*;
+use glam::IVec3;
+use crate::{modification::VoxelModification, terrain::TerrainResource};
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockEventKind {
+	Place,
+	Interact,
+	Break,
+}
+impl BlockEventKind {
+	pub fn from_str(s: &str) -> Option<Self> {
+		match s {
+			"place" => Some(Self::Place),
+			"interact" => Some(Self::Interact),
+			"break" => Some(Self::Break),
+			_ => None,
+		}
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockEventOutcome {
+	Continue,
+	// The modification will not happen
+	Cancel,
+	// The modification will set the voxel to this instead
+	Transform(Option<BlockKey>),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct BlockEvent {
+	pub kind: BlockEventKind,
+	// The block being placed, interacted with, or broken
+	pub block: BlockKey,
+	pub block_name: String,
+	// World-relative
+	pub position: IVec3,
+	pub entity: Option<Entity>,
+	pub outcome: BlockEventOutcome,
+	// Index of the deferred modification that this event belongs to
+	modification: usize,
+}
+impl BlockEvent {
+	pub fn cancel(&mut self) {
+		self.outcome = BlockEventOutcome::Cancel;
+	}
+
+	/// Makes the modification set the voxel to something else.
+	/// Does nothing if the event has been cancelled.
+	pub fn transform(&mut self, set_to: Option<BlockKey>) {
+		if self.outcome != BlockEventOutcome::Cancel {
+			self.outcome = BlockEventOutcome::Transform(set_to);
+		}
+	}
+}
+
+
+pub type BlockEventHandler = fn(&mut BlockEvent);
+
+
+/// Holds this tick's block events and the handlers that will see them.
+///
+/// Modifications that cause events are held here until the events are resolved.
+/// Rust handlers are registered with [Self::register] and run by `block_event_dispatch`.
+///
+/// Lua handlers are not registered here, because dispatch has no Lua state to call them with.
+/// Instead, an extension adds a "client_tick" system that runs after "block_event_dispatch" and before "block_event_resolution".
+/// That system finds its block's events with `events(kind, block)` and can then `cancel` or `transform` each of them.
+/// The grass handler in base_blocks.lua is an example of this.
+#[derive(Debug, Resource, Default)]
+#[sda(lua = true)]
+pub struct BlockEventResource {
+	pub events: Vec<BlockEvent>,
+	deferred: Vec<VoxelModification>,
+	// (kind, block name) -> handlers
+	handlers: HashMap<(BlockEventKind, String), Vec<BlockEventHandler>>,
+}
+impl BlockEventResource {
+	/// Registers a handler for events of this kind for the block with this name.
+	/// Handlers are run in the order that they were registered.
+	pub fn register(&mut self, kind: BlockEventKind, block: impl Into<String>, handler: BlockEventHandler) {
+		self.handlers.entry((kind, block.into())).or_default().push(handler);
+	}
+
+	/// Holds a modification until its events are resolved.
+	/// Events should be added using [Self::emit] right after this.
+	pub fn defer(&mut self, modification: VoxelModification) -> usize {
+		self.deferred.push(modification);
+		self.deferred.len() - 1
+	}
+
+	/// Is a modification of this world-relative voxel waiting for its events to be resolved?
+	pub fn is_deferred(&self, position: IVec3) -> bool {
+		self.deferred.iter().any(|m| m.position == position)
+	}
+
+	pub fn emit(&mut self, kind: BlockEventKind, block: BlockKey, block_name: impl Into<String>, modification: usize) {
+		let m = &self.deferred[modification];
+		self.events.push(BlockEvent {
+			kind, block,
+			block_name: block_name.into(),
+			position: m.position,
+			entity: m.entity,
+			outcome: BlockEventOutcome::Continue,
+			modification,
+		});
+	}
+
+	/// Emits an interaction event.
+	/// Interactions do not change the voxel unless a handler transforms them.
+	pub fn interact(&mut self, position: IVec3, block: BlockKey, block_name: impl Into<String>, entity: Option<Entity>) {
+		let i = self.defer(VoxelModification {
+			position, set_to: Some(block), state: BlockState::default(), priority: 0, entity, emit_events: false,
+		});
+		self.emit(BlockEventKind::Interact, block, block_name, i);
+	}
+
+	/// Indices of events of this kind for this block.
+	pub fn matching(&self, kind: BlockEventKind, block_name: &str) -> Vec<usize> {
+		self.events.iter().enumerate()
+			.filter(|(_, e)| e.kind == kind && e.block_name == block_name)
+			.map(|(i, _)| i)
+			.collect()
+	}
+
+	fn dispatch(&mut self) {
+		for event in self.events.iter_mut() {
+			if let Some(handlers) = self.handlers.get(&(event.kind, event.block_name.clone())) {
+				for handler in handlers {
+					handler(event);
+				}
+			}
+		}
+	}
+
+	/// Gives the modifications that should go ahead.
+	/// An interaction that was not transformed gives nothing.
+	fn resolve(&mut self) -> Vec<VoxelModification> {
+		let mut modifications = self.deferred.drain(..).map(Some).collect::<Vec<_>>();
+		let mut transformed = vec![false; modifications.len()];
+		for event in self.events.drain(..) {
+			let Some(m) = modifications[event.modification].as_mut() else {
+				continue
+			};
+			match event.outcome {
+				BlockEventOutcome::Continue => {},
+				BlockEventOutcome::Cancel => modifications[event.modification] = None,
+				BlockEventOutcome::Transform(set_to) => {
+					m.set_to = set_to;
+					m.state = BlockState::default();
+					transformed[event.modification] = true;
+				},
+			}
+		}
+		modifications.into_iter().zip(transformed)
+			.filter_map(|(m, transformed)| m.filter(|m| m.emit_events || transformed))
+			.map(|mut m| {
+				// Don't create events for this again
+				m.emit_events = false;
+				m
+			})
+			.collect()
+	}
+}
+impl mlua::UserData for BlockEventResource {
+	fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+		// Gives the indices of events of a kind ("place", "interact", "break") for a block
+		methods.add_method("events", |_lua, this, (kind, block): (String, String)| {
+			let kind = BlockEventKind::from_str(&kind)
+				.ok_or_else(|| mlua::Error::runtime(format!("Unknown block event kind '{kind}'")))?;
+			Ok(this.matching(kind, &block))
+		});
+		methods.add_method("position", |_lua, this, i: usize| {
+			let e = this.events.get(i)
+				.ok_or_else(|| mlua::Error::runtime("No such event"))?;
+			Ok((e.position.x, e.position.y, e.position.z))
+		});
+		methods.add_method("entity", |_lua, this, i: usize| {
+			let e = this.events.get(i)
+				.ok_or_else(|| mlua::Error::runtime("No such event"))?;
+			Ok(e.entity.map(|e| ron::to_string(&e).unwrap()))
+		});
+		methods.add_method_mut("cancel", |_lua, this, i: usize| {
+			let e = this.events.get_mut(i)
+				.ok_or_else(|| mlua::Error::runtime("No such event"))?;
+			e.cancel();
+			Ok(())
+		});
+		// Block name "air" removes the voxel
+		methods.add_method_mut("transform", |_lua, this, (i, block, blocks): (usize, String, mlua::UserDataRef<BlockResource>)| {
+			let set_to = if block == "air" {
+				None
+			} else {
+				Some(blocks.read().key_by_name(&block)
+					.ok_or_else(|| mlua::Error::runtime(format!("Unknown block '{block}'")))?)
+			};
+			let e = this.events.get_mut(i)
+				.ok_or_else(|| mlua::Error::runtime("No such event"))?;
+			e.transform(set_to);
+			Ok(())
+		});
+	}
+}
+
+
+/// Runs Rust handlers for this tick's events.
+pub fn block_event_dispatch(
+	mut events: ResMut<BlockEventResource>,
+) {
+	events.dispatch();
+}
+
+
+/// Queues the modifications whose events were not cancelled.
+pub fn block_event_resolution(
+	terrain: Res<TerrainResource>,
+	mut events: ResMut<BlockEventResource>,
+) {
+	let modifications = events.resolve();
+	if !modifications.is_empty() {
+		terrain.modify_voxels(&modifications);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slotmap::KeyData;
+
+	fn key(i: u64) -> BlockKey {
+		BlockKey::from(KeyData::from_ffi(i))
+	}
+
+	fn modification(set_to: Option<BlockKey>) -> VoxelModification {
+		VoxelModification { position: IVec3::ZERO, set_to, state: BlockState::default(), priority: 0, entity: None, emit_events: true, }
+	}
+
+	#[test]
+	fn test_event_outcomes() {
+		let grass = key(1);
+		let dirt = key(2);
+		let mut events = BlockEventResource::default();
+		events.register(BlockEventKind::Place, "grass", |e| e.transform(Some(key(2))));
+		events.register(BlockEventKind::Break, "dirt", |e| e.cancel());
+
+		let i = events.defer(modification(Some(grass)));
+		events.emit(BlockEventKind::Place, grass, "grass", i);
+		let i = events.defer(modification(None));
+		events.emit(BlockEventKind::Break, dirt, "dirt", i);
+		// Nobody handles this interaction, so it should do nothing
+		events.interact(IVec3::ONE, dirt, "dirt", None);
+
+		events.dispatch();
+		let resolved = events.resolve();
+		assert_eq!(1, resolved.len());
+		assert_eq!(Some(dirt), resolved[0].set_to);
+		assert!(!resolved[0].emit_events);
+		assert!(events.events.is_empty());
+	}
+}
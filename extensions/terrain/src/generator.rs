@@ -1,11 +1,11 @@
 use std::path::Path;
 use chunks::{blocks::BlockKey, cube_iterator_xyz_uvec, CHUNK_SIZE};
-use glam::{IVec2, IVec3, UVec2, UVec3};
+use glam::{IVec2, IVec3, UVec2, UVec3, Vec3Swizzles};
 use simdnoise::FbmSettings;
 use splines::Spline;
 use thiserror::Error;
 
-use crate::terrain::TerrainContents;
+use crate::{biomes::{BiomeBlocks, BiomeManager}, liquids::{LiquidContents, LiquidVoxel}, terrain::TerrainContents};
 
 
 
@@ -110,8 +110,10 @@ impl InteroplatedGeneratorNoise {
 		let scale = UVec3::new(x_scale, y_scale, z_scale);
 		let world_offset = IVec3::new(x_offset, y_offset, z_offset);
 		let world_extent = UVec3::new(x_extent, y_extent, z_extent);
-		let samples_extent = (world_extent / scale) + UVec3::ONE;	
 		let samples_origin = world_offset.div_euclid(scale.as_ivec3());
+		// One past the sample cell of the last voxel, which is needed for interpolation
+		// This is (world_extent / scale) + 1 for aligned extents
+		let samples_extent = ((world_offset + world_extent.as_ivec3() - IVec3::ONE).div_euclid(scale.as_ivec3()) - samples_origin + IVec3::splat(2)).as_uvec3();
 
 		let [x_offset_f, y_offset_f, z_offset_f] = samples_origin.as_vec3().to_array();
 		let [width, height, depth] = samples_extent.to_array();
@@ -191,9 +193,30 @@ impl InteroplatedGeneratorNoise {
 }
 
 
-/// Splines are loaded from disk when calling [Self::new]. 
-/// If something fails during that, the prgoram will panic.  
-#[derive(Debug)]
+/// Gives (origin, extent) rectangles covering the columns within `outer` of a centre 
+/// that are not within `inner` of it. 
+fn square_ring(centre: IVec2, inner: Option<u32>, outer: u32) -> Vec<(IVec2, UVec2)> {
+	let o = outer as i32;
+	let Some(inner) = inner else {
+		return vec![(centre - IVec2::splat(o), UVec2::splat(outer * 2 + 1))];
+	};
+	let i = inner as i32;
+	let (width, band) = (outer * 2 + 1, outer - inner);
+	vec![
+		// Below and above the inner square
+		(centre - IVec2::splat(o), UVec2::new(width, band)),
+		(IVec2::new(centre.x - o, centre.y + i + 1), UVec2::new(width, band)),
+		// Either side of it
+		(IVec2::new(centre.x - o, centre.y - i), UVec2::new(band, inner * 2 + 1)),
+		(IVec2::new(centre.x + i + 1, centre.y - i), UVec2::new(band, inner * 2 + 1)),
+	]
+}
+
+
+/// Splines and biomes are loaded from disk when calling [Self::new]. 
+/// If a spline fails to load, the prgoram will panic. 
+/// If the biomes do, terrain is generated without them.  
+#[derive(Debug, Clone)]
 pub struct NewTerrainGenerator {
 	// The noise used to determine the base density of a voxel
 	density_noise: RawFbmSettings,
@@ -204,13 +227,22 @@ pub struct NewTerrainGenerator {
 	// The noise used to determine the intended height of the world
 	height_noise: RawFbmSettings,
 	// Maps raw height noise -> intended world terrain height
+	// Only used if there are no biomes, otherwise the biomes' splines are used
 	height_spline: Spline<f32, f32>,
 
+	// The noise used to select biomes
+	temperature_noise: RawFbmSettings,
+	humidity_noise: RawFbmSettings,
+	pub biomes: BiomeManager,
+
 	// The noise used to create a multiplier for the difference from intended height
 	// Think of this as a "weirdness" value
 	height_difference_noise: RawFbmSettings,
 	// Maps raw height difference noise -> height difference multiplier
 	height_difference_spline: Spline<f32, f32>,
+
+	// Columns with surfaces below this are underwater
+	pub sea_level: i32,
 }
 impl NewTerrainGenerator {
 	pub fn new(seed: i32) -> Self {
@@ -240,12 +272,210 @@ impl NewTerrainGenerator {
 				octaves: 1,
 			},
 			height_difference_spline: load_spline("resources/difference_spline.ron").unwrap(),
+			temperature_noise: RawFbmSettings {
+				seed: seed + 3,
+				freq: 1.0 / 800.0,
+				lacunarity: 2.0,
+				gain: 0.5,
+				octaves: 2,
+			},
+			humidity_noise: RawFbmSettings {
+				seed: seed + 4,
+				freq: 1.0 / 600.0,
+				lacunarity: 2.0,
+				gain: 0.5,
+				octaves: 2,
+			},
+			// Without biomes, terrain has the default height and isn't covered
+			biomes: BiomeManager::read_directory("resources/biomes", 0.05).unwrap_or_else(|e| {
+				error!("Failed to read biomes, terrain will have none - {e}");
+				BiomeManager::new(0.05)
+			}),
+			sea_level: 0,
+		}
+	}
+
+	/// Samples (temperature, humidity) for some columns. 
+	/// Outputs in zx order. 
+	pub fn climate(
+		&self, 
+		world_position: IVec2,
+		extent: UVec2,
+	) -> Vec<(f32, f32)> {
+		let [x_offset, z_offset] = world_position.to_array();
+		let [x_extent, z_extent] = extent.to_array();
+
+		let temperature_scale = self.temperature_noise.compute_scale();
+		let temperatures = simdnoise::NoiseBuilder::fbm_2d_offset(
+			x_offset as f32 + 0.5, x_extent as usize, 
+			z_offset as f32 + 0.5, z_extent as usize,
+		).apply_raw_settings(self.temperature_noise).generate().0;
+
+		let humidity_scale = self.humidity_noise.compute_scale();
+		let humidities = simdnoise::NoiseBuilder::fbm_2d_offset(
+			x_offset as f32 + 0.5, x_extent as usize, 
+			z_offset as f32 + 0.5, z_extent as usize,
+		).apply_raw_settings(self.humidity_noise).generate().0;
+
+		temperatures.into_iter().zip(humidities)
+			.map(|(t, h)| (
+				((t * temperature_scale + 1.0) / 2.0).clamp(0.0, 1.0), 
+				((h * humidity_scale + 1.0) / 2.0).clamp(0.0, 1.0),
+			))
+			.collect()
+	}
+
+	/// Finds the index of the biome used for the surface of a column. 
+	pub fn biome_at(&self, x: i32, z: i32) -> Option<usize> {
+		let (temperature, humidity) = self.climate(IVec2::new(x, z), UVec2::ONE)[0];
+		self.biomes.closest(temperature, humidity)
+	}
+
+	/// Samples the intended terrain height of some columns. 
+	/// Height splines are blended at biome borders. 
+	/// Outputs in zx order. 
+	pub fn heights(
+		&self, 
+		world_position: IVec2,
+		extent: UVec2,
+	) -> Vec<f32> {
+		let [x_offset, z_offset] = world_position.to_array();
+		let [x_extent, z_extent] = extent.to_array();
+
+		let height_scale = self.height_noise.compute_scale();
+		let height_noises = simdnoise::NoiseBuilder::fbm_2d_offset(
+			x_offset as f32 + 0.5, x_extent as usize, 
+			z_offset as f32 + 0.5, z_extent as usize,
+		).apply_raw_settings(self.height_noise).generate().0.into_iter()
+			.map(|d| (d * height_scale + 1.0) / 2.0); // Normalize
+
+		if self.biomes.is_empty() {
+			height_noises
+				.map(|height_noise| self.height_spline.clamped_sample(height_noise).unwrap())
+				.collect()
+		} else {
+			height_noises.zip(self.climate(world_position, extent))
+				.map(|(height_noise, (temperature, humidity))| {
+					self.biomes.height(temperature, humidity, height_noise).unwrap()
+				})
+				.collect()
 		}
 	}
 
-	pub fn max_height(_world_position: IVec2, _extent: UVec2) -> Option<Vec<i32>> {
-		// None if the max x key's y value in density adjustment is not 1
-		todo!("Max height")
+	/// Finds the range of height differences for which solidity is not known in advance. 
+	/// Density noise is in [0.0, 1.0], so a height difference is always empty if its 
+	/// adjustment is less than `threshold - 1.0` and always solid if its adjustment is 
+	/// at least `threshold`. 
+	/// 
+	/// Returns (always empty at or below, always solid at or above). 
+	/// None if the density spline does not guarantee both (floating islands, caves). 
+	fn density_bounds(&self) -> Option<(f32, f32)> {
+		let keys = self.density_spline.keys();
+		let (first, last) = (keys.first()?.t, keys.last()?.t);
+		let adjustment = |t: f32| self.density_spline.clamped_sample(t).unwrap();
+		let is_empty = |t: f32| adjustment(t) + 1.0 < self.density_threshold;
+		let is_solid = |t: f32| adjustment(t) >= self.density_threshold;
+
+		// The spline is clamped, so it's constant before the first key and after the last
+		if !is_empty(first) || !is_solid(last) {
+			return None;
+		}
+
+		// Height differences are in voxels, a quarter voxel step is plenty
+		const STEP: f32 = 0.25;
+		let mut empty_below = first;
+		while empty_below + STEP <= last && is_empty(empty_below + STEP) {
+			empty_below += STEP;
+		}
+		let mut solid_above = last;
+		while solid_above - STEP >= first && is_solid(solid_above - STEP) {
+			solid_above -= STEP;
+		}
+
+		Some((empty_below, solid_above))
+	}
+
+	/// Finds the y position of the highest solid voxel for some columns. 
+	/// Only the heights where solidity is uncertain are generated, so this is 
+	/// much cheaper than generating whole chunks. 
+	/// Outputs in zx order. 
+	/// 
+	/// None if the density spline does not bound solidity (see [Self::density_bounds]). 
+	pub fn max_height(&self, world_position: IVec2, extent: UVec2) -> Option<Vec<i32>> {
+		let (empty_below, solid_above) = self.density_bounds()?;
+		let heights = self.heights(world_position, extent);
+
+		// height_diff = height - y, so 
+		// y >= height - empty_below is always empty and 
+		// y <= height - solid_above is always solid 
+		let column_bounds = heights.iter()
+			.map(|&h| ((h - solid_above).floor() as i32, (h - empty_below).ceil() as i32))
+			.collect::<Vec<_>>();
+		let y_min = column_bounds.iter().map(|&(lo, _)| lo).min()?;
+		let y_max = column_bounds.iter().map(|&(_, hi)| hi).max()?;
+
+		let [x_extent, z_extent] = extent.to_array();
+		let y_extent = (y_max - y_min + 1) as u32;
+		let solidity = self.is_solid(
+			IVec3::new(world_position.x, y_min, world_position.y), 
+			UVec3::new(x_extent, y_extent, z_extent),
+		);
+
+		let mut output = Vec::with_capacity(column_bounds.len());
+		for z in 0..z_extent {
+			for x in 0..x_extent {
+				let (lo, hi) = column_bounds[(z * x_extent + x) as usize];
+				let y = (lo..=hi).rev()
+					.find(|&y| solidity[(x * y_extent * z_extent + (y - y_min) as u32 * z_extent + z) as usize])
+					.unwrap_or(lo);
+				output.push(y);
+			}
+		}
+
+		Some(output)
+	}
+
+	/// Finds a place for something to stand near a column. 
+	/// Spawn points are above sea level and have solid ground beneath them, 
+	/// which should keep players out of water and off of cave ceilings. 
+	/// Looks in growing squares around `near` out to `search_radius` columns. 
+	/// 
+	/// Returns the position of the first empty voxel above the ground. 
+	pub fn spawn_point(&self, near: IVec2, search_radius: u32) -> Option<IVec3> {
+		// How much solid ground must be beneath a spawn point 
+		const GROUND_DEPTH: u32 = 4;
+		const SEARCH_STEP: u32 = 16;
+
+		// Each step only looks at the columns that the last one didn't
+		let mut searched = None;
+		let mut radius = 0;
+		while radius <= search_radius {
+			let mut candidates = Vec::new();
+			for (origin, extent) in square_ring(near, searched, radius) {
+				let max_heights = self.max_height(origin, extent)?;
+				candidates.extend(max_heights.into_iter().enumerate().map(|(i, y)| {
+					let i = i as u32;
+					IVec3::new(origin.x + (i % extent.x) as i32, y, origin.y + (i / extent.x) as i32)
+				}));
+			}
+			candidates.retain(|p| p.y >= self.sea_level);
+			candidates.sort_by_key(|p| p.xz().distance_squared(near));
+
+			for candidate in candidates {
+				let below = self.is_solid(
+					candidate - IVec3::Y * (GROUND_DEPTH as i32 - 1), 
+					UVec3::new(1, GROUND_DEPTH, 1),
+				);
+				if below.into_iter().all(|s| s) {
+					return Some(candidate + IVec3::Y);
+				}
+			}
+
+			searched = Some(radius);
+			radius += SEARCH_STEP;
+		}
+
+		None
 	}
 
 	/// A lookahead method for knowing if a block will be solid. 
@@ -258,18 +488,9 @@ impl NewTerrainGenerator {
 		let [x_offset, y_offset, z_offset] = world_position.to_array();
 		let [x_extent, y_extent, z_extent] = extent.to_array();
 
-		// Sample height (2d fbm -> height spline)
-		// Outputs in yx order
-		let height_scale = self.height_noise.compute_scale();
-		let heights = simdnoise::NoiseBuilder::fbm_2d_offset(
-			x_offset as f32 + 0.5, x_extent as usize, 
-			z_offset as f32 + 0.5, z_extent as usize,
-		).apply_raw_settings(self.height_noise).generate().0.into_iter()
-			.map(|d| (d * height_scale + 1.0) / 2.0) // Normalize
-			.map(|height_noise| {
-				self.height_spline.clamped_sample(height_noise).unwrap()
-			})
-			.collect::<Vec<_>>();
+		// Sample height (2d fbm -> biome height splines)
+		// Outputs in zx order
+		let heights = self.heights(IVec2::new(x_offset, z_offset), UVec2::new(x_extent, z_extent));
 
 		let height_difference_scale = self.height_difference_noise.compute_scale();
 		let height_differences = simdnoise::NoiseBuilder::fbm_2d_offset(
@@ -334,15 +555,14 @@ impl NewTerrainGenerator {
 				// 	p.x
 				// ) as usize];
 				let density = densities[(
-					p.x * y_extent * x_extent +
-					p.y * x_extent +
+					p.x * y_extent * z_extent +
+					p.y * z_extent +
 					p.z
 				) as usize];
-				// let height = heights[(
-				// 	p.z * x_extent +
-				// 	p.x
-				// ) as usize];
-				let height = 0.0;
+				let height = heights[(
+					p.z * x_extent +
+					p.x
+				) as usize];
 				// let height_difference = height_differences[(
 				// 	p.z * x_extent +
 				// 	p.x
@@ -398,56 +618,74 @@ impl NewTerrainGenerator {
 		&self,
 		chunk_position: IVec3, 
 		volume: &mut TerrainContents,
-		top: BlockKey,
-		fill: BlockKey,
-		fill_depth: i32, // n following top placement
+		biome_blocks: &[BiomeBlocks], // Indexed like self.biomes
 	) {
-		// for x in 0..CHUNK_SIZE {
-		// 	for z in 0..CHUNK_SIZE {
-		// 		// Generate column solidity
-		// 		// The orderign of this might be wrong, just do .rev() if it is
-		// 		let solidity = self.is_solid(
-		// 			CHUNK_SIZE as i32 * chunk_position + IVec3::new(x as i32, 0, z as i32), 
-		// 			UVec3::new(1, CHUNK_SIZE + fill_depth as u32, 1),
-		// 		);
-
-		// 		let mut fill_to_place = 0;
-		// 		let mut last_was_empty = false;
-		// 		// Descend y
-		// 		for (y, solid) in solidity.into_iter().enumerate().rev() {
-		// 			// Never set an empty voxel
-		// 			if !solid {
-		// 				// Reset fill counter
-		// 				last_was_empty = true;
-		// 				fill_to_place = 0;
-		// 				continue
-		// 			} else {
-		// 				let in_chunk = y < CHUNK_SIZE as usize;
-
-		// 				// Set top if exposed on top
-		// 				if last_was_empty {
-		// 					// Begin placing fill
-		// 					fill_to_place = fill_depth;
-		// 					if in_chunk {
-		// 						// This y could be wrong
-		// 						volume.insert(UVec3::new(x as u32, y as u32, z as u32), top);
-		// 					}
-		// 				} else {
-		// 					// If not exposed and more fill to place, set fill
-		// 					if fill_to_place != 0 {
-		// 						fill_to_place -= 1;
-		// 						if in_chunk {
-		// 							// This y could be wrong
-		// 							volume.insert(UVec3::new(x as u32, y as u32, z as u32), fill);
-		// 						}
-		// 					}
-		// 				}
-
-		// 				last_was_empty = false;
-		// 			}
-		// 		}
-		// 	}
-		// }
+		if biome_blocks.is_empty() {
+			return;
+		}
+		assert_eq!(self.biomes.biomes.len(), biome_blocks.len(), "biome blocks do not match biomes");
+
+		// We need to look above the chunk to know if a voxel is exposed 
+		// or how much subsurface should be placed above it
+		let max_depth = biome_blocks.iter().map(|b| b.subsurface_depth).max().unwrap();
+		let chunk_base = chunk_position * CHUNK_SIZE as i32;
+		let extent = UVec3::new(CHUNK_SIZE, CHUNK_SIZE + max_depth + 1, CHUNK_SIZE);
+		let solidity = self.is_solid(chunk_base, extent);
+		let is_solid = |x: u32, y: u32, z: u32| solidity[(x * extent.y * extent.z + y * extent.z + z) as usize];
+
+		let climate = self.climate(IVec2::new(chunk_base.x, chunk_base.z), UVec2::splat(CHUNK_SIZE));
+
+		for x in 0..CHUNK_SIZE {
+			for z in 0..CHUNK_SIZE {
+				let (temperature, humidity) = climate[(z * CHUNK_SIZE + x) as usize];
+				let blocks = biome_blocks[self.biomes.closest(temperature, humidity).unwrap()];
+
+				// Descend y, counting solid voxels since the last empty one
+				let mut depth = None;
+				for y in (0..extent.y).rev() {
+					if !is_solid(x, y, z) {
+						depth = Some(0);
+						continue
+					}
+					// Voxels at the top of the column have unknown exposure
+					let Some(d) = depth.as_mut() else { continue };
+
+					if y < CHUNK_SIZE {
+						let position = UVec3::new(x, y, z);
+						if *d == 0 {
+							volume.insert(position, blocks.surface);
+						} else if *d <= blocks.subsurface_depth {
+							volume.insert(position, blocks.subsurface);
+						}
+					}
+					*d += 1;
+				}
+			}
+		}
+	}
+
+	/// Fills empty voxels below sea level with sources of a liquid. 
+	pub fn sea(
+		&self,
+		chunk_position: IVec3, 
+		terrain: &TerrainContents,
+		liquid: BlockKey,
+	) -> LiquidContents {
+		let chunk_base = chunk_position.y * CHUNK_SIZE as i32;
+		let mut contents = LiquidContents::new();
+		if chunk_base >= self.sea_level {
+			return contents;
+		}
+		if terrain.is_empty() && chunk_base + CHUNK_SIZE as i32 <= self.sea_level {
+			return LiquidContents::Filled(liquid);
+		}
+
+		for position in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)) {
+			if chunk_base + (position.y as i32) < self.sea_level && terrain.get(position).is_none() {
+				contents.set(position, Some(LiquidVoxel::source(liquid)));
+			}
+		}
+		contents
 	}
 
 	#[deprecated]
@@ -465,6 +703,86 @@ impl NewTerrainGenerator {
 pub mod tests {
 	use super::*;
 	use test::Bencher;
+	use splines::{Interpolation, Key};
+
+	/// A generator that does not read splines or biomes from disk
+	pub(crate) fn test_generator(seed: i32) -> NewTerrainGenerator {
+		let settings = |seed, freq| RawFbmSettings {
+			seed,
+			freq,
+			lacunarity: 2.0,
+			gain: 0.5,
+			octaves: 1,
+		};
+		NewTerrainGenerator {
+			density_noise: settings(seed, 1.0 / 50.0),
+			density_threshold: 0.5,
+			density_spline: Spline::from_vec(vec![
+				Key::new(-20.0, -1.0, Interpolation::Cosine),
+				Key::new(0.0, 0.0, Interpolation::Cosine),
+				Key::new(10.0, 1.0, Interpolation::Cosine),
+			]),
+			height_noise: settings(seed + 1, 1.0 / 100.0),
+			height_spline: Spline::from_vec(vec![
+				Key::new(0.0, -30.0, Interpolation::Cosine),
+				Key::new(1.0, 60.0, Interpolation::Cosine),
+			]),
+			temperature_noise: settings(seed + 3, 1.0 / 800.0),
+			humidity_noise: settings(seed + 4, 1.0 / 600.0),
+			biomes: BiomeManager::new(0.05),
+			height_difference_noise: settings(seed + 2, 1.0 / 100.0),
+			height_difference_spline: Spline::from_vec(vec![
+				Key::new(0.0, 1.0, Interpolation::Cosine),
+			]),
+			sea_level: 0,
+		}
+	}
+
+	/// Max height should find the same voxel as a search through fully generated columns
+	#[test]
+	fn test_max_height() {
+		let generator = test_generator(7);
+		let origin = IVec2::new(-5, 3);
+		let extent = UVec2::new(6, 4);
+		let max_heights = generator.max_height(origin, extent).unwrap();
+
+		let (y_min, y_extent) = (-128, 256);
+		for z in 0..extent.y {
+			for x in 0..extent.x {
+				let column = generator.is_solid(
+					IVec3::new(origin.x + x as i32, y_min, origin.y + z as i32), 
+					UVec3::new(1, y_extent, 1),
+				);
+				let expected = column.iter().rposition(|&s| s).unwrap() as i32 + y_min;
+				assert_eq!(expected, max_heights[(z * extent.x + x) as usize], "column ({x}, {z})");
+			}
+		}
+	}
+
+	#[test]
+	fn test_square_ring() {
+		let centre = IVec2::new(3, -2);
+		let columns = |rects: Vec<(IVec2, UVec2)>| rects.into_iter()
+			.flat_map(|(origin, extent)| (0..extent.x).flat_map(move |x| (0..extent.y).map(move |z| origin + UVec2::new(x, z).as_ivec2())))
+			.collect::<Vec<_>>();
+		let within = |p: IVec2, r: i32| (p - centre).abs().max_element() <= r;
+
+		assert_eq!(vec![centre], columns(square_ring(centre, None, 0)));
+		let ring = columns(square_ring(centre, Some(2), 5));
+		// Every column between the squares, once
+		assert_eq!(11 * 11 - 5 * 5, ring.len());
+		assert_eq!(ring.len(), ring.iter().collect::<std::collections::HashSet<_>>().len());
+		assert!(ring.iter().all(|&p| within(p, 5) && !within(p, 2)));
+	}
+
+	#[test]
+	fn test_spawn_point() {
+		let generator = test_generator(7);
+		let spawn = generator.spawn_point(IVec2::ZERO, 256).unwrap();
+		assert!(spawn.y > generator.sea_level);
+		let column = generator.is_solid(spawn - IVec3::Y * 4, UVec3::new(1, 8, 1));
+		assert_eq!(vec![true, true, true, true, false, false, false, false], column);
+	}
 
 	/// Tests that my magic scaling number is still working 
 	#[test]
@@ -1,17 +1,30 @@
 #![feature(test)]
 
+pub mod biomes;
+pub mod editor;
+pub mod events;
 pub mod generator;
 pub mod modification;
+pub mod raycast;
 pub mod terrain;
 pub mod liquids;
+pub mod saving;
 pub mod script;
+#[cfg(test)]
+mod test_util;
+pub mod ticks;
 
 use pinecore::controls::ControlMap;
 use eeks::prelude::*;
+use editor::{terrain_editor_history_system, terrain_editor_system, TerrainEditorResource};
+use events::{block_event_dispatch, block_event_resolution, BlockEventResource};
 use modification::{terrain_modification_application, terrain_placement_queue, VoxelModifierComponent};
 use pinecore::player::PlayerSpawnResource;
-use terrain::{terrain_loading_system, TerrainLoadingResource, TerrainResource};
+use liquids::{liquid_loading_system, liquid_update_system, LiquidsResource};
+use terrain::{terrain_loading_system, terrain_saving_system, TerrainLoadingResource, TerrainResource};
+use ticks::{block_tick_application, block_tick_collection, BlockTickResource};
 use pinecore::transform::TransformComponent;
+use glam::{IVec2, Vec3};
 
 #[macro_use]
 extern crate log;
@@ -19,6 +32,8 @@ extern crate log;
 extern crate test;
 
 
+const PLAYER_EYE_HEIGHT: f32 = 1.6;
+
 
 fn player_terrain_modifier(
 	psr: Res<PlayerSpawnResource>,
@@ -33,17 +48,39 @@ fn player_terrain_modifier(
 
 fn place_player_at_terrain_height(
 	psr: Res<PlayerSpawnResource>,
+	loading: Res<TerrainLoadingResource>,
 	mut transforms: CompMut<TransformComponent>,
 ) {
 	for entity in psr.entities.iter().copied() {
 		let t = transforms.get_mut(entity).unwrap();
-		warn!("TODO: set player y to terrain height");
-		t.translation.y = 42.0;
+		let column = IVec2::new(t.translation.x.floor() as i32, t.translation.z.floor() as i32);
+		if let Some(spawn) = loading.generator.spawn_point(column, 256) {
+			debug!("Spawn player at {spawn}");
+			// Centre in the voxel and leave room for the player's eyes
+			t.translation = spawn.as_vec3() + Vec3::new(0.5, PLAYER_EYE_HEIGHT, 0.5);
+		} else {
+			warn!("Failed to find a spawn point near {column}");
+			t.translation.y = 42.0;
+		}
 	}
 }
 
 
 
+// terrain fill 0 0 0 4 4 4 stone
+// terrain undo
+// terrain set history_length 16
+// terrain biome_at 0 0
+// terrain set sea_level 16
+fn terrain_command(world: &World, command: &[&str]) -> anyhow::Result<String> {
+	match command {
+		[] => Err(anyhow::anyhow!("Give a terrain command")),
+		["biome_at", ..] | ["set", "sea_level", ..] => world.resource_mut::<TerrainLoadingResource>().command(command),
+		_ => world.resource_mut::<TerrainEditorResource>().command(command),
+	}
+}
+
+
 #[info]
 pub fn dependencies() -> Vec<String> {
 	env_logger::init();
@@ -60,9 +97,38 @@ pub fn systems(loader: &mut ExtensionSystemsLoader) {
 
 	loader.system("client_tick", "terrain_placement_queue", terrain_placement_queue);
 
-	loader.system("client_tick", "terrain_modification_application", terrain_modification_application)
+	loader.system("client_tick", "terrain_editor_system", terrain_editor_system)
 		.run_after("terrain_placement_queue");
 
+	loader.system("client_tick", "terrain_modification_application", terrain_modification_application)
+		.run_after("terrain_editor_system");
+
+	loader.system("client_tick", "block_event_dispatch", block_event_dispatch)
+		.run_after("terrain_modification_application");
+
+	// Lua event handlers should run between these
+	loader.system("client_tick", "block_event_resolution", block_event_resolution)
+		.run_after("block_event_dispatch");
+
+	loader.system("client_tick", "terrain_editor_history_system", terrain_editor_history_system)
+		.run_after("block_event_resolution");
+
+	loader.system("client_tick", "block_tick_collection", block_tick_collection)
+		.run_after("block_event_resolution");
+
+	// Lua tick handlers should run between these
+	loader.system("client_tick", "block_tick_application", block_tick_application)
+		.run_after("block_tick_collection");
+
+	loader.system("client_tick", "liquid_loading_system", liquid_loading_system)
+		.run_after("terrain_loading_system")
+		.run_after("terrain_modification_application");
+
+	loader.system("client_tick", "liquid_update_system", liquid_update_system)
+		.run_after("liquid_loading_system");
+
+	loader.system("client_shutdown", "terrain_saving_system", terrain_saving_system);
+
 	loader.system("client_tick", "player_terrain_modifier", player_terrain_modifier)
 		.run_after("player_spawn")
 		.run_before("player_spawned");
@@ -78,4 +144,9 @@ pub fn load(storages: &mut eeks::ExtensionStorageLoader) {
 	storages.component::<VoxelModifierComponent>();
 	storages.resource(TerrainLoadingResource::new(0));
 	storages.resource(TerrainResource::default());
+	storages.resource(LiquidsResource::new());
+	storages.resource(TerrainEditorResource::new());
+	storages.resource(BlockEventResource::default());
+	storages.resource(BlockTickResource::new());
+	storages.command("terrain", terrain_command);
 }
@@ -1,10 +1,766 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use chunks::{blocks::{BlockKey, BlockManager, BlockResource, LiquidSpecification}, chunk_of_voxel, chunks::{ChunkEvent, ChunkKey, Chunks, ChunksResource}, generation::KGeneration, voxel_relative_to_chunk, CHUNK_SIZE};
+use eeks::prelude::*;
+use glam::{IVec3, UVec3};
+use parking_lot::RwLock;
+use rayon::prelude::*;
+use slotmap::SecondaryMap;
+use crate::terrain::{TerrainEntry, TerrainResource};
 
 
+
+/// The level of a source or of falling liquid.
+pub const LIQUID_LEVEL_MAX: u8 = 15;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiquidVoxel {
+	pub liquid: BlockKey,
+	// In [0, LIQUID_LEVEL_MAX]
+	pub level: u8,
+	// Sources do not drain
+	pub source: bool,
+}
+impl LiquidVoxel {
+	pub fn source(liquid: BlockKey) -> Self {
+		Self { liquid, level: LIQUID_LEVEL_MAX, source: true, }
+	}
+}
+
+
+#[derive(Debug, Clone)]
 pub enum LiquidContents {
-	Empty, 
-	Filled, 
-	Pallete4(Vec<bool>, Vec<u8>),
-	// Pallete12(Vec<bool>, Vec<u16>),
-	// Pallete28(Vec<bool>, Vec<u32>),
+	Empty,
+	// Every voxel is a source of this liquid, which is common below sea level
+	Filled(BlockKey),
+	// Contents and the number of voxels with liquid
+	Mixed(Box<[Option<LiquidVoxel>]>, usize),
+}
+impl Default for LiquidContents {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl LiquidContents {
+	pub fn new() -> Self {
+		Self::Empty
+	}
+
+	pub fn is_empty(&self) -> bool {
+		matches!(self, Self::Empty)
+	}
+
+	fn index_of(position: UVec3) -> usize {
+		let [x, y, z] = position.to_array();
+		(x * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + z) as usize
+	}
+
+	pub fn get(&self, position: UVec3) -> Option<LiquidVoxel> {
+		match self {
+			Self::Empty => None,
+			Self::Filled(liquid) => Some(LiquidVoxel::source(*liquid)),
+			Self::Mixed(c, _) => c[Self::index_of(position)],
+		}
+	}
+
+	pub fn set(&mut self, position: UVec3, voxel: Option<LiquidVoxel>) {
+		if self.get(position) == voxel {
+			return;
+		}
+		// Expand into a mixed volume
+		match self {
+			Self::Empty => *self = Self::Mixed(vec![None; CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), 0),
+			&mut Self::Filled(liquid) => *self = Self::Mixed(vec![Some(LiquidVoxel::source(liquid)); CHUNK_SIZE.pow(3) as usize].into_boxed_slice(), CHUNK_SIZE.pow(3) as usize),
+			Self::Mixed(_, _) => {},
+		}
+		if let Self::Mixed(c, count) = self {
+			let i = Self::index_of(position);
+			match (c[i].is_some(), voxel.is_some()) {
+				(false, true) => *count += 1,
+				(true, false) => *count -= 1,
+				_ => {},
+			}
+			c[i] = voxel;
+			// If no contents remain, deallocate
+			if *count == 0 {
+				*self = Self::Empty;
+			}
+		}
+	}
+
+	/// The number of voxels containing liquid.
+	pub fn count(&self) -> usize {
+		match self {
+			Self::Empty => 0,
+			Self::Filled(_) => CHUNK_SIZE.pow(3) as usize,
+			Self::Mixed(_, count) => *count,
+		}
+	}
+
+	pub fn run_length_encode(&self) -> Vec<(Option<LiquidVoxel>, u32)> {
+		match self {
+			Self::Empty => vec![(None, CHUNK_SIZE.pow(3))],
+			&Self::Filled(liquid) => vec![(Some(LiquidVoxel::source(liquid)), CHUNK_SIZE.pow(3))],
+			Self::Mixed(c, _) => {
+				let mut runs: Vec<(Option<LiquidVoxel>, u32)> = Vec::new();
+				for &v in c.iter() {
+					match runs.last_mut() {
+						Some((last, len)) if *last == v => *len += 1,
+						_ => runs.push((v, 1)),
+					}
+				}
+				runs
+			},
+		}
+	}
+
+	pub fn run_length_decode(rle: &[(Option<LiquidVoxel>, u32)]) -> Self {
+		if let [(Some(v), _)] = rle {
+			if *v == LiquidVoxel::source(v.liquid) {
+				return Self::Filled(v.liquid);
+			}
+		}
+		let mut s = Self::new();
+		let mut i = 0;
+		for &(v, length) in rle {
+			if v.is_some() {
+				for j in i..i + length as usize {
+					let j = j as u32;
+					s.set(UVec3::new(j / (CHUNK_SIZE * CHUNK_SIZE), j / CHUNK_SIZE % CHUNK_SIZE, j % CHUNK_SIZE), v);
+				}
+			}
+			i += length as usize;
+		}
+		s
+	}
+
+	pub fn size(&self) -> usize {
+		let mut base = std::mem::size_of::<Self>();
+		if let Self::Mixed(c, _) = self {
+			base += c.len() * std::mem::size_of::<Option<LiquidVoxel>>();
+		}
+		base
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct LiquidChunk {
+	contents: LiquidContents,
+	pub generation: KGeneration,
+	// Has this changed since it was generated? 
+	// If so, it is saved with its terrain
+	pub modified: bool,
+}
+impl LiquidChunk {
+	pub fn new(contents: LiquidContents) -> Self {
+		Self {
+			contents,
+			generation: KGeneration::new(),
+			modified: false,
+		}
+	}
+}
+impl std::ops::Deref for LiquidChunk {
+	type Target = LiquidContents;
+	fn deref(&self) -> &Self::Target {
+		&self.contents
+	}
+}
+impl std::ops::DerefMut for LiquidChunk {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.contents
+	}
+}
+
+
+/// A chunk-relative region that should be re-evaluated, inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRegion {
+	pub min: IVec3,
+	pub max: IVec3,
+}
+impl DirtyRegion {
+	pub fn whole_chunk() -> Self {
+		Self { min: IVec3::ZERO, max: IVec3::splat(CHUNK_SIZE as i32 - 1), }
+	}
+
+	/// Creates a region around a chunk-relative voxel, clamped to the chunk.
+	/// None if the expanded voxel does not touch the chunk.
+	pub fn around(voxel: IVec3, radius: i32) -> Option<Self> {
+		let min = (voxel - radius).max(IVec3::ZERO);
+		let max = (voxel + radius).min(IVec3::splat(CHUNK_SIZE as i32 - 1));
+		min.cmple(max).all().then_some(Self { min, max })
+	}
+
+	pub fn union(self, other: Self) -> Self {
+		Self { min: self.min.min(other.min), max: self.max.max(other.max), }
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = UVec3> + '_ {
+		(self.min.x..=self.max.x).flat_map(move |x|
+			(self.min.y..=self.max.y).flat_map(move |y|
+				(self.min.z..=self.max.z).map(move |z| IVec3::new(x, y, z).as_uvec3())))
+	}
+}
+
+
+/// Read-only access to the state of the world at the start of a step.
+/// Unloaded voxels are treated as solid so that liquid does not flow into them.
+struct LiquidView<'a> {
+	chunks: &'a Chunks,
+	terrain: &'a SecondaryMap<ChunkKey, TerrainEntry>,
+	liquids: &'a SecondaryMap<ChunkKey, Arc<LiquidChunk>>,
+	specifications: &'a HashMap<BlockKey, LiquidSpecification>,
+}
+impl<'a> LiquidView<'a> {
+	fn lookup(&self, voxel: IVec3) -> Option<(ChunkKey, UVec3)> {
+		let chunk = chunk_of_voxel(voxel);
+		self.chunks.get_position(chunk)
+			.map(|k| (k, voxel_relative_to_chunk(voxel, chunk).as_uvec3()))
+	}
+
+	fn is_solid(&self, voxel: IVec3) -> bool {
+		self.lookup(voxel)
+			.and_then(|(k, p)| self.terrain.get(k)
+				.and_then(|e| e.complete_ref())
+				.map(|c| c.get(p).is_some()))
+			.unwrap_or(true)
+	}
+
+	fn liquid(&self, voxel: IVec3) -> Option<LiquidVoxel> {
+		self.lookup(voxel)
+			.and_then(|(k, p)| self.liquids.get(k).and_then(|c| c.get(p)))
+	}
+
+	/// If liquid in this voxel cannot fall, it may flow sideways.
+	fn is_supported(&self, voxel: IVec3) -> bool {
+		let below = voxel - IVec3::Y;
+		self.is_solid(below) || self.liquid(below).is_some()
+	}
+
+	/// Finds what this voxel should contain in the next step.
+	fn evaluate(&self, voxel: IVec3) -> Option<LiquidVoxel> {
+		if self.is_solid(voxel) {
+			return None;
+		}
+		let current = self.liquid(voxel);
+		if current.is_some_and(|l| l.source) {
+			return current;
+		}
+
+		// Liquid flows down first
+		if let Some(above) = self.liquid(voxel + IVec3::Y) {
+			return Some(LiquidVoxel { liquid: above.liquid, level: LIQUID_LEVEL_MAX, source: false, });
+		}
+
+		// Then sideways, but only from liquid that cannot fall
+		[IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z].into_iter()
+			.map(|offset| voxel + offset)
+			.filter_map(|neighbour| self.liquid(neighbour).map(|l| (neighbour, l)))
+			.filter(|&(neighbour, _)| self.is_supported(neighbour))
+			.filter_map(|(_, l)| {
+				let spread = self.specifications.get(&l.liquid).map(|s| s.spread).unwrap_or(1);
+				l.level.checked_sub(spread).map(|level| LiquidVoxel { liquid: l.liquid, level, source: false, })
+			})
+			.max_by_key(|l| l.level)
+	}
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct LiquidModification {
+	pub position: IVec3, // World-relative
+	pub set_to: Option<LiquidVoxel>,
+}
+
+
+#[derive(Debug, Resource)]
+#[sda(commands = true)]
+pub struct LiquidsResource {
+	pub chunks: Arc<RwLock<SecondaryMap<ChunkKey, Arc<LiquidChunk>>>>,
+	pub liquid_mods: RwLock<Vec<LiquidModification>>,
+	// Regions that should be evaluated in the next step
+	pub dirty: SecondaryMap<ChunkKey, DirtyRegion>,
+	// The terrain generation that each liquid chunk last saw
+	terrain_generations: SecondaryMap<ChunkKey, KGeneration>,
+
+	pub ticks_per_step: u32,
+	ticks: u32,
+	// For profiling
+	last_step_chunks: usize,
+	last_step_voxels: usize,
+	last_step_duration: Duration,
+}
+impl Default for LiquidsResource {
+	fn default() -> Self {
+		Self::new()
+	}
 }
+impl LiquidsResource {
+	pub fn new() -> Self {
+		Self {
+			chunks: Arc::new(RwLock::new(SecondaryMap::new())),
+			liquid_mods: RwLock::new(Vec::new()),
+			dirty: SecondaryMap::new(),
+			terrain_generations: SecondaryMap::new(),
+			ticks_per_step: 5,
+			ticks: 0,
+			last_step_chunks: 0,
+			last_step_voxels: 0,
+			last_step_duration: Duration::ZERO,
+		}
+	}
 
+	pub fn get_liquid(&self, cr: &ChunksResource, voxel: IVec3) -> Option<LiquidVoxel> {
+		let chunk = chunk_of_voxel(voxel);
+		let voxel = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
+		let chunk = cr.read().get_position(chunk)?;
+		self.chunks.read().get(chunk).and_then(|c| c.get(voxel))
+	}
+
+	/// Queues the placement of a liquid source.
+	pub fn add_source(&self, position: IVec3, liquid: BlockKey) {
+		self.liquid_mods.write().push(LiquidModification { position, set_to: Some(LiquidVoxel::source(liquid)), });
+	}
+
+	/// Queues the removal of any liquid in a voxel.
+	/// If liquid flows into this voxel then it will flow back in.
+	pub fn remove(&self, position: IVec3) {
+		self.liquid_mods.write().push(LiquidModification { position, set_to: None, });
+	}
+
+	/// Marks a chunk-relative region as needing evaluation.
+	pub fn mark_dirty(&mut self, chunk: ChunkKey, region: DirtyRegion) {
+		if let Some(d) = self.dirty.get_mut(chunk) {
+			*d = d.union(region);
+		} else {
+			self.dirty.insert(chunk, region);
+		}
+	}
+
+	/// Marks a world-relative voxel and its neighbours as needing evaluation.
+	fn mark_dirty_around(&mut self, chunks: &Chunks, voxel: IVec3) {
+		let chunk = chunk_of_voxel(voxel);
+		let relative = voxel_relative_to_chunk(voxel, chunk);
+		// The neighbours may be in other chunks
+		for x in -1..=1 {
+			for y in -1..=1 {
+				for z in -1..=1 {
+					let offset = IVec3::new(x, y, z);
+					let region = DirtyRegion::around(relative - offset * CHUNK_SIZE as i32, 1);
+					if let (Some(k), Some(region)) = (chunks.get_position(chunk + offset), region) {
+						if self.chunks.read().contains_key(k) {
+							self.mark_dirty(k, region);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	/// Removes the liquid of chunks that have been unloaded. 
+	pub fn unload(&mut self, chunks: &Chunks) {
+		let mut liquid_chunks = self.chunks.write();
+		for event in chunks.events.iter() {
+			if let &ChunkEvent::Unloaded(key, _) = event {
+				liquid_chunks.remove(key);
+				self.dirty.remove(key);
+				self.terrain_generations.remove(key);
+			}
+		}
+	}
+
+	/// Marks liquid in and around chunks whose terrain has changed since this last looked. 
+	pub fn terrain_changed(&mut self, chunks: &Chunks, terrain_chunks: &SecondaryMap<ChunkKey, TerrainEntry>) {
+		let changed = {
+			let liquid_chunks = self.chunks.read();
+			liquid_chunks.keys()
+				.filter_map(|key| terrain_chunks.get(key)
+					.and_then(|e| e.complete_ref())
+					.map(|c| (key, c.generation)))
+				.filter(|&(key, generation)| self.terrain_generations.get(key) != Some(&generation))
+				.collect::<Vec<_>>()
+		};
+
+		for (key, generation) in changed {
+			let first_seen = self.terrain_generations.insert(key, generation).is_none();
+			if first_seen {
+				// Liquid is generated in a settled state
+				continue
+			}
+
+			// Liquid in this chunk or its neighbours' borders may now flow
+			let position = chunks.chunks[key];
+			self.mark_dirty(key, DirtyRegion::whole_chunk());
+			for offset in [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z] {
+				let Some(neighbour) = chunks.get_position(position + offset) else { continue };
+				if !self.chunks.read().contains_key(neighbour) {
+					continue
+				}
+				// The two layers of the neighbour facing this chunk
+				let mut region = DirtyRegion::whole_chunk();
+				for axis in 0..3 {
+					match offset[axis] {
+						1 => region.max[axis] = 1,
+						-1 => region.min[axis] = CHUNK_SIZE as i32 - 2,
+						_ => {},
+					}
+				}
+				self.mark_dirty(neighbour, region);
+			}
+		}
+	}
+
+	/// Applies queued modifications, marking them and their neighbours dirty. 
+	pub fn apply_modifications(&mut self, chunks: &Chunks) {
+		let modifications = std::mem::take(&mut *self.liquid_mods.write());
+		for modification in modifications {
+			let chunk = chunk_of_voxel(modification.position);
+			let relative = voxel_relative_to_chunk(modification.position, chunk).as_uvec3();
+			let Some(key) = chunks.get_position(chunk) else {
+				warn!("Liquid modification at {} is not loaded", modification.position);
+				continue
+			};
+			let mut liquid_chunks = self.chunks.write();
+			let Some(liquid_chunk) = liquid_chunks.get_mut(key) else {
+				warn!("Liquid modification at {} has not been generated", modification.position);
+				continue
+			};
+			let inner = Arc::make_mut(liquid_chunk);
+			inner.set(relative, modification.set_to);
+			inner.generation.increment();
+			inner.modified = true;
+			drop(liquid_chunks);
+			self.mark_dirty_around(chunks, modification.position);
+		}
+	}
+
+	/// Evaluates the dirty regions once. 
+	///
+	/// Dirty chunks are evaluated in parallel against a snapshot of the world.
+	/// Voxels that change mark themselves and their neighbours dirty for the next step.
+	/// Gives the number of voxels that changed. 
+	pub fn step(&mut self, chunks: &Chunks, blocks: &BlockManager, terrain_chunks: &SecondaryMap<ChunkKey, TerrainEntry>) -> usize {
+		if self.dirty.is_empty() {
+			return 0;
+		}
+		let start = Instant::now();
+
+		let specifications = blocks.blocks.iter()
+			.filter_map(|(key, entry)| entry.specification.liquid.map(|l| (key, l)))
+			.collect::<HashMap<_, _>>();
+		let dirty = std::mem::take(&mut self.dirty).into_iter().collect::<Vec<_>>();
+
+		let changes = {
+			let snapshot = self.chunks.read().clone();
+			let view = LiquidView {
+				chunks,
+				terrain: terrain_chunks,
+				liquids: &snapshot,
+				specifications: &specifications,
+			};
+
+			dirty.par_iter()
+				.map(|&(key, region)| {
+					let base = chunks.chunks[key] * CHUNK_SIZE as i32;
+					let current = &view.liquids[key];
+					let changes = region.iter()
+						.map(|p| (p, view.evaluate(base + p.as_ivec3())))
+						.filter(|&(p, l)| current.get(p) != l)
+						.collect::<Vec<_>>();
+					(key, changes)
+				})
+				.collect::<Vec<_>>()
+		};
+
+		let mut n_changed = 0;
+		for (key, changes) in changes {
+			if changes.is_empty() {
+				continue
+			}
+			n_changed += changes.len();
+			let base = chunks.chunks[key] * CHUNK_SIZE as i32;
+			{
+				let mut liquid_chunks = self.chunks.write();
+				let inner = Arc::make_mut(liquid_chunks.get_mut(key).unwrap());
+				for &(p, l) in changes.iter() {
+					inner.set(p, l);
+				}
+				inner.generation.increment();
+				inner.modified = true;
+			}
+			for (p, _) in changes {
+				self.mark_dirty_around(chunks, base + p.as_ivec3());
+			}
+		}
+
+		self.last_step_chunks = dirty.len();
+		self.last_step_voxels = n_changed;
+		self.last_step_duration = start.elapsed();
+		trace!("Liquid step evaluated {} chunks, changed {} voxels", dirty.len(), n_changed);
+		n_changed
+	}
+
+	pub fn approximate_size(&self) -> usize {
+		self.chunks.read().values().map(|c| c.size()).sum()
+	}
+}
+impl StorageCommandExpose for LiquidsResource {
+	// resource LiquidsResource set ticks_per_step 5
+	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
+		match command[0] {
+			"set" => match command.get(1).copied() {
+				Some("ticks_per_step") => if let Some(v) = command.get(2) {
+						let v = v.parse::<u32>()?;
+						if v == 0 {
+							return Err(anyhow::anyhow!("Must be at least 1"));
+						}
+						self.ticks_per_step = v;
+						Ok(format!("LiquidsResource ticks_per_step {}", v))
+					} else {
+						Err(anyhow::anyhow!("Give a set value"))
+					},
+				_ => Err(anyhow::anyhow!("Unknown field")),
+			},
+			"stats" => {
+				let chunks = self.chunks.read();
+				Ok([
+					format!("chunks: {}", chunks.len()),
+					format!("chunks_with_liquid: {}", chunks.values().filter(|c| !c.is_empty()).count()),
+					format!("dirty_chunks: {}", self.dirty.len()),
+					format!("ticks_per_step: {}", self.ticks_per_step),
+					format!("last_step_chunks: {}", self.last_step_chunks),
+					format!("last_step_voxels: {}", self.last_step_voxels),
+					format!("last_step_duration: {:.1}ms", self.last_step_duration.as_secs_f32() * 1000.0),
+					format!("approx_size: {}", self.approximate_size()),
+				].join("\n"))
+			},
+			_ => Err(anyhow::anyhow!("Unknown command")),
+		}
+	}
+}
+
+
+/// Removes liquid storage for non-existing chunks.
+/// Liquid chunks are created alongside terrain in [crate::terrain::terrain_loading_system].
+/// Marks liquid as dirty where its terrain has changed.
+pub fn liquid_loading_system(
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	mut liquids: ResMut<LiquidsResource>,
+) {
+	let chunks = chunks.read();
+	liquids.unload(&chunks);
+	liquids.terrain_changed(&chunks, &terrain.chunks.read());
+}
+
+
+/// Steps the simulation of liquids every [LiquidsResource::ticks_per_step] ticks.
+pub fn liquid_update_system(
+	chunks: Res<ChunksResource>,
+	blocks: Res<BlockResource>,
+	terrain: Res<TerrainResource>,
+	mut liquids: ResMut<LiquidsResource>,
+) {
+	let chunks = chunks.read();
+	liquids.apply_modifications(&chunks);
+
+	liquids.ticks += 1;
+	if liquids.ticks < liquids.ticks_per_step {
+		return;
+	}
+	liquids.ticks = 0;
+
+	liquids.step(&chunks, &blocks.read(), &terrain.chunks.read());
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slotmap::KeyData;
+	use crate::terrain::{TerrainChunk, TerrainContents};
+
+	// One chunk with a stone floor at y = 0, surrounded by unloaded chunks that liquid treats as solid
+	struct Basin {
+		chunks: ChunksResource,
+		terrain: SecondaryMap<ChunkKey, TerrainEntry>,
+		liquids: LiquidsResource,
+		blocks: BlockManager,
+		key: ChunkKey,
+		stone: BlockKey,
+		water: BlockKey,
+	}
+	impl Basin {
+		fn new(walls: &[IVec3]) -> Self {
+			let stone = BlockKey::from(KeyData::from_ffi(1));
+			let water = BlockKey::from(KeyData::from_ffi(2));
+			let chunks = ChunksResource::new();
+			let key = chunks.write().load(IVec3::ZERO);
+			let mut contents = TerrainContents::new();
+			for x in 0..CHUNK_SIZE {
+				for z in 0..CHUNK_SIZE {
+					contents.insert(UVec3::new(x, 0, z), stone);
+				}
+			}
+			for w in walls {
+				contents.insert(w.as_uvec3(), stone);
+			}
+			let mut terrain = SecondaryMap::new();
+			terrain.insert(key, TerrainEntry::Complete(Arc::new(TerrainChunk::new(contents))));
+			let mut liquids = LiquidsResource::new();
+			liquids.chunks.write().insert(key, Arc::new(LiquidChunk::new(LiquidContents::new())));
+			// Sees the generated terrain
+			liquids.terrain_changed(&chunks.read(), &terrain);
+			Self { chunks, terrain, liquids, blocks: BlockManager::new(), key, stone, water, }
+		}
+
+		// Steps until nothing changes, giving the number of steps
+		fn settle(&mut self) -> usize {
+			let chunks = self.chunks.read();
+			self.liquids.apply_modifications(&chunks);
+			for i in 0..1000 {
+				if self.liquids.step(&chunks, &self.blocks, &self.terrain) == 0 {
+					assert!(self.liquids.dirty.is_empty());
+					return i;
+				}
+			}
+			panic!("Liquid did not settle");
+		}
+
+		fn get(&self, voxel: IVec3) -> Option<LiquidVoxel> {
+			self.liquids.get_liquid(&self.chunks, voxel)
+		}
+
+		fn level(&self, voxel: IVec3) -> Option<u8> {
+			self.get(voxel).map(|l| l.level)
+		}
+
+		fn set_terrain(&mut self, voxel: IVec3, block: Option<BlockKey>) {
+			let TerrainEntry::Complete(chunk) = &mut self.terrain[self.key] else { unreachable!() };
+			let inner = Arc::make_mut(chunk);
+			let p = voxel.as_uvec3();
+			match block {
+				Some(b) => inner.insert(p, b),
+				None => inner.remove(p),
+			}
+			inner.record_change(p, p);
+			self.liquids.terrain_changed(&self.chunks.read(), &self.terrain);
+		}
+	}
+
+	#[test]
+	fn test_liquid_spreads_and_settles() {
+		let mut basin = Basin::new(&[]);
+		basin.liquids.add_source(IVec3::new(8, 1, 8), basin.water);
+		assert!(basin.settle() > 0);
+
+		assert_eq!(Some(LiquidVoxel::source(basin.water)), basin.get(IVec3::new(8, 1, 8)));
+		// Levels drop by one for each voxel it spreads along the floor
+		for k in 1..8 {
+			let flowing = basin.get(IVec3::new(8 + k, 1, 8)).unwrap();
+			assert!(!flowing.source);
+			assert_eq!(15 - k as u8, flowing.level);
+			assert_eq!(Some(15 - k as u8), basin.level(IVec3::new(8, 1, 8 - k)));
+		}
+		assert_eq!(Some(13), basin.level(IVec3::new(9, 1, 9)));
+		// It does not climb
+		assert_eq!(None, basin.get(IVec3::new(8, 2, 8)));
+		// Settled liquid is not evaluated again
+		assert_eq!(0, basin.settle());
+	}
+
+	#[test]
+	fn test_liquid_falls() {
+		let mut basin = Basin::new(&[]);
+		basin.liquids.add_source(IVec3::new(8, 5, 8), basin.water);
+		basin.settle();
+		for y in 1..5 {
+			let falling = basin.get(IVec3::new(8, y, 8)).unwrap();
+			assert_eq!((LIQUID_LEVEL_MAX, false), (falling.level, falling.source), "y = {y}");
+		}
+		assert_eq!(None, basin.get(IVec3::new(8, 6, 8)));
+	}
+
+	#[test]
+	fn test_liquid_drains() {
+		let mut basin = Basin::new(&[]);
+		basin.liquids.add_source(IVec3::new(8, 1, 8), basin.water);
+		basin.settle();
+		assert!(basin.liquids.dirty.is_empty());
+
+		// Removal marks only the voxel and its neighbours
+		basin.liquids.remove(IVec3::new(8, 1, 8));
+		basin.liquids.apply_modifications(&basin.chunks.read());
+		assert_eq!(Some(&DirtyRegion { min: IVec3::new(7, 0, 7), max: IVec3::new(9, 2, 9) }), basin.liquids.dirty.get(basin.key));
+
+		// Without a source the flowing liquid runs out
+		basin.settle();
+		assert_eq!(0, basin.liquids.chunks.read()[basin.key].count());
+		assert!(basin.liquids.chunks.read()[basin.key].modified);
+	}
+
+	#[test]
+	fn test_liquid_terrain_changes() {
+		// A wall across the basin at x = 5
+		let wall = (0..CHUNK_SIZE as i32).map(|z| IVec3::new(5, 1, z)).collect::<Vec<_>>();
+		let mut basin = Basin::new(&wall);
+		basin.liquids.add_source(IVec3::new(2, 1, 8), basin.water);
+		basin.settle();
+		assert_eq!(Some(13), basin.level(IVec3::new(4, 1, 8)));
+		assert_eq!(None, basin.get(IVec3::new(6, 1, 8)));
+
+		// It flows through a hole in the wall
+		basin.set_terrain(IVec3::new(5, 1, 8), None);
+		assert!(basin.liquids.dirty.contains_key(basin.key));
+		basin.settle();
+		assert_eq!(Some(12), basin.level(IVec3::new(5, 1, 8)));
+		assert_eq!(Some(11), basin.level(IVec3::new(6, 1, 8)));
+
+		// And is pushed out by terrain placed in it
+		let stone = basin.stone;
+		basin.set_terrain(IVec3::new(3, 1, 8), Some(stone));
+		basin.settle();
+		assert_eq!(None, basin.get(IVec3::new(3, 1, 8)));
+		// Which the liquid now has to flow around
+		assert_eq!(Some(11), basin.level(IVec3::new(4, 1, 8)));
+	}
+
+	#[test]
+	fn test_liquid_contents_run_length() {
+		let water = BlockKey::from(KeyData::from_ffi(1));
+		let contents = LiquidContents::Filled(water);
+		assert!(matches!(LiquidContents::run_length_decode(&contents.run_length_encode()), LiquidContents::Filled(w) if w == water));
+
+		let mut contents = LiquidContents::new();
+		let flowing = LiquidVoxel { liquid: water, level: 3, source: false, };
+		contents.set(UVec3::new(1, 2, 3), Some(flowing));
+		contents.set(UVec3::new(15, 0, 9), Some(LiquidVoxel::source(water)));
+		let decoded = LiquidContents::run_length_decode(&contents.run_length_encode());
+		assert_eq!(2, decoded.count());
+		assert_eq!(Some(flowing), decoded.get(UVec3::new(1, 2, 3)));
+		assert_eq!(Some(LiquidVoxel::source(water)), decoded.get(UVec3::new(15, 0, 9)));
+	}
+
+	#[test]
+	fn test_liquid_contents_set() {
+		let water = BlockKey::from(KeyData::from_ffi(1));
+		let mut contents = LiquidContents::Filled(water);
+		assert_eq!(CHUNK_SIZE.pow(3) as usize, contents.count());
+
+		contents.set(UVec3::ZERO, None);
+		assert!(matches!(contents, LiquidContents::Mixed(_, _)));
+		assert_eq!(CHUNK_SIZE.pow(3) as usize - 1, contents.count());
+		assert_eq!(None, contents.get(UVec3::ZERO));
+		assert_eq!(Some(LiquidVoxel::source(water)), contents.get(UVec3::ONE));
+
+		let mut contents = LiquidContents::new();
+		let flowing = LiquidVoxel { liquid: water, level: 3, source: false, };
+		contents.set(UVec3::new(1, 2, 3), Some(flowing));
+		assert_eq!(1, contents.count());
+		assert_eq!(Some(flowing), contents.get(UVec3::new(1, 2, 3)));
+		contents.set(UVec3::new(1, 2, 3), None);
+		assert!(contents.is_empty());
+	}
+}
@@ -1,10 +1,10 @@
 use std::{time::{Instant, Duration}, sync::Arc};
-use chunks::{blocks::{BlockKey, BlockResource}, chunk_of_voxel, chunks::ChunksResource, fvt::FVTIterator, CHUNK_SIZE};
+use chunks::{blocks::{BlockKey, BlockResource}, chunk_of_voxel, states::BlockState, chunks::ChunksResource, CHUNK_SIZE};
 use pinecore::controls::{ControlComponent, ControlKey, ControlMap, KeyCode, KeyCombo, KeyKey, KeyModifiers};
 use eeks::prelude::*;
-use glam::{IVec3, Vec3};
+use glam::{IVec3, UVec3, Vec3};
 use pinecore::transform::TransformComponent;
-use crate::terrain::{TerrainEntry, TerrainResource};
+use crate::{editor::{EditCommand, EditShape, TerrainEditorResource}, events::{BlockEventKind, BlockEventResource}, liquids::LiquidsResource, raycast::{Ray, RaycastFilter, RaycastHit}, terrain::{TerrainEntry, TerrainResource}};
 
 
 
@@ -12,7 +12,14 @@ use crate::terrain::{TerrainEntry, TerrainResource};
 pub struct VoxelModification {
 	pub position: IVec3, // Usually world-relative, but it's left unclear so we don't have to write as much code
 	pub set_to: Option<BlockKey>,
+	// The state of the new block
+	pub state: BlockState,
 	pub priority: u32,
+	// Whoever caused this modification
+	pub entity: Option<Entity>,
+	// Should this create block events?
+	// False for modifications that have already had their events resolved
+	pub emit_events: bool,
 }
 impl VoxelModification {
 	// This should return another type of struct but I'm lazy
@@ -25,9 +32,15 @@ impl VoxelModification {
 
 
 #[derive(Debug, Component)]
+#[sda(commands = true)]
 pub struct VoxelModifierComponent {
 	pub place: ControlKey,
 	pub remove: ControlKey,
+	pub interact: ControlKey,
+	pub undo: ControlKey,
+	pub redo: ControlKey,
+	// The name of the block to place
+	pub block: String,
 	pub last_modification: Option<Instant>
 }
 impl VoxelModifierComponent {
@@ -56,13 +69,67 @@ impl VoxelModifierComponent {
 			control
 		};
 
+		let interact = {
+			let control = control_map.new_control(
+				"interact with voxel", 
+				"Interact with the voxel you are looking at",
+			);
+			control_map.add_control_binding(control, KeyCombo::new(
+				[KeyKey::BoardKey(KeyCode::KeyF.into())],
+				KeyModifiers::EMPTY,	
+			));
+			control
+		};
+
+		let undo = {
+			let control = control_map.new_control(
+				"undo terrain edit", 
+				"Reverts your last terrain edit",
+			);
+			control_map.add_control_binding(control, KeyCombo::new(
+				[KeyKey::BoardKey(KeyCode::KeyZ.into())],
+				KeyModifiers::LCtrl.into(),	
+			));
+			control
+		};
+
+		let redo = {
+			let control = control_map.new_control(
+				"redo terrain edit", 
+				"Reapplies your last undone terrain edit",
+			);
+			control_map.add_control_binding(control, KeyCombo::new(
+				[KeyKey::BoardKey(KeyCode::KeyY.into())],
+				KeyModifiers::LCtrl.into(),	
+			));
+			control
+		};
+
 		Self {
 			place, 
 			remove,
+			interact,
+			undo,
+			redo,
+			block: "grass".into(),
 			last_modification: None,
 		}
 	}
 }
+impl StorageCommandExpose for VoxelModifierComponent {
+	// component VoxelModifierComponent (index:0,generation:0) block stone
+	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
+		match command[0] {
+			"block" => if let Some(&block) = command.get(1) {
+				self.block = block.into();
+				Ok(format!("Placing {}", self.block))
+			} else {
+				Ok(format!("Placing {}", self.block))
+			},
+			_ => Err(anyhow::anyhow!("Unknown command")),
+		}
+	}
+}
 
 
 pub fn terrain_placement_queue(
@@ -71,78 +138,245 @@ pub fn terrain_placement_queue(
 	transforms: Comp<TransformComponent>,
 	controls: Comp<ControlComponent>,
 	mut modifiers: CompMut<VoxelModifierComponent>,
+	mut editor: ResMut<TerrainEditorResource>,
 	blocks: Res<BlockResource>,
+	mut events: ResMut<BlockEventResource>,
 ) {
-	for (transform, control, modifier) in (&transforms, &controls, &mut modifiers).iter() {
+	for (entity, (transform, control, modifier)) in (&transforms, &controls, &mut modifiers).iter().with_entities() {
+		let can_modify = modifier.last_modification.and_then(|i| Some(i.elapsed() > Duration::from_secs_f32(0.1))).unwrap_or(true);
+		let ray = Ray::new(transform.translation, transform.rotation.mul_vec3(Vec3::Z));
+
 		// Voxel placement
-		if control.last_tick_pressed(modifier.place) && modifier.last_modification.and_then(|i| Some(i.elapsed() > Duration::from_secs_f32(0.1))).unwrap_or(true) {
+		if control.last_tick_pressed(modifier.place) && can_modify {
 			modifier.last_modification = Some(Instant::now());
 
-			// Find the first filled voxel
-			let v = FVTIterator::new(
-				transform.translation, 
-				transform.rotation.mul_vec3(Vec3::Z), 
-				0.0, 10.0, 1.0,
-			).find(|r| terrain.get_voxel(&cr, r.voxel).is_some());
-
-			if let Some(v) = v {
+			if let Some(v) = modification_target(&terrain, &cr, ray) {
 				let position = v.voxel + v.normal;
-				debug!("Place voxel at {position}");
-				terrain.modify_voxel(VoxelModification {
-					position,
-					set_to: Some(blocks.read().key_by_name(&"grass".to_string()).unwrap()),
-					priority: 0,
-				});
+				// Orient the block from where it was placed
+				let blocks = blocks.read();
+				let block = match blocks.key_by_name(&modifier.block) {
+					Some(key) => {
+						let entry = blocks.get(key).unwrap();
+						let state = entry.states.placement_state(v.normal, transform.rotation.mul_vec3(Vec3::Z));
+						blocks.block_state_string(key, state).unwrap()
+					},
+					None => modifier.block.clone(),
+				};
+				debug!("Place {block} at {position}");
+				editor.edit(Some(entity), EditCommand::Apply(EditShape::Box(position, position), block));
 			}
 		}
 
-		if control.last_tick_pressed(modifier.remove) && modifier.last_modification.and_then(|i| Some(i.elapsed() > Duration::from_secs_f32(0.1))).unwrap_or(true) {
+		if control.last_tick_pressed(modifier.remove) && can_modify {
 			modifier.last_modification = Some(Instant::now());
 
-			// Find the first filled voxel
-			let v = FVTIterator::new(
-				transform.translation, 
-				transform.rotation.mul_vec3(Vec3::Z), 
-				0.0, 10.0, 1.0,
-			).find(|r| terrain.get_voxel(&cr, r.voxel).is_some());
-
-			if let Some(v) = v {
+			if let Some(v) = modification_target(&terrain, &cr, ray) {
 				debug!("Remove voxel at {}", v.voxel);
-				terrain.modify_voxel(VoxelModification {
-					position: v.voxel,
-					set_to: None,
-					priority: 0,
-				});
+				editor.edit(Some(entity), EditCommand::Apply(EditShape::Box(v.voxel, v.voxel), "air".into()));
+			}
+		}
+
+		if control.last_tick_pressed(modifier.interact) {
+			let v = terrain.raycast(&cr, ray, 10.0, RaycastFilter::default());
+
+			if let Some(RaycastHit { voxel: position, block, .. }) = v {
+				let blocks = blocks.read();
+				let entry = blocks.get(block).unwrap();
+				if entry.specification.on_interact {
+					debug!("Interact with {} at {position}", entry.specification.name);
+					events.interact(position, block, &entry.specification.name, Some(entity));
+				}
 			}
 		}
+
+		if control.last_tick_pressed(modifier.undo) {
+			editor.edit(Some(entity), EditCommand::Undo);
+		}
+		if control.last_tick_pressed(modifier.redo) {
+			editor.edit(Some(entity), EditCommand::Redo);
+		}
 	}
 }
 
 
+/// Finds the first filled voxel in front of a modifier. 
+/// The voxel it is in is skipped so that it can't remove the block it is inside of
+/// and always has a face to place against. 
+fn modification_target(terrain: &TerrainResource, cr: &ChunksResource, ray: Ray) -> Option<RaycastHit> {
+	terrain.raycast(cr, ray, 10.0, RaycastFilter::default().from_outside())
+}
+
+
 /// Applies queued voxel modifications. 
+/// Blocks that are liquids become liquid sources instead of terrain. 
+/// Modifications that place or break blocks with event callbacks are held
+/// in [BlockEventResource] until their events are resolved. 
 pub fn terrain_modification_application(
 	chunks: Res<ChunksResource>, 
+	blocks: Res<BlockResource>,
 	terrain: ResMut<TerrainResource>,
+	liquids: Res<LiquidsResource>,
+	mut events: ResMut<BlockEventResource>,
 ) {
 	let chunks = chunks.read();
+	let blocks = blocks.read();
 	let mut terrain_chunks = terrain.chunks.write();
 	let mut mods = terrain.block_mods.write();
 	
 	mods.retain(|c, modifications| {
 		if let Some(TerrainEntry::Complete(chunk)) = chunks.get_position(*c).and_then(|k| terrain_chunks.get_mut(k)) {
-			let inner = Arc::make_mut(chunk);
+			// Bounds of the voxels that were written
+			// Modifications that are deferred or become liquid leave the chunk as it was
+			let mut written: Option<(UVec3, UVec3)> = None;
 			for modification in modifications {
+				let position = *c * CHUNK_SIZE as i32 + modification.position;
+
+				if modification.emit_events {
+					let breaks = chunk.get(modification.position.as_uvec3())
+						.filter(|&b| Some(b) != modification.set_to)
+						.and_then(|b| blocks.get(b).map(|e| (b, e)))
+						.filter(|(_, e)| e.specification.on_break);
+					let places = modification.set_to
+						.and_then(|b| blocks.get(b).map(|e| (b, e)))
+						.filter(|(_, e)| e.specification.on_place);
+					if breaks.is_some() || places.is_some() {
+						let i = events.defer(VoxelModification { position, ..*modification });
+						if let Some((b, e)) = breaks {
+							events.emit(BlockEventKind::Break, b, &e.specification.name, i);
+						}
+						if let Some((b, e)) = places {
+							events.emit(BlockEventKind::Place, b, &e.specification.name, i);
+						}
+						continue
+					}
+				}
+
+				let is_liquid = modification.set_to
+					.and_then(|b| blocks.get(b))
+					.is_some_and(|e| e.specification.liquid.is_some());
+				if is_liquid {
+					liquids.add_source(position, modification.set_to.unwrap());
+					continue
+				}
+
+				let inner = Arc::make_mut(chunk);
+				let p = modification.position.as_uvec3();
+				written = Some(written.map_or((p, p), |(min, max)| (min.min(p), max.max(p))));
 				if let Some(b) = modification.set_to {
-					inner.insert(modification.position.as_uvec3(), b);
+					inner.insert_with_state(p, b, modification.state);
+					// Replace any old instance data
+					match blocks.get(b).and_then(|e| e.specification.instance_data.as_ref()) {
+						Some(defaults) => inner.instances.insert(p, defaults.clone()),
+						None => inner.instances.remove(&p),
+					};
 				} else {
-					inner.remove(modification.position.as_uvec3());
+					inner.remove(p);
+					inner.instances.remove(&p);
 				}
 			}
-			inner.generation.increment();
+			if let Some((min, max)) = written {
+				let inner = Arc::make_mut(chunk);
+				inner.record_change(min, max);
+				inner.modified = true;
+			}
 			
 			false
 		} else {
 			true
 		}
 	});
+	drop(mods);
+
+	terrain.instance_mods.write().retain(|(position, field, value)| {
+		let c = chunk_of_voxel(*position);
+		let Some(TerrainEntry::Complete(chunk)) = chunks.get_position(c).and_then(|k| terrain_chunks.get_mut(k)) else {
+			// Wait for it to be loaded
+			return true;
+		};
+		let inner = Arc::make_mut(chunk);
+		let p = (*position - c * CHUNK_SIZE as i32).as_uvec3();
+		if let Some(data) = inner.instance_mut(p) {
+			data.insert(field.clone(), value.clone());
+			inner.record_change(p, p);
+			inner.modified = true;
+		} else {
+			warn!("Tried to set '{field}' of voxel {position}, which has no instance data");
+		}
+		false
+	});
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{blocks::{BlockManager, BlockRenderType}, test_util::{block_with_events, colour_block, BlockEvents}};
+	use parking_lot::RwLock;
+	use crate::{liquids::{LiquidChunk, LiquidContents}, test_util::terrain};
+
+	#[test]
+	fn test_modify_from_inside_liquid() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let water = colour_block(&mut blocks, "water");
+		let (cr, terrain) = terrain(IVec3::new(-2, 0, 0), IVec3::new(1, 0, 0), &[
+			(IVec3::new(-1, 0, 0), stone),
+			(IVec3::new(-8, 0, 0), stone),
+		]);
+		let liquids = LiquidsResource::new();
+		let key = cr.read().get_position(IVec3::NEG_X).unwrap();
+		liquids.chunks.write().insert(key, Arc::new(LiquidChunk::new(LiquidContents::Filled(water))));
+
+		// The camera is underwater
+		let origin = Vec3::new(-4.5, 0.5, 0.5);
+		assert!(liquids.get_liquid(&cr, origin.floor().as_ivec3()).is_some());
+		let hit = modification_target(&terrain, &cr, Ray::new(origin, Vec3::NEG_X)).unwrap();
+		// Placement goes against the near face and removal takes the block itself
+		assert_eq!(IVec3::new(-7, 0, 0), hit.voxel + hit.normal);
+		assert_eq!(IVec3::new(-8, 0, 0), hit.voxel);
+
+		// From inside of a block the block is skipped
+		let hit = modification_target(&terrain, &cr, Ray::new(Vec3::new(-0.5, 0.5, 0.5), Vec3::NEG_X)).unwrap();
+		assert_eq!((IVec3::new(-8, 0, 0), IVec3::X), (hit.voxel, hit.normal));
+	}
+
+	#[test]
+	fn test_deferred_modifications_leave_chunk() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let events = BlockEvents { on_place: true, on_break: true, ..Default::default() };
+		let flower = block_with_events(&mut blocks, "flower", BlockRenderType::Colour, true, events, "");
+		let (cr, terrain) = terrain(IVec3::ZERO, IVec3::ZERO, &[
+			(IVec3::new(1, 1, 1), flower),
+		]);
+		let key = cr.read().get_position(IVec3::ZERO).unwrap();
+		let generation = |terrain: &TerrainResource| match terrain.chunks.read().get(key) {
+			Some(TerrainEntry::Complete(c)) => (c.generation, c.modified),
+			_ => panic!("chunk is not loaded"),
+		};
+		let start = generation(&terrain);
+
+		// Placing a flower and breaking one both wait for their events
+		let modification = |position, set_to| VoxelModification {
+			position, set_to, state: BlockState::default(), priority: 0, entity: None, emit_events: true,
+		};
+		terrain.modify_voxels(&[
+			modification(IVec3::new(2, 2, 2), Some(flower)),
+			modification(IVec3::new(1, 1, 1), Some(stone)),
+		]);
+
+		let mut ecs = eeks::prelude::World::new();
+		ecs.insert_resource(cr);
+		ecs.insert_resource(terrain);
+		ecs.insert_resource(LiquidsResource::new());
+		ecs.insert_resource(BlockEventResource::default());
+		ecs.insert_resource(BlockResource { blocks: Arc::new(RwLock::new(blocks)) });
+		ecs.run(terrain_modification_application);
+
+		ecs.run(|terrain: Res<TerrainResource>, events: Res<BlockEventResource>| {
+			assert_eq!(2, events.events.len());
+			assert!(terrain.block_mods.read().is_empty());
+			assert_eq!(start, generation(&terrain));
+		});
+	}
 }
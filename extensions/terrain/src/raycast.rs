@@ -0,0 +1,172 @@
+use chunks::{blocks::{BlockKey, BlockManager}, chunk_of_voxel, fvt::FVTIterator, states::BlockState, chunks::ChunksResource, voxel_relative_to_chunk};
+use glam::{IVec3, Vec3};
+use crate::terrain::{TerrainChunk, TerrainResource};
+
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+	pub origin: Vec3,
+	pub direction: Vec3,
+}
+impl Ray {
+	pub fn new(origin: Vec3, direction: Vec3) -> Self {
+		Self { origin, direction: direction.normalize_or_zero() }
+	}
+}
+
+
+/// Which blocks a raycast can hit.
+/// The default hits every block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RaycastFilter<'a> {
+	// If given, blocks that are not solid are passed through
+	pub solid: Option<&'a BlockManager>,
+	pub ignore: &'a [BlockKey],
+	// If set, the voxel that the ray starts in is never hit
+	pub outside: bool,
+}
+impl<'a> RaycastFilter<'a> {
+	pub fn solid(blocks: &'a BlockManager) -> Self {
+		Self { solid: Some(blocks), ..Default::default() }
+	}
+
+	pub fn ignoring(self, ignore: &'a [BlockKey]) -> Self {
+		Self { ignore, ..self }
+	}
+
+	/// Skips the voxel that the ray starts in, so every hit has a normal to place against. 
+	pub fn from_outside(self) -> Self {
+		Self { outside: true, ..self }
+	}
+
+	pub fn hits(&self, block: BlockKey) -> bool {
+		!self.ignore.contains(&block)
+			&& self.solid.map_or(true, |blocks| blocks.get(block).is_some_and(|e| e.is_solid()))
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+	pub block: BlockKey,
+	pub state: BlockState,
+	pub voxel: IVec3,
+	// The face that was entered, zero if the ray started inside of this voxel
+	pub normal: IVec3,
+	// Along the ray to where it entered the voxel
+	pub distance: f32,
+}
+
+
+impl TerrainResource {
+	/// Finds the first block along a ray that passes the filter.
+	/// Unloaded and incomplete chunks are treated as empty.
+	///
+	/// Locks are taken once for the whole ray and the current chunk is kept
+	/// until the ray leaves it, so this is much cheaper than calling
+	/// [TerrainResource::get_voxel] for every step.
+	pub fn raycast(&self, cr: &ChunksResource, ray: Ray, max_distance: f32, filter: RaycastFilter) -> Option<RaycastHit> {
+		// The traversal can't start with these
+		if ray.direction == Vec3::ZERO || !ray.direction.is_finite() || max_distance.is_nan() || max_distance < 0.0 {
+			return None;
+		}
+
+		let chunks = cr.read();
+		let terrain_chunks = self.chunks.read();
+		let mut current: Option<(IVec3, Option<&TerrainChunk>)> = None;
+
+		// The traversal skips the voxel it starts in
+		let start = (ray.origin.floor().as_ivec3(), 0.0, IVec3::ZERO);
+		let steps = FVTIterator::new(ray.origin, ray.direction, 0.0, max_distance, 1.0)
+			.map(|i| (i.voxel, i.t, i.normal));
+		let start = (!filter.outside).then_some(start);
+		for (voxel, distance, normal) in start.into_iter().chain(steps) {
+			let c = chunk_of_voxel(voxel);
+			if current.map(|(p, _)| p) != Some(c) {
+				let chunk = chunks.get_position(c)
+					.and_then(|key| terrain_chunks.get(key))
+					.and_then(|entry| entry.complete_ref())
+					.map(|chunk| chunk.as_ref());
+				current = Some((c, chunk));
+			}
+			let Some((_, Some(chunk))) = current else {
+				continue
+			};
+
+			let p = voxel_relative_to_chunk(voxel, c).as_uvec3();
+			if let Some(block) = chunk.get(p).filter(|&b| filter.hits(b)) {
+				return Some(RaycastHit { block, state: chunk.get_state(p), voxel, normal, distance });
+			}
+		}
+		None
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{blocks::{BlockRenderType, PathOrLabel}, test_util::{block, colour_block}, CHUNK_SIZE};
+	use crate::test_util;
+
+	// The chunks from -2 to 1 along x
+	fn terrain(voxels: &[(IVec3, BlockKey)]) -> (ChunksResource, TerrainResource) {
+		test_util::terrain(IVec3::new(-2, 0, 0), IVec3::new(1, 0, 0), voxels)
+	}
+
+	#[test]
+	fn test_raycast_negative_chunks() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let far = -(CHUNK_SIZE as i32) - 8;
+		let (cr, terrain) = terrain(&[(IVec3::new(far, 0, 0), stone)]);
+
+		let ray = Ray::new(Vec3::new(0.5, 0.5, 0.5), Vec3::NEG_X);
+		let hit = terrain.raycast(&cr, ray, 100.0, RaycastFilter::default()).unwrap();
+		assert_eq!(stone, hit.block);
+		assert_eq!(IVec3::new(far, 0, 0), hit.voxel);
+		assert_eq!(IVec3::X, hit.normal);
+		assert_eq!(39.5, hit.distance);
+
+		// Too short to get there
+		assert_eq!(None, terrain.raycast(&cr, ray, 39.0, RaycastFilter::default()));
+		// Going the other way leaves the loaded chunks
+		assert_eq!(None, terrain.raycast(&cr, Ray::new(ray.origin, Vec3::X), 1000.0, RaycastFilter::default()));
+
+		// Across a chunk boundary in z too
+		let ray = Ray::new(Vec3::new(far as f32 + 0.5, 0.5, 20.5), Vec3::new(0.0, 0.0, -1.0));
+		assert_eq!(Some(IVec3::new(far, 0, 0)), terrain.raycast(&cr, ray, 100.0, RaycastFilter::default()).map(|h| h.voxel));
+	}
+
+	#[test]
+	fn test_raycast_filters() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let glass = colour_block(&mut blocks, "glass");
+		let sign = block(&mut blocks, "sign", BlockRenderType::Model {
+			mesh: PathOrLabel::Label("sign".into()),
+			material: Default::default(),
+			covers: [false; 6],
+		}, true, "");
+		let (cr, terrain) = terrain(&[
+			(IVec3::new(-1, 0, 0), sign),
+			(IVec3::new(-40, 0, 0), glass),
+			(IVec3::new(-50, 0, 0), stone),
+		]);
+		let ray = Ray::new(Vec3::new(-0.5, 0.5, 0.5), Vec3::NEG_X);
+
+		// Starts inside of the sign
+		let hit = terrain.raycast(&cr, ray, 100.0, RaycastFilter::default()).unwrap();
+		assert_eq!((sign, IVec3::ZERO, 0.0), (hit.block, hit.normal, hit.distance));
+
+		let hit = terrain.raycast(&cr, ray, 100.0, RaycastFilter::default().from_outside()).unwrap();
+		assert_eq!((glass, IVec3::X), (hit.block, hit.normal));
+
+		let hit = terrain.raycast(&cr, ray, 100.0, RaycastFilter::solid(&blocks)).unwrap();
+		assert_eq!(glass, hit.block);
+
+		let hit = terrain.raycast(&cr, ray, 100.0, RaycastFilter::solid(&blocks).ignoring(&[glass])).unwrap();
+		assert_eq!((stone, IVec3::new(-50, 0, 0)), (hit.block, hit.voxel));
+	}
+}
@@ -0,0 +1,172 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use chunks::{blocks::{BlockKey, BlockManager}, instance::BlockInstanceData, states::BlockState};
+use eeks::prelude::*;
+use glam::{IVec3, UVec3};
+use crate::{liquids::{LiquidContents, LiquidVoxel}, terrain::{TerrainChunk, TerrainContents}};
+
+
+
+/// A terrain chunk as it is stored on disk.
+///
+/// Block keys are not stable between runs, so blocks are stored by name.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SavedTerrainChunk {
+	// Block names
+	pub palette: Vec<String>,
+	// Run length encoded palette indices
+	pub runs: Vec<(Option<u32>, u32)>,
+	// Chunk-relative position and data
+	pub instances: Vec<([u32; 3], BlockInstanceData)>,
+	// Chunk-relative position and state for voxels not in their default state
+	pub states: Vec<([u32; 3], BlockState)>,
+	// Run length encoded liquid as (palette index, level, source)
+	pub liquid_runs: Vec<(Option<(u32, u8, bool)>, u32)>,
+}
+impl SavedTerrainChunk {
+	pub fn new(chunk: &TerrainChunk, liquid: &LiquidContents, blocks: &BlockManager) -> Self {
+		let mut palette = Vec::new();
+		let mut palette_indices = HashMap::new();
+		let mut index_of = |key: BlockKey| *palette_indices.entry(key).or_insert_with(|| {
+			let name = blocks.get(key).map(|e| e.specification.name.clone()).unwrap_or_else(|| {
+				warn!("Saving a chunk with an unknown block");
+				"unknown".into()
+			});
+			palette.push(name);
+			palette.len() as u32 - 1
+		});
+		let runs = chunk.run_length_encode().into_iter()
+			.map(|(key, length)| (key.map(&mut index_of), length))
+			.collect();
+		let liquid_runs = liquid.run_length_encode().into_iter()
+			.map(|(v, length)| (v.map(|v| (index_of(v.liquid), v.level, v.source)), length))
+			.collect();
+
+		let mut instances = chunk.instances.iter()
+			.map(|(p, d)| (p.to_array(), d.clone()))
+			.collect::<Vec<_>>();
+		instances.sort_by_key(|&(p, _)| p);
+
+		let states = chunk.states()
+			.map(|(p, s)| (p.to_array(), s))
+			.collect();
+
+		Self { palette, runs, instances, states, liquid_runs, }
+	}
+
+	/// Creates a terrain chunk and its liquid.
+	/// Blocks that no longer exist are removed.
+	pub fn restore(self, blocks: &BlockManager) -> anyhow::Result<(TerrainChunk, LiquidContents)> {
+		let keys = self.palette.iter().map(|name| {
+			let key = blocks.key_by_name(name);
+			if key.is_none() {
+				warn!("Saved chunk uses unknown block '{name}', it will be removed");
+			}
+			key
+		}).collect::<Vec<Option<BlockKey>>>();
+
+		let mut length = 0;
+		let rle = self.runs.into_iter().map(|(index, run)| {
+			length += run as usize;
+			let key = match index {
+				Some(i) => *keys.get(i as usize)
+					.ok_or_else(|| anyhow::anyhow!("Palette index {i} is out of bounds"))?,
+				None => None,
+			};
+			Ok((key, run))
+		}).collect::<anyhow::Result<Vec<_>>>()?;
+		if length != chunks::CHUNK_SIZE.pow(3) as usize {
+			return Err(anyhow::anyhow!("Saved chunk has {length} voxels"));
+		}
+
+		let mut chunk = TerrainChunk::new(TerrainContents::run_length_decode(&rle));
+		for (p, state) in self.states {
+			let p = UVec3::from_array(p);
+			// Skip states that the block no longer has
+			let Some(key) = chunk.get(p) else { continue };
+			if blocks.get(key).is_some_and(|e| (state.0 as usize) < e.states.count()) {
+				chunk.insert_with_state(p, key, state);
+			}
+		}
+		chunk.instances = self.instances.into_iter()
+			.map(|(p, d)| (UVec3::from_array(p), d))
+			.filter(|&(p, _)| chunk.get(p).is_some())
+			.collect();
+
+		let mut length = 0;
+		let liquid_rle = self.liquid_runs.into_iter().map(|(v, run)| {
+			length += run as usize;
+			let v = match v {
+				Some((i, level, source)) => keys.get(i as usize)
+					.ok_or_else(|| anyhow::anyhow!("Palette index {i} is out of bounds"))?
+					.map(|liquid| LiquidVoxel { liquid, level, source, }),
+				None => None,
+			};
+			Ok((v, run))
+		}).collect::<anyhow::Result<Vec<_>>>()?;
+		if length != chunks::CHUNK_SIZE.pow(3) as usize {
+			return Err(anyhow::anyhow!("Saved liquid has {length} voxels"));
+		}
+
+		Ok((chunk, LiquidContents::run_length_decode(&liquid_rle)))
+	}
+
+	pub fn path(directory: impl AsRef<Path>, position: IVec3) -> PathBuf {
+		directory.as_ref().join(format!("{}_{}_{}.chunk", position.x, position.y, position.z))
+	}
+
+	pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+		let b = std::fs::read(path.as_ref())?;
+		let s = bincode::deserialize(b.as_slice())?;
+		Ok(s)
+	}
+
+	pub fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+		let path = path.as_ref();
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		std::fs::write(path, bincode::serialize(self)?)?;
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::{instance::InstanceValue, test_util::colour_block};
+
+	#[test]
+	fn test_saved_chunk_round_trip() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let sign = colour_block(&mut blocks, "sign");
+
+		let mut contents = TerrainContents::new();
+		contents.insert(UVec3::new(0, 0, 0), stone);
+		contents.insert(UVec3::new(1, 2, 3), sign);
+		let mut chunk = TerrainChunk::new(contents);
+		chunk.instances.insert(UVec3::new(1, 2, 3), BlockInstanceData::from([
+			("text".to_string(), InstanceValue::Text("hello".into())),
+		]));
+
+		let water = colour_block(&mut blocks, "water");
+		let mut liquid = LiquidContents::new();
+		liquid.set(UVec3::new(4, 5, 6), Some(LiquidVoxel::source(water)));
+		liquid.set(UVec3::new(4, 4, 6), Some(LiquidVoxel { liquid: water, level: 7, source: false, }));
+
+		let saved = SavedTerrainChunk::new(&chunk, &liquid, &blocks);
+		assert_eq!(3, saved.palette.len());
+		let b = bincode::serialize(&saved).unwrap();
+		let (restored, restored_liquid) = bincode::deserialize::<SavedTerrainChunk>(&b).unwrap()
+			.restore(&blocks).unwrap();
+		assert_eq!(liquid.run_length_encode(), restored_liquid.run_length_encode());
+
+		assert_eq!(Some(stone), restored.get(UVec3::new(0, 0, 0)));
+		assert_eq!(Some(sign), restored.get(UVec3::new(1, 2, 3)));
+		assert_eq!(None, restored.get(UVec3::new(1, 1, 1)));
+		assert_eq!(chunk.instances, restored.instances);
+		// Its file is already up to date
+		assert!(!restored.modified);
+	}
+}
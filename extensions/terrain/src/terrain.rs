@@ -1,11 +1,11 @@
-use std::{sync::Arc, collections::HashMap, time::Instant};
-use chunks::{blocks::{BlockKey, BlockResource}, chunk_of_voxel, chunks::{ChunkKey, ChunksResource}, generation::KGeneration, voxel_relative_to_chunk, CHUNK_SIZE};
+use std::{sync::Arc, collections::HashMap, path::{Path, PathBuf}, time::Instant};
+use chunks::{array_volume::VoxelVolume, blocks::{BlockKey, BlockManager, BlockResource}, chunk_of_voxel, cube_iterator_xyz_uvec, instance::{BlockInstanceData, InstanceValue}, octree::Octree, states::BlockState, chunks::{ChunkEvent, ChunkKey, ChunksResource}, generation::{ChangeHistory, KGeneration}, voxel_relative_to_chunk, CHUNK_SIZE};
 use crossbeam_channel::{Sender, Receiver, unbounded};
 use eeks::prelude::*;
-use glam::{IVec3, UVec3};
+use glam::{IVec2, IVec3, UVec2, UVec3};
 use parking_lot::RwLock;
 use slotmap::SecondaryMap;
-use crate::{generator::NewTerrainGenerator, modification::VoxelModification};
+use crate::{biomes::BiomeBlocks, generator::NewTerrainGenerator, saving::SavedTerrainChunk, liquids::{LiquidChunk, LiquidContents, LiquidsResource}, modification::VoxelModification};
 
 
 
@@ -16,12 +16,15 @@ pub struct TerrainContents {
 	// TODO: that 
 	contents: Option<Box<[Option<BlockKey>]>>,
 	contents_count: usize,
+	// Most blocks have only one state, so this is only allocated if needed
+	states: Option<Box<[BlockState]>>,
 }
 impl TerrainContents {
 	pub fn new() -> Self {
 		Self { 
 			contents: None, 
 			contents_count: 0,
+			states: None,
 		}
 	}
 
@@ -43,11 +46,32 @@ impl TerrainContents {
 		self.contents.as_ref().and_then(|c| c[i])
 	}
 	
+	pub fn get_state(&self, position: UVec3) -> BlockState {
+		let i = self.index_of(position);
+		self.states.as_ref().map(|s| s[i]).unwrap_or_default()
+	}
+	
+	/// Inserts a block in its default state.
 	pub fn insert(&mut self, position: UVec3, data: BlockKey) {
+		self.insert_with_state(position, data, BlockState::default());
+	}
+
+	pub fn insert_with_state(&mut self, position: UVec3, data: BlockKey, state: BlockState) {
 		let i = self.index_of(position);
 		let c = self.contents.get_or_insert_with(|| vec![None; CHUNK_SIZE.pow(3) as usize].into_boxed_slice());
-		c[i] = Some(data);
-		self.contents_count += 1;
+		if c[i].replace(data).is_none() {
+			self.contents_count += 1;
+		}
+		self.set_state(i, state);
+	}
+
+	fn set_state(&mut self, i: usize, state: BlockState) {
+		if state != BlockState::default() {
+			let s = self.states.get_or_insert_with(|| vec![BlockState::default(); CHUNK_SIZE.pow(3) as usize].into_boxed_slice());
+			s[i] = state;
+		} else if let Some(s) = self.states.as_mut() {
+			s[i] = state;
+		}
 	}
 
 	pub fn remove(&mut self, position: UVec3) {
@@ -55,19 +79,34 @@ impl TerrainContents {
 		if let Some(c) = self.contents.as_mut() {
 			if c[i].take().is_some() {
 				self.contents_count -= 1;
+				self.set_state(i, BlockState::default());
 				// If no contents remain, deallocate
 				if self.contents_count == 0 {
 					self.contents.take();
+					self.states.take();
 				}
 			}
 		}
 	}
 
+	/// Positions and states of voxels that are not in their default state. 
+	pub fn states(&self) -> impl Iterator<Item = (UVec3, BlockState)> + '_ {
+		self.states.iter().flat_map(|s| s.iter().copied().enumerate())
+			.filter(|&(_, s)| s != BlockState::default())
+			.map(|(i, s)| {
+				let i = i as u32;
+				(UVec3::new(i / (CHUNK_SIZE * CHUNK_SIZE), (i / CHUNK_SIZE) % CHUNK_SIZE, i % CHUNK_SIZE), s)
+			})
+	}
+
 	pub fn size(&self) -> usize {
 		let mut base = std::mem::size_of::<Self>();
 		if let Some(c) = self.contents.as_ref() {
 			base += c.len() * std::mem::size_of::<Option<BlockKey>>();
 		}
+		if let Some(s) = self.states.as_ref() {
+			base += s.len() * std::mem::size_of::<BlockState>();
+		}
 		base
 	}
 
@@ -110,13 +149,71 @@ impl TerrainContents {
 		}
 		s
 	}
+
+	/// Block states are not kept. 
+	pub fn to_octree(&self) -> Octree<BlockKey> {
+		let mut octree = Octree::new(CHUNK_SIZE.trailing_zeros());
+		if !self.is_empty() {
+			for p in cube_iterator_xyz_uvec(UVec3::splat(CHUNK_SIZE)) {
+				if let Some(key) = self.get(p) {
+					octree.insert(p, key);
+				}
+			}
+		}
+		octree
+	}
+
+	pub fn from_octree(octree: &Octree<BlockKey>) -> Self {
+		assert_eq!(CHUNK_SIZE, octree.edge_length(), "Octree is not the size of a chunk");
+		let mut s = Self::new();
+		for (p, &key) in octree.iter() {
+			s.insert(p, key);
+		}
+		s
+	}
 }
 
 
 #[derive(Debug, Clone)]
 pub struct TerrainChunk {
 	contents: TerrainContents,
+	// Block instance data by chunk-relative position
+	pub instances: HashMap<UVec3, BlockInstanceData>,
 	pub generation: KGeneration,
+	// Has this changed since it was generated? 
+	// If so, it is saved when unloaded
+	pub modified: bool,
+	changes: ChangeHistory,
+}
+impl TerrainChunk {
+	pub fn new(contents: TerrainContents) -> Self {
+		Self {
+			contents,
+			instances: HashMap::new(),
+			generation: KGeneration::new(),
+			modified: false,
+			changes: ChangeHistory::new(),
+		}
+	}
+
+	/// Increments the generation and remembers which voxels were changed. 
+	pub fn record_change(&mut self, min: UVec3, max: UVec3) {
+		self.changes.record(&mut self.generation, min, max);
+	}
+
+	/// The bounds of the voxels changed since a generation, or None if it is current. 
+	/// If the changes aren't all known then this is the whole chunk. 
+	pub fn changed_since(&self, generation: KGeneration) -> Option<(UVec3, UVec3)> {
+		self.changes.changed_since(self.generation, generation)
+	}
+
+	pub fn instance(&self, position: UVec3) -> Option<&BlockInstanceData> {
+		self.instances.get(&position)
+	}
+
+	pub fn instance_mut(&mut self, position: UVec3) -> Option<&mut BlockInstanceData> {
+		self.instances.get_mut(&position)
+	}
 }
 impl std::ops::Deref for TerrainChunk {
 	type Target = TerrainContents;
@@ -141,12 +238,29 @@ pub enum TerrainEntry {
 
 
 #[derive(Debug, Default, Resource)]
-#[sda(commands = true)]
+#[sda(commands = true, lua = true)]
 pub struct TerrainResource {
 	pub chunks: Arc<RwLock<SecondaryMap<ChunkKey, TerrainEntry>>>,
 	pub block_mods: RwLock<HashMap<IVec3, Vec<VoxelModification>>>,
+	// World-relative position, field, value
+	pub instance_mods: RwLock<Vec<(IVec3, String, InstanceValue)>>,
+	// Modified chunks are saved here when unloaded and on shutdown, nothing is saved if None
+	pub save_directory: Option<PathBuf>,
 }
 impl TerrainResource {
+	pub fn get_voxel_state(&self, cr: &ChunksResource, voxel: IVec3) -> Option<(BlockKey, BlockState)> {
+		let chunk = chunk_of_voxel(voxel);
+		let voxel = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
+
+		let chunks = self.chunks.read();
+		let terrain_chunk = cr.read().get_position(chunk)
+			.and_then(|chunk_key| chunks.get(chunk_key))
+			.and_then(|entry| entry.complete_ref())
+			.cloned();
+
+		terrain_chunk.and_then(|tc| tc.contents.get(voxel).map(|b| (b, tc.contents.get_state(voxel))))
+	}
+
 	pub fn get_voxel(&self, cr: &ChunksResource, voxel: IVec3) -> Option<BlockKey> {
 		let chunk = chunk_of_voxel(voxel);
 		let voxel = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
@@ -160,6 +274,24 @@ impl TerrainResource {
 		terrain_chunk.and_then(|tc| tc.contents.get(voxel))
 	}
 
+	/// Gets a copy of the instance data for a voxel.
+	pub fn get_instance(&self, cr: &ChunksResource, voxel: IVec3) -> Option<BlockInstanceData> {
+		let chunk = chunk_of_voxel(voxel);
+		let voxel = voxel_relative_to_chunk(voxel, chunk).as_uvec3();
+
+		let chunks = self.chunks.read();
+		cr.read().get_position(chunk)
+			.and_then(|chunk_key| chunks.get(chunk_key))
+			.and_then(|entry| entry.complete_ref())
+			.and_then(|tc| tc.instance(voxel).cloned())
+	}
+
+	/// Queues a change to a voxel's instance data. 
+	/// This does nothing if the voxel has no instance data when the change is applied. 
+	pub fn set_instance_value(&self, voxel: IVec3, field: impl Into<String>, value: InstanceValue) {
+		self.instance_mods.write().push((voxel, field.into(), value));
+	}
+
 	pub fn modify_voxel(&self, modification: VoxelModification) {
 		let (c, r) = modification.as_chunk_relative();
 		self.block_mods.write().entry(c).or_default().push(r);
@@ -174,6 +306,28 @@ impl TerrainResource {
 		}
 	}
 
+	/// Saves every loaded chunk whose terrain or liquid has been modified since it was generated or last saved. 
+	/// Gives the number of chunks that were saved. 
+	pub fn save_modified(&self, cr: &ChunksResource, liquids: &LiquidsResource, blocks: &BlockManager, directory: &Path) -> usize {
+		let chunks = cr.read();
+		let mut terrain_chunks = self.chunks.write();
+		let mut liquid_chunks = liquids.chunks.write();
+		let mut n = 0;
+		for (key, entry) in terrain_chunks.iter_mut() {
+			let (Some(&position), TerrainEntry::Complete(chunk)) = (chunks.chunks.get(key), entry) else { continue };
+			let liquid = liquid_chunks.get_mut(key);
+			if chunk.modified || liquid.as_ref().is_some_and(|l| l.modified) {
+				save_chunk(directory, position, chunk, liquid.as_deref().map(|l| &**l), blocks);
+				Arc::make_mut(chunk).modified = false;
+				if let Some(l) = liquid {
+					Arc::make_mut(l).modified = false;
+				}
+				n += 1;
+			}
+		}
+		n
+	}
+
 	/// Gets some estimate of the map's data usage. 
 	/// Assumes that chunks are stored with array volumes with no optimization
 	pub fn approximate_size(&self) -> u64 {
@@ -181,11 +335,40 @@ impl TerrainResource {
 		(self.chunks.read().len() as u64) * 8 * CHUNK_SIZE as u64
 	}
 }
+impl mlua::UserData for TerrainResource {
+	fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+		methods.add_method("instance_value", |_lua, this, (chunks, x, y, z, field): (mlua::UserDataRef<ChunksResource>, i32, i32, i32, String)| {
+			Ok(this.get_instance(&chunks, IVec3::new(x, y, z))
+				.and_then(|mut d| d.remove(&field)))
+		});
+		// Gives the block at a position like "name[property=value]", or nil if it is empty or not loaded
+		methods.add_method("block", |_lua, this, (chunks, blocks, x, y, z): (mlua::UserDataRef<ChunksResource>, mlua::UserDataRef<BlockResource>, i32, i32, i32)| {
+			Ok(this.get_voxel_state(&chunks, IVec3::new(x, y, z))
+				.and_then(|(key, state)| blocks.read().block_state_string(key, state)))
+		});
+		methods.add_method("set_instance_value", |_lua, this, (x, y, z, field, value): (i32, i32, i32, String, InstanceValue)| {
+			this.set_instance_value(IVec3::new(x, y, z), field, value);
+			Ok(())
+		});
+	}
+}
 impl StorageCommandExpose for TerrainResource {
+	// resource TerrainResource set save_directory saves/0/terrain
 	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
 		match command[0] {
+			"set" => match command.get(1).copied() {
+				// "none" stops saving
+				Some("save_directory") => if let Some(&v) = command.get(2) {
+						self.save_directory = (v != "none").then(|| PathBuf::from(v));
+						Ok(format!("TerrainResource save_directory {:?}", self.save_directory))
+					} else {
+						Err(anyhow::anyhow!("Give a set value"))
+					},
+				_ => Err(anyhow::anyhow!("Unknown field")),
+			},
 			"stats" => Ok([
 				format!("approx_size: {}", self.approximate_size()),
+				format!("save_directory: {:?}", self.save_directory),
 			].join("\n")),
 			_ => Err(anyhow::anyhow!("Unknown command")),
 		}
@@ -196,8 +379,8 @@ impl StorageCommandExpose for TerrainResource {
 #[derive(Debug, Resource)]
 #[sda(commands = true)]
 pub struct TerrainLoadingResource {
-	pub chunk_sender: Sender<(IVec3, TerrainContents, Vec<VoxelModification>)>,
-	pub chunk_receiver: Receiver<(IVec3, TerrainContents, Vec<VoxelModification>)>,
+	pub chunk_sender: Sender<(IVec3, TerrainChunk, LiquidContents, Vec<VoxelModification>)>,
+	pub chunk_receiver: Receiver<(IVec3, TerrainChunk, LiquidContents, Vec<VoxelModification>)>,
 	pub max_generation_jobs: u8,
 	pub cur_generation_jobs: u8,
 	pub vec_generation_jobs: Vec<(IVec3, Instant)>, // For profiling
@@ -206,9 +389,15 @@ pub struct TerrainLoadingResource {
 	pub seed: u32,
 	pub pending_blockmods: HashMap<IVec3, Vec<VoxelModification>>,
 	pub generator: Arc<NewTerrainGenerator>,
+	// Resolved once, empty if a biome's blocks could not be found
+	biome_blocks: Option<Arc<[BiomeBlocks]>>,
 }
 impl TerrainLoadingResource {
 	pub fn new(seed: u32) -> Self {
+		Self::with_generator(seed, NewTerrainGenerator::new(seed as i32))
+	}
+
+	pub fn with_generator(seed: u32, generator: NewTerrainGenerator) -> Self {
 		let (chunk_sender, chunk_receiver) = unbounded();
 		Self {
 			chunk_sender, chunk_receiver, 
@@ -218,14 +407,45 @@ impl TerrainLoadingResource {
 			// generation_durations: RingDataHolder::new(32),
 			seed, 
 			pending_blockmods: HashMap::new(),
-			generator: Arc::new(NewTerrainGenerator::new(seed as i32)),
+			generator: Arc::new(generator),
+			biome_blocks: None,
 		}
 	}
+
+	/// The blocks of each biome, these are found the first time that they are needed. 
+	/// If any are missing then terrain is generated without biomes. 
+	pub fn biome_blocks(&mut self, blocks: &BlockManager) -> Arc<[BiomeBlocks]> {
+		self.biome_blocks.get_or_insert_with(|| {
+			self.generator.biomes.block_keys(blocks).unwrap_or_else(|e| {
+				error!("Failed to find biome blocks, terrain will be generated without biomes: {e}");
+				Vec::new()
+			}).into()
+		}).clone()
+	}
 }
 impl StorageCommandExpose for TerrainLoadingResource {
 	// resource TerrainLoadingResource set max_jobs 32
+	// terrain set sea_level 16
+	// terrain biome_at 0 0
 	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
 		match command[0] {
+			"biome_at" => {
+				let x = command.get(1).ok_or_else(|| anyhow::anyhow!("Give an x position"))?.parse::<i32>()?;
+				let z = command.get(2).ok_or_else(|| anyhow::anyhow!("Give a z position"))?.parse::<i32>()?;
+				let position = IVec2::new(x, z);
+				let (temperature, humidity) = self.generator.climate(position, UVec2::ONE)[0];
+				let height = self.generator.heights(position, UVec2::ONE)[0];
+				let biome = self.generator.biome_at(x, z)
+					.and_then(|i| self.generator.biomes.get(i))
+					.map(|b| b.name.as_str())
+					.unwrap_or("None");
+				Ok([
+					format!("biome: {}", biome),
+					format!("temperature: {:.3}", temperature),
+					format!("humidity: {:.3}", humidity),
+					format!("height: {:.1}", height),
+				].join("\n"))
+			},
 			"set" => match command[1] {
 				"max_jobs" => if let Some(v) = command.get(2) {
 						let v = v.parse::<u8>()?;
@@ -234,6 +454,14 @@ impl StorageCommandExpose for TerrainLoadingResource {
 					} else {
 						Err(anyhow::anyhow!("Give a set value"))
 					},
+				// Chunks that are already generated or generating keep the old sea level
+				"sea_level" => if let Some(v) = command.get(2) {
+						let v = v.parse::<i32>()?;
+						Arc::make_mut(&mut self.generator).sea_level = v;
+						Ok(format!("TerrainLoadingResource sea_level {}", v))
+					} else {
+						Err(anyhow::anyhow!("Give a set value"))
+					},
 				_ => Err(anyhow::anyhow!("Unknown field")),
 			},
 			"stats" => {
@@ -251,6 +479,7 @@ impl StorageCommandExpose for TerrainLoadingResource {
 					format!("current_jobs: {}", self.cur_generation_jobs),
 					s,
 					format!("seed: {}", self.seed),
+					format!("sea_level: {}", self.generator.sea_level),
 				].join("\n"))
 			},
 			_ => Err(anyhow::anyhow!("Unknown command")),
@@ -259,10 +488,36 @@ impl StorageCommandExpose for TerrainLoadingResource {
 }
 
 
+fn save_chunk(directory: &Path, position: IVec3, chunk: &TerrainChunk, liquid: Option<&LiquidChunk>, blocks: &BlockManager) {
+	trace!("Save chunk {position}");
+	let path = SavedTerrainChunk::path(directory, position);
+	let empty = LiquidContents::new();
+	let liquid = liquid.map_or(&empty, |l| &**l);
+	if let Err(e) = SavedTerrainChunk::new(chunk, liquid, blocks).write(&path) {
+		error!("Failed to save chunk {position} to {path:?}: {e}");
+	}
+}
+
+
+/// Saves the modified chunks that are still loaded, which would otherwise only be saved when unloaded. 
+pub fn terrain_saving_system(
+	blocks: Res<BlockResource>,
+	chunks: Res<ChunksResource>,
+	terrain: Res<TerrainResource>,
+	liquids: Res<LiquidsResource>,
+) {
+	if let Some(directory) = terrain.save_directory.as_ref() {
+		let n = terrain.save_modified(&chunks, &liquids, &blocks.read(), directory);
+		info!("Saved {n} modified chunks");
+	}
+}
+
+
 pub fn terrain_loading_system(
 	blocks: Res<BlockResource>,
 	chunks: Res<ChunksResource>,
 	terrain: ResMut<TerrainResource>,
+	liquids: Res<LiquidsResource>,
 	mut loading: ResMut<TerrainLoadingResource>,
 	// loaders: Comp<ChunkLoadingComponent>, 
 	// transforms: Comp<TransformComponent>, 
@@ -271,15 +526,26 @@ pub fn terrain_loading_system(
 	let chunks = chunks.read();
 	
 	// Prune chunks that should not be loaded
-	// Todo: save data
+	// Modified chunks are saved
 	{ 
 		// profiling::scope!("Prune chunks");
-		terrain_chunks.retain(|k, _| chunks.chunks.contains_key(k));
+		let blocks = blocks.read();
+		for event in chunks.events.iter() {
+			let &ChunkEvent::Unloaded(k, position) = event else { continue };
+			// Liquid is removed afterwards by liquid_loading_system
+			if let (Some(TerrainEntry::Complete(chunk)), Some(directory)) = (terrain_chunks.remove(k), terrain.save_directory.as_ref()) {
+				let liquid_chunks = liquids.chunks.read();
+				let liquid = liquid_chunks.get(k);
+				if chunk.modified || liquid.is_some_and(|l| l.modified) {
+					save_chunk(directory, position, &chunk, liquid.map(|l| &**l), &blocks);
+				}
+			}
+		}
 	}
 
 	{ // Receive new chunks
 		// profiling::scope!("Receive new chunks");
-		while let Ok((position, chunk, modifications)) = loading.chunk_receiver.try_recv() {
+		while let Ok((position, chunk, liquid, modifications)) = loading.chunk_receiver.try_recv() {
 			trace!("Received generated chunk for {position}");
 			let n = chunks.chunks.len();
 			let n_loaded = terrain_chunks.values().filter(|e| e.is_complete()).count();
@@ -291,10 +557,8 @@ pub fn terrain_loading_system(
 			// loading.generation_durations.insert(t_start.elapsed());
 
 			if let Some(k) = chunks.get_position(position) {
-				terrain_chunks.insert(k, TerrainEntry::Complete(Arc::new(TerrainChunk { 
-					contents: chunk, 
-					generation: KGeneration::new(), 
-				})));
+				terrain_chunks.insert(k, TerrainEntry::Complete(Arc::new(chunk)));
+				liquids.chunks.write().insert(k, Arc::new(LiquidChunk::new(liquid)));
 				terrain.modify_voxels(modifications.as_slice());
 				loading.cur_generation_jobs -= 1;
 			} else {
@@ -305,30 +569,52 @@ pub fn terrain_loading_system(
 	
 	if loading.cur_generation_jobs < loading.max_generation_jobs {
 		// profiling::scope!("Start new jobs");
+		let biome_blocks = loading.biome_blocks(&blocks.read());
 		for &(key, d) in chunks.chunks_by_distance.iter() {
 			let position = chunks.chunks[key];
 			if !terrain_chunks.contains_key(key) {
 				trace!("Begin generating chunk {position} (distance {d})");
 				terrain_chunks.insert(key, TerrainEntry::Loading);
+
+				let saved = terrain.save_directory.as_ref()
+					.map(|d| SavedTerrainChunk::path(d, position))
+					.filter(|p| p.exists());
+				let block_manager = blocks.blocks.clone();
 	
 				let blocks = blocks.read();
-				let grass = blocks.key_by_name(&"grass".into()).unwrap();
-				let dirt = blocks.key_by_name(&"dirt".into()).unwrap();
 				let stone = blocks.key_by_name(&"stone".into()).unwrap();
+				let biome_blocks = biome_blocks.clone();
+				let sea = blocks.key_by_name(&"water".into());
 	
 				let generator = loading.generator.clone();
 				let sender = loading.chunk_sender.clone();
 				rayon::spawn(move || {
-					let mut c = TerrainContents::new();
-	
-					// let tgen = TerrainGenerator::new(0);
-					// tgen.chunk_base_3d(position, &mut c, stone);
-					// tgen.cover_chunk(&mut c, position, grass, dirt, 3);
+					let restored = saved.and_then(|path| {
+						match SavedTerrainChunk::read(&path).and_then(|s| s.restore(&block_manager.read())) {
+							Ok(c) => Some(c),
+							Err(e) => {
+								error!("Failed to load chunk {position} from {path:?}, it will be generated instead: {e}");
+								None
+							},
+						}
+					});
+
+					let (c, l) = restored.unwrap_or_else(|| {
+						let mut c = TerrainContents::new();
 	
-					generator.base(position, &mut c, stone);
-					generator.cover(position, &mut c, grass, dirt, 3);
+						// let tgen = TerrainGenerator::new(0);
+						// tgen.chunk_base_3d(position, &mut c, stone);
+						// tgen.cover_chunk(&mut c, position, grass, dirt, 3);
+		
+						generator.base(position, &mut c, stone);
+						generator.cover(position, &mut c, &biome_blocks);
+						let l = sea
+							.map(|sea| generator.sea(position, &c, sea))
+							.unwrap_or_else(LiquidContents::new);
+						(TerrainChunk::new(c), l)
+					});
 	
-					sender.send((position, c, Vec::new())).unwrap();
+					sender.send((position, c, l, Vec::new())).unwrap();
 				});
 
 				loading.vec_generation_jobs.push((position, Instant::now()));
@@ -341,3 +627,102 @@ pub fn terrain_loading_system(
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chunks::test_util::colour_block;
+	use crate::{biomes::BiomeSpecification, generator::tests::test_generator};
+
+	#[test]
+	fn test_changed_since() {
+		let mut chunk = TerrainChunk::new(TerrainContents::new());
+		let start = chunk.generation;
+		assert_eq!(None, chunk.changed_since(start));
+
+		chunk.record_change(UVec3::new(3, 4, 5), UVec3::new(3, 4, 5));
+		let middle = chunk.generation;
+		chunk.record_change(UVec3::new(0, 10, 6), UVec3::new(1, 10, 6));
+		assert_eq!(Some((UVec3::new(0, 4, 5), UVec3::new(3, 10, 6))), chunk.changed_since(start));
+		assert_eq!(Some((UVec3::new(0, 10, 6), UVec3::new(1, 10, 6))), chunk.changed_since(middle));
+
+		// Changes that weren't recorded could be anywhere
+		let whole = Some((UVec3::ZERO, UVec3::splat(CHUNK_SIZE - 1)));
+		chunk.generation.increment();
+		assert_eq!(whole, chunk.changed_since(middle));
+
+		// As could those that have been forgotten
+		let mut chunk = TerrainChunk::new(TerrainContents::new());
+		for _ in 0..=ChangeHistory::LENGTH {
+			chunk.record_change(UVec3::ZERO, UVec3::ZERO);
+		}
+		assert_eq!(whole, chunk.changed_since(start));
+	}
+
+	#[test]
+	fn test_missing_biome_blocks() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let mut loading = TerrainLoadingResource::with_generator(0, test_generator(0));
+		Arc::make_mut(&mut loading.generator).biomes.biomes.push(BiomeSpecification {
+			name: "stony".into(),
+			temperature: 0.5,
+			humidity: 0.5,
+			surface: "stone".into(),
+			subsurface: "stone".into(),
+			subsurface_depth: 1,
+			height_spline: splines::Spline::from_vec(Vec::new()),
+			structures: Vec::new(),
+		});
+		let found = loading.biome_blocks(&blocks);
+		assert_eq!(vec![stone], found.iter().map(|b| b.surface).collect::<Vec<_>>());
+
+		// Generates without biomes instead of failing
+		Arc::make_mut(&mut loading.generator).biomes.biomes[0].surface = "grass".into();
+		loading.biome_blocks = None;
+		assert!(loading.biome_blocks(&blocks).is_empty());
+		// It is not looked up again
+		colour_block(&mut blocks, "grass");
+		assert!(loading.biome_blocks(&blocks).is_empty());
+	}
+
+	#[test]
+	fn test_save_modified() {
+		let mut blocks = BlockManager::new();
+		let stone = colour_block(&mut blocks, "stone");
+		let cr = ChunksResource::new();
+		let terrain = TerrainResource::default();
+		for (position, modified) in [(IVec3::ZERO, true), (IVec3::X, false)] {
+			let mut chunk = TerrainChunk::new(TerrainContents::new());
+			chunk.insert(UVec3::ONE, stone);
+			chunk.modified = modified;
+			let key = cr.write().load(position);
+			terrain.chunks.write().insert(key, TerrainEntry::Complete(Arc::new(chunk)));
+		}
+
+		// Unique so that concurrent test runs don't delete each other's saves
+		let directory = std::env::temp_dir().join(format!("pinefruit_test_save_modified_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		let liquids = LiquidsResource::new();
+		assert_eq!(1, terrain.save_modified(&cr, &liquids, &blocks, &directory));
+		let (restored, _) = SavedTerrainChunk::read(SavedTerrainChunk::path(&directory, IVec3::ZERO)).unwrap()
+			.restore(&blocks).unwrap();
+		assert_eq!(Some(stone), restored.get(UVec3::ONE));
+		assert!(!SavedTerrainChunk::path(&directory, IVec3::X).exists());
+		// It is not saved again until it is modified again
+		assert_eq!(0, terrain.save_modified(&cr, &liquids, &blocks, &directory));
+
+		// Liquid that has changed is saved with its terrain
+		let water = colour_block(&mut blocks, "water");
+		let key = cr.read().get_position(IVec3::X).unwrap();
+		let mut liquid = LiquidChunk::new(LiquidContents::Filled(water));
+		liquid.modified = true;
+		liquids.chunks.write().insert(key, Arc::new(liquid));
+		assert_eq!(1, terrain.save_modified(&cr, &liquids, &blocks, &directory));
+		let (_, restored) = SavedTerrainChunk::read(SavedTerrainChunk::path(&directory, IVec3::X)).unwrap()
+			.restore(&blocks).unwrap();
+		assert!(matches!(restored, LiquidContents::Filled(w) if w == water));
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+}
@@ -0,0 +1,22 @@
+//! Helpers for the tests of terrain.
+
+use std::sync::Arc;
+use chunks::{blocks::BlockKey, chunk_of_voxel, chunks::ChunksResource, test_util::load_chunks, voxel_relative_to_chunk};
+use glam::IVec3;
+use crate::terrain::{TerrainChunk, TerrainContents, TerrainEntry, TerrainResource};
+
+
+
+/// Loads complete terrain for the chunks from min to max inclusive, with blocks at these voxels.
+pub fn terrain(min: IVec3, max: IVec3, voxels: &[(IVec3, BlockKey)]) -> (ChunksResource, TerrainResource) {
+	let cr = ChunksResource::new();
+	let terrain = TerrainResource::default();
+	for (c, key) in load_chunks(&mut cr.write(), min, max) {
+		let mut contents = TerrainContents::new();
+		for &(v, b) in voxels.iter().filter(|(v, _)| chunk_of_voxel(*v) == c) {
+			contents.insert(voxel_relative_to_chunk(v, c).as_uvec3(), b);
+		}
+		terrain.chunks.write().insert(key, TerrainEntry::Complete(Arc::new(TerrainChunk::new(contents))));
+	}
+	(cr, terrain)
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use chunks::{blocks::{BlockKey, BlockManager, BlockResource}, chunk_of_point, chunk_of_voxel, chunks::{ChunkLoadingComponent, ChunksResource}, states::BlockState, VoxelCube, CHUNK_SIZE};
+use eeks::prelude::*;
+use glam::{IVec3, UVec3};
+use pinecore::transform::TransformComponent;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::{modification::VoxelModification, terrain::TerrainResource};
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockTickKind {
+	// Some voxels in every ticking chunk are chosen each tick
+	Random,
+	// The block asked to be ticked
+	Scheduled,
+}
+impl BlockTickKind {
+	pub fn from_str(s: &str) -> Option<Self> {
+		match s {
+			"random" => Some(Self::Random),
+			"scheduled" => Some(Self::Scheduled),
+			_ => None,
+		}
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct BlockTick {
+	pub kind: BlockTickKind,
+	pub block: BlockKey,
+	pub block_name: String,
+	pub state: BlockState,
+	// World-relative
+	pub position: IVec3,
+}
+
+
+/// What a tick handler can see and do.
+pub struct BlockTickContext<'a> {
+	chunks: &'a ChunksResource,
+	terrain: &'a TerrainResource,
+	pub blocks: &'a BlockManager,
+	pub rng: &'a mut StdRng,
+	tick: u64,
+	modifications: &'a mut Vec<VoxelModification>,
+	scheduled: &'a mut BTreeMap<u64, BTreeSet<[i32; 3]>>,
+}
+impl<'a> BlockTickContext<'a> {
+	pub fn get(&self, position: IVec3) -> Option<(BlockKey, BlockState)> {
+		self.terrain.get_voxel_state(self.chunks, position)
+	}
+
+	/// Gives the name of the block at this position.
+	pub fn get_name(&self, position: IVec3) -> Option<&'a str> {
+		let blocks = self.blocks;
+		self.get(position)
+			.and_then(|(key, _)| blocks.get(key))
+			.map(|e| e.specification.name.as_str())
+	}
+
+	/// Changes a voxel after this tick's handlers have run.
+	pub fn set(&mut self, position: IVec3, set_to: Option<(BlockKey, BlockState)>) {
+		self.modifications.push(tick_modification(position, set_to));
+	}
+
+	/// Ticks the voxel at this position after some number of ticks.
+	pub fn schedule(&mut self, position: IVec3, delay: u64) {
+		schedule(self.scheduled, self.tick, position, delay);
+	}
+}
+
+
+pub type BlockTickHandler = fn(&BlockTick, &mut BlockTickContext);
+
+
+fn tick_modification(position: IVec3, set_to: Option<(BlockKey, BlockState)>) -> VoxelModification {
+	VoxelModification {
+		position,
+		set_to: set_to.map(|(k, _)| k),
+		state: set_to.map(|(_, s)| s).unwrap_or_default(),
+		priority: 0,
+		entity: None,
+		// Ticks are the world changing by itself, nobody placed or broke anything
+		emit_events: false,
+	}
+}
+
+
+fn schedule(scheduled: &mut BTreeMap<u64, BTreeSet<[i32; 3]>>, tick: u64, position: IVec3, delay: u64) {
+	// A delay of zero would be ticked in the same tick that scheduled it, which we've already done
+	scheduled.entry(tick + delay.max(1)).or_default().insert(position.to_array());
+}
+
+
+/// Chooses the chunk-relative positions of a chunk's random ticks.
+/// The same seed, tick, and chunk always give the same positions.
+pub fn random_tick_positions(seed: u64, tick: u64, chunk_position: IVec3, count: usize) -> Vec<UVec3> {
+	let mut rng = chunk_rng(seed, tick, chunk_position);
+	(0..count).map(|_| UVec3::new(
+		rng.gen_range(0..CHUNK_SIZE),
+		rng.gen_range(0..CHUNK_SIZE),
+		rng.gen_range(0..CHUNK_SIZE),
+	)).collect()
+}
+
+
+fn chunk_rng(seed: u64, tick: u64, chunk_position: IVec3) -> StdRng {
+	// Mixes the inputs so that neighbouring chunks and ticks get unrelated seeds
+	let mut h = seed ^ 0x9E37_79B9_7F4A_7C15;
+	for v in [tick, chunk_position.x as u64, chunk_position.y as u64, chunk_position.z as u64] {
+		h = (h ^ v).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		h ^= h >> 31;
+	}
+	StdRng::seed_from_u64(h)
+}
+
+
+/// Makes blocks change over time.
+///
+/// Each tick, some random voxels in every chunk within `radius` chunks of a [ChunkLoadingComponent] are ticked,
+/// as are the voxels whose scheduled ticks are due.
+/// Rust handlers are run by `block_tick_collection`.
+/// Lua systems can see ticks if they run after "block_tick_collection" and before "block_tick_application".
+/// Changes made by handlers are applied in "block_tick_application".
+#[derive(Debug, Resource)]
+#[sda(lua = true, commands = true)]
+pub struct BlockTickResource {
+	pub ticks: Vec<BlockTick>,
+	// The number of ticks that have happened
+	pub tick: u64,
+	pub seed: u64,
+	// Random ticks per chunk per tick
+	pub random_ticks: usize,
+	// Distance in chunks from a loader within which chunks are ticked
+	pub radius: u32,
+	pub paused: bool,
+	// Tick -> positions
+	scheduled: BTreeMap<u64, BTreeSet<[i32; 3]>>,
+	modifications: Vec<VoxelModification>,
+	// Seeded from the seed and tick, shared by Rust handlers and then Lua systems
+	rng: StdRng,
+	// (kind, block name) -> handlers
+	handlers: HashMap<(BlockTickKind, String), Vec<BlockTickHandler>>,
+}
+impl Default for BlockTickResource {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl BlockTickResource {
+	pub fn new() -> Self {
+		Self {
+			ticks: Vec::new(),
+			tick: 0,
+			seed: 0,
+			random_ticks: 24,
+			radius: 4,
+			paused: false,
+			scheduled: BTreeMap::new(),
+			modifications: Vec::new(),
+			rng: StdRng::seed_from_u64(0),
+			handlers: HashMap::new(),
+		}
+	}
+
+	/// Registers a handler for ticks of this kind for the block with this name.
+	/// Handlers are run in the order that they were registered.
+	pub fn register(&mut self, kind: BlockTickKind, block: impl Into<String>, handler: BlockTickHandler) {
+		self.handlers.entry((kind, block.into())).or_default().push(handler);
+	}
+
+	/// Ticks the voxel at this position after some number of ticks.
+	pub fn schedule(&mut self, position: IVec3, delay: u64) {
+		schedule(&mut self.scheduled, self.tick, position, delay);
+	}
+
+	pub fn scheduled_len(&self) -> usize {
+		self.scheduled.values().map(|s| s.len()).sum()
+	}
+
+	/// Indices of ticks of this kind for this block.
+	pub fn matching(&self, kind: BlockTickKind, block_name: &str) -> Vec<usize> {
+		self.ticks.iter().enumerate()
+			.filter(|(_, t)| t.kind == kind && t.block_name == block_name)
+			.map(|(i, _)| i)
+			.collect()
+	}
+
+	/// Takes the positions whose scheduled ticks are due.
+	fn due(&mut self) -> Vec<IVec3> {
+		let later = self.scheduled.split_off(&(self.tick + 1));
+		std::mem::replace(&mut self.scheduled, later).into_values()
+			.flatten()
+			.map(IVec3::from_array)
+			.collect()
+	}
+
+	fn collect(&mut self, chunks: &ChunksResource, terrain: &TerrainResource, blocks: &BlockManager, ticking: &[VoxelCube]) {
+		self.tick += 1;
+		let in_range = |chunk: IVec3| ticking.iter().any(|v| v.contains(chunk));
+		let tick_at = |kind, position: IVec3| {
+			let (block, state) = terrain.get_voxel_state(chunks, position)?;
+			let block_name = blocks.get(block)?.specification.name.clone();
+			Some(BlockTick { kind, block, block_name, state, position, })
+		};
+
+		// Scheduled ticks outside of the ticking area are dropped
+		let mut ticks = self.due().into_iter()
+			.filter(|&p| in_range(chunk_of_voxel(p)))
+			.filter_map(|p| tick_at(BlockTickKind::Scheduled, p))
+			.collect::<Vec<_>>();
+
+		let positions = chunks.read().chunks.values().copied()
+			.filter(|&c| in_range(c))
+			.collect::<Vec<_>>();
+		for chunk_position in positions {
+			ticks.extend(random_tick_positions(self.seed, self.tick, chunk_position, self.random_ticks).into_iter()
+				.filter_map(|p| tick_at(BlockTickKind::Random, chunk_position * CHUNK_SIZE as i32 + p.as_ivec3())));
+		}
+
+		self.rng = StdRng::seed_from_u64(self.seed ^ self.tick);
+		let mut context = BlockTickContext {
+			chunks, terrain, blocks,
+			rng: &mut self.rng,
+			tick: self.tick,
+			modifications: &mut self.modifications,
+			scheduled: &mut self.scheduled,
+		};
+		for tick in ticks.iter() {
+			if let Some(handlers) = self.handlers.get(&(tick.kind, tick.block_name.clone())) {
+				for handler in handlers {
+					handler(tick, &mut context);
+				}
+			}
+		}
+		self.ticks = ticks;
+	}
+}
+impl mlua::UserData for BlockTickResource {
+	fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+		// Gives the indices of ticks of a kind ("random", "scheduled") for a block
+		methods.add_method("ticks", |_lua, this, (kind, block): (String, String)| {
+			let kind = BlockTickKind::from_str(&kind)
+				.ok_or_else(|| mlua::Error::runtime(format!("Unknown block tick kind '{kind}'")))?;
+			Ok(this.matching(kind, &block))
+		});
+		methods.add_method("position", |_lua, this, i: usize| {
+			let t = this.ticks.get(i)
+				.ok_or_else(|| mlua::Error::runtime("No such tick"))?;
+			Ok((t.position.x, t.position.y, t.position.z))
+		});
+		// A random integer in [min, max] from this tick's seeded generator
+		methods.add_method_mut("random", |_lua, this, (min, max): (i32, i32)| {
+			if min > max {
+				return Err(mlua::Error::runtime(format!("Empty range {min}..={max}")));
+			}
+			Ok(this.rng.gen_range(min..=max))
+		});
+		methods.add_method_mut("schedule", |_lua, this, (x, y, z, delay): (i32, i32, i32, u64)| {
+			this.schedule(IVec3::new(x, y, z), delay);
+			Ok(())
+		});
+		// Block "air" removes the voxel, otherwise it is parsed like "name[property=value]"
+		methods.add_method_mut("set_block", |_lua, this, (x, y, z, block, blocks): (i32, i32, i32, String, mlua::UserDataRef<BlockResource>)| {
+			let set_to = if block == "air" {
+				None
+			} else {
+				Some(blocks.read().parse_block_state(&block)
+					.ok_or_else(|| mlua::Error::runtime(format!("Unknown block '{block}'")))?)
+			};
+			this.modifications.push(tick_modification(IVec3::new(x, y, z), set_to));
+			Ok(())
+		});
+	}
+}
+impl StorageCommandExpose for BlockTickResource {
+	fn command(&mut self, command: &[&str]) -> anyhow::Result<String> {
+		match command[0] {
+			"stats" => Ok([
+				format!("tick: {}", self.tick),
+				format!("ticks: {}", self.ticks.len()),
+				format!("scheduled: {}", self.scheduled_len()),
+				format!("paused: {}", self.paused),
+			].join("\n")),
+			"pause" => {
+				self.paused = !self.paused;
+				Ok(format!("paused: {}", self.paused))
+			},
+			"set" => {
+				let value = command.get(2).ok_or_else(|| anyhow::anyhow!("No value given"))?;
+				match command.get(1).copied() {
+					Some("random_ticks") => self.random_ticks = value.parse()?,
+					Some("radius") => self.radius = value.parse()?,
+					Some("seed") => self.seed = value.parse()?,
+					_ => return Err(anyhow::anyhow!("Unknown field")),
+				}
+				Ok("Set".into())
+			},
+			_ => Err(anyhow::anyhow!("Unknown command")),
+		}
+	}
+}
+
+
+/// Finds this tick's block ticks and runs Rust handlers for them.
+pub fn block_tick_collection(
+	chunks: Res<ChunksResource>,
+	blocks: Res<BlockResource>,
+	terrain: Res<TerrainResource>,
+	loaders: Comp<ChunkLoadingComponent>,
+	transforms: Comp<TransformComponent>,
+	mut ticks: ResMut<BlockTickResource>,
+) {
+	if ticks.paused {
+		ticks.ticks.clear();
+		return;
+	}
+	let ticking = (&loaders, &transforms).iter()
+		.map(|(_, transform)| VoxelCube::new(chunk_of_point(transform.translation), UVec3::splat(ticks.radius)))
+		.collect::<Vec<_>>();
+	let blocks = blocks.read();
+	ticks.collect(&chunks, &terrain, &blocks, &ticking);
+}
+
+
+/// Queues the changes made by tick handlers.
+pub fn block_tick_application(
+	terrain: Res<TerrainResource>,
+	mut ticks: ResMut<BlockTickResource>,
+) {
+	let modifications = std::mem::take(&mut ticks.modifications);
+	if !modifications.is_empty() {
+		terrain.modify_voxels(&modifications);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_random_tick_positions() {
+		let a = random_tick_positions(7, 100, IVec3::new(-1, 2, 3), 16);
+		assert_eq!(16, a.len());
+		assert!(a.iter().all(|p| p.cmplt(UVec3::splat(CHUNK_SIZE)).all()));
+		// Deterministic
+		assert_eq!(a, random_tick_positions(7, 100, IVec3::new(-1, 2, 3), 16));
+		// But not the same everywhere
+		assert_ne!(a, random_tick_positions(7, 101, IVec3::new(-1, 2, 3), 16));
+		assert_ne!(a, random_tick_positions(7, 100, IVec3::new(-1, 2, 4), 16));
+	}
+
+	#[test]
+	fn test_tick_rng() {
+		let numbers = |seed| {
+			let mut ticks = BlockTickResource::new();
+			ticks.seed = seed;
+			ticks.collect(&ChunksResource::new(), &TerrainResource::default(), &BlockManager::new(), &[]);
+			(0..8).map(|_| ticks.rng.gen_range(-1..=1)).collect::<Vec<i32>>()
+		};
+		// Lua handlers see the same numbers for the same seed
+		assert_eq!(numbers(3), numbers(3));
+		assert_ne!(numbers(3), numbers(4));
+	}
+
+	#[test]
+	fn test_scheduled_ticks() {
+		let mut ticks = BlockTickResource::new();
+		ticks.schedule(IVec3::ONE, 2);
+		ticks.schedule(IVec3::ONE, 2);
+		ticks.schedule(IVec3::ZERO, 0);
+		assert_eq!(2, ticks.scheduled_len());
+
+		ticks.tick = 1;
+		assert_eq!(vec![IVec3::ZERO], ticks.due());
+		ticks.tick = 2;
+		assert_eq!(vec![IVec3::ONE], ticks.due());
+		assert_eq!(0, ticks.scheduled_len());
+	}
+}
@@ -36,13 +36,13 @@ impl Blitter {
 						view_dimension: wgpu::TextureViewDimension::D2, 
 						multisampled: false, 
 					},
-					count: std::num::NonZeroU32::new(1),
+					count: None,
 				},
 				wgpu::BindGroupLayoutEntry {
 					binding: 1,
 					visibility: wgpu::ShaderStages::FRAGMENT,
 					ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-					count: std::num::NonZeroU32::new(1),
+					count: None,
 				},
 			]
 		});
@@ -125,11 +125,13 @@ impl Blitter {
 				view: destination_view,
 				resolve_target: None,
 				ops: wgpu::Operations {
-					load: wgpu::LoadOp::Load,
-					store: true,
+					load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+					store: wgpu::StoreOp::Store,
 				}
 			})],
 			depth_stencil_attachment: None,
+			timestamp_writes: None,
+			occlusion_query_set: None,
 		});
 
 		rp.set_pipeline(&self.pipeline);
@@ -25,6 +25,18 @@ impl BufferManager {
 		k
 	}
 
+	/// Removes a buffer, its binding is freed with it.
+	pub fn remove(&mut self, key: BufferKey) -> Option<Buffer> {
+		let b = self.buffers.remove(key);
+		if let Some(b) = b.as_ref() {
+			// Another buffer may have been inserted with the same name since
+			if self.buffers_by_id.get(&b.name) == Some(&key) {
+				self.buffers_by_id.remove(&b.name);
+			}
+		}
+		b
+	}
+
 	pub fn get(&self, key: BufferKey) -> Option<&Buffer> {
 		self.buffers.get(key)
 	}
@@ -220,7 +232,9 @@ impl Buffer {
 		device: &wgpu::Device, 
 		bind_groups: &BindGroupManager,
 	) {
-		if self.used_by_materials.read().is_empty() {
+		// Buffers that are only drawn from aren't in any bind group
+		let drawn_from = wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::INDEX | wgpu::BufferUsages::INDIRECT;
+		if self.used_by_materials.read().is_empty() && !self.base_usages.intersects(drawn_from) {
 			return;
 		}
 
@@ -1,4 +1,4 @@
-use std::{num::NonZeroU32, sync::Arc};
+use std::{num::NonZeroU32, ops::Range, sync::Arc};
 use arrayvec::ArrayVec;
 use eks::{entity::Entity, World};
 use slotmap::SecondaryMap;
@@ -30,8 +30,8 @@ enum DrawItem {
 	Draw(MaterialKey, VertexSource, Entity), 
 	// Mesh draw range is controlled by indirect buffer, so full mesh is bound 
 	// Mesh decides if indexed or not, and if no mesh then not indexed
-	// Indirect buffer, instance buffer 
-	Indirect(MaterialKey, Option<MeshKey>, BufferKey, BufferKey), 
+	// Indirect buffer, instance buffer, first command, command count
+	Indirect(MaterialKey, Option<MeshKey>, BufferKey, Option<BufferKey>, u32, u32), 
 	// Must be last because they reset the render pass' state
 	// How do we know that buffers will not have been re-bound since this bundle was created? 
 	Bundle(usize),
@@ -132,8 +132,8 @@ enum DrawInstance {
 	// Indirect draw instance buffers do not include offset :(
 	// But if you're doing indirect draw calls you are likely using bindless
 	// stuff and don't need offsets anyway 'cause that's the whole point 
-	// Instance buffer, indirect buffer
-	Indirect(BufferKey, BufferKey),
+	// Instance buffer, indirect buffer, first command, command count
+	Indirect(Option<BufferKey>, BufferKey, u32, u32),
 }
 
 
@@ -141,6 +141,8 @@ enum DrawInstance {
 pub struct RenderInput2 {
 	// Stage name, depends_on, order in queue (max for not in queue) 
 	stages: Vec<(String, Vec<StageKey>, u8)>,
+	// Stages whose items are drawn in the order they were queued 
+	ordered: Vec<StageKey>,
 	// Targets[0] is a dummy target for compute shaders 
 	// Indices into this should start at 1 
 	targets: Vec<AbstractRenderTarget>,
@@ -162,6 +164,7 @@ impl RenderInput2 {
 	pub fn new() -> Self {
 		Self {
 			stages: Vec::new(),
+			ordered: Vec::new(),
 			targets: Vec::new(),
 			bundles: Vec::new(),
 			items: Vec::new(),
@@ -172,6 +175,7 @@ impl RenderInput2 {
 
 	pub fn clear(&mut self) {
 		self.stages.clear(); // Not strictly needed
+		self.ordered.clear();
 		self.targets.clear();
 		self.items.clear();
 		self.items_buffer.clear();
@@ -300,7 +304,7 @@ impl RenderInput2 {
 					},
 					StageItem::Draw(t, d) => StageOp::Draw(*t, match d {
 						DrawItem::Bundle(b) => DrawOp::Bundle(b.clone()),
-						DrawItem::Indirect(mtl, mesh, ind, ins) => {
+						DrawItem::Indirect(mtl, mesh, ind, ins, first, count) => {
 							let m = materials.get(*mtl).unwrap();
 							let shader = m.shader_key.unwrap();
 							let bgc = context.material_bindings.get(*mtl).unwrap().bind_groups;
@@ -310,7 +314,7 @@ impl RenderInput2 {
 								None => VertexSource::Static,
 							};
 							// Confusing order swap here
-							let di = DrawInstance::Indirect(*ins, *ind);
+							let di = DrawInstance::Indirect(*ins, *ind, *first, *count);
 							DrawOp::Draw(shader, bgc, vs, di)
 						},
 						DrawItem::Draw(mtl, vs, _) => {
@@ -350,7 +354,9 @@ impl RenderInput2 {
 				.cloned()
 				.enumerate()
 				.collect::<Vec<_>>();
-			indexed_buffer.sort_by_key(|e| e.1);
+			// Stable, so items of ordered stages keep their queue order 
+			let ordered = self.ordered.iter().map(|&s| self.stages[s as usize].2).collect::<Vec<_>>();
+			indexed_buffer.sort_by_key(|(_, (stage, op))| (*stage, (!ordered.contains(stage)).then_some(*op)));
 
 			let mut old_items = self.items.clone();
 			for (i_new, (i_old, op)) in indexed_buffer.into_iter().enumerate() {
@@ -460,6 +466,7 @@ impl RenderInput2 {
 			});
 			buffers.do_writes(&queue, &mut encoder);
 			textures.do_writes(&queue, &mut encoder);
+			textures.mip_unmipped(device, &mut encoder);
 			encoder.finish()
 		};
 
@@ -838,23 +845,40 @@ fn execute_sequence(
 											trace!("Set instance buffer to main at offset {}", st);
 											pass.set_vertex_buffer(1, instance_buffer.slice((st as u64)..));
 										},
-										DrawInstance::Indirect(k, _) => {
+										DrawInstance::Indirect(Some(k), _, _, _) => {
 											let b = buffers.get(k).unwrap();
 											trace!("Set instance buffer to {:?}", b.name);
 											pass.set_vertex_buffer(1, b.binding.as_ref().unwrap().slice(..));
 										},
+										// Vertices are made from storage buffers
+										DrawInstance::Indirect(None, _, _, _) => {},
 									}
 								}
 
-								if let DrawInstance::Indirect(_, ib) = instance.unwrap() {
+								if let DrawInstance::Indirect(_, ib, first, count) = instance.unwrap() {
 									let indirect_buffer = buffers.get(ib).unwrap().binding.as_ref().unwrap();
-
-									if !indexed {
-										trace!("Draw indirect");
-										pass.draw_indirect(indirect_buffer, 0);
+									// wgpu::util::DrawIndirectArgs and DrawIndexedIndirectArgs
+									let stride = if indexed { 20 } else { 16 };
+									let offset = first as u64 * stride;
+
+									if count > 1 && device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+										trace!("Multi draw {count} indirect (indexed {indexed})");
+										if !indexed {
+											pass.multi_draw_indirect(indirect_buffer, offset, count);
+										} else {
+											pass.multi_draw_indexed_indirect(indirect_buffer, offset, count);
+										}
 									} else {
-										trace!("Draw indexed indirect");
-										pass.draw_indexed_indirect(indirect_buffer, 0)
+										// One at a time if we must
+										for i in 0..count as u64 {
+											if !indexed {
+												trace!("Draw indirect");
+												pass.draw_indirect(indirect_buffer, offset + i * stride);
+											} else {
+												trace!("Draw indexed indirect");
+												pass.draw_indexed_indirect(indirect_buffer, offset + i * stride)
+											}
+										}
 									}
 								} else {
 									// Find batches to draw
@@ -929,6 +953,15 @@ impl<'a> StageBuilder<'a> {
 		todo!()
 	}
 
+	/// Draws this stage's items in the order they are queued instead of grouping them by pipeline. 
+	/// Needed for blending, which depends on draw order. 
+	pub fn ordered(self) -> Self {
+		if !self.input.ordered.contains(&self.stage) {
+			self.input.ordered.push(self.stage);
+		}
+		self
+	}
+
 	pub fn key(self) -> StageKey {
 		self.stage
 	}
@@ -963,12 +996,23 @@ impl<'a> TargetQueue<'a> {
 	}
 
 	pub fn indirect(&mut self, material: MaterialKey, indirect: BufferKey, instance: BufferKey) -> &mut Self {
-		self.stage_builder.input.items.push((self.stage_builder.stage, StageItem::Draw(self.target, DrawItem::Indirect(material, None, indirect, instance))));
+		self.stage_builder.input.items.push((self.stage_builder.stage, StageItem::Draw(self.target, DrawItem::Indirect(material, None, indirect, Some(instance), 0, 1))));
 		self
 	}
 
 	pub fn indirect_mesh(&mut self, material: MaterialKey, mesh: MeshKey, indirect: BufferKey, instance: BufferKey) -> &mut Self {
-		self.stage_builder.input.items.push((self.stage_builder.stage, StageItem::Draw(self.target, DrawItem::Indirect(material, Some(mesh), indirect, instance))));
+		self.stage_builder.input.items.push((self.stage_builder.stage, StageItem::Draw(self.target, DrawItem::Indirect(material, Some(mesh), indirect, Some(instance), 0, 1))));
+		self
+	}
+
+	/// Draws a range of non-indexed commands from an indirect buffer without an instance buffer. 
+	/// The shader is expected to find its data from the vertex and instance indices. 
+	/// This is a single multi-draw if the device supports it.
+	pub fn multi_indirect(&mut self, material: MaterialKey, indirect: BufferKey, commands: Range<u32>) -> &mut Self {
+		if commands.is_empty() {
+			return self;
+		}
+		self.stage_builder.input.items.push((self.stage_builder.stage, StageItem::Draw(self.target, DrawItem::Indirect(material, None, indirect, None, commands.start, commands.len() as u32))));
 		self
 	}
 
@@ -27,6 +27,7 @@ mod rendercontext;
 mod util;
 pub mod allocator;
 mod bundle;
+mod blit;
 pub mod input_v2;
 
 pub mod prelude {
@@ -39,7 +40,7 @@ pub mod prelude {
 	pub use crate::vertex::{InstanceAttributeSource, FetchedInstanceAttributeSource};
 	pub use crate::shader::{ShaderManager, ShaderEntry};
 	pub use crate::bindgroup::BindGroupManager;
-	pub use crate::material::{MaterialManager, MaterialSpecification};
+	pub use crate::material::{MaterialManager, MaterialSpecification, MaterialResourceLocation, GlobalResourceIdentifier};
 	pub use crate::rendertarget::*;
 	pub use crate::rendercontext::*;
 }
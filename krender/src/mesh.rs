@@ -241,15 +241,32 @@ impl MeshManager {
 	
 	pub fn insert(&mut self, mesh: Mesh) -> MeshKey {
 		let name = mesh.name.clone();
+		let path = mesh.path.clone();
 		let idx = self.meshes.insert(mesh);
 		self.key_by_name.insert(name, idx);
+		if let Some(path) = path {
+			self.key_by_path.insert(path, idx);
+		}
 		idx
 	}
 
 	pub fn remove(&mut self, key: MeshKey) -> Option<Mesh> {
 		let m = self.meshes.remove(key);
 		if let Some(m) = m.as_ref() {
-			self.key_by_name.remove(&m.name);
+			// Another mesh may have been inserted with the same name or path since
+			if self.key_by_name.get(&m.name) == Some(&key) {
+				self.key_by_name.remove(&m.name);
+			}
+			if let Some(path) = m.path.as_ref() {
+				if self.key_by_path.get(path) == Some(&key) {
+					self.key_by_path.remove(path);
+				}
+			}
+			// Free the buffers now instead of when the slot is reused
+			for bindings in self.vertex_bindings.values_mut() {
+				bindings.remove(key);
+			}
+			self.index_bindings.remove(key);
 		}
 		m
 	}
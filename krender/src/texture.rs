@@ -3,7 +3,7 @@ use image::DynamicImage;
 use parking_lot::RwLock;
 use serde::{Serialize, Deserialize};
 use slotmap::SlotMap;
-use crate::{TextureKey, MaterialKey, BindGroupKey, prelude::BindGroupManager, RenderContextKey, util::read_ron};
+use crate::{TextureKey, MaterialKey, BindGroupKey, prelude::BindGroupManager, RenderContextKey, util::read_ron, blit::Blitter};
 
 
 
@@ -320,6 +320,38 @@ impl Texture {
 		}	
 	}
 
+	/// An array of two-dimensional layers, given one after another. 
+	pub fn new_d2_array(
+		name: impl Into<String>, 
+		format: TextureFormat,
+		width: u32,
+		height: u32,
+		layers: u32,
+		data: Vec<u8>,
+	) -> Self {
+		assert_eq!((format.bytes_per_element() * width * height * layers) as usize, data.len(), "Texture data is the wrong size");
+		Self {
+			label: name.into(),
+			readable: false, writable: true, persistent: true, 
+
+			spec_path: None,
+			source: TextureDataSource::UserGenerated(None, wgpu::TextureViewDimension::D2Array),
+			data: Some(data),
+			format,
+			size: wgpu::Extent3d {
+				width, height, depth_or_array_layers: layers,
+			},
+
+			base_usages: wgpu::TextureUsages::COPY_DST,
+			derived_usages: RwLock::new(HashMap::new()),
+			bind_groups: RwLock::new(HashSet::new()),
+			mip_count: NonZeroU32::new(1).unwrap(),
+			dirty: AtomicBool::new(true),
+			binding: None,
+			queued_writes: Vec::new(),
+		}
+	}
+
 	/// A convenience method for loading a texture from "whatever.png". 
 	pub fn from_d2_path(
 		name: impl Into<String>, 
@@ -359,21 +391,24 @@ impl Texture {
 		self
 	}
 
+	/// Mips are made from the first level by [TextureManager::mip_unmipped]. 
 	pub fn with_mips(mut self, mip_count: u32) -> Self {
 		assert_ne!(0, mip_count);
-		if mip_count > 1 {
-			warn!("entering untested mip code stuff, ye be warned");
-			let missing_usages = (self.base_usages & wgpu::TextureUsages::TEXTURE_BINDING) ^ wgpu::TextureUsages::all();
-
-			if missing_usages == wgpu::TextureUsages::empty() {
-				warn!("Texture {} is missing usages required for mipmapping, adding usages {missing_usages:?}", self.label);
-				self.base_usages |= wgpu::TextureUsages::TEXTURE_BINDING;
-			}
-		}
 		self.mip_count = NonZeroU32::new(mip_count).unwrap();
 		self
 	}
 
+	// Enough mips for the current size to go down to a single texel
+	fn full_mip_count(&self) -> u32 {
+		32 - self.size.width.max(self.size.height).max(1).leading_zeros()
+	}
+
+	/// Enough mips to go down to a single texel. 
+	pub fn with_full_mips(self) -> Self {
+		let levels = self.full_mip_count();
+		self.with_mips(levels)
+	}
+
 	pub fn write(
 		&mut self, 
 		queue: &wgpu::Queue, 
@@ -429,6 +464,18 @@ impl Texture {
 		}
 	}
 
+	/// Sets enough mips to go down to a single texel at the current size. 
+	pub fn set_full_mips(&mut self) {
+		self.set_mip_count(self.full_mip_count());
+	}
+
+	/// Replaces the contents, which are loaded when the texture is next bound. 
+	pub fn set_data(&mut self, data: Vec<u8>) {
+		assert_eq!(self.total_bytes() as usize, data.len(), "Texture data is the wrong size");
+		self.data = Some(data);
+		self.dirty.store(true, Ordering::Relaxed);
+	}
+
 	pub fn set_size(&mut self, x: u32, y: u32, z: u32) {
 		let new_size = wgpu::Extent3d {
 			width: x, height: y, depth_or_array_layers: z,
@@ -473,9 +520,15 @@ impl Texture {
 	}
 
 	pub fn usages(&self) -> wgpu::TextureUsages {
+		// Mips are made by drawing each level from the last
+		let mip_usages = if self.mip_count.get() > 1 {
+			wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT
+		} else {
+			wgpu::TextureUsages::empty()
+		};
 		self.derived_usages.read().values()
 			.copied()
-			.fold(self.base_usages, |a, u| a | u)
+			.fold(self.base_usages | mip_usages, |a, u| a | u)
 	}
 
 	pub fn view(&self) -> Option<&wgpu::TextureView> {
@@ -485,7 +538,7 @@ impl Texture {
 	// Should mipping be done here? 
 	// Texture manager can store a blitter for each format, then pass here for encode
 	pub(crate) fn do_writes(&mut self, queue: &wgpu::Queue, _encoder: &mut wgpu::CommandEncoder) {
-		if let Some((t, _, _)) = self.binding.as_ref() {
+		if let Some((t, _, mipped)) = self.binding.as_mut() {
 			for (i, (mip_level, origin, data)) in self.queued_writes.drain(..).enumerate() {
 				debug!("Writing queued write {i} for texture '{}'", self.label);
 				let bytes_per_row = std::num::NonZeroU32::new(self.format.bytes_per_element() * self.size.width).and_then(|u| Some(u.get()));
@@ -506,6 +559,10 @@ impl Texture {
 					},
 					size,
 				);
+				// The other levels are made from the first
+				if mip_level == 0 && self.mip_count.get() > 1 {
+					*mipped = false;
+				}
 			}
 		} else {
 			warn!("Skipping update for texture '{}' because binding does not exist", self.label);
@@ -661,6 +718,8 @@ pub struct TextureManager {
 	textures: SlotMap<TextureKey, Texture>,
 	textures_by_name: HashMap<String, TextureKey>,
 	textures_by_path: HashMap<PathBuf, TextureKey>,
+	// Used to make mips, one for each format
+	blitters: HashMap<TextureFormat, Blitter>,
 }
 impl TextureManager {
 	pub fn new() -> Self {
@@ -668,6 +727,7 @@ impl TextureManager {
 			textures: SlotMap::with_key(),
 			textures_by_name: HashMap::new(),
 			textures_by_path: HashMap::new(),
+			blitters: HashMap::new(),
 		}
 	}
 
@@ -718,12 +778,46 @@ impl TextureManager {
 		}
 	}
 
-	// This function should take all unmipped textures and generate mips for them.
-	// Maybe it should take an encoder? Yes, it probably should.
-	// Perhaps boundtexture should have a function to decide how it is mipped based on its dimension?
-	/// Must be called after bind_unbound()
-	pub fn mip_unmipped(&mut self, _encoder: &mut wgpu::CommandEncoder) {
-		// Iterate through all entries, if loaded check if mipped yet
-		todo!()
+	/// Makes the mips of textures that have been bound or written to since they were last mipped. 
+	/// Each level is drawn from the one before it, for every layer. 
+	/// Must be called after [Self::update_bindings] and after the first level is written. 
+	pub fn mip_unmipped(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+		for texture in self.textures.values_mut() {
+			let Some((t, _, mipped)) = texture.binding.as_mut() else { continue };
+			if *mipped {
+				continue
+			}
+			*mipped = true;
+
+			if texture.source.dimension() != wgpu::TextureDimension::D2 {
+				warn!("Texture '{}' is not two-dimensional, so it can't be mipped", texture.label);
+				continue
+			}
+			// These can't be filtered or drawn to
+			if matches!(texture.format, TextureFormat::Depth32Float | TextureFormat::Rgba32Float) {
+				warn!("Texture '{}' has format {:?}, which can't be mipped", texture.label, texture.format);
+				continue
+			}
+
+			let format = texture.format.into();
+			let blitter = self.blitters.entry(texture.format)
+				.or_insert_with(|| Blitter::new(device, format));
+			let view = |level, layer| t.create_view(&wgpu::TextureViewDescriptor {
+				label: Some("mip view"),
+				format: Some(format),
+				dimension: Some(wgpu::TextureViewDimension::D2),
+				base_mip_level: level,
+				mip_level_count: Some(1),
+				base_array_layer: layer,
+				array_layer_count: Some(1),
+				..Default::default()
+			});
+			debug!("Make {} mips for {} layers of texture '{}'", t.mip_level_count() - 1, t.depth_or_array_layers(), texture.label);
+			for layer in 0..t.depth_or_array_layers() {
+				for level in 1..t.mip_level_count() {
+					blitter.blit(device, encoder, &view(level - 1, layer), &view(level, layer));
+				}
+			}
+		}
 	}
 }
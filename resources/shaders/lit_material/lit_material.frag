@@ -1,18 +1,21 @@
 #version 450
 
 layout(location = 0) in vec2 tc;
-layout(location = 1) in float light;
+layout(location = 1) in vec3 torchlight;
+layout(location = 2) in vec3 sunlight;
+layout(location = 3) in float occlusion;
 layout(location = 0) out vec4 outAttatchment0;
 
-layout(set = 0, binding = 1) uniform Time {
-	float time;
-};
-
 layout(set = 1, binding = 0) uniform texture2D ttexture;
 layout(set = 1, binding = 1) uniform sampler ssampler;
 
+// So that unlit places aren't completely black
+const float AMBIENT = 0.05;
+// How dark a fully occluded vertex is
+const float OCCLUSION_MIN = 0.35;
+
 void main() {
-    float adjusted_light = mix(1.0, light, (sin(time) + 1.0) / 2.0);
-    // float adjusted_light = light;
-    outAttatchment0 = vec4(texture(sampler2D(ttexture, ssampler), tc).rgb * adjusted_light, 1.0);
+    vec3 light = max(max(torchlight, sunlight), vec3(AMBIENT));
+    float ao = mix(OCCLUSION_MIN, 1.0, occlusion);
+    outAttatchment0 = vec4(texture(sampler2D(ttexture, ssampler), tc).rgb * light * ao, 1.0);
 }
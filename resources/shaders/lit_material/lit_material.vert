@@ -18,17 +18,21 @@ uniform Camera {
 
 // Vertex input
 layout(location = 0) in vec3 position;
-layout(location = 1) in float light_v;
-layout(location = 2) in vec2 tc_v;
+layout(location = 1) in vec3 torchlight_v;
+layout(location = 2) in vec3 sunlight_v;
+layout(location = 3) in float occlusion_v;
+layout(location = 4) in vec2 tc_v;
 
 // Instance input
-layout(location = 3) in vec4 model_matrix_0;
-layout(location = 4) in vec4 model_matrix_1;
-layout(location = 5) in vec4 model_matrix_2;
-layout(location = 6) in vec4 model_matrix_3;
+layout(location = 5) in vec4 model_matrix_0;
+layout(location = 6) in vec4 model_matrix_1;
+layout(location = 7) in vec4 model_matrix_2;
+layout(location = 8) in vec4 model_matrix_3;
 
 layout(location = 0) out vec2 tc_f;
-layout(location = 1) out float light_f;
+layout(location = 1) out vec3 torchlight_f;
+layout(location = 2) out vec3 sunlight_f;
+layout(location = 3) out float occlusion_f;
 
 void main() {
 	mat4 model_matrix = mat4(
@@ -42,5 +46,7 @@ void main() {
 
     gl_Position = mvp * vec4(position, 1.0);
 	tc_f = tc_v;
-	light_f = light_v;
+	torchlight_f = torchlight_v;
+	sunlight_f = sunlight_v;
+	occlusion_f = occlusion_v;
 }
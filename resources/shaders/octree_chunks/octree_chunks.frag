@@ -15,6 +15,10 @@ layout(set=0, binding=0) uniform Camera {
 } camera;
 
 
+// Must match CHUNK_SIZE
+const int CHUNK_SIZE = 32;
+
+
 layout(set=1, binding=0) uniform ChunkAcceleratorInfo {
 	uint extent;
 	uint chunk_st;
@@ -84,7 +88,7 @@ uint octant_offset(uint octant, uint leaf, uint node, uint leaf_size) {
 // Finds the content of an octree at some index with some position
 // could have out has_leaf to signify having hit something!
 uint octree_get(uint index, ivec3 vpos) {
-	bool in_bounds = (all(lessThanEqual(vpos, ivec3(15))) && all(greaterThanEqual(vpos, ivec3(0))));
+	bool in_bounds = (all(lessThanEqual(vpos, ivec3(CHUNK_SIZE - 1))) && all(greaterThanEqual(vpos, ivec3(0))));
 	if (!in_bounds) return 0;
 
 	// Read root
@@ -94,7 +98,7 @@ uint octree_get(uint index, ivec3 vpos) {
 	to_node(voxel_data.contents[index], content, node, leaf);
 
 	// Find next octant
-	uint hel = 16 / 2;
+	uint hel = CHUNK_SIZE / 2;
 	bvec3 octant_cmp = greaterThanEqual(vpos, ivec3(hel));
 	vpos -= ivec3(octant_cmp) * ivec3(hel);
 	uint octant = uint(octant_cmp.x) * 4 + uint(octant_cmp.y) * 2 + uint(octant_cmp.z);
@@ -135,10 +139,10 @@ uint octree_get(uint index, ivec3 vpos) {
 
 
 uint chunk_get(uint index, ivec3 vpos) {
-	bool in_bounds = (all(lessThanEqual(vpos, ivec3(15))) && all(greaterThanEqual(vpos, ivec3(0))));
+	bool in_bounds = (all(lessThanEqual(vpos, ivec3(CHUNK_SIZE - 1))) && all(greaterThanEqual(vpos, ivec3(0))));
 	if (!in_bounds) return 0;
 
-	index += vpos.x * 16 * 16 + vpos.y * 16 + vpos.z;
+	index += vpos.x * CHUNK_SIZE * CHUNK_SIZE + vpos.y * CHUNK_SIZE + vpos.z;
 	return voxel_data.contents[index];
 }
 
@@ -161,11 +165,11 @@ vec3 bl_ray(uint chunk_index, vec3 origin, vec3 direction, float tlimit, out uin
 	int iters = 0;
 	bvec3 dmask;
 	while (true) {
-		if (iters >= 50) return vec3(0.0, 0.86, 1.0); // Debug purple
+		if (iters >= CHUNK_SIZE * 3) return vec3(0.0, 0.86, 1.0); // Debug purple
 		if (t >= tlimit) return vec3(0.0, 0.0, 1.0); // Red
 		iters += 1;
 
-		bool inbounds = all(lessThanEqual(vpos, ivec3(15))) && all(greaterThanEqual(vpos, ivec3(0)));
+		bool inbounds = all(lessThanEqual(vpos, ivec3(CHUNK_SIZE - 1))) && all(greaterThanEqual(vpos, ivec3(0)));
 
 		uint v_content = octree_get(chunk_index, vpos);
 		// uint v_content = chunk_get(chunk_index, vpos);
@@ -195,7 +199,7 @@ vec3 hl_ray(vec3 origin, vec3 direction, float tlimit, out float hit, out uint i
 	index = 0;
 	t = 0.0;
 
-	float vscale = float(16);
+	float vscale = float(CHUNK_SIZE);
 	ivec3 vpos = ivec3(floor(origin / vscale));
 	ivec3 vstep = ivec3(sign(direction));
 	vec3 tdelta = abs(vec3(vscale / direction));
@@ -227,7 +231,7 @@ vec3 hl_ray(vec3 origin, vec3 direction, float tlimit, out float hit, out uint i
 			uint bl_index;
 			float bl_t;
 			vec3 bl_normal = normal;
-			vec3 bl_result = bl_ray(chunk_index, bl_origin, direction, min(tlimit - t, 28.0), bl_index, bl_t, bl_normal);
+			vec3 bl_result = bl_ray(chunk_index, bl_origin, direction, min(tlimit - t, float(CHUNK_SIZE) * 1.75), bl_index, bl_t, bl_normal);
 
 			if (bl_index != 0) {
 				float light_angle = acos(dot(bl_normal, normalize(vec3(3.0, 5.0, 7.0))));
@@ -248,7 +252,7 @@ vec3 hl_ray(vec3 origin, vec3 direction, float tlimit, out float hit, out uint i
 
 
 void main() {
-	vec3 origin = mod(vec3(camera.position), vec3(16.0));
+	vec3 origin = mod(vec3(camera.position), vec3(float(CHUNK_SIZE)));
 	// put uv in [-1, 1] space 
 	vec2 ss_uv = (uv * vec2(2.0) - vec2(1.0));
 	// Flip so that u is right and v is up
@@ -263,7 +267,7 @@ void main() {
 	uint index;
 	float t;
 	float hit;
-	vec3 proto_result = hl_ray(origin, direction, float(voxel_info.extent)*16.0/2.0, hit, index, t);
+	vec3 proto_result = hl_ray(origin, direction, float(voxel_info.extent * CHUNK_SIZE)/2.0, hit, index, t);
 	if (index == 0) {
 		discard;
 	}
@@ -0,0 +1,92 @@
+#version 450
+
+layout(set=0, binding=0)
+uniform Camera {
+	float near;
+	float far;
+	float fovy;
+	float aspect;
+	vec4 camera_position;
+	mat4 rotation;
+	mat4 view;
+	mat4 view_i;
+	mat4 projection;
+	mat4 projection_i;
+	mat4 view_projection;
+};
+
+// Six words for each quad, see meshing's packed.rs
+layout(set=2, binding=0) readonly buffer ChunkQuads {
+	uint quads[];
+};
+// Chunk positions by slot
+layout(set=2, binding=1) readonly buffer ChunkOrigins {
+	ivec4 origins[];
+};
+
+layout(location = 0) out vec2 tc_f;
+layout(location = 1) out vec3 torchlight_f;
+layout(location = 2) out vec3 sunlight_f;
+layout(location = 3) out float occlusion_f;
+layout(location = 4) flat out uint layer_f;
+
+const float CHUNK_SIZE = 32.0;
+
+// Which of the four corners each of the six vertices is
+// Positive, negative, positive flipped, negative flipped
+const uint INDICES[24] = uint[24](
+	0, 1, 2, 2, 3, 0,
+	0, 3, 2, 2, 1, 0,
+	1, 2, 3, 3, 0, 1,
+	1, 0, 3, 3, 2, 1
+);
+// Corners in (u, v)
+const vec2 CORNERS[4] = vec2[4](
+	vec2(0.0, 0.0),
+	vec2(1.0, 0.0),
+	vec2(1.0, 1.0),
+	vec2(0.0, 1.0)
+);
+
+vec3 channels(uint v) {
+	return vec3(v & 31u, (v >> 5) & 31u, (v >> 10) & 31u) / 31.0;
+}
+
+void main() {
+	uint quad = uint(gl_VertexIndex) / 6u;
+	uint i = uint(gl_VertexIndex) % 6u;
+	uint w0 = quads[quad * 6u];
+	uint w1 = quads[quad * 6u + 1u];
+
+	vec3 position = vec3(w0 & 63u, (w0 >> 6) & 63u, (w0 >> 12) & 63u);
+	uint direction = (w0 >> 18) & 7u;
+	vec2 extent = vec2(((w0 >> 21) & 31u) + 1u, ((w0 >> 26) & 31u) + 1u);
+	uint slot = w1 & 65535u;
+	uint layer = (w1 >> 16) & 32767u;
+	bool flipped = (w1 >> 31) != 0u;
+
+	uint corner = INDICES[((flipped ? 2u : 0u) + (direction & 1u)) * 6u + i];
+	vec2 uv = CORNERS[corner] * extent;
+
+	// u and v axes are z and y for x quads, x and z for y quads, and x and y for z quads
+	uint axis = direction >> 1;
+	vec3 offset;
+	if (axis == 0u) {
+		offset = vec3(0.0, uv.y, uv.x);
+	} else if (axis == 1u) {
+		offset = vec3(uv.x, 0.0, uv.y);
+	} else {
+		offset = vec3(uv.x, uv.y, 0.0);
+	}
+	vec3 world_position = vec3(origins[slot].xyz) * CHUNK_SIZE + position + offset;
+	gl_Position = view_projection * vec4(world_position, 1.0);
+
+	// Textures repeat across merged quads
+	tc_f = vec2(uv.x, extent.y - uv.y);
+	layer_f = layer;
+
+	uint shade = quads[quad * 6u + 2u + corner];
+	torchlight_f = channels(shade);
+	sunlight_f = channels(shade >> 15);
+	occlusion_f = float((shade >> 30) & 3u) / 3.0;
+}
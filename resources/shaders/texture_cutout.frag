@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec2 tc;
+layout(location = 0) out vec4 outAttatchment0;
+
+layout(set = 1, binding = 0) uniform texture2D ttexture;
+layout(set = 1, binding = 1) uniform sampler ssampler;
+
+void main() {
+    vec4 colour = texture(sampler2D(ttexture, ssampler), tc);
+    // Cutout blocks are either fully there or not there at all
+    if (colour.a < 0.5) {
+        discard;
+    }
+    outAttatchment0 = vec4(colour.rgb, 1.0);
+}
@@ -0,0 +1,24 @@
+#version 450
+
+layout(location = 0) in vec2 tc;
+layout(location = 1) in vec3 torchlight;
+layout(location = 2) in vec3 sunlight;
+layout(location = 3) in float occlusion;
+layout(location = 4) flat in uint layer;
+layout(location = 0) out vec4 outAttatchment0;
+
+// Block face textures, see chunks' textures.rs
+layout(set = 1, binding = 0) uniform texture2DArray ttexture;
+layout(set = 1, binding = 1) uniform sampler ssampler;
+
+// So that unlit places aren't completely black
+const float AMBIENT = 0.05;
+// How dark a fully occluded vertex is
+const float OCCLUSION_MIN = 0.35;
+
+void main() {
+    vec4 colour = texture(sampler2DArray(ttexture, ssampler), vec3(tc, layer));
+    vec3 light = max(max(torchlight, sunlight), vec3(AMBIENT));
+    float ao = mix(OCCLUSION_MIN, 1.0, occlusion);
+    outAttatchment0 = vec4(colour.rgb * light * ao, colour.a);
+}
@@ -65,6 +65,13 @@ impl GameInstance {
 		self.extensions.run(&mut self.world, "client_tick").unwrap();
 	}
 
+	/// Lets extensions save things before the world is dropped. 
+	pub fn shutdown(&mut self) {
+		if let Err(e) = self.extensions.run(&mut self.world, "client_shutdown") {
+			warn!("Error running 'client_shutdown': {}", e);
+		}
+	}
+
 	// Borrow checker is angry if we try to do this outside of self
 	pub fn reload_extensions(&mut self) {
 		self.extensions.reload(&mut self.world, |_| {}).unwrap();
@@ -753,6 +753,7 @@ impl WindowManager {
 						// It may be wise to do per-window shutdown code here
 						info!("Closing a window");
 					}
+					s.client.lock().shutdown();
 				},
 				_ => {},
 			}